@@ -1,20 +1,10 @@
 fn main() {
 
-    let mmal_lib_dir = locate_dir("MMAL_LIB_DIR", "/opt/vc/lib", "MMAL libraries");
-
-    // Tell cargo to tell rustc to link the system shared libraries.
-    println!("cargo:rustc-link-lib=mmal_util");
-    println!("cargo:rustc-link-lib=mmal_core");
-    println!("cargo:rustc-link-lib=mmal_vc_client");
-    println!("cargo:rustc-link-lib=vcos");
-    println!("cargo:rustc-link-lib=bcm_host");
-    println!("cargo:rustc-link-lib=vchiq_arm");
-    println!("cargo:rustc-link-lib=vcsm");
     println!("cargo:rerun-if-env-changed=HOST");
     println!("cargo:rerun-if-env-changed=TARGET");
     println!("cargo:rerun-if-env-changed=MMAL_INCLUDE_DIR");
     println!("cargo:rerun-if-env-changed=MMAL_LIB_DIR");
-    println!("cargo:rustc-link-search=native={}", mmal_lib_dir);
+    link_libraries();
 
     /*
     println!("=======================================================");
@@ -26,9 +16,46 @@ fn main() {
     generate_bindings();
 }
 
-#[cfg(not(feature = "generate_bindings"))]
+#[cfg(not(any(feature = "emulation", feature = "dynamic-loading")))]
+fn link_libraries() {
+    let mmal_lib_dir = locate_dir("MMAL_LIB_DIR", "/opt/vc/lib", "MMAL libraries");
+
+    // Tell cargo to tell rustc to link the system shared libraries.
+    println!("cargo:rustc-link-lib=mmal_util");
+    println!("cargo:rustc-link-lib=mmal_core");
+    println!("cargo:rustc-link-lib=mmal_vc_client");
+    println!("cargo:rustc-link-lib=vcos");
+    println!("cargo:rustc-link-lib=bcm_host");
+    println!("cargo:rustc-link-lib=vchiq_arm");
+    println!("cargo:rustc-link-lib=vcsm");
+    println!("cargo:rustc-link-search=native={}", mmal_lib_dir);
+}
+
+// The emulation provides the ffi symbols itself, nothing to link against
+#[cfg(feature = "emulation")]
+fn link_libraries() { }
+
+// MMAL libraries are opened at runtime, only dlopen() itself is needed
+#[cfg(all(feature = "dynamic-loading", not(feature = "emulation")))]
+fn link_libraries() {
+    println!("cargo:rustc-link-lib=dl");
+}
+
+#[cfg(all(not(feature = "generate_bindings"), not(feature = "emulation"), not(feature = "dynamic-loading")))]
 pub fn generate_bindings() { }
 
+/// The bundled bindings are used as is, unless they need to be rewritten for emulation or dynamic loading.
+/// They were generated for 32-bit ARM, so their layout tests are restricted to ARM targets, as the
+/// features are typically used on other hosts.
+#[cfg(all(not(feature = "generate_bindings"), any(feature = "emulation", feature = "dynamic-loading")))]
+pub fn generate_bindings() {
+    println!("cargo:rerun-if-changed=src/bindings.rs");
+    let bindings = std::fs::read_to_string("src/bindings.rs")
+        .expect("Couldn't read bundled bindings")
+        .replace("#[test]\nfn bindgen_test_layout_", "#[cfg(target_arch = \"arm\")]\n#[test]\nfn bindgen_test_layout_");
+    write_bindings(bindings);
+}
+
 #[cfg(feature = "generate_bindings")]
 pub fn generate_bindings() { 
 
@@ -83,14 +110,128 @@ pub fn generate_bindings() {
         // Unwrap the Result and panic on failure.
         .expect("Unable to generate bindings");
 
-    // Write the bindings to the $OUT_DIR/bindings.rs file.
+    write_bindings(bindings.to_string());
+}
+
+/// Writes the bindings to the $OUT_DIR/bindings.rs file.
+#[cfg(any(feature = "generate_bindings", feature = "emulation", feature = "dynamic-loading"))]
+fn write_bindings(bindings: String) {
+    #[cfg(all(feature = "dynamic-loading", not(feature = "emulation")))]
+    let bindings = dynamic_loading::rewrite_extern_blocks(&bindings);
     let out_path = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
-    bindings
-        .write_to_file(out_path.join("bindings.rs"))
+    std::fs::write(out_path.join("bindings.rs"), bindings)
         .expect("Couldn't write bindings!");
 }
 
+/// Replaces the `extern "C"` function declarations of the bindings with wrappers, calling the functions
+/// through pointers resolved at runtime by `crate::dynamic_loading`. Variadic functions can't be
+/// wrapped and are dropped.
+#[cfg(all(feature = "dynamic-loading", not(feature = "emulation")))]
+mod dynamic_loading {
+    const EXTERN_BLOCK: &str = "extern \"C\" {\n";
+
+    pub fn rewrite_extern_blocks(bindings: &str) -> String {
+        let mut out = String::with_capacity(bindings.len());
+        let mut rest = bindings;
+        while let Some(start) = rest.find(EXTERN_BLOCK) {
+            out.push_str(&rest[..start]);
+            rest = &rest[start + EXTERN_BLOCK.len()..];
+            let end = rest.find("\n}\n").expect("unterminated extern block");
+            for item in split_top_level(&rest[..end], ';') {
+                rewrite_item(item, &mut out);
+            }
+            rest = &rest[end + 3..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn rewrite_item(item: &str, out: &mut String) {
+        let item = item.trim();
+        if item.is_empty() {
+            return;
+        }
+        let fn_pos = item.find("pub fn ").unwrap_or_else(|| panic!("unsupported extern item: {}", item));
+        let (attrs, decl) = item.split_at(fn_pos);
+        let decl = &decl["pub fn ".len()..];
+        let open = decl.find('(').unwrap();
+        let name = decl[..open].trim();
+        let close = matching_paren(decl, open);
+        let args: Vec<(&str, &str)> = split_top_level(&decl[open + 1..close], ',')
+            .into_iter()
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(|a| a.split_once(':').map(|(n, t)| (n.trim(), t.trim())).unwrap_or((a, "")))
+            .collect();
+        if args.iter().any(|(n, _)| *n == "...") {
+            return;
+        }
+        let ret = decl[close + 1..].trim();
+        let arg_decls = args.iter().map(|(n, t)| format!("{}: {}", n, t)).collect::<Vec<_>>().join(", ");
+        let arg_types = args.iter().map(|(_, t)| *t).collect::<Vec<_>>().join(", ");
+        let arg_names = args.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ");
+        let ret = if ret.is_empty() { String::new() } else { format!(" {}", ret) };
+
+        for a in attrs.lines().map(str::trim).filter(|a| !a.is_empty()) {
+            out.push_str(a);
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]\n\
+            pub unsafe fn {name}({arg_decls}){ret} {{\n\
+            \x20   static SYMBOL: crate::dynamic_loading::Symbol = crate::dynamic_loading::Symbol::new(\"{name}\\0\");\n\
+            \x20   let f: unsafe extern \"C\" fn({arg_types}){ret} = ::std::mem::transmute(SYMBOL.get());\n\
+            \x20   f({arg_names})\n\
+            }}\n"
+        ));
+    }
+
+    /// Splits on `sep` outside of string literals, parentheses and angle brackets
+    fn split_top_level(s: &str, sep: char) -> Vec<&str> {
+        let mut parts = Vec::new();
+        let (mut depth, mut start) = (0i32, 0);
+        let (mut prev, mut in_str) = (' ', false);
+        for (i, c) in s.char_indices() {
+            if in_str {
+                in_str = !(c == '"' && prev != '\\');
+                prev = if c == '\\' && prev == '\\' { ' ' } else { c };
+                continue;
+            }
+            match c {
+                '"' => in_str = true,
+                '(' | '<' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                '>' if prev != '-' => depth -= 1,
+                c if c == sep && depth == 0 => {
+                    parts.push(&s[start..i]);
+                    start = i + 1;
+                }
+                _ => { }
+            }
+            prev = c;
+        }
+        parts.push(&s[start..]);
+        parts
+    }
+
+    fn matching_paren(s: &str, open: usize) -> usize {
+        let mut depth = 0;
+        for (i, c) in s[open..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 { return open + i }
+                }
+                _ => { }
+            }
+        }
+        panic!("unbalanced parentheses in {}", s)
+    }
+}
+
 
+#[cfg(any(feature = "generate_bindings", not(any(feature = "emulation", feature = "dynamic-loading"))))]
 fn locate_dir(varname: &str, default_path: &str, descr: &str) -> String {
     let path = if let Ok(env_path) = std::env::var(varname) {
         env_path
@@ -1,7 +1,8 @@
 
-use std::{pin::Pin, sync::Arc, fmt};
+use std::{pin::Pin, fmt};
 use mmal_rs::{*, ffi::MMAL_ENCODING_MJPEG};
-use tokio::{net::{TcpListener, TcpStream}, sync::broadcast, io::{AsyncReadExt,AsyncWriteExt}};
+use tokio::net::TcpListener;
+use futures_util::StreamExt;
 
 use log::{debug, info, error};
 
@@ -36,8 +37,8 @@ type Result<T> = std::result::Result<T, Error>;
 
 struct Settings {
     max_frame_count: usize,
-    initial_frame_buffer_len: usize,
-    client_hs_buffer_len: usize, 
+    max_clients: usize,
+    max_fps: Option<f64>,
     stats_period: usize,
     bind_addr: String,
     bitrate: u32,
@@ -49,8 +50,8 @@ impl Default for Settings {
     fn default() -> Self {
         Self { 
             max_frame_count: 0, 
-            initial_frame_buffer_len: 100_000, 
-            client_hs_buffer_len: 1_000,
+            max_clients: 16,
+            max_fps: None,
             stats_period: 1_000, 
             bind_addr: "0.0.0.0:9990".to_owned(),
             bitrate: 1_000_000,
@@ -147,85 +148,45 @@ impl VideoCamera {
         Ok(Self { camera, _encoder: encoder, connection, encoder_sink, settings })
     }
 
-    async fn run_server(mut stream: TcpStream, mut rx: broadcast::Receiver<Arc<Vec<u8>>>, client_hs_buffer_len: usize) -> Result<()> {
-        let mut client_hs = vec![0u8; client_hs_buffer_len];
-        let client_hs_len = stream.read(&mut client_hs).await?;
-        debug!("client hs: {}", String::from_utf8_lossy(&client_hs[0..client_hs_len]));
-
-        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace;boundary=MJPEGBOUNDARY\r\n").await?;
-
-        loop {
-            let payload = match rx.recv().await {
-                Ok(payload) => payload,
-                Err(broadcast::error::RecvError::Closed) => {
-                    info!("{}: disconnect", stream.peer_addr()?);
-                    return Ok(());
-                }
-                Err(broadcast::error::RecvError::Lagged(n)) => {
-                    debug!("{}: lagged by {}", stream.peer_addr()?, n);
-                    continue;
-                }
-            };
-
-            let header = format!("\r\n--MJPEGBOUNDARY\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Timestamp: 0.000000\r\n\r\n", payload.len());
-            stream.write_all(header.as_bytes()).await?;
-            stream.write_all(payload.as_ref()).await?;
-        }
-    }
-
     async fn stream(&mut self) -> Result<()> {
         let listener = TcpListener::bind(&self.settings.bind_addr).await?;
-        let (tx, _) = broadcast::channel(3);
+        let broadcaster = MjpegBroadcaster::new(self.settings.max_clients);
+        let mut server = MjpegServer::new(broadcaster.clone());
+        if let Some(max_fps) = self.settings.max_fps {
+            server = server.with_max_fps(max_fps);
+        }
+        info!("Streaming at http://{}/stream, snapshots at /snapshot", listener.local_addr()?);
+        tokio::spawn(async move { if let Err(e) = server.serve(listener).await {
+            error!("Server error: {}", e);
+        } });
 
         let mut count = 0usize;
         let mut total = 0usize;
         let mut last;
         let mut average;
 
-        let mut frame = Vec::with_capacity(self.settings.initial_frame_buffer_len);
-
         self.encoder_sink.feed_all()?;
         CameraVideoPort::write(&self.camera, &PCaptureVideo::from(true))?;
-
-        loop {
-            tokio::select! {
-                sa = listener.accept() => {
-                    let (stream, addr) = sa?;
-                    info!("Connection from {}", addr);
-                    let rx = tx.subscribe();
-                    let client_hs_buffer_len = self.settings.client_hs_buffer_len;
-                    tokio::spawn(async move { if let Err(e) = Self::run_server(stream, rx, client_hs_buffer_len).await {
-                        error!("Server error: {}", e);
-                    } });
-                }
-            
-                buffer = &mut self.encoder_sink => {
-                    let (_, is_terminal) = self.encoder_sink.consume(buffer, |flags, payload|{
-                        frame.extend_from_slice(payload);
-                        Ok((true, flags.is_terminal_frame()))
-                    })?;
-                    if is_terminal {
-                        count += 1;
-                        if self.settings.stats_period > 0 {
-                            last = frame.len();
-
-                            total += last;
-                            average = total / count;
-                            if count % self.settings.stats_period == 0 {
-                                let clients = tx.receiver_count();
-                                debug!("avg={average}(last={last}) total={total}/count={count} clients={clients}");
-                            }
-                        }
-
-                        if self.settings.max_frame_count > 0 && count >= self.settings.max_frame_count {
-                            break
-                        }
-                        let mut payload = Vec::with_capacity(self.settings.initial_frame_buffer_len);
-                        std::mem::swap(&mut payload, &mut frame);
-                        let _ = tx.send(Arc::new(payload));                     
-                    }
+        let mut frames = self.encoder_sink.frames();
+
+        while let Some(frame) = frames.next().await {
+            let frame = frame?;
+            count += 1;
+            if self.settings.stats_period > 0 {
+                last = frame.data.len();
+
+                total += last;
+                average = total / count;
+                if count.is_multiple_of(self.settings.stats_period) {
+                    let clients = broadcaster.clients();
+                    debug!("avg={average}(last={last}) total={total}/count={count} clients={clients}");
                 }
             }
+
+            if self.settings.max_frame_count > 0 && count >= self.settings.max_frame_count {
+                break
+            }
+            broadcaster.publish_frame(frame);
         }
         Ok(())
     }
@@ -241,7 +202,7 @@ impl Drop for VideoCamera {
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()>{
     env_logger::init();
-    mmal_rs::init();
+    mmal_rs::init()?;
 
     let mut settings = Settings::default();
 
@@ -257,6 +218,8 @@ async fn main() -> Result<()>{
             "-f" | "--frame-rate" => settings.frame_rate = a.parse().expect("expected an uint next to --frame-rate"),
             "-B" | "--bitrate" => settings.bitrate = a.parse().expect("expected an uint next to --bitrate"),
             "-s" | "--shutter-speed" => settings.shutter_speed = a.parse().expect("expected an uint next to --shutter-speed"),
+            "-m" | "--max-clients" => settings.max_clients = a.parse().expect("expected an uint next to --max-clients"),
+            "-F" | "--max-fps" => settings.max_fps = Some(a.parse().expect("expected a number next to --max-fps")),
             _ => panic!("invalid command line arg: `{s}`")
         }
         None
@@ -273,6 +236,8 @@ Options are:
 --frame-rate|-f <uint>          Camera frame rate (default {f})
 --bitrate|-B <uint>             Encoder output bitrate (default {B})
 --shutter-speed|-s <uint>       Camera shutter speed in microseconds (default {s})
+--max-clients|-m <uint>         Maximum number of simultaneous stream clients (default {m})
+--max-fps|-F <number>           Maximum frame rate sent to each client, clients can ask for less with `?fps=` (default: unlimited)
 --help|-h                       Print this help message and exit
 "#,
 b=ss.bind_addr,
@@ -280,6 +245,7 @@ c=ss.max_frame_count,
 f=ss.frame_rate,
 B=ss.bitrate,
 s=ss.shutter_speed,
+m=ss.max_clients,
 );
                 std::process::exit(0);
             }
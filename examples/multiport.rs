@@ -119,6 +119,6 @@ pub fn service() -> Result<()> {
 
 fn main() -> Result<()>{
     env_logger::init();
-    mmal_rs::init();
+    mmal_rs::init()?;
     service()
 }
\ No newline at end of file
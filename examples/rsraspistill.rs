@@ -37,7 +37,7 @@ struct StillCamera {
 
 impl StillCamera {
 
-    fn create_camera(selected_camera: &CameraInstanceInfo) -> Result<ComponentEnabler<CameraEntity>> {
+    fn create_camera(selected_camera: &CameraInstanceInfo, raw: bool) -> Result<ComponentEnabler<CameraEntity>> {
         let camera = CameraComponentHandle::create()?;
         let camera_num = PCameraNum::from(0);
         let camera_config = PCameraConfig::from(CameraConfig::from_instance_info(selected_camera));
@@ -60,23 +60,22 @@ impl StillCamera {
         CameraControlPort::write_multi(&camera, 
             param_iter![&camera_num, &camera_config, &camera_shutter_speed, &annotate_p])?;    
         CameraCapturePort::configure(&camera, CAMERA_PORT_CONFIG_320X240)?;
+        CameraCapturePort::write(&camera, &PEnableRawCapture::from(raw))?;
         ComponentEnabler::new(camera)
     }
 
-    fn create_encoder() -> Result<ComponentEnabler<EncoderEntity>> {
+    fn create_encoder(encoding: ImageEncoding) -> Result<ComponentEnabler<EncoderEntity>> {
         let encoder = EncoderComponentHandle::create()?;
     
-        EncoderOutputPort::configure(&encoder, EncoderOutFormat::default())?;
-        EncoderOutputPort::write(&encoder, &JpegQFactor::from(90))?;
-        EncoderOutputPort::write(&encoder, &JpegRestartInterval::from(0))?;
+        encoding.configure(&encoder)?;
         ComponentEnabler::new(encoder)
     }
 
-    fn create() -> Result<Self> {
+    fn create(encoding: ImageEncoding, raw: bool) -> Result<Self> {
         let selected_camera = select_camera(true)?;
 
-        let camera = Self::create_camera(&selected_camera)?;
-        let encoder = Self::create_encoder()?;
+        let camera = Self::create_camera(&selected_camera, raw)?;
+        let encoder = Self::create_encoder(encoding)?;
 
         let connection = 
             ConnectionHandle::<CameraCapturePort, EncoderInputPort>::create(&camera, &encoder)?;
@@ -88,9 +87,10 @@ impl StillCamera {
         Ok(Self { camera, _encoder: encoder, connection, encoder_sink })
     } 
 
-    fn take_one_shot<P: AsRef<Path>>(&self, output_file: P) -> Result<()> {
+    fn take_one_shot<P: AsRef<Path>>(&self, output_file: P, raw: bool) -> Result<()> {
 
-        let mut file_out = std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(output_file).unwrap();
+        let mut file_out = std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(&output_file).unwrap();
+        let mut image = Vec::new();
 
         let start = std::time::Instant::now();
 
@@ -101,12 +101,21 @@ impl StillCamera {
             let (_, is_terminal) = self.encoder_sink.consume(b, |flags, payload|{
                 println!("rec'd {}", payload.len());
                 file_out.write_all(payload).unwrap();
+                if raw {
+                    image.extend_from_slice(payload);
+                }
                 Ok((true, flags.is_terminal_frame()))
             })?;
             if is_terminal { break }
         }
         println!("time: {:?}", std::time::Instant::now()-start);
 
+        if raw {
+            let bayer = BayerRaw::find(&image)?;
+            println!("raw: {} {}x{} {:?} {} bits", bayer.sensor, bayer.width, bayer.height, bayer.order, bayer.bits);
+            let dng = std::fs::File::create(output_file.as_ref().with_extension("dng"))?;
+            DngWriter::new().write(&bayer, std::io::BufWriter::new(dng))?;
+        }
         Ok(())
     }
 }
@@ -158,20 +167,18 @@ impl VideoCamera {
         ComponentEnabler::new(camera)
     }
 
-    fn create_encoder() -> Result<ComponentEnabler<EncoderEntity>> {
+    fn create_encoder(encoding: ImageEncoding) -> Result<ComponentEnabler<EncoderEntity>> {
         let encoder = EncoderComponentHandle::create()?;
     
-        EncoderOutputPort::configure(&encoder, EncoderOutFormat::default())?;
-        EncoderOutputPort::write(&encoder, &JpegQFactor::from(90))?;
-        EncoderOutputPort::write(&encoder, &JpegRestartInterval::from(0))?;
+        encoding.configure(&encoder)?;
         println!("encoder buffers: {:?}", EncoderOutputPort::get_buffers_config(&encoder));
         ComponentEnabler::new(encoder)    
     }
 
-    fn create() -> Result<Self> {
+    fn create(encoding: ImageEncoding) -> Result<Self> {
         let camera = Self::create_camera()?;
 
-        let encoder = Self::create_encoder()?;
+        let encoder = Self::create_encoder(encoding)?;
     
         let connection = 
             ConnectionHandle::<CameraVideoPort, EncoderInputPort>::create(&camera, &encoder)?;
@@ -244,22 +251,26 @@ fn test_add_fn() {
 
 fn main() -> Result<()>{
     env_logger::init();
-    mmal_rs::init();
+    mmal_rs::init()?;
 
     let mut use_video = false;
+    let mut raw = false;
     let mut stills_count = 10;
-    let mut output_file = "/var/tmp/f.jpg".to_owned();
+    let mut output_file = None;
+    let mut encoding = ImageEncoding::default();
 
     let rmdr: Option<String> = std::env::args().skip(1).fold(None, |s, a| if let Some(s) = s {
         match s.as_ref() {
             "-c" | "--count" => stills_count = a.parse().expect("expected an uint next to --count"),
-            "-o" | "--output-file" => output_file = a,
+            "-o" | "--output-file" => output_file = Some(a),
+            "-e" | "--encoding" => encoding = a.parse().expect("expected one of jpg, png, gif, bmp, tga, ppm next to --encoding"),
             _ => panic!("invalid command line arg: `{s}`")
         }
         None
     } else {
         match a.as_ref() {
             "-v" | "--video" => use_video = true,
+            "-r" | "--raw" => raw = true,
             _ => return Some(a)
         }
         None
@@ -268,13 +279,15 @@ fn main() -> Result<()>{
         panic!("Invalid command line syntax at EOL: `{w}`")
     }
 
-    println!("use_video={use_video}");
+    let output_file = output_file.unwrap_or_else(|| format!("/var/tmp/f.{}", encoding.extension()));
+
+    println!("use_video={use_video} encoding={encoding}");
 
     if use_video {
-        let cam = VideoCamera::create()?;
+        let cam = VideoCamera::create(encoding)?;
         cam.stream(output_file, stills_count)
     } else {
-        let cam = StillCamera::create()?;
-        cam.take_one_shot(output_file)
+        let cam = StillCamera::create(encoding, raw)?;
+        cam.take_one_shot(output_file, raw)
     }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 
-use std::{io::Write, pin::Pin};
+use std::io::Write;
 use mmal_rs::{*, ffi::{MMAL_ENCODING_MJPEG, MMAL_ENCODING_H264}};
 
 use log::error;
@@ -26,15 +26,22 @@ fn wr<R>(l: &str, r: Result<R>) {
     }
 }
 
+/// Raw elementary stream, fragmented MP4 for `*.mp4`, MPEG-TS for `*.ts` or an HLS stream for `*/index.m3u8` outputs
+enum Output {
+    Raw(std::fs::File),
+    Mp4(Box<Fmp4Writer<std::io::BufWriter<std::fs::File>>>, FrameAssembler),
+    Ts(Box<TsWriter<std::io::BufWriter<std::fs::File>>>),
+    Hls(Box<HlsWriter>),
+}
+
 struct VideoCamera {
-    camera: ComponentEnabler<CameraEntity>,
-    _encoder: ComponentEnabler<VideoEncoderEntity>,
-    connection: ConnectionHandle<CameraVideoPort, VideoEncoderInputPort>,
-    encoder_sink: Pin<Box<SinkAggregate<VideoEncoderOutputPort>>>,
+    pipeline: Pipeline,
+    camera: Node<CameraEntity>,
+    encoder_sink: Sink<VideoEncoderOutputPort>,
 }
 
 impl VideoCamera {
-    fn create_camera() -> Result<ComponentEnabler<CameraEntity>> {
+    fn create_camera() -> Result<CameraComponentHandle> {
         let selected_camera = select_camera(true)?;
 
         let camera = CameraComponentHandle::create()?;
@@ -62,10 +69,10 @@ impl VideoCamera {
         vcfg.buffer_count_policy = BufferCountPolicy::Recommended;
         CameraVideoPort::configure(&camera, vcfg)?;
         println!("video buffers: {:?}", CameraVideoPort::get_buffers_config(&camera));
-        ComponentEnabler::new(camera)
+        Ok(camera)
     }
 
-    fn create_encoder(enc: u32) -> Result<ComponentEnabler<VideoEncoderEntity>> {
+    fn create_encoder(enc: u32) -> Result<VideoEncoderComponentHandle> {
         let encoder = VideoEncoderComponentHandle::create()?;
 
         match enc {
@@ -81,35 +88,43 @@ impl VideoCamera {
         
                 let p_video_profile = PVideoProfile::from((VideoProfile::H264Baseline, VideoLevel::H264_4));
                 VideoEncoderOutputPort::write(&encoder, &p_video_profile)?;
+                // an IDR frame with SPS/PPS every second, so that players can join mid-stream
+                VideoEncoderOutputPort::write_multi(&encoder, param_iter![&PIntraPeriod::from(10), &PInlineHeader::from(true)])?;
             }
             _ => panic!("Unsupported encoding")
         }
 
         println!("encoder buffers: {:?}", VideoEncoderOutputPort::get_buffers_config(&encoder));
-        ComponentEnabler::new(encoder)    
+        Ok(encoder)
     }
 
     fn create(encoding: u32) -> Result<Self> {
-        let camera = Self::create_camera()?;
-
-        let encoder = Self::create_encoder(encoding)?;
-    
-        let connection = 
-            ConnectionHandle::<CameraVideoPort, VideoEncoderInputPort>::create(&camera, &encoder)?;
-        connection.enable()?;
-
-        let encoder_sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone())?;
-        encoder_sink.enable()?;
-
-        Ok(Self { camera, _encoder: encoder, connection, encoder_sink })
+        let mut builder = PipelineBuilder::new();
+        let camera = builder.node(Self::create_camera()?);
+        let encoder = builder.node(Self::create_encoder(encoding)?);
+        builder.connect::<CameraVideoPort, VideoEncoderInputPort>(camera, encoder);
+        let encoder_sink = builder.sink::<VideoEncoderOutputPort>(encoder);
+        let pipeline = builder.build()?;
+
+        Ok(Self { pipeline, camera, encoder_sink })
     } 
 
     fn stream(&self, output_file: String, max_frames: usize) -> Result<()> {
-        let mut file_out = std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(output_file).unwrap();
+        let mut out = if output_file.ends_with(".mp4") {
+            Output::Mp4(Box::new(Fmp4Writer::create(&output_file)?), FrameAssembler::new())
+        } else if output_file.ends_with(".ts") {
+            Output::Ts(Box::new(TsWriter::create(&output_file)?))
+        } else if output_file.ends_with(HLS_PLAYLIST) {
+            let dir = std::path::Path::new(&output_file).parent().unwrap_or(std::path::Path::new("."));
+            Output::Hls(Box::new(HlsWriter::create(dir)?))
+        } else {
+            Output::Raw(std::fs::OpenOptions::new().write(true).create(true).truncate(true).open(output_file).unwrap())
+        };
         let start = std::time::Instant::now();
 
-        self.encoder_sink.feed_all()?;
-        CameraVideoPort::write(&self.camera, &PCaptureVideo::from(true))?;
+        let camera = self.pipeline.component(self.camera);
+        let encoder_sink = self.pipeline.sink(self.encoder_sink);
+        CameraVideoPort::write(camera, &PCaptureVideo::from(true))?;
 
         let mut count = 0usize;
         let mut total = 0usize;
@@ -117,11 +132,18 @@ impl VideoCamera {
         let mut average;
         let mut frame = 0usize;
     
-        while let Some(b) = self.encoder_sink.timedwait(5000) {
-            let (_, is_terminal) = self.encoder_sink.consume(b, |flags, payload|{
-                frame += payload.len();
-                file_out.write_all(payload).unwrap();
-                Ok((true, flags.is_terminal_frame()))
+        while let Some(b) = encoder_sink.timedwait(5000) {
+            let (_, is_terminal) = encoder_sink.consume_view(b, |view|{
+                frame += view.data.len();
+                match &mut out {
+                    Output::Raw(file_out) => file_out.write_all(view.data).unwrap(),
+                    Output::Mp4(writer, assembler) => if let Some(f) = assembler.push(view) {
+                        writer.write_frame(&f)?
+                    },
+                    Output::Ts(writer) => writer.write_view(view)?,
+                    Output::Hls(writer) => writer.write_view(view)?,
+                }
+                Ok((true, view.meta.flags.is_terminal_frame()))
             })?;
             if is_terminal {
                 count += 1;
@@ -140,20 +162,26 @@ impl VideoCamera {
             }
         }
         println!("time: {:?}", std::time::Instant::now()-start);
+        CameraVideoPort::write(camera, &PCaptureVideo::from(false))?;
+        match out {
+            Output::Raw(_) => { }
+            Output::Mp4(writer, _) => { writer.finish()?; }
+            Output::Ts(writer) => { writer.finish()?; }
+            Output::Hls(writer) => { writer.finish()?; }
+        }
         Ok(())
     }
 }
 
 impl Drop for VideoCamera {
     fn drop(&mut self) {
-        wr("encoder_sink.disable", self.encoder_sink.disable());
-        wr("capture_connection.disable", self.connection.disable());
+        wr("pipeline.stop", self.pipeline.stop());
     }
 }
 
 fn main() -> Result<()>{
     env_logger::init();
-    mmal_rs::init();
+    mmal_rs::init()?;
 
     let mut encoding = MMAL_ENCODING_H264;
     let mut frame_count = 10;
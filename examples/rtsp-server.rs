@@ -0,0 +1,196 @@
+use mmal_rs::*;
+
+use log::{debug, error, info};
+
+struct Settings {
+    max_frame_count: usize,
+    stats_period: usize,
+    bind_addr: String,
+    bitrate: u32,
+    frame_rate: i32,
+    intra_period: u32,
+    shutter_speed: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            max_frame_count: 0,
+            stats_period: 1_000,
+            bind_addr: format!("0.0.0.0:{RTSP_DEFAULT_PORT}"),
+            bitrate: 1_000_000,
+            frame_rate: 25,
+            intra_period: 25,
+            shutter_speed: 40_000
+        }
+    }
+}
+
+fn select_camera(print: bool) -> Result<CameraInstanceInfo> {
+    let camera_info = CameraInfoComponentHandle::create()?;
+
+    let mut camera_info_param = CameraInformation::default();
+    camera_info_param.read(&camera_info)?;
+    let w: CameraInfo = camera_info_param.get();
+
+    if print {
+        for c in &w.cameras {
+            println!("[{}] {} {}x{} lens={}", c.port_id, c.camera_name, c.max_width, c.max_height, c.lens_present)
+        }
+    }
+
+    Ok(w.cameras.into_iter().next().unwrap())
+}
+
+fn wr<R>(l: &str, r: Result<R>) {
+    if let Err(e) = r {
+        error!("Error[@{l}]: {e}");
+    }
+}
+
+struct VideoCamera {
+    pipeline: Pipeline,
+    camera: Node<CameraEntity>,
+    encoder_sink: Sink<VideoEncoderOutputPort>,
+    settings: Settings,
+}
+
+impl VideoCamera {
+    fn create_camera(settings: &Settings) -> Result<CameraComponentHandle> {
+        let selected_camera = select_camera(true)?;
+
+        let camera = CameraComponentHandle::create()?;
+        let camera_num = PCameraNum::from(0);
+        let mut ccfg = CameraConfig::from_instance_info(&selected_camera);
+        ccfg.one_shot_stills = false;
+        let camera_config = PCameraConfig::from(ccfg);
+        CameraControlPort::write_multi(&camera, param_iter![&camera_num, &camera_config])?;
+
+        CameraControlPort::write(&camera, &PShutterSpeed::from(settings.shutter_speed))?;
+
+        let mut vcfg = CAMERA_PORT_CONFIG_320X240;
+        vcfg.encoding = ffi::MMAL_ENCODING_I420;
+        vcfg.encoding_variant = ffi::MMAL_ENCODING_I420;
+        vcfg.es_video_frame_rate_num = settings.frame_rate;
+        vcfg.es_video_frame_rate_den = 1;
+        vcfg.buffer_count_policy = BufferCountPolicy::Recommended;
+        CameraVideoPort::configure(&camera, vcfg)?;
+        Ok(camera)
+    }
+
+    fn create_encoder(settings: &Settings) -> Result<VideoEncoderComponentHandle> {
+        let encoder = VideoEncoderComponentHandle::create()?;
+        let format = VideoEncoderOutFormat { encoding: ffi::MMAL_ENCODING_H264, bitrate: settings.bitrate };
+        VideoEncoderOutputPort::configure(&encoder, format)?;
+
+        let p_video_profile = PVideoProfile::from((VideoProfile::H264ConstrainedBaseline, VideoLevel::H264_4));
+        VideoEncoderOutputPort::write(&encoder, &p_video_profile)?;
+        // clients join at the next IDR frame
+        VideoEncoderOutputPort::write_multi(&encoder, param_iter![
+            &PIntraPeriod::from(settings.intra_period), &PInlineHeader::from(true), &PSpsTiming::from(true)
+        ])?;
+        Ok(encoder)
+    }
+
+    fn create(settings: Settings) -> Result<Self> {
+        let mut builder = PipelineBuilder::new();
+        let camera = builder.node(Self::create_camera(&settings)?);
+        let encoder = builder.node(Self::create_encoder(&settings)?);
+        builder.connect::<CameraVideoPort, VideoEncoderInputPort>(camera, encoder);
+        let encoder_sink = builder.sink::<VideoEncoderOutputPort>(encoder);
+        let pipeline = builder.build()?;
+
+        Ok(Self { pipeline, camera, encoder_sink, settings })
+    }
+
+    fn stream(&self) -> Result<()> {
+        let server = RtspServer::bind(&self.settings.bind_addr)?;
+        info!("Streaming at rtsp://{}/", server.local_addr());
+
+        let camera = self.pipeline.component(self.camera);
+        let encoder_sink = self.pipeline.sink(self.encoder_sink);
+        CameraVideoPort::write(camera, &PCaptureVideo::from(true))?;
+
+        let mut assembler = FrameAssembler::new();
+        let mut count = 0usize;
+        let mut total = 0usize;
+        while let Some(b) = encoder_sink.timedwait(5000) {
+            let (_, frame) = encoder_sink.consume_view(b, |view| Ok((true, assembler.push(view))))?;
+            let Some(frame) = frame else { continue };
+            server.send_frame(&frame);
+
+            count += 1;
+            total += frame.data.len();
+            if self.settings.stats_period > 0 && count.is_multiple_of(self.settings.stats_period) {
+                debug!("avg={} total={total}/count={count} clients={}", total / count, server.playing());
+            }
+            if self.settings.max_frame_count > 0 && count >= self.settings.max_frame_count {
+                break
+            }
+        }
+        CameraVideoPort::write(camera, &PCaptureVideo::from(false))?;
+        Ok(())
+    }
+}
+
+impl Drop for VideoCamera {
+    fn drop(&mut self) {
+        wr("pipeline.stop", self.pipeline.stop());
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    mmal_rs::init()?;
+
+    let mut settings = Settings::default();
+
+    let rmdr: Option<String> = std::env::args().skip(1).fold(None, |s, a| if let Some(s) = s {
+        match s.as_ref() {
+            "-c" | "--count" => settings.max_frame_count = a.parse().expect("expected an uint next to --count"),
+            "-b" | "--bind-addr" => settings.bind_addr = a,
+            "-f" | "--frame-rate" => settings.frame_rate = a.parse().expect("expected an uint next to --frame-rate"),
+            "-B" | "--bitrate" => settings.bitrate = a.parse().expect("expected an uint next to --bitrate"),
+            "-i" | "--intra-period" => settings.intra_period = a.parse().expect("expected an uint next to --intra-period"),
+            "-s" | "--shutter-speed" => settings.shutter_speed = a.parse().expect("expected an uint next to --shutter-speed"),
+            _ => panic!("invalid command line arg: `{s}`")
+        }
+        None
+    } else {
+        match a.as_ref() {
+            "-h" | "--help" => {
+                let ss = Settings::default();
+                println!(r#"
+Usage: rtsp-server [options...]
+
+Streams H.264 from the camera over RTSP/RTP, e.g. `ffplay rtsp://<host>:{port}/`
+
+Options are:
+--count|-c <uint>               Exit after streaming this number of frames. 0 to stream infinitely (default {c})
+--bind-addr|-b <addr>           Socket address to bind to (default {b})
+--frame-rate|-f <uint>          Camera frame rate (default {f})
+--bitrate|-B <uint>             Encoder output bitrate (default {B})
+--intra-period|-i <uint>        Frames between IDR frames, at which clients can join (default {i})
+--shutter-speed|-s <uint>       Camera shutter speed in microseconds (default {s})
+--help|-h                       Print this help message and exit
+"#,
+port=RTSP_DEFAULT_PORT,
+b=ss.bind_addr,
+c=ss.max_frame_count,
+f=ss.frame_rate,
+B=ss.bitrate,
+i=ss.intra_period,
+s=ss.shutter_speed,
+);
+                std::process::exit(0);
+            }
+            _ => Some(a)
+        }
+    });
+    if let Some(w) = rmdr {
+        panic!("Invalid command line syntax at EOL: `{w}`")
+    }
+
+    let cam = VideoCamera::create(settings)?;
+    cam.stream()
+}
@@ -0,0 +1,225 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Size of the header of the raw sensor data appended to JPEG stills by `PEnableRawCapture`, the pixels follow it
+pub const BRCM_HEADER_SIZE: usize = 32768;
+
+const BRCM_MAGIC: &[u8; 4] = b"BRCM";
+/// Offset of the sensor description in the header
+const SENSOR_HEADER_OFFSET: usize = 176;
+const SENSOR_HEADER_SIZE: usize = 70;
+
+/// Color filter array layout, by the colors of the top left 2x2 pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BayerOrder {
+    Rggb,
+    Grbg,
+    Bggr,
+    Gbrg,
+}
+
+impl BayerOrder {
+    /// Order of the `bayer_order` byte of the BRCM header, as decoded by picamera's `PiBayerArray.BAYER_OFFSETS`
+    fn from_brcm(order: u8) -> Option<Self> {
+        Some(match order {
+            0 => Self::Rggb,
+            1 => Self::Gbrg,
+            2 => Self::Bggr,
+            3 => Self::Grbg,
+            _ => return None,
+        })
+    }
+
+    /// Colors of the 2x2 pixels row by row, 0 for red, 1 for green and 2 for blue as in the DNG `CFAPattern` tag
+    pub fn cfa_pattern(self) -> [u8; 4] {
+        match self {
+            Self::Rggb => [0, 1, 1, 2],
+            Self::Grbg => [1, 0, 2, 1],
+            Self::Bggr => [2, 1, 1, 0],
+            Self::Gbrg => [1, 2, 0, 1],
+        }
+    }
+}
+
+/// Raw Bayer data of the sensor, as appended to JPEG stills by the camera with `PEnableRawCapture`.
+///
+/// The block starts with `BRCM` and a 32 KB header which describes the sensor, followed by the pixels packed as
+/// MIPI RAW10, 4 pixels in 5 bytes, or RAW12, 2 pixels in 3 bytes, in rows aligned to 32 bytes: 10 bits for the
+/// OV5647 and IMX219, 12 bits for the IMX477. The header gives the packing, the size of the block must match it.
+#[derive(Debug, Clone)]
+pub struct BayerRaw<'a> {
+    /// Offset of the block in the data it was found in, which is the size of the JPEG before it
+    pub offset: usize,
+    /// Sensor model, e.g. `ov5647`, `imx219` or `imx477`
+    pub sensor: String,
+    pub width: u32,
+    pub height: u32,
+    pub order: BayerOrder,
+    /// Bits per pixel, 10 or 12
+    pub bits: u8,
+    /// Bytes per row of packed pixels
+    pub stride: usize,
+    pixels: &'a [u8],
+}
+
+fn invalid(message: String) -> MmalError {
+    MmalError::new(Cause::InvalidBitstream, message)
+}
+
+impl<'a> BayerRaw<'a> {
+    /// Finds the raw data appended to a JPEG still
+    pub fn find(data: &'a [u8]) -> Result<Self> {
+        let mut last_error = None;
+        let candidates = data.windows(BRCM_MAGIC.len()).enumerate().filter(|(_, w)| w == BRCM_MAGIC);
+        for (offset, _) in candidates {
+            // the magic may appear in the JPEG or the pixels too
+            match Self::parse(&data[offset..]) {
+                Ok(raw) => return Ok(Self { offset, ..raw }),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| invalid("no BRCM raw data".to_owned())))
+    }
+
+    /// Parses a block of raw data, which must start with `BRCM` and end with the pixels
+    pub fn parse(block: &'a [u8]) -> Result<Self> {
+        if !block.starts_with(BRCM_MAGIC) || block.len() < BRCM_HEADER_SIZE {
+            return Err(invalid(format!("BRCM raw data of {} bytes has no header", block.len())));
+        }
+        let header = &block[SENSOR_HEADER_OFFSET..SENSOR_HEADER_OFFSET + SENSOR_HEADER_SIZE];
+        let name = &header[..32];
+        let sensor = String::from_utf8_lossy(&name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())]).into_owned();
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]) as u32;
+        let (width, height) = (u16_at(32), u16_at(34));
+        let order = BayerOrder::from_brcm(header[68])
+            .ok_or_else(|| invalid(format!("unknown Bayer order {} of {sensor}", header[68])))?;
+        let bits = match header[69] {
+            0 => 10,
+            2 => 12,
+            format => return Err(invalid(format!("unknown Bayer format {format} of {sensor}"))),
+        };
+        let pixels = &block[BRCM_HEADER_SIZE..];
+        // rows are padded to 32 bytes and the picture to more rows than the height
+        let stride = (width as usize * bits as usize).div_ceil(8).next_multiple_of(32);
+        if stride == 0 || !pixels.len().is_multiple_of(stride) || !(height as usize..height as usize + 64).contains(&(pixels.len() / stride)) {
+            return Err(invalid(format!("{} bytes of raw data do not match {sensor} {width}x{height} RAW{bits}", pixels.len())));
+        }
+        Ok(Self { offset: 0, sensor, width, height, order, bits, stride, pixels })
+    }
+
+    /// Packed pixels of row `y`, including the padding
+    pub fn packed_row(&self, y: usize) -> Option<&'a [u8]> {
+        (y < self.height as usize).then(|| &self.pixels[y * self.stride..(y + 1) * self.stride])
+    }
+
+    /// Unpacks row `y` into `out`, which must hold `width` pixels
+    pub fn unpack_row(&self, y: usize, out: &mut [u16]) -> Option<()> {
+        let row = self.packed_row(y)?;
+        let out = out.get_mut(..self.width as usize)?;
+        if self.bits == 10 {
+            for (o, p) in out.chunks_mut(4).zip(row.chunks_exact(5)) {
+                for (i, o) in o.iter_mut().enumerate() {
+                    *o = (p[i] as u16) << 2 | (p[4] as u16 >> (i * 2)) & 3;
+                }
+            }
+        } else {
+            for (o, p) in out.chunks_mut(2).zip(row.chunks_exact(3)) {
+                for (i, o) in o.iter_mut().enumerate() {
+                    *o = (p[i] as u16) << 4 | (p[2] as u16 >> (i * 4)) & 0xf;
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Unpacks the pixels, row by row
+    pub fn unpack(&self) -> Vec<u16> {
+        let width = self.width as usize;
+        let mut pixels = vec![0; width * self.height as usize];
+        if width > 0 {
+            for (y, row) in pixels.chunks_exact_mut(width).enumerate() {
+                self.unpack_row(y, row);
+            }
+        }
+        pixels
+    }
+
+    /// Usual black level of the sensor, 0 for unknown sensors
+    pub fn black_level(&self) -> u16 {
+        match self.sensor.as_str() {
+            "ov5647" => 16,
+            "imx219" => 64,
+            "imx477" => 256,
+            _ => 0,
+        }
+    }
+
+    pub fn white_level(&self) -> u16 {
+        (1 << self.bits) - 1
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Builds a BRCM block of `width` x `height` pixels, packed with `bits`
+#[cfg(test)]
+pub(crate) fn test_brcm_block(sensor: &str, width: u32, height: u32, bits: u8, order: u8, pixel: impl Fn(usize, usize) -> u16) -> Vec<u8> {
+    let stride = (width as usize * bits as usize).div_ceil(8).next_multiple_of(32);
+    let rows = (height as usize + 1).next_multiple_of(16);
+    let mut block = vec![0u8; BRCM_HEADER_SIZE + stride * rows];
+    block[..4].copy_from_slice(BRCM_MAGIC);
+    let header = &mut block[SENSOR_HEADER_OFFSET..];
+    header[..sensor.len()].copy_from_slice(sensor.as_bytes());
+    header[32..34].copy_from_slice(&(width as u16).to_le_bytes());
+    header[34..36].copy_from_slice(&(height as u16).to_le_bytes());
+    header[68] = order;
+    header[69] = if bits == 10 { 0 } else { 2 };
+    for (y, row) in block[BRCM_HEADER_SIZE..].chunks_exact_mut(stride).take(height as usize).enumerate() {
+        let per_group = if bits == 10 { 4 } else { 2 };
+        for (g, p) in row.chunks_exact_mut(per_group + 1).take((width as usize).div_ceil(per_group)).enumerate() {
+            for i in 0..per_group {
+                let v = pixel(g * per_group + i, y);
+                p[i] = (v >> (bits - 8)) as u8;
+                p[per_group] |= ((v & ((1 << (bits - 8)) - 1)) << (i * (bits as usize - 8))) as u8;
+            }
+        }
+    }
+    block
+}
+
+#[test]
+fn test_bayer_raw() {
+    let pixel = |x: usize, y: usize| ((x * 7 + y * 3) % 1024) as u16;
+    let block = test_brcm_block("ov5647", 2592, 1944, 10, 3, pixel);
+    assert_eq!(block.len(), 6404096);
+    let mut jpeg = vec![0xff, 0xd8, b'B', b'R', b'C', b'M', 0xff, 0xd9];
+    jpeg.extend_from_slice(&block);
+    let raw = BayerRaw::find(&jpeg).unwrap();
+    assert_eq!((raw.offset, raw.sensor.as_str(), raw.width, raw.height), (8, "ov5647", 2592, 1944));
+    assert_eq!((raw.order, raw.bits, raw.stride), (BayerOrder::Grbg, 10, 3264));
+    assert_eq!((raw.black_level(), raw.white_level()), (16, 1023));
+    let pixels = raw.unpack();
+    assert_eq!(pixels.len(), 2592 * 1944);
+    assert!(pixels.chunks_exact(2592).enumerate().all(|(y, row)| row.iter().enumerate().all(|(x, &v)| v == pixel(x, y))));
+
+    let pixel = |x: usize, y: usize| ((x * 13 + y) % 4096) as u16;
+    let block = test_brcm_block("imx477", 4056, 3040, 12, 2, pixel);
+    assert_eq!(block.len(), 18711040);
+    let raw = BayerRaw::parse(&block).unwrap();
+    assert_eq!((raw.order, raw.bits, raw.stride, raw.white_level()), (BayerOrder::Bggr, 12, 6112, 4095));
+    let mut row = vec![0; 4056];
+    raw.unpack_row(3039, &mut row).unwrap();
+    assert!(row.iter().enumerate().all(|(x, &v)| v == pixel(x, 3039)));
+    assert!(raw.unpack_row(3040, &mut row).is_none());
+
+    assert!(matches!(BayerRaw::parse(&block[..block.len() - 1]).unwrap_err().cause(), Cause::InvalidBitstream));
+    // the packing of the header must match the size of the pixels
+    let mut raw10 = block.clone();
+    raw10[SENSOR_HEADER_OFFSET + 69] = 0;
+    assert!(BayerRaw::parse(&raw10).is_err());
+    raw10[SENSOR_HEADER_OFFSET + 69] = 5;
+    assert!(BayerRaw::parse(&raw10).is_err());
+    assert!(BayerRaw::find(&jpeg[..100]).is_err());
+    assert_eq!(BayerOrder::Rggb.cfa_pattern(), [0, 1, 1, 2]);
+}
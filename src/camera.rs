@@ -424,6 +424,10 @@ pub type PCapture = Param<CameraCapturePort, Boolean<MMAL_PARAMETER_CAPTURE>>;
 /// Activate/deactivate capture
 pub type PCaptureVideo = Param<CameraVideoPort, Boolean<MMAL_PARAMETER_CAPTURE>>;
 
+idp!{MMAL_PARAMETER_ENABLE_RAW_CAPTURE}
+/// Append the raw Bayer data of the sensor to the JPEG stills, see `BayerRaw`
+pub type PEnableRawCapture = Param<CameraCapturePort, Boolean<MMAL_PARAMETER_ENABLE_RAW_CAPTURE>>;
+
 enumize!{ExposureMode,
     Off => MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_OFF,
     Auto => MMAL_PARAM_EXPOSUREMODE_T_MMAL_PARAM_EXPOSUREMODE_AUTO,
@@ -746,6 +750,26 @@ idp!{MMAL_PARAMETER_CAPTURE_STATS_PASS}
 pub type PStatsPass = Param<CameraControlPort, Boolean<MMAL_PARAMETER_CAPTURE_STATS_PASS>>;
 
 
+pub struct ChangeEventRequestInnerType {
+    inner: ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST_T,
+}
+
+impl_inner_param_default!{ChangeEventRequestInnerType, MMAL_PARAMETER_CHANGE_EVENT_REQUEST_T, MMAL_PARAMETER_CHANGE_EVENT_REQUEST}
+impl_inner_param_type!{ChangeEventRequestInnerType}
+
+impl Apply<(u32, bool)> for ChangeEventRequestInnerType {
+    fn apply(&mut self, (change_id, enable): (u32, bool)) {
+        self.inner.change_id = change_id;
+        self.inner.enable = bool_rust_to_mmal(enable);
+    }
+}
+
+/// Request `MMAL_EVENT_PARAMETER_CHANGED` events for a parameter - (parameter id, enable).
+/// 
+/// With `MMAL_PARAMETER_CAMERA_SETTINGS`, the camera reports `ComponentEvent::CameraSettings` for each frame.
+pub type PChangeEventRequest = Param<CameraControlPort, ChangeEventRequestInnerType>;
+
+
 pub struct StereoModeInnerType {
     inner: ffi::MMAL_PARAMETER_STEREOSCOPIC_MODE_T,
 }
@@ -0,0 +1,323 @@
+use super::*;
+use std::{collections::VecDeque, io::Write, time::Duration};
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Destination of encoded frames, e.g. a muxer writing a file
+pub trait FrameWriter {
+    fn write_frame(&mut self, frame: &Frame) -> Result<()>;
+}
+
+impl<W: Write> FrameWriter for TsWriter<W> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> { TsWriter::write_frame(self, frame) }
+}
+
+impl<W: Write> FrameWriter for Fmp4Writer<W> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> { Fmp4Writer::write_frame(self, frame) }
+}
+
+impl FrameWriter for HlsWriter {
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> { HlsWriter::write_frame(self, frame) }
+}
+
+/// Collects the frames, e.g. to hand them over to another thread
+impl FrameWriter for Vec<Frame> {
+    fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.push(frame.clone());
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Keeps the latest encoded frames of a video encoder, like the circular mode of raspivid.
+///
+/// Frames are held in whole GOPs, so that the buffer always starts at a keyframe (`FrameFlags::FLAG_KEYFRAME`). The
+/// oldest GOP is dropped once the newer ones cover the duration, so the buffer holds at least that much video when the
+/// stream is long enough. The duration is measured with the timestamps of the frames. The optional byte limit is strict:
+/// GOPs are dropped until the buffer fits, including the current one if it is larger than the limit on its own, in which
+/// case buffering resumes at the next keyframe.
+///
+/// The latest codec config frame is kept apart and returned first by `frames()`, as the encoder sends the parameter sets
+/// only once by default.
+#[derive(Debug)]
+pub struct CircularBuffer {
+    duration: Duration,
+    max_bytes: Option<usize>,
+    config: Option<Frame>,
+    gops: VecDeque<Vec<Frame>>,
+    bytes: usize,
+}
+
+impl CircularBuffer {
+    /// A buffer holding at least `duration` of video
+    pub fn new(duration: Duration) -> Self {
+        Self { duration, max_bytes: None, config: None, gops: VecDeque::new(), bytes: 0 }
+    }
+
+    /// Limits the size of the buffered frames, not counting the config frame
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Adds a frame assembled from the encoder output, see `FrameAssembler`. Frames before the first keyframe and codec
+    /// side info frames, e.g. motion vectors, are dropped.
+    pub fn push(&mut self, frame: Frame) {
+        if frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            return;
+        }
+        if frame.flags.test_one(FrameFlags::FLAG_CONFIG) {
+            self.config = Some(frame);
+            return;
+        }
+        if frame.flags.test_one(FrameFlags::FLAG_KEYFRAME) {
+            self.gops.push_back(Vec::new());
+        }
+        let Some(gop) = self.gops.back_mut() else { return };
+        self.bytes += frame.data.len();
+        gop.push(frame);
+
+        while self.gops.len() > 1 && self.duration_from(1) >= self.duration {
+            self.pop_gop();
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            while self.bytes > max_bytes {
+                self.pop_gop();
+            }
+        }
+    }
+
+    /// The config frame, if any, then the buffered frames from the oldest keyframe on
+    pub fn frames(&self) -> impl Iterator<Item = &Frame> {
+        self.config.iter().chain(self.gops.iter().flatten())
+    }
+
+    /// Time between the oldest and the newest frame
+    pub fn duration(&self) -> Duration {
+        if self.gops.is_empty() { Duration::ZERO } else { self.duration_from(0) }
+    }
+
+    /// Size of the buffered frames, not counting the config frame
+    pub fn bytes(&self) -> usize { self.bytes }
+
+    /// Whether no frames are buffered, a config frame may still be
+    pub fn is_empty(&self) -> bool { self.gops.is_empty() }
+
+    /// Timestamp of the newest frame
+    pub fn last_pts(&self) -> Option<Duration> {
+        self.gops.back().and_then(|gop| gop.last()).and_then(|f| f.pts)
+    }
+
+    /// Drops the buffered frames, but keeps the config frame
+    pub fn clear(&mut self) {
+        self.gops.clear();
+        self.bytes = 0;
+    }
+
+    fn duration_from(&self, gop: usize) -> Duration {
+        match (self.gops[gop].first().and_then(|f| f.pts), self.last_pts()) {
+            (Some(first), Some(last)) => last.saturating_sub(first),
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn pop_gop(&mut self) {
+        if let Some(gop) = self.gops.pop_front() {
+            self.bytes -= gop.iter().map(|f| f.data.len()).sum::<usize>();
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// When `EventRecorder` ends a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingStop {
+    /// Once this much video after the trigger was written, measured with the frame timestamps
+    After(Duration),
+    /// Once this many frames after the trigger were written
+    Frames(usize),
+    /// Only with `EventRecorder::stop`
+    Manual,
+}
+
+struct Recording<W> {
+    writer: W,
+    stop: RecordingStop,
+    /// Timestamp of the newest frame at the trigger
+    since: Option<Duration>,
+    frames: usize,
+}
+
+/// Records the video around events: the frames of a `CircularBuffer` before the trigger, then the frames after it until
+/// a `RecordingStop` condition.
+///
+/// Frames keep going into the buffer during a recording, so the recording of a later event starts with its own
+/// pre-event video, even if that overlaps with the previous recording.
+pub struct EventRecorder<W: FrameWriter> {
+    buffer: CircularBuffer,
+    assembler: FrameAssembler,
+    recording: Option<Recording<W>>,
+}
+
+impl<W: FrameWriter> EventRecorder<W> {
+    pub fn new(buffer: CircularBuffer) -> Self {
+        Self { buffer, assembler: FrameAssembler::new(), recording: None }
+    }
+
+    /// Adds a frame assembled from the encoder output. Returns the writer once the stop condition of the recording is met,
+    /// the frame which meets it isn't written.
+    pub fn push(&mut self, frame: Frame) -> Result<Option<W>> {
+        let mut finished = None;
+        if let Some(r) = &mut self.recording {
+            let picture = !frame.flags.test_one(FrameFlags::FLAG_CONFIG | FrameFlags::FLAG_CODECSIDEINFO);
+            if picture && r.since.is_none() {
+                r.since = frame.pts;
+            }
+            let done = picture && match r.stop {
+                RecordingStop::After(duration) => match (frame.pts, r.since) {
+                    (Some(pts), Some(since)) => pts.saturating_sub(since) >= duration,
+                    _ => false,
+                },
+                RecordingStop::Frames(frames) => r.frames >= frames,
+                RecordingStop::Manual => false,
+            };
+            if done {
+                finished = self.recording.take().map(|r| r.writer);
+            } else {
+                r.writer.write_frame(&frame)?;
+                r.frames += picture as usize;
+            }
+        }
+        self.buffer.push(frame);
+        Ok(finished)
+    }
+
+    /// Adds an encoder output buffer, see `push`. The frame is added once its last buffer arrives.
+    pub fn push_view(&mut self, view: BufferView<'_>) -> Result<Option<W>> {
+        match self.assembler.push(view) {
+            Some(frame) => self.push(frame),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the buffered frames to `writer` and records the following ones until `stop`. A recording in progress is
+    /// ended and its writer returned.
+    pub fn trigger(&mut self, mut writer: W, stop: RecordingStop) -> Result<Option<W>> {
+        for frame in self.buffer.frames() {
+            writer.write_frame(frame)?;
+        }
+        let since = self.buffer.last_pts();
+        Ok(self.recording.replace(Recording { writer, stop, since, frames: 0 }).map(|r| r.writer))
+    }
+
+    /// Restarts the stop condition of the recording in progress from the newest frame, e.g. when the event goes on.
+    /// Returns whether a recording is in progress.
+    pub fn retrigger(&mut self, stop: RecordingStop) -> bool {
+        let since = self.buffer.last_pts();
+        match &mut self.recording {
+            Some(r) => {
+                (r.stop, r.since, r.frames) = (stop, since, 0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ends the recording in progress and returns its writer
+    pub fn stop(&mut self) -> Option<W> {
+        self.recording.take().map(|r| r.writer)
+    }
+
+    pub fn is_recording(&self) -> bool { self.recording.is_some() }
+
+    pub fn buffer(&self) -> &CircularBuffer { &self.buffer }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_circular_buffer() {
+    use crate::hls::test_frames;
+    let frames = test_frames(10, Duration::from_millis(100));
+    let gop_bytes: usize = frames[1..6].iter().map(|f| f.data.len()).sum();
+
+    let mut buffer = CircularBuffer::new(Duration::from_millis(1200));
+    // frames before the first keyframe are dropped
+    buffer.push(frames[2].clone());
+    assert!(buffer.is_empty() && buffer.frames().next().is_none());
+    for frame in &frames {
+        buffer.push(frame.clone());
+    }
+    // the GOPs from 4.0s only cover 0.9s
+    let pts: Vec<_> = buffer.frames().map(|f| f.pts).collect();
+    assert_eq!(pts.len(), 16);
+    assert_eq!(pts[..2], [None, Some(Duration::from_millis(3500))]);
+    assert!(buffer.frames().next().unwrap().flags.test_one(FrameFlags::FLAG_CONFIG));
+    assert!(buffer.frames().nth(1).unwrap().flags.test_one(FrameFlags::FLAG_KEYFRAME));
+    assert_eq!(buffer.duration(), Duration::from_millis(1400));
+    assert_eq!(buffer.bytes(), 3 * gop_bytes);
+
+    // the byte limit drops a GOP as soon as the next one is complete
+    let mut buffer = CircularBuffer::new(Duration::from_secs(10)).with_max_bytes(2 * gop_bytes - 1);
+    for frame in &frames {
+        buffer.push(frame.clone());
+    }
+    assert_eq!(buffer.frames().count(), 6);
+    assert_eq!(buffer.frames().nth(1).unwrap().pts, Some(Duration::from_millis(4500)));
+    // a GOP larger than the limit is dropped whole, buffering resumes at the next keyframe
+    let mut buffer = CircularBuffer::new(Duration::from_secs(10)).with_max_bytes(gop_bytes - 1);
+    for frame in frames[..6].iter().chain([&frames[7]]) {
+        buffer.push(frame.clone());
+    }
+    assert!(buffer.is_empty());
+    buffer.push(frames[11].clone());
+    assert_eq!((buffer.frames().count(), buffer.bytes()), (2, frames[11].data.len()));
+}
+
+#[test]
+fn test_event_recorder() {
+    use crate::hls::test_frames;
+    let frames = test_frames(10, Duration::from_millis(100));
+    let mut recorder = EventRecorder::<Vec<Frame>>::new(CircularBuffer::new(Duration::from_secs(1)));
+    for frame in &frames[..16] {
+        assert!(recorder.push(frame.clone()).unwrap().is_none());
+    }
+    // pre-event video from 0s to 1.4s, then until 2.4s
+    assert!(recorder.trigger(Vec::new(), RecordingStop::After(Duration::from_secs(1))).unwrap().is_none());
+    assert!(recorder.is_recording());
+    let mut recorded = None;
+    for frame in &frames[16..] {
+        if let Some(r) = recorder.push(frame.clone()).unwrap() {
+            recorded = Some(r);
+            break;
+        }
+    }
+    let recorded = recorded.unwrap();
+    assert!(!recorder.is_recording());
+    assert_eq!(recorded.len(), 1 + 24);
+    assert!(recorded[0].flags.test_one(FrameFlags::FLAG_CONFIG));
+    assert!(recorded[1].flags.test_one(FrameFlags::FLAG_KEYFRAME));
+    assert_eq!(recorded.last().unwrap().pts, Some(Duration::from_millis(2300)));
+
+    // the next recording starts with its own pre-event video, and is extended by a retrigger
+    let mut recorder = EventRecorder::<Vec<Frame>>::new(CircularBuffer::new(Duration::from_millis(400)));
+    for frame in &frames[..24] {
+        recorder.push(frame.clone()).unwrap();
+    }
+    assert!(!recorder.retrigger(RecordingStop::Manual));
+    recorder.trigger(Vec::new(), RecordingStop::Frames(5)).unwrap();
+    for frame in &frames[24..27] {
+        recorder.push(frame.clone()).unwrap();
+    }
+    assert!(recorder.retrigger(RecordingStop::Frames(5)));
+    for frame in &frames[27..32] {
+        assert!(recorder.push(frame.clone()).unwrap().is_none());
+    }
+    let recorded = recorder.push(frames[32].clone()).unwrap().unwrap();
+    // GOPs from 1.5s, until 3.1s
+    assert_eq!(recorded[1].pts, Some(Duration::from_millis(1500)));
+    assert_eq!(recorded.len(), 1 + 8 + 8);
+    assert!(recorder.stop().is_none());
+}
@@ -0,0 +1,763 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// YUV matrix and range of raw frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    /// ITU-R BT.601 with video range, as the camera outputs by default
+    #[default]
+    Bt601,
+    /// ITU-R BT.709 with video range
+    Bt709,
+    /// BT.601 with full range, as in JPEG files
+    Jfif,
+}
+
+impl ColorSpace {
+    /// Color space of `VideoFormat::color_space`, BT.601 if unknown
+    pub fn from_mmal(color_space: u32) -> Self {
+        match color_space {
+            ffi::MMAL_COLOR_SPACE_ITUR_BT709 => Self::Bt709,
+            ffi::MMAL_COLOR_SPACE_JPEG_JFIF => Self::Jfif,
+            _ => Self::Bt601,
+        }
+    }
+
+    fn matrix(self) -> &'static Matrix {
+        match self {
+            Self::Bt601 => &BT601,
+            Self::Bt709 => &BT709,
+            Self::Jfif => &JFIF,
+        }
+    }
+}
+
+const SHIFT: u32 = 14;
+const ROUND: i32 = 1 << (SHIFT - 1);
+
+const fn fixed(c: f64) -> i32 {
+    (c * (1 << SHIFT) as f64 + if c < 0.0 { -0.5 } else { 0.5 }) as i32
+}
+
+/// Fixed point conversion factors, with `SHIFT` fractional bits
+struct Matrix {
+    /// Luma of black
+    y_offset: i32,
+    /// Luma scale, V to R, U to G, V to G and U to B
+    to_rgb: [i32; 5],
+    /// R, G and B factors of Y, U and V
+    to_yuv: [[i32; 3]; 3],
+}
+
+/// Matrix of the luma weights `kr` and `kb`, with Y in 16..=235 and UV in 16..=240 for video range
+const fn matrix(kr: f64, kb: f64, video_range: bool) -> Matrix {
+    let kg = 1.0 - kr - kb;
+    let (ys, cs) = if video_range { (219.0 / 255.0, 224.0 / 255.0) } else { (1.0, 1.0) };
+    let (cb, cr) = (cs / (2.0 * (1.0 - kb)), cs / (2.0 * (1.0 - kr)));
+    Matrix {
+        y_offset: if video_range { 16 } else { 0 },
+        to_rgb: [
+            fixed(1.0 / ys),
+            fixed(2.0 * (1.0 - kr) / cs),
+            fixed(2.0 * kb * (1.0 - kb) / (kg * cs)),
+            fixed(2.0 * kr * (1.0 - kr) / (kg * cs)),
+            fixed(2.0 * (1.0 - kb) / cs),
+        ],
+        to_yuv: [
+            [fixed(kr * ys), fixed(kg * ys), fixed(kb * ys)],
+            [fixed(-kr * cb), fixed(-kg * cb), fixed((1.0 - kb) * cb)],
+            [fixed((1.0 - kr) * cr), fixed(-kg * cr), fixed(-kb * cr)],
+        ],
+    }
+}
+
+const BT601: Matrix = matrix(0.299, 0.114, true);
+const BT709: Matrix = matrix(0.2126, 0.0722, true);
+const JFIF: Matrix = matrix(0.299, 0.114, false);
+
+#[inline(always)]
+fn clamp(v: i32) -> u8 {
+    ((v + ROUND) >> SHIFT).clamp(0, 255) as u8
+}
+
+impl Matrix {
+    #[inline(always)]
+    fn luma(&self, r: u8, g: u8, b: u8) -> u8 {
+        let [kr, kg, kb] = self.to_yuv[0];
+        clamp((self.y_offset << SHIFT) + kr * r as i32 + kg * g as i32 + kb * b as i32)
+    }
+
+    /// U and V of the sums of the R, G and B of a 2x2 block of pixels
+    #[inline(always)]
+    fn chroma(&self, r: i32, g: i32, b: i32) -> (u8, u8) {
+        let [_, [ur, ug, ub], [vr, vg, vb]] = self.to_yuv;
+        // sums of 4 pixels have 2 more fractional bits
+        let clamp4 = |v: i32| (((128 << (SHIFT + 2)) + (ROUND << 2) + v) >> (SHIFT + 2)).clamp(0, 255) as u8;
+        (clamp4(ur * r + ug * g + ub * b), clamp4(vr * r + vg * g + vb * b))
+    }
+
+    /// Terms of `u` and `v` in R, G and B, the G one to subtract
+    #[inline(always)]
+    fn chroma_terms(&self, u: u8, v: u8) -> (i32, i32, i32) {
+        let [_, rv, gu, gv, bu] = self.to_rgb;
+        let (u, v) = (u as i32 - 128, v as i32 - 128);
+        (rv * v, gu * u + gv * v, bu * u)
+    }
+
+    #[inline(always)]
+    fn luma_term(&self, y: u8) -> i32 {
+        (y as i32 - self.y_offset) * self.to_rgb[0]
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Packed RGB encodings, green being the second byte of their pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RgbLayout {
+    Rgb24,
+    Bgr24,
+    Rgba,
+}
+
+impl RgbLayout {
+    fn of(encoding: u32) -> Option<Self> {
+        match encoding {
+            ffi::MMAL_ENCODING_RGB24 => Some(Self::Rgb24),
+            ffi::MMAL_ENCODING_BGR24 => Some(Self::Bgr24),
+            ffi::MMAL_ENCODING_RGBA => Some(Self::Rgba),
+            _ => None,
+        }
+    }
+
+    fn bpp(self) -> usize {
+        if self == Self::Rgba { 4 } else { 3 }
+    }
+}
+
+/// Row kernel `$kernel::<BPP, R, B>` for `$layout`, with its bytes per pixel and the offsets of red and blue as
+/// constants so that the pixel loops are unrolled and vectorized
+macro_rules! rgb_kernel {
+    ($layout:expr, $kernel:ident) => {
+        match $layout {
+            RgbLayout::Rgb24 => $kernel::<3, 0, 2> as _,
+            RgbLayout::Bgr24 => $kernel::<3, 2, 0> as _,
+            RgbLayout::Rgba => $kernel::<4, 0, 2> as _,
+        }
+    };
+}
+
+/// `rgb_to_luma_row` and `yuyv_to_rgb_row`
+type RowKernel = fn(&Matrix, &[u8], &mut [u8]);
+/// `yuv_to_rgb_row`
+type YuvRowKernel = fn(&Matrix, &[u8], &[u8], &[u8], usize, &mut [u8]);
+/// `rgb_to_chroma_row`
+type ChromaRowKernel = fn(&Matrix, &[u8], &[u8], &mut [u8], &mut [u8]);
+/// `shuffle_row`
+type ShuffleRow = fn(&[u8], &mut [u8]);
+
+#[inline(always)]
+fn rgb_pixel<const BPP: usize, const R: usize, const B: usize>(m: &Matrix, px: &mut [u8], y: u8, (rc, gc, bc): (i32, i32, i32)) {
+    let l = m.luma_term(y);
+    (px[R], px[1], px[B]) = (clamp(l + rc), clamp(l - gc), clamp(l + bc));
+}
+
+/// Converts a row of luma and its chroma, a U and V per pair of pixels, to RGB. With an odd `phase`, see
+/// `chroma_phase`, the first pixel has a chroma sample of its own.
+fn yuv_to_rgb_row<const BPP: usize, const R: usize, const B: usize>(m: &Matrix, y: &[u8], u: &[u8], v: &[u8], phase: usize, out: &mut [u8]) {
+    let (y, u, v, out) = if phase == 1 {
+        let (first, out) = out.split_at_mut(BPP);
+        rgb_pixel::<BPP, R, B>(m, first, y[0], m.chroma_terms(u[0], v[0]));
+        (&y[1..], &u[1..], &v[1..], out)
+    } else {
+        (y, u, v, out)
+    };
+    let pairs = y.len() / 2;
+    let (out, last) = out.split_at_mut(pairs * 2 * BPP);
+    for (((px, y), &u), &v) in out.chunks_exact_mut(2 * BPP).zip(y.chunks_exact(2)).zip(u).zip(v) {
+        let terms = m.chroma_terms(u, v);
+        let (left, right) = px.split_at_mut(BPP);
+        rgb_pixel::<BPP, R, B>(m, left, y[0], terms);
+        rgb_pixel::<BPP, R, B>(m, right, y[1], terms);
+    }
+    if let (Some(&y), Some(&u), Some(&v)) = (y.get(pairs * 2), u.get(pairs), v.get(pairs)) {
+        rgb_pixel::<BPP, R, B>(m, last, y, m.chroma_terms(u, v));
+    }
+}
+
+/// Converts a row of YUYV pixels to RGB
+fn yuyv_to_rgb_row<const BPP: usize, const R: usize, const B: usize>(m: &Matrix, row: &[u8], out: &mut [u8]) {
+    let pairs = row.len() / 4;
+    let (out, last) = out.split_at_mut(pairs * 2 * BPP);
+    for (px, yuyv) in out.chunks_exact_mut(2 * BPP).zip(row.chunks_exact(4)) {
+        let terms = m.chroma_terms(yuyv[1], yuyv[3]);
+        let (left, right) = px.split_at_mut(BPP);
+        rgb_pixel::<BPP, R, B>(m, left, yuyv[0], terms);
+        rgb_pixel::<BPP, R, B>(m, right, yuyv[2], terms);
+    }
+    // the last pixel of an odd width has no V
+    if let &[y, u] = &row[pairs * 4..] {
+        rgb_pixel::<BPP, R, B>(m, last, y, m.chroma_terms(u, 128));
+    }
+}
+
+fn rgb_to_luma_row<const BPP: usize, const R: usize, const B: usize>(m: &Matrix, row: &[u8], out: &mut [u8]) {
+    for (y, px) in out.iter_mut().zip(row.chunks_exact(BPP)) {
+        *y = m.luma(px[R], px[1], px[B]);
+    }
+}
+
+/// Chroma of the 2x2 blocks of two rows of RGB pixels, the last column of an odd width counting twice
+fn rgb_to_chroma_row<const BPP: usize, const R: usize, const B: usize>(m: &Matrix, top: &[u8], bottom: &[u8], u: &mut [u8], v: &mut [u8]) {
+    let pairs = top.len() / (2 * BPP);
+    let blocks = top.chunks_exact(2 * BPP).zip(bottom.chunks_exact(2 * BPP));
+    for (((t, b), u), v) in blocks.zip(u.iter_mut()).zip(v.iter_mut()) {
+        let sum = |c: usize| t[c] as i32 + t[BPP + c] as i32 + b[c] as i32 + b[BPP + c] as i32;
+        (*u, *v) = m.chroma(sum(R), sum(1), sum(B));
+    }
+    let (t, b) = (&top[pairs * 2 * BPP..], &bottom[pairs * 2 * BPP..]);
+    if let (Some(u), Some(v), Some(t), Some(b)) = (u.get_mut(pairs), v.get_mut(pairs), t.get(..BPP), b.get(..BPP)) {
+        let sum = |c: usize| 2 * (t[c] as i32 + b[c] as i32);
+        (*u, *v) = m.chroma(sum(R), sum(1), sum(B));
+    }
+}
+
+/// Reorders the channels of a row of packed RGB pixels
+fn shuffle_row<const FROM_BPP: usize, const FROM_R: usize, const FROM_B: usize, const BPP: usize, const R: usize, const B: usize>(
+    row: &[u8], out: &mut [u8],
+) {
+    for (o, px) in out.chunks_exact_mut(BPP).zip(row.chunks_exact(FROM_BPP)) {
+        (o[R], o[1], o[B]) = (px[FROM_R], px[1], px[FROM_B]);
+    }
+}
+
+/// `shuffle_row` from the layout of the type parameters to `to`
+fn shuffle_kernel<const BPP: usize, const R: usize, const B: usize>(to: RgbLayout) -> ShuffleRow {
+    match to {
+        RgbLayout::Rgb24 => shuffle_row::<BPP, R, B, 3, 0, 2>,
+        RgbLayout::Bgr24 => shuffle_row::<BPP, R, B, 3, 2, 0>,
+        RgbLayout::Rgba => shuffle_row::<BPP, R, B, 4, 0, 2>,
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Column and row of the crop of a frame in its chroma grid, 1 if odd: the first chroma sample then covers one
+/// column or row of the picture
+fn chroma_phase(layout: &RawFrameLayout) -> (usize, usize) {
+    match layout.encoding {
+        ffi::MMAL_ENCODING_I420 | ffi::MMAL_ENCODING_YV12 | ffi::MMAL_ENCODING_NV12 => {
+            let luma = layout.planes()[0];
+            (luma.crop_x % 2, luma.crop_y % 2)
+        }
+        // YUYV crops start at an even column
+        _ => (0, 0),
+    }
+}
+
+/// Chroma of a row of a YUV frame, a U and V per pair of pixels
+#[derive(Debug, Clone, Copy)]
+enum Chroma<'a> {
+    /// Rows of the U and V planes
+    Planar(&'a [u8], &'a [u8]),
+    /// Row of NV12 UV pairs
+    Interleaved(&'a [u8]),
+    /// Row of YUYV pixels, with U and V at 1 and 3 of every 4 bytes
+    Yuyv(&'a [u8]),
+}
+
+impl<'a> Chroma<'a> {
+    fn split_into(self, u: &mut [u8], v: &mut [u8]) {
+        match self {
+            Self::Planar(su, sv) => {
+                u.copy_from_slice(&su[..u.len()]);
+                v.copy_from_slice(&sv[..v.len()]);
+            }
+            Self::Interleaved(uv) => {
+                for ((u, v), uv) in u.iter_mut().zip(v.iter_mut()).zip(uv.chunks_exact(2)) {
+                    (*u, *v) = (uv[0], uv[1]);
+                }
+            }
+            Self::Yuyv(row) => {
+                let pairs = row.len() / 4;
+                for ((u, v), yuyv) in u.iter_mut().zip(v.iter_mut()).zip(row.chunks_exact(4)) {
+                    (*u, *v) = (yuyv[1], yuyv[3]);
+                }
+                if let (&[_, su], Some(u), Some(v)) = (&row[pairs * 4..], u.get_mut(pairs), v.get_mut(pairs)) {
+                    (*u, *v) = (su, 128);
+                }
+            }
+        }
+    }
+
+    fn interleave_into(self, uv: &mut [u8]) {
+        match self {
+            Self::Planar(u, v) => {
+                for ((uv, &u), &v) in uv.chunks_exact_mut(2).zip(u).zip(v) {
+                    (uv[0], uv[1]) = (u, v);
+                }
+            }
+            Self::Interleaved(su) => uv.copy_from_slice(&su[..uv.len()]),
+            Self::Yuyv(row) => {
+                let pairs = row.len() / 4;
+                for (uv, yuyv) in uv.chunks_exact_mut(2).zip(row.chunks_exact(4)) {
+                    (uv[0], uv[1]) = (yuyv[1], yuyv[3]);
+                }
+                if let (&[_, u], Some(uv)) = (&row[pairs * 4..], uv.get_mut(pairs * 2..pairs * 2 + 2)) {
+                    (uv[0], uv[1]) = (u, 128);
+                }
+            }
+        }
+    }
+
+    /// U and V as rows of their own, split into `u` and `v` unless they are
+    fn planar<'b>(self, u: &'b mut [u8], v: &'b mut [u8]) -> (&'b [u8], &'b [u8]) where 'a: 'b {
+        if let Self::Planar(u, v) = self {
+            return (u, v);
+        }
+        self.split_into(u, v);
+        (u, v)
+    }
+
+    fn write(self, out: ChromaMut) {
+        match out {
+            ChromaMut::Planar(u, v) => self.split_into(u, v),
+            ChromaMut::Interleaved(uv) => self.interleave_into(uv),
+        }
+    }
+}
+
+/// Chroma of an unpadded I420, YV12 or NV12 frame being written
+enum ChromaMut<'a> {
+    Planar(&'a mut [u8], &'a mut [u8]),
+    Interleaved(&'a mut [u8]),
+}
+
+impl<'a> ChromaMut<'a> {
+    /// The chroma planes of a frame of `encoding`, which follow its luma
+    fn planes(chroma: &'a mut [u8], encoding: u32) -> Self {
+        if encoding == ffi::MMAL_ENCODING_NV12 {
+            return Self::Interleaved(chroma);
+        }
+        let (first, second) = chroma.split_at_mut(chroma.len() / 2);
+        if encoding == ffi::MMAL_ENCODING_YV12 { Self::Planar(second, first) } else { Self::Planar(first, second) }
+    }
+
+    /// Row `y` of planes `width` samples wide
+    fn row(&mut self, y: usize, width: usize) -> ChromaMut<'_> {
+        match self {
+            Self::Planar(u, v) => ChromaMut::Planar(&mut u[y * width..][..width], &mut v[y * width..][..width]),
+            Self::Interleaved(uv) => ChromaMut::Interleaved(&mut uv[y * width * 2..][..width * 2]),
+        }
+    }
+}
+
+/// Interleaves a row of luma and chroma as YUYV, the last pixel of an odd width without V
+fn write_yuyv_row(y: &[u8], u: &[u8], v: &[u8], out: &mut [u8]) {
+    let pairs = y.len() / 2;
+    for (((out, y), &u), &v) in out.chunks_exact_mut(4).zip(y.chunks_exact(2)).zip(u).zip(v) {
+        out.copy_from_slice(&[y[0], u, y[1], v]);
+    }
+    if y.len() % 2 == 1 {
+        (out[pairs * 4], out[pairs * 4 + 1]) = (y[pairs * 2], u[pairs]);
+    }
+}
+
+/// Visible part of a YUV frame, row by row
+struct YuvFrame<'a> {
+    encoding: u32,
+    planes: Vec<Plane<'a>>,
+    width: usize,
+    /// See `chroma_phase`
+    phase: (usize, usize),
+}
+
+impl<'a> YuvFrame<'a> {
+    fn new(frame: &RawFrame<'a>) -> Self {
+        let layout = frame.layout();
+        Self { encoding: layout.encoding, planes: frame.planes().collect(), width: layout.crop_size().0, phase: chroma_phase(layout) }
+    }
+
+    /// Row `y` of the luma plane, or of the YUYV pixels
+    fn row(&self, y: usize) -> &'a [u8] {
+        self.planes[0].row(y).unwrap_or_default()
+    }
+
+    fn luma_into(&self, y: usize, out: &mut [u8]) {
+        let row = self.row(y);
+        if self.encoding == ffi::MMAL_ENCODING_YUYV {
+            for (out, yu) in out.iter_mut().zip(row.chunks_exact(2)) {
+                *out = yu[0];
+            }
+        } else {
+            out.copy_from_slice(&row[..out.len()]);
+        }
+    }
+
+    /// Row of the chroma planes of luma row `y`
+    fn chroma_row(&self, y: usize) -> usize {
+        if self.encoding == ffi::MMAL_ENCODING_YUYV { y } else { (y + self.phase.1) / 2 }
+    }
+
+    fn chroma(&self, y: usize) -> Chroma<'a> {
+        let row = |i: usize| self.planes[i].row(self.chroma_row(y)).unwrap_or_default();
+        match self.encoding {
+            ffi::MMAL_ENCODING_YUYV => Chroma::Yuyv(self.row(y)),
+            ffi::MMAL_ENCODING_NV12 => Chroma::Interleaved(row(1)),
+            ffi::MMAL_ENCODING_YV12 => Chroma::Planar(row(2), row(1)),
+            _ => Chroma::Planar(row(1), row(2)),
+        }
+    }
+
+    /// Chroma samples per row, one more than the pairs of pixels with an odd phase
+    fn chroma_width(&self) -> usize {
+        (self.width + self.phase.0).div_ceil(2)
+    }
+
+    /// Chroma of the pairs of pixels of luma rows `top` and `bottom`: the samples of the frame if they cover the same
+    /// pixels, else their average. `scratch` holds 4 rows of `chroma_width`.
+    fn pair_chroma<'b>(&self, top: usize, bottom: usize, scratch: &'b mut [Vec<u8>; 4]) -> Chroma<'b> where 'a: 'b {
+        if self.phase.0 == 0 && self.chroma_row(top) == self.chroma_row(bottom) {
+            return self.chroma(top);
+        }
+        let [u, v, u2, v2] = scratch;
+        self.chroma(top).split_into(u, v);
+        self.chroma(bottom).split_into(u2, v2);
+        let pairs = self.width.div_ceil(2);
+        if self.phase.0 == 0 {
+            for (a, b) in u.iter_mut().zip(u2.iter()).chain(v.iter_mut().zip(v2.iter())) {
+                *a = (*a as u16 + *b as u16).div_ceil(2) as u8;
+            }
+        } else {
+            // the first and second sample of each pair of pixels, the same one for the last pixel of an odd width
+            let last = u.len() - 1;
+            for (a, b) in [(&mut *u, &*u2), (&mut *v, &*v2)] {
+                for x in 0..pairs {
+                    let x1 = (x + 1).min(last);
+                    a[x] = ((a[x] as u16 + a[x1] as u16 + b[x] as u16 + b[x1] as u16 + 2) / 4) as u8;
+                }
+            }
+        }
+        Chroma::Planar(&u[..pairs], &v[..pairs])
+    }
+
+    fn to_rgb(&self, height: usize, layout: RgbLayout, m: &Matrix) -> Vec<u8> {
+        let (width, bpp) = (self.width, layout.bpp());
+        let mut data = vec![0xff; width * height * bpp];
+        let rows = data.chunks_exact_mut(width * bpp).enumerate();
+        if self.encoding == ffi::MMAL_ENCODING_YUYV {
+            let kernel: RowKernel = rgb_kernel!(layout, yuyv_to_rgb_row);
+            rows.for_each(|(y, out)| kernel(m, self.row(y), out));
+        } else {
+            let kernel: YuvRowKernel = rgb_kernel!(layout, yuv_to_rgb_row);
+            let (mut u, mut v) = (vec![0; self.chroma_width()], vec![0; self.chroma_width()]);
+            for (y, out) in rows {
+                let (u, v) = self.chroma(y).planar(&mut u, &mut v);
+                kernel(m, self.row(y), u, v, self.phase.0, out);
+            }
+        }
+        data
+    }
+
+    /// Converts to the unpadded frame of a YUV `encoding`
+    fn to_yuv(&self, height: usize, encoding: u32) -> Vec<u8> {
+        let (width, cw) = (self.width, self.width.div_ceil(2));
+        let mut scratch = [(); 4].map(|_| vec![0; self.chroma_width()]);
+        if encoding == ffi::MMAL_ENCODING_YUYV {
+            let mut data = vec![0; width * height * 2];
+            let (mut luma, mut u, mut v) = (vec![0; width], vec![0; cw], vec![0; cw]);
+            for (y, out) in data.chunks_exact_mut(width * 2).enumerate() {
+                self.luma_into(y, &mut luma);
+                let (u, v) = self.pair_chroma(y, y, &mut scratch).planar(&mut u, &mut v);
+                write_yuyv_row(&luma, u, v, out);
+            }
+            return data;
+        }
+        let mut data = vec![0; width * height + 2 * cw * height.div_ceil(2)];
+        let (luma, chroma) = data.split_at_mut(width * height);
+        for (y, out) in luma.chunks_exact_mut(width).enumerate() {
+            self.luma_into(y, out);
+        }
+        let mut chroma = ChromaMut::planes(chroma, encoding);
+        for y in 0..height.div_ceil(2) {
+            self.pair_chroma(2 * y, (2 * y + 1).min(height - 1), &mut scratch).write(chroma.row(y, cw));
+        }
+        data
+    }
+}
+
+/// Converts the visible part of a packed RGB frame to the unpadded frame of a YUV `encoding`
+fn rgb_to_yuv(frame: &RawFrame, layout: RgbLayout, encoding: u32, m: &Matrix) -> Vec<u8> {
+    let (width, height) = frame.layout().crop_size();
+    let cw = width.div_ceil(2);
+    let rows: Vec<&[u8]> = frame.plane(0).map(|p| p.rows().collect()).unwrap_or_default();
+    let luma: RowKernel = rgb_kernel!(layout, rgb_to_luma_row);
+    let chroma: ChromaRowKernel = rgb_kernel!(layout, rgb_to_chroma_row);
+    let (mut u, mut v) = (vec![0; cw], vec![0; cw]);
+    if encoding == ffi::MMAL_ENCODING_YUYV {
+        let mut data = vec![0; width * height * 2];
+        let mut y = vec![0; width];
+        for (out, row) in data.chunks_exact_mut(width * 2).zip(&rows) {
+            luma(m, row, &mut y);
+            chroma(m, row, row, &mut u, &mut v);
+            write_yuyv_row(&y, &u, &v, out);
+        }
+        return data;
+    }
+    let mut data = vec![0; width * height + 2 * cw * height.div_ceil(2)];
+    let (luma_plane, chroma_planes) = data.split_at_mut(width * height);
+    for (out, row) in luma_plane.chunks_exact_mut(width).zip(&rows) {
+        luma(m, row, out);
+    }
+    let mut chroma_planes = ChromaMut::planes(chroma_planes, encoding);
+    // the last row of an odd height counts twice
+    for (y, block) in rows.chunks(2).enumerate() {
+        let (top, bottom) = (block[0], block[block.len() - 1]);
+        match chroma_planes.row(y, cw) {
+            ChromaMut::Planar(out_u, out_v) => chroma(m, top, bottom, out_u, out_v),
+            out => {
+                chroma(m, top, bottom, &mut u, &mut v);
+                Chroma::Planar(&u, &v).write(out);
+            }
+        }
+    }
+    data
+}
+
+/// Reorders the channels of a packed RGB frame
+fn shuffle(frame: &RawFrame, from: RgbLayout, to: RgbLayout) -> Vec<u8> {
+    let (width, height) = frame.layout().crop_size();
+    let kernel: fn(RgbLayout) -> ShuffleRow = rgb_kernel!(from, shuffle_kernel);
+    let kernel = kernel(to);
+    let mut data = vec![0xff; width * height * to.bpp()];
+    let rows = frame.plane(0).into_iter().flat_map(|p| p.rows());
+    for (out, row) in data.chunks_exact_mut(width * to.bpp()).zip(rows) {
+        kernel(row, out);
+    }
+    data
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// A converted frame, without padding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawImage {
+    pub layout: RawFrameLayout,
+    pub data: Vec<u8>,
+}
+
+impl RawImage {
+    pub fn frame(&self) -> RawFrame<'_> {
+        RawFrame::new(&self.layout, &self.data).expect("converted frames have the size of their layout")
+    }
+}
+
+/// Converts the visible part of raw frames between the encodings of `RawFrameLayout`, on the CPU.
+///
+/// Rows go through kernels with fixed point arithmetic, instantiated for the channel order of each RGB encoding and
+/// working on pairs of pixels, one per chroma sample, so that the compiler can vectorize them. Chroma is averaged over
+/// the pixels of a sample when subsampling, and repeated when upsampling; between I420, YV12 and NV12 the planes are
+/// copied as they are. Use `RawFrameLayout::from_port` for camera and splitter frames, which gives their actual RGB
+/// channel order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Converter {
+    color_space: ColorSpace,
+}
+
+impl Converter {
+    /// A converter for BT.601 frames
+    pub fn new() -> Self { Self::default() }
+
+    /// A converter for the color space of `format`
+    pub fn from_format(format: &EsFormat) -> Self {
+        Self { color_space: ColorSpace::from_mmal(format.video.as_ref().map_or(0, |v| v.color_space)) }
+    }
+
+    pub fn with_color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    /// Converts `frame` to `encoding`. Fails with `MMAL_ENOSYS` if `RawFrameLayout` does not support `encoding`.
+    pub fn convert(&self, frame: &RawFrame, encoding: u32) -> Result<RawImage> {
+        let (width, height) = frame.layout().crop_size();
+        let layout = RawFrameLayout::new(encoding, width as u32, height as u32)?;
+        let source = frame.layout().encoding;
+        let m = self.color_space.matrix();
+        // frames cropped at an odd column or row of their chroma get the chroma of the pairs of the crop
+        let data = if (source == encoding && chroma_phase(frame.layout()) == (0, 0)) || width == 0 || height == 0 {
+            frame.to_packed()
+        } else {
+            match (RgbLayout::of(source), RgbLayout::of(encoding)) {
+                (Some(from), Some(to)) => shuffle(frame, from, to),
+                (Some(from), None) => rgb_to_yuv(frame, from, encoding, m),
+                (None, Some(to)) => YuvFrame::new(frame).to_rgb(height, to, m),
+                (None, None) => YuvFrame::new(frame).to_yuv(height, encoding),
+            }
+        };
+        Ok(RawImage { layout, data })
+    }
+
+    /// Luma of the visible part of `frame`, one byte per pixel.
+    ///
+    /// This is the Y plane of YUV frames, and the Y of RGB frames converted to YUV, so in video range except for JFIF.
+    pub fn to_gray(&self, frame: &RawFrame) -> Vec<u8> {
+        let (width, height) = frame.layout().crop_size();
+        let mut gray = vec![0; width * height];
+        if width == 0 {
+            return gray;
+        }
+        let rows = gray.chunks_exact_mut(width).enumerate();
+        match RgbLayout::of(frame.layout().encoding) {
+            Some(layout) => {
+                let (m, luma): (_, RowKernel) = (self.color_space.matrix(), rgb_kernel!(layout, rgb_to_luma_row));
+                let plane = frame.plane(0).expect("RGB frames have a plane");
+                rows.for_each(|(y, out)| luma(m, plane.row(y).unwrap_or_default(), out));
+            }
+            None => {
+                let yuv = YuvFrame::new(frame);
+                rows.for_each(|(y, out)| yuv.luma_into(y, out));
+            }
+        }
+        gray
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+const ENCODINGS: [u32; 7] = [ffi::MMAL_ENCODING_I420, ffi::MMAL_ENCODING_YV12, ffi::MMAL_ENCODING_NV12, ffi::MMAL_ENCODING_YUYV,
+    ffi::MMAL_ENCODING_RGB24, ffi::MMAL_ENCODING_BGR24, ffi::MMAL_ENCODING_RGBA];
+
+/// RGB24 picture of 2x2 blocks of one color, so that chroma subsampling is lossless, padded to 16x12 and cropped at
+/// (2, 2)
+#[cfg(test)]
+fn test_rgb_frame(width: u32, height: u32) -> RawImage {
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_RGB24, 16, 12, (2, 2, width, height)).unwrap();
+    let mut data = vec![0u8; layout.frame_size()];
+    for (y, row) in data.chunks_exact_mut(16 * 3).enumerate() {
+        for (x, px) in row.chunks_exact_mut(3).enumerate() {
+            let (bx, by) = (x / 2, y / 2);
+            px.copy_from_slice(&[(bx * 37 + by * 11) as u8, (bx * 5 + by * 53 + 40) as u8, (200 - bx * 17 - by * 9) as u8]);
+        }
+    }
+    RawImage { layout, data }
+}
+
+#[cfg(test)]
+fn max_diff(a: &[u8], b: &[u8]) -> u8 {
+    assert_eq!(a.len(), b.len());
+    a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0)
+}
+
+#[test]
+fn test_convert_round_trips() {
+    for color_space in [ColorSpace::Bt601, ColorSpace::Bt709, ColorSpace::Jfif] {
+        let converter = Converter::new().with_color_space(color_space);
+        let rgb = test_rgb_frame(12, 8);
+        let rgb_packed = rgb.frame().to_packed();
+        let i420 = converter.convert(&rgb.frame(), ffi::MMAL_ENCODING_I420).unwrap();
+        assert_eq!(i420.data.len(), 12 * 8 + 2 * 6 * 4);
+        for encoding in ENCODINGS {
+            // YUV formats convert losslessly between each other, RGB ones between each other
+            let image = converter.convert(&rgb.frame(), encoding).unwrap();
+            assert_eq!(image.data.len(), image.layout.frame_size());
+            let back = converter.convert(&image.frame(), ffi::MMAL_ENCODING_RGB24).unwrap();
+            let tolerance = if RgbLayout::of(encoding).is_some() { 0 } else { 3 };
+            assert!(max_diff(&back.data, &rgb_packed) <= tolerance, "{color_space:?} RGB24 -> {encoding:#x}");
+
+            let image = converter.convert(&i420.frame(), encoding).unwrap();
+            let back = converter.convert(&image.frame(), ffi::MMAL_ENCODING_I420).unwrap();
+            let tolerance = if RgbLayout::of(encoding).is_some() { 2 } else { 0 };
+            assert!(max_diff(&back.data, &i420.data) <= tolerance, "{color_space:?} I420 -> {encoding:#x}");
+            for target in ENCODINGS {
+                let converted = converter.convert(&image.frame(), target).unwrap();
+                assert_eq!(converted.data.len(), converted.layout.frame_size(), "{encoding:#x} -> {target:#x}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_convert_values() {
+    let converter = Converter::new();
+    // black, white, red and blue in BT.601 video range
+    let layout = RawFrameLayout::new(ffi::MMAL_ENCODING_RGB24, 4, 2).unwrap();
+    let data = [0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0xff].repeat(2);
+    let frame = RawFrame::new(&layout, &data).unwrap();
+    let yuyv = converter.convert(&frame, ffi::MMAL_ENCODING_YUYV).unwrap();
+    assert_eq!(&yuyv.data[..8], &[16, 128, 235, 128, 81, 165, 41, 175]);
+    assert_eq!(converter.to_gray(&frame), [16, 235, 81, 41].repeat(2));
+    assert_eq!(converter.to_gray(&yuyv.frame()), converter.to_gray(&frame));
+    let jfif = Converter::new().with_color_space(ColorSpace::Jfif);
+    assert_eq!(jfif.to_gray(&frame), [0, 255, 76, 29].repeat(2));
+
+    let bgr = converter.convert(&frame, ffi::MMAL_ENCODING_BGR24).unwrap().data;
+    assert_eq!(&bgr[6..12], &[0, 0, 0xff, 0xff, 0, 0]);
+    let rgba = converter.convert(&frame, ffi::MMAL_ENCODING_RGBA).unwrap().data;
+    assert_eq!(&rgba[8..16], &[0xff, 0, 0, 0xff, 0, 0, 0xff, 0xff]);
+
+    // NV12 interleaves U and V, YV12 stores V first
+    let i420 = converter.convert(&frame, ffi::MMAL_ENCODING_I420).unwrap();
+    let nv12 = converter.convert(&i420.frame(), ffi::MMAL_ENCODING_NV12).unwrap();
+    let yv12 = converter.convert(&i420.frame(), ffi::MMAL_ENCODING_YV12).unwrap();
+    assert_eq!(&i420.data[8..], &[128, 165, 128, 175]);
+    assert_eq!(&nv12.data[8..], &[128, 128, 165, 175]);
+    assert_eq!(&yv12.data[8..], &[128, 175, 128, 165]);
+
+    // odd sizes have a chroma sample for the last column and row, except for the V of YUYV
+    let rgb = test_rgb_frame(5, 3);
+    for encoding in ENCODINGS.into_iter().filter(|&e| e != ffi::MMAL_ENCODING_YUYV) {
+        let image = converter.convert(&rgb.frame(), encoding).unwrap();
+        assert_eq!(image.layout.crop_size(), (5, 3));
+        let back = converter.convert(&image.frame(), ffi::MMAL_ENCODING_RGB24).unwrap();
+        assert!(max_diff(&back.data, &rgb.frame().to_packed()) <= 3, "{encoding:#x}");
+    }
+    let e = converter.convert(&frame, ffi::MMAL_ENCODING_H264).unwrap_err();
+    assert!(matches!(e.cause(), Cause::Status(ffi::MMAL_STATUS_T::MMAL_ENOSYS)));
+}
+
+#[test]
+fn test_convert_odd_crops() {
+    let converter = Converter::new();
+    // the picture of `test_rgb_frame` without its crop, so with real chroma in every encoding
+    let rgb = RawImage { layout: RawFrameLayout::new(ffi::MMAL_ENCODING_RGB24, 16, 12).unwrap(), data: test_rgb_frame(14, 10).data };
+    for encoding in ENCODINGS {
+        let image = converter.convert(&rgb.frame(), encoding).unwrap();
+        let expected = converter.convert(&image.frame(), ffi::MMAL_ENCODING_RGB24).unwrap();
+        for crop in [(3, 1, 9, 7), (1, 3, 11, 6), (5, 5, 1, 1), (2, 1, 14, 11)] {
+            let layout = RawFrameLayout::with_crop(encoding, 16, 12, crop).unwrap();
+            let frame = RawFrame::new(&layout, &image.data).unwrap();
+            // YUYV crops start at an even column
+            let (x, y, (width, height)) = (layout.planes()[0].crop_x, layout.planes()[0].crop_y, layout.crop_size());
+            let rows = expected.data.chunks_exact(16 * 3).skip(y).take(height);
+            let expected: Vec<u8> = rows.flat_map(|row| &row[x * 3..(x + width) * 3]).copied().collect();
+            let converted = converter.convert(&frame, ffi::MMAL_ENCODING_RGB24).unwrap();
+            assert_eq!(converted.data, expected, "{encoding:#x} {crop:?}");
+
+            // chroma straddling the pairs of the crop is averaged
+            for target in ENCODINGS {
+                let converted = converter.convert(&frame, target).unwrap();
+                assert_eq!(converted.layout.crop_size(), (width, height));
+                assert_eq!(converted.data.len(), converted.layout.frame_size(), "{encoding:#x} {crop:?} -> {target:#x}");
+                let rgb_layout = RawFrameLayout::new(ffi::MMAL_ENCODING_RGB24, width as u32, height as u32).unwrap();
+                let via_rgb = converter.convert(&RawFrame::new(&rgb_layout, &expected).unwrap(), target).unwrap();
+                assert!(max_diff(&converted.data, &via_rgb.data) <= 3, "{encoding:#x} {crop:?} -> {target:#x}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rgb_encoding_order() {
+    for (encoding, swapped) in [(ffi::MMAL_ENCODING_RGB24, ffi::MMAL_ENCODING_BGR24), (ffi::MMAL_ENCODING_BGR24, ffi::MMAL_ENCODING_RGB24)] {
+        assert_eq!(rgb_encoding(encoding, true), encoding);
+        assert_eq!(rgb_encoding(encoding, false), swapped);
+        // frames of a port set to the swapped encoding on old firmware have the requested order
+        assert_eq!(rgb_encoding(rgb_encoding(encoding, false), false), encoding);
+    }
+    assert_eq!(rgb_encoding(ffi::MMAL_ENCODING_I420, false), ffi::MMAL_ENCODING_I420);
+    assert_eq!(ColorSpace::from_mmal(ffi::MMAL_COLOR_SPACE_ITUR_BT709), ColorSpace::Bt709);
+    assert_eq!(ColorSpace::from_mmal(ffi::MMAL_COLOR_SPACE_UNKNOWN), ColorSpace::Bt601);
+}
@@ -0,0 +1,238 @@
+use super::*;
+use std::io::Write;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Value of a TIFF tag
+enum TagValue {
+    Byte(Vec<u8>),
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    SRational(Vec<(i32, i32)>),
+}
+
+impl TagValue {
+    /// TIFF type and count
+    fn kind(&self) -> (u16, u32) {
+        match self {
+            Self::Byte(v) => (1, v.len() as u32),
+            Self::Ascii(s) => (2, s.len() as u32 + 1),
+            Self::Short(v) => (3, v.len() as u32),
+            Self::Long(v) => (4, v.len() as u32),
+            Self::Rational(v) => (5, v.len() as u32),
+            Self::SRational(v) => (10, v.len() as u32),
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Byte(v) => v.clone(),
+            Self::Ascii(s) => s.bytes().chain([0]).collect(),
+            Self::Short(v) => v.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            Self::Long(v) => v.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            Self::Rational(v) => v.iter().flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()]).flatten().collect(),
+            Self::SRational(v) => v.iter().flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()]).flatten().collect(),
+        }
+    }
+}
+
+const DNG_RATIONAL_DENOMINATOR: i32 = 10000;
+
+/// Size of the 16 bit pixels, for the 32 bit `StripByteCounts`
+fn strip_size(width: u32, height: u32) -> Result<u32> {
+    width.checked_mul(height).and_then(|n| n.checked_mul(2))
+        .ok_or_else(|| MmalError::with_status(ffi::MMAL_STATUS_T::MMAL_EINVAL, format!("dng: {width}x{height} pixels do not fit a strip")))
+}
+
+fn srational(v: f32) -> (i32, i32) {
+    ((v * DNG_RATIONAL_DENOMINATOR as f32).round() as i32, DNG_RATIONAL_DENOMINATOR)
+}
+
+/// Writes the raw sensor data of a `BayerRaw` as an uncompressed DNG, for raw processors to demosaic.
+///
+/// The pixels are stored as 16 bit values with the black and white levels of the sensor, unless set. The color matrix
+/// maps XYZ to the camera colors under D65, as the DNG `ColorMatrix1` tag; it is the identity by default, as the
+/// calibration of the camera is not known. The as shot neutral gives the white balance, in camera colors.
+#[derive(Debug, Clone)]
+pub struct DngWriter {
+    make: String,
+    black_level: Option<u16>,
+    white_level: Option<u16>,
+    color_matrix: [[f32; 3]; 3],
+    as_shot_neutral: [f32; 3],
+}
+
+impl Default for DngWriter {
+    fn default() -> Self {
+        Self {
+            make: "Raspberry Pi".to_owned(),
+            black_level: None,
+            white_level: None,
+            color_matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            as_shot_neutral: [1.0; 3],
+        }
+    }
+}
+
+impl DngWriter {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn with_make(mut self, make: impl Into<String>) -> Self {
+        self.make = make.into();
+        self
+    }
+
+    pub fn with_black_level(mut self, level: u16) -> Self {
+        self.black_level = Some(level);
+        self
+    }
+
+    pub fn with_white_level(mut self, level: u16) -> Self {
+        self.white_level = Some(level);
+        self
+    }
+
+    /// XYZ to camera matrix, row by row
+    pub fn with_color_matrix(mut self, matrix: [[f32; 3]; 3]) -> Self {
+        self.color_matrix = matrix;
+        self
+    }
+
+    /// White balance: the camera values of a neutral color, e.g. the inverse of the red, green and blue gains
+    pub fn with_as_shot_neutral(mut self, neutral: [f32; 3]) -> Self {
+        self.as_shot_neutral = neutral;
+        self
+    }
+
+    fn tags(&self, raw: &BayerRaw, strip_offset: u32) -> Result<Vec<(u16, TagValue)>> {
+        let (width, height) = (raw.width, raw.height);
+        Ok(vec![
+            (254, TagValue::Long(vec![0])),
+            (256, TagValue::Long(vec![width])),
+            (257, TagValue::Long(vec![height])),
+            (258, TagValue::Short(vec![16])),
+            (259, TagValue::Short(vec![1])),
+            // color filter array
+            (262, TagValue::Short(vec![32803])),
+            (271, TagValue::Ascii(self.make.clone())),
+            (272, TagValue::Ascii(raw.sensor.clone())),
+            (273, TagValue::Long(vec![strip_offset])),
+            (274, TagValue::Short(vec![1])),
+            (277, TagValue::Short(vec![1])),
+            (278, TagValue::Long(vec![height])),
+            (279, TagValue::Long(vec![strip_size(width, height)?])),
+            (284, TagValue::Short(vec![1])),
+            (305, TagValue::Ascii(concat!("mmal-rs ", env!("CARGO_PKG_VERSION")).to_owned())),
+            (33421, TagValue::Short(vec![2, 2])),
+            (33422, TagValue::Byte(raw.order.cfa_pattern().to_vec())),
+            (50706, TagValue::Byte(vec![1, 4, 0, 0])),
+            (50707, TagValue::Byte(vec![1, 1, 0, 0])),
+            (50708, TagValue::Ascii(format!("{} {}", self.make, raw.sensor))),
+            (50710, TagValue::Byte(vec![0, 1, 2])),
+            (50711, TagValue::Short(vec![1])),
+            (50714, TagValue::Long(vec![self.black_level.unwrap_or_else(|| raw.black_level()) as u32])),
+            (50717, TagValue::Long(vec![self.white_level.unwrap_or_else(|| raw.white_level()) as u32])),
+            (50721, TagValue::SRational(self.color_matrix.iter().flatten().map(|v| srational(*v)).collect())),
+            (50728, TagValue::Rational(self.as_shot_neutral.iter().map(|v| {
+                let (n, d) = srational(*v);
+                (n.max(0) as u32, d as u32)
+            }).collect())),
+            // D65
+            (50778, TagValue::Short(vec![21])),
+        ])
+    }
+
+    /// Writes `raw` as a DNG file
+    pub fn write(&self, raw: &BayerRaw, mut out: impl Write) -> Result<()> {
+        // the header, the IFD, the values which do not fit in their entry, then the pixels
+        let tags = self.tags(raw, 0)?;
+        let entries = tags.len();
+        let ifd_end = 8 + 2 + entries * 12 + 4;
+        let extra_size: usize = tags.iter().map(|(_, v)| v.bytes().len()).filter(|n| *n > 4).map(|n| n.next_multiple_of(2)).sum();
+        let strip_offset = ifd_end + extra_size;
+        let tags = self.tags(raw, strip_offset as u32)?;
+
+        let mut head = b"II*\0".to_vec();
+        head.extend_from_slice(&8u32.to_le_bytes());
+        head.extend_from_slice(&(entries as u16).to_le_bytes());
+        let mut extra = Vec::new();
+        for (tag, value) in &tags {
+            let (kind, count) = value.kind();
+            let mut bytes = value.bytes();
+            head.extend_from_slice(&tag.to_le_bytes());
+            head.extend_from_slice(&kind.to_le_bytes());
+            head.extend_from_slice(&count.to_le_bytes());
+            if bytes.len() > 4 {
+                head.extend_from_slice(&((ifd_end + extra.len()) as u32).to_le_bytes());
+                bytes.resize(bytes.len().next_multiple_of(2), 0);
+                extra.extend_from_slice(&bytes);
+            } else {
+                bytes.resize(4, 0);
+                head.extend_from_slice(&bytes);
+            }
+        }
+        // no next IFD
+        head.extend_from_slice(&[0; 4]);
+        head.extend_from_slice(&extra);
+        out.write_all(&head)?;
+
+        let width = raw.width as usize;
+        let (mut row, mut bytes) = (vec![0u16; width], vec![0u8; width * 2]);
+        for y in 0..raw.height as usize {
+            raw.unpack_row(y, &mut row);
+            for (b, v) in bytes.chunks_exact_mut(2).zip(&row) {
+                b.copy_from_slice(&v.to_le_bytes());
+            }
+            out.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_dng_writer() {
+    let pixel = |x: usize, y: usize| (x * 100 + y) as u16;
+    let block = bayer::test_brcm_block("imx219", 10, 4, 10, 1, pixel);
+    let raw = BayerRaw::parse(&block).unwrap();
+    let mut dng = Vec::new();
+    DngWriter::new().with_as_shot_neutral([0.5, 1.0, 0.625]).write(&raw, &mut dng).unwrap();
+
+    assert_eq!(&dng[..8], b"II*\0\x08\0\0\0");
+    let u16_at = |i: usize| u16::from_le_bytes([dng[i], dng[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(dng[i..i + 4].try_into().unwrap());
+    let entries = u16_at(8) as usize;
+    let tag = |id: u16| (0..entries).map(|i| 10 + i * 12).find(|&e| u16_at(e) == id).map(|e| (u16_at(e + 2), u32_at(e + 4), e + 8));
+    let tags: Vec<_> = (0..entries).map(|i| u16_at(10 + i * 12)).collect();
+    assert!(tags.windows(2).all(|w| w[0] < w[1]), "{tags:?}");
+    assert_eq!(u32_at(10 + entries * 12), 0);
+
+    assert_eq!(tag(256).map(|(_, _, v)| u32_at(v)), Some(10));
+    assert_eq!(tag(262).map(|(_, _, v)| u16_at(v)), Some(32803));
+    // GBRG
+    assert_eq!(tag(33422).map(|(_, _, v)| &dng[v..v + 4]), Some(&[1, 2, 0, 1][..]));
+    assert_eq!(tag(50714).map(|(_, _, v)| u32_at(v)), Some(64));
+    assert_eq!(tag(50717).map(|(_, _, v)| u32_at(v)), Some(1023));
+    let (kind, count, v) = tag(50728).unwrap();
+    let neutral = u32_at(v) as usize;
+    assert_eq!((kind, count, u32_at(neutral), u32_at(neutral + 4), u32_at(neutral + 16)), (5, 3, 5000, 10000, 6250));
+    let (_, _, v) = tag(50721).unwrap();
+    let matrix = u32_at(v) as usize;
+    assert_eq!((u32_at(matrix), u32_at(matrix + 8)), (10000, 0));
+    let (_, count, v) = tag(272).unwrap();
+    assert_eq!(&dng[u32_at(v) as usize..][..count as usize], b"imx219\0");
+
+    let strip = u32_at(tag(273).unwrap().2) as usize;
+    assert_eq!(u32_at(tag(279).unwrap().2), 10 * 4 * 2);
+    assert_eq!(dng.len(), strip + 80);
+    let pixels: Vec<u16> = dng[strip..].chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect();
+    assert_eq!(pixels, raw.unpack());
+    assert_eq!(pixels[13], pixel(3, 1));
+
+    assert_eq!(strip_size(65535, 32767).unwrap(), 65535 * 32767 * 2);
+    let e = strip_size(65535, 65535).unwrap_err();
+    assert!(matches!(e.cause(), Cause::Status(ffi::MMAL_STATUS_T::MMAL_EINVAL)), "{e}");
+}
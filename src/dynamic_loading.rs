@@ -0,0 +1,111 @@
+//! Runtime loading of the MMAL libraries, enabled by the `dynamic-loading` feature
+//!
+//! `build.rs` rewrites every function declared in `ffi` into a wrapper calling through a [`Symbol`], an entry of
+//! the function table which is resolved on first use. The libraries themselves are opened by `init()`, or by
+//! the first `ffi` call made without it.
+//!
+//! The libraries are looked up in `$MMAL_LIB_DIR` if set, otherwise in `/opt/vc/lib` and then on the default
+//! search path of the dynamic linker.
+
+use std::{
+    ffi::{c_void, CStr, CString},
+    sync::{atomic::{AtomicPtr, Ordering}, OnceLock},
+    ptr,
+};
+
+/// Libraries providing the `ffi` functions, in dependency order
+const LIBRARIES: &[&str] = &[
+    "libvcos.so",
+    "libvchiq_arm.so",
+    "libvcsm.so",
+    "libbcm_host.so",
+    "libmmal_core.so",
+    "libmmal_util.so",
+    "libmmal_vc_client.so",
+];
+
+const DEFAULT_LIB_DIR: &str = "/opt/vc/lib";
+
+struct Handle(*mut c_void);
+
+unsafe impl Send for Handle { }
+unsafe impl Sync for Handle { }
+
+pub(crate) struct Libraries {
+    handles: Vec<Handle>,
+}
+
+impl Libraries {
+    fn open(lib_dir: Option<&str>) -> std::result::Result<Self, String> {
+        let handles = LIBRARIES.iter()
+            .map(|name| match lib_dir {
+                Some(dir) => open_library(&format!("{}/{}", dir, name)),
+                None => open_library(&format!("{}/{}", DEFAULT_LIB_DIR, name)).or_else(|_| open_library(name)),
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self { handles })
+    }
+
+    fn lookup(&self, name: &CStr) -> *mut c_void {
+        self.handles.iter()
+            .map(|h| unsafe { libc::dlsym(h.0, name.as_ptr()) })
+            .find(|p| !p.is_null())
+            .unwrap_or(ptr::null_mut())
+    }
+}
+
+fn open_library(path: &str) -> std::result::Result<Handle, String> {
+    let c_path = CString::new(path).map_err(|_| format!("invalid library path `{}`", path))?;
+    unsafe {
+        let h = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL);
+        if h.is_null() {
+            let e = libc::dlerror();
+            Err(if e.is_null() { format!("unable to load `{}`", path) } else { CStr::from_ptr(e).to_string_lossy().into_owned() })
+        } else {
+            Ok(Handle(h))
+        }
+    }
+}
+
+/// Opens the libraries once, returns the reason if they are not available
+pub(crate) fn load() -> std::result::Result<&'static Libraries, &'static str> {
+    static LIBRARIES: OnceLock<std::result::Result<Libraries, String>> = OnceLock::new();
+    LIBRARIES
+        .get_or_init(|| Libraries::open(std::env::var("MMAL_LIB_DIR").ok().as_deref()))
+        .as_ref()
+        .map_err(|e| e.as_str())
+}
+
+/// Function table entry, resolved on first use
+pub struct Symbol {
+    name: &'static str,
+    p: AtomicPtr<c_void>,
+}
+
+impl Symbol {
+    /// `name` must be nul-terminated
+    pub const fn new(name: &'static str) -> Self { Self { name, p: AtomicPtr::new(ptr::null_mut()) } }
+
+    /// Panics if the function can't be resolved, as there is no way to report it through the C signature.
+    /// Call `init()` first to have missing libraries reported as an error.
+    pub fn get(&self) -> *mut c_void {
+        let p = self.p.load(Ordering::Acquire);
+        if !p.is_null() {
+            return p;
+        }
+        let name = CStr::from_bytes_with_nul(self.name.as_bytes()).expect("symbol name is not nul-terminated");
+        let libraries = load().unwrap_or_else(|e| panic!("MMAL not available: {}", e));
+        let p = libraries.lookup(name);
+        if p.is_null() {
+            panic!("MMAL not available: symbol `{}` not found", name.to_string_lossy());
+        }
+        self.p.store(p, Ordering::Release);
+        p
+    }
+}
+
+#[test]
+fn test_missing_libraries() {
+    let e = Libraries::open(Some("/nonexistent")).err().expect("libraries loaded from a nonexistent directory");
+    assert!(e.contains("libvcos.so"), "{}", e);
+}
@@ -0,0 +1,2858 @@
+//! In-process emulation of the MMAL C API
+//!
+//! Enabled by the `emulation` feature. The functions in this module are exported under the very
+//! names `ffi` declares, so the rest of the crate links against them instead of libmmal, and
+//! pipelines can be exercised on any host, e.g. in `cargo test`.
+//!
+//! Emulated components:
+//! * `vc.camera_info` reports a single camera
+//! * `vc.ril.camera` produces synthetic frames on its preview, video and capture ports
+//! * `vc.ril.image_encode` wraps each input frame into a minimal JPEG (or PNG, GIF, ...) container
+//! * `vc.ril.video_encode` produces an H.264 Annex-B or MJPEG elementary stream
+//! * `vc.null_sink` discards everything it is fed
+//!
+//! Each component runs a worker thread while enabled, and port callbacks are invoked from that
+//! thread, the way the firmware invokes them from the VCHIQ thread. All connections are treated as
+//! tunnelled: frames are passed from the output port directly to the connected input port.
+//!
+//! Not emulated: clock ports, non-tunnelled connections, and the actual image processing; the
+//! frame payloads are synthetic.
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{c_char, c_int, c_uint, CStr, CString},
+    mem, ptr,
+    sync::{atomic::{AtomicU32, Ordering}, Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use crate::ffi::{self, MMAL_STATUS_T::{self, *}};
+
+type Status = MMAL_STATUS_T::Type;
+
+/// Name reported by the emulated camera_info component
+pub const EMULATED_CAMERA_NAME: &str = "emulated";
+/// Maximum resolution reported by the emulated camera_info component
+pub const EMULATED_CAMERA_MAX_SIZE: (u32, u32) = (2592, 1944);
+
+const DEFAULT_FRAME_RATE: (i32, i32) = (30, 1);
+const CONTROL_BUFFER_SIZE: u32 = 256;
+const EVENT_POOL_SIZE: u32 = 8;
+const OPAQUE_BUFFER_SIZE: u32 = 128;
+const IMAGE_ENCODE_BUFFER_SIZE: u32 = 81_920;
+const VIDEO_ENCODE_BUFFER_SIZE: u32 = 65_536;
+const DEFAULT_INTRA_PERIOD: u32 = 60;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Raw pointers handed between client threads and component workers
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Ptr<T>(*mut T);
+
+unsafe impl<T> Send for Ptr<T> { }
+
+type HeaderPtr = Ptr<ffi::MMAL_BUFFER_HEADER_T>;
+type PortPtr = Ptr<ffi::MMAL_PORT_T>;
+
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Queues
+
+struct Queue {
+    q: Mutex<VecDeque<HeaderPtr>>,
+    cv: Condvar,
+}
+
+impl Queue {
+    unsafe fn from_ptr<'a>(queue: *mut ffi::MMAL_QUEUE_T) -> &'a Queue { &*(queue as *const Queue) }
+
+    fn put(&self, b: HeaderPtr, back: bool) {
+        let mut q = lock(&self.q);
+        if back { q.push_front(b) } else { q.push_back(b) }
+        self.cv.notify_one();
+    }
+
+    fn get(&self, timeout: Option<Duration>) -> *mut ffi::MMAL_BUFFER_HEADER_T {
+        let mut q = lock(&self.q);
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(b) = q.pop_front() {
+                return b.0;
+            }
+            match deadline {
+                None => q = self.cv.wait(q).unwrap_or_else(|e| e.into_inner()),
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d { return ptr::null_mut() }
+                    q = self.cv.wait_timeout(q, d - now).unwrap_or_else(|e| e.into_inner()).0;
+                }
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_create() -> *mut ffi::MMAL_QUEUE_T {
+    let q = Box::new(Queue { q: Mutex::new(VecDeque::new()), cv: Condvar::new() });
+    Box::into_raw(q) as *mut ffi::MMAL_QUEUE_T
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_put(queue: *mut ffi::MMAL_QUEUE_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    Queue::from_ptr(queue).put(Ptr(buffer), false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_put_back(queue: *mut ffi::MMAL_QUEUE_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    Queue::from_ptr(queue).put(Ptr(buffer), true)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_get(queue: *mut ffi::MMAL_QUEUE_T) -> *mut ffi::MMAL_BUFFER_HEADER_T {
+    Queue::from_ptr(queue).get(Some(Duration::ZERO))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_wait(queue: *mut ffi::MMAL_QUEUE_T) -> *mut ffi::MMAL_BUFFER_HEADER_T {
+    Queue::from_ptr(queue).get(None)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_timedwait(queue: *mut ffi::MMAL_QUEUE_T, timeout: ffi::VCOS_UNSIGNED) -> *mut ffi::MMAL_BUFFER_HEADER_T {
+    Queue::from_ptr(queue).get(Some(Duration::from_millis(timeout as u64)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_length(queue: *mut ffi::MMAL_QUEUE_T) -> c_uint {
+    lock(&Queue::from_ptr(queue).q).len() as c_uint
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_queue_destroy(queue: *mut ffi::MMAL_QUEUE_T) {
+    drop(Box::from_raw(queue as *mut Queue))
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Buffer headers and pools
+
+#[repr(C)]
+struct Header {
+    h: ffi::MMAL_BUFFER_HEADER_T,
+    type_specific: ffi::MMAL_BUFFER_HEADER_TYPE_SPECIFIC_T,
+    refcount: AtomicU32,
+    pool: *mut Pool,
+    payload: Vec<u8>,
+}
+
+#[repr(C)]
+struct Pool {
+    p: ffi::MMAL_POOL_T,
+    headers: Vec<*mut ffi::MMAL_BUFFER_HEADER_T>,
+}
+
+unsafe fn reset_header(h: &mut ffi::MMAL_BUFFER_HEADER_T) {
+    h.cmd = 0;
+    h.length = 0;
+    h.offset = 0;
+    h.flags = 0;
+    h.pts = ffi::MMAL_TIME_UNKNOWN;
+    h.dts = ffi::MMAL_TIME_UNKNOWN;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_pool_create(headers: c_uint, payload_size: u32) -> *mut ffi::MMAL_POOL_T {
+    let pool = Box::into_raw(Box::new(Pool {
+        p: ffi::MMAL_POOL_T { queue: mmal_queue_create(), headers_num: headers, header: ptr::null_mut() },
+        headers: Vec::with_capacity(headers as usize),
+    }));
+    for _ in 0..headers {
+        let mut payload = vec![0u8; payload_size as usize];
+        let mut h: ffi::MMAL_BUFFER_HEADER_T = mem::zeroed();
+        h.data = payload.as_mut_ptr();
+        h.alloc_size = payload_size;
+        reset_header(&mut h);
+        let header = Box::into_raw(Box::new(Header { h, type_specific: mem::zeroed(), refcount: AtomicU32::new(1), pool, payload }));
+        (*header).h.type_ = &mut (*header).type_specific;
+        (*pool).headers.push(header as *mut ffi::MMAL_BUFFER_HEADER_T);
+        mmal_queue_put((*pool).p.queue, header as *mut ffi::MMAL_BUFFER_HEADER_T);
+    }
+    (*pool).p.header = (*pool).headers.as_mut_ptr();
+    pool as *mut ffi::MMAL_POOL_T
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_pool_destroy(pool: *mut ffi::MMAL_POOL_T) {
+    let pool = Box::from_raw(pool as *mut Pool);
+    mmal_queue_destroy(pool.p.queue);
+    for h in &pool.headers {
+        drop(Box::from_raw(*h as *mut Header));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_pool_create(_port: *mut ffi::MMAL_PORT_T, headers: c_uint, payload_size: u32) -> *mut ffi::MMAL_POOL_T {
+    mmal_pool_create(headers, payload_size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_pool_destroy(_port: *mut ffi::MMAL_PORT_T, pool: *mut ffi::MMAL_POOL_T) {
+    mmal_pool_destroy(pool)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_buffer_header_acquire(header: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    (*(header as *mut Header)).refcount.fetch_add(1, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_buffer_header_release(header: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    let h = &mut *(header as *mut Header);
+    if h.refcount.fetch_sub(1, Ordering::SeqCst) != 1 {
+        return;
+    }
+    reset_header(&mut h.h);
+    h.refcount.store(1, Ordering::SeqCst);
+    mmal_queue_put((*h.pool).p.queue, header);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_buffer_header_reset(header: *mut ffi::MMAL_BUFFER_HEADER_T) {
+    reset_header(&mut *header)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_buffer_header_mem_lock(_header: *mut ffi::MMAL_BUFFER_HEADER_T) -> Status {
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_buffer_header_mem_unlock(_header: *mut ffi::MMAL_BUFFER_HEADER_T) { }
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Formats
+
+/// Committed port format, as seen by the component
+#[derive(Clone, Copy, Debug, Default)]
+struct Format {
+    encoding: u32,
+    width: u32,
+    height: u32,
+    crop: (i32, i32, i32, i32),
+    frame_rate: (i32, i32),
+    bitrate: u32,
+}
+
+impl Format {
+    unsafe fn read(f: *const ffi::MMAL_ES_FORMAT_T) -> Self {
+        let f = &*f;
+        let v = &(*f.es).video;
+        Self {
+            encoding: f.encoding,
+            width: v.width,
+            height: v.height,
+            crop: (v.crop.x, v.crop.y, v.crop.width, v.crop.height),
+            frame_rate: (v.frame_rate.num, v.frame_rate.den),
+            bitrate: f.bitrate,
+        }
+    }
+
+    fn frame_interval(&self) -> Duration {
+        let (num, den) = if self.frame_rate.0 > 0 && self.frame_rate.1 > 0 { self.frame_rate } else { DEFAULT_FRAME_RATE };
+        Duration::from_micros(1_000_000 * den as u64 / num as u64)
+    }
+
+    fn display_size(&self) -> (u32, u32) {
+        let (_, _, w, h) = self.crop;
+        if w > 0 && h > 0 { (w as u32, h as u32) } else { (self.width, self.height) }
+    }
+
+    /// Size of a raw frame in this format, or `None` if the encoding is not a raw one
+    fn raw_frame_size(&self) -> Option<u32> {
+        let pixels = self.width * self.height;
+        Some(match self.encoding {
+            ffi::MMAL_ENCODING_I420 | ffi::MMAL_ENCODING_YV12 | ffi::MMAL_ENCODING_NV12 | ffi::MMAL_ENCODING_NV21 => pixels * 3 / 2,
+            ffi::MMAL_ENCODING_I422 | ffi::MMAL_ENCODING_YUYV | ffi::MMAL_ENCODING_YVYU | ffi::MMAL_ENCODING_UYVY
+                | ffi::MMAL_ENCODING_VYUY | ffi::MMAL_ENCODING_RGB16 | ffi::MMAL_ENCODING_BGR16 => pixels * 2,
+            ffi::MMAL_ENCODING_RGB24 | ffi::MMAL_ENCODING_BGR24 => pixels * 3,
+            ffi::MMAL_ENCODING_RGBA | ffi::MMAL_ENCODING_BGRA | ffi::MMAL_ENCODING_ARGB | ffi::MMAL_ENCODING_ABGR
+                | ffi::MMAL_ENCODING_RGB32 | ffi::MMAL_ENCODING_BGR32 => pixels * 4,
+            ffi::MMAL_ENCODING_OPAQUE => OPAQUE_BUFFER_SIZE,
+            _ => return None
+        })
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_event_format_changed_get(buffer: *mut ffi::MMAL_BUFFER_HEADER_T) -> *mut ffi::MMAL_EVENT_FORMAT_CHANGED_T {
+    let h = &*buffer;
+    if h.cmd != ffi::MMAL_EVENT_FORMAT_CHANGED || (h.length as usize) < FORMAT_CHANGED_EVENT_SIZE {
+        return ptr::null_mut();
+    }
+    // the format follows the event, its pointers are fixed up like libmmal does
+    let event = h.data.add(h.offset as usize) as *mut ffi::MMAL_EVENT_FORMAT_CHANGED_T;
+    let format = event.add(1) as *mut ffi::MMAL_ES_FORMAT_T;
+    (*event).format = format;
+    (*format).es = format.add(1) as *mut ffi::MMAL_ES_SPECIFIC_FORMAT_T;
+    (*format).extradata = (*format).es.add(1) as *mut u8;
+    (*format).extradata_size = h.length - FORMAT_CHANGED_EVENT_SIZE as u32;
+    event
+}
+
+const FORMAT_CHANGED_EVENT_SIZE: usize = mem::size_of::<ffi::MMAL_EVENT_FORMAT_CHANGED_T>()
+    + mem::size_of::<ffi::MMAL_ES_FORMAT_T>() + mem::size_of::<ffi::MMAL_ES_SPECIFIC_FORMAT_T>();
+
+/// Payload of a `MMAL_EVENT_FORMAT_CHANGED` buffer announcing `f`
+fn format_changed_event(f: &Format, buffer_size: u32) -> Vec<u8> {
+    unsafe {
+        let mut event: ffi::MMAL_EVENT_FORMAT_CHANGED_T = mem::zeroed();
+        event.buffer_num_min = 1;
+        event.buffer_num_recommended = 3;
+        event.buffer_size_min = buffer_size;
+        event.buffer_size_recommended = buffer_size;
+        let mut format: ffi::MMAL_ES_FORMAT_T = mem::zeroed();
+        let mut es: ffi::MMAL_ES_SPECIFIC_FORMAT_T = mem::zeroed();
+        format.type_ = ffi::MMAL_ES_TYPE_T_MMAL_ES_TYPE_VIDEO;
+        format.es = &mut es;
+        let mut port: ffi::MMAL_PORT_T = mem::zeroed();
+        port.format = &mut format;
+        write_format(&mut port, f);
+        format.es = ptr::null_mut();
+        let mut data = struct_bytes(&event);
+        data.extend_from_slice(&struct_bytes(&format));
+        data.extend_from_slice(&struct_bytes(&es));
+        data
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_format_copy(format_dest: *mut ffi::MMAL_ES_FORMAT_T, format_src: *mut ffi::MMAL_ES_FORMAT_T) {
+    let (d, s) = (&mut *format_dest, &*format_src);
+    d.type_ = s.type_;
+    d.encoding = s.encoding;
+    d.encoding_variant = s.encoding_variant;
+    d.bitrate = s.bitrate;
+    d.flags = s.flags;
+    *d.es = *s.es;
+    d.extradata_size = 0;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_format_full_copy(format_dest: *mut ffi::MMAL_ES_FORMAT_T, format_src: *mut ffi::MMAL_ES_FORMAT_T) -> Status {
+    mmal_format_copy(format_dest, format_src);
+    MMAL_SUCCESS
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Components
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    CameraInfo,
+    Camera,
+    ImageEncode,
+    VideoEncode,
+    VideoDecode,
+    ImageDecode,
+    Splitter,
+    Isp,
+    Resize,
+    NullSink,
+}
+
+impl Kind {
+    fn from_name(name: &[u8]) -> Option<Self> {
+        let strip = |s: &'static [u8]| &s[..s.len() - 1];
+        match name {
+            w if w == strip(ffi::MMAL_COMPONENT_DEFAULT_CAMERA_INFO) => Some(Self::CameraInfo),
+            w if w == strip(ffi::MMAL_COMPONENT_DEFAULT_CAMERA) => Some(Self::Camera),
+            w if w == strip(ffi::MMAL_COMPONENT_DEFAULT_IMAGE_ENCODER) => Some(Self::ImageEncode),
+            w if w == strip(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_ENCODER) => Some(Self::VideoEncode),
+            w if w == strip(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_DECODER) => Some(Self::VideoDecode),
+            w if w == strip(ffi::MMAL_COMPONENT_DEFAULT_IMAGE_DECODER) => Some(Self::ImageDecode),
+            w if w == strip(ffi::MMAL_COMPONENT_DEFAULT_VIDEO_SPLITTER) => Some(Self::Splitter),
+            w if w == strip(crate::MMAL_COMPONENT_ISP) => Some(Self::Isp),
+            w if w == strip(crate::MMAL_COMPONENT_RESIZER) => Some(Self::Resize),
+            w if w == strip(ffi::MMAL_COMPONENT_NULL_SINK) => Some(Self::NullSink),
+            _ => None
+        }
+    }
+
+    /// (inputs, outputs)
+    fn port_counts(&self) -> (usize, usize) {
+        match self {
+            Self::CameraInfo => (0, 0),
+            Self::Camera => (0, 3),
+            Self::ImageEncode | Self::VideoEncode | Self::VideoDecode | Self::ImageDecode | Self::Resize => (1, 1),
+            Self::Splitter => (1, 4),
+            Self::Isp => (1, 2),
+            Self::NullSink => (1, 0),
+        }
+    }
+
+    /// Format an output port starts with
+    fn default_output_format(&self) -> Format {
+        let (width, height) = (1920, 1088);
+        let encoding = match self {
+            Self::Camera => ffi::MMAL_ENCODING_OPAQUE,
+            Self::ImageEncode => ffi::MMAL_ENCODING_JPEG,
+            Self::VideoEncode => ffi::MMAL_ENCODING_H264,
+            Self::VideoDecode | Self::ImageDecode | Self::Splitter | Self::Isp | Self::Resize => ffi::MMAL_ENCODING_I420,
+            _ => 0
+        };
+        Format { encoding, width, height, crop: (0, 0, width as i32, 1080), frame_rate: (0, 1), bitrate: 0 }
+    }
+
+    fn supports_output(&self, encoding: u32) -> bool {
+        match self {
+            Self::Camera | Self::VideoDecode | Self::ImageDecode | Self::Splitter | Self::Isp | Self::Resize => Format { encoding, ..Format::default() }.raw_frame_size().is_some(),
+            Self::ImageEncode => image_magic(encoding).is_some(),
+            Self::VideoEncode => encoding == ffi::MMAL_ENCODING_H264 || encoding == ffi::MMAL_ENCODING_MJPEG,
+            _ => false
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PortType { Control, Input, Output }
+
+/// Frame travelling between components, or waiting to be copied into client buffers
+struct Frame {
+    data: Vec<u8>,
+    flags: u32,
+    pts: i64,
+    /// Event sent through the port in place of data, 0 for data
+    cmd: u32,
+    /// Number of bytes already copied into client buffers
+    offset: usize,
+}
+
+impl Frame {
+    fn new(data: Vec<u8>, flags: u32, pts: i64) -> Self { Self { data, flags, pts, cmd: 0, offset: 0 } }
+    fn event(cmd: u32, data: Vec<u8>) -> Self { Self { data, flags: 0, pts: ffi::MMAL_TIME_UNKNOWN, cmd, offset: 0 } }
+}
+
+struct Tunnel {
+    target: Arc<Shared>,
+    input: usize,
+}
+
+/// Component-side state of a port
+struct PortState {
+    port: PortPtr,
+    cb: ffi::MMAL_PORT_BH_CB_T,
+    enabled: bool,
+    format: Format,
+    buffer_size: u32,
+    /// Buffers sent to the port by the client
+    buffers: VecDeque<HeaderPtr>,
+    /// Output data waiting for client buffers
+    pending: VecDeque<Frame>,
+    /// Input data assembled from client buffers
+    assembling: Vec<u8>,
+    params: HashMap<u32, Vec<u8>>,
+    /// `key=value` payloads of `MMAL_PARAMETER_EXIF`, one per key
+    exif: Vec<String>,
+    tunnel: Option<Tunnel>,
+}
+
+impl PortState {
+    fn is_active(&self) -> bool { self.enabled || self.tunnel.is_some() }
+}
+
+/// Per-port bookkeeping of a frame source
+#[derive(Default)]
+struct SourceState {
+    capturing: bool,
+    start: Option<Instant>,
+    next_due: Option<Instant>,
+    frame_no: u64,
+}
+
+/// State of the encoders
+#[derive(Default)]
+struct CodecState {
+    frame_no: u64,
+    config_sent: bool,
+    /// End of the previous decoder input chunk, for start codes and markers split between chunks
+    tail: Vec<u8>,
+    /// Output format announced with `MMAL_EVENT_FORMAT_CHANGED`, until the client commits it
+    awaiting: Option<Format>,
+    /// Output held back until then
+    deferred: VecDeque<Frame>,
+    /// Frames encoded since the last IDR frame
+    gop_pos: u64,
+    /// `MMAL_PARAMETER_VIDEO_REQUEST_I_FRAME` received since
+    idr_requested: bool,
+}
+
+struct State {
+    enabled: bool,
+    control: PortState,
+    inputs: Vec<PortState>,
+    outputs: Vec<PortState>,
+    inbox: VecDeque<(usize, Frame)>,
+    sources: Vec<SourceState>,
+    codec: CodecState,
+    enabled_at: Option<Instant>,
+    /// Control port events waiting to be sent: (cmd, payload)
+    events: VecDeque<(u32, Vec<u8>)>,
+    event_pool: Ptr<ffi::MMAL_POOL_T>,
+}
+
+impl State {
+    fn port_mut(&mut self, type_: PortType, index: usize) -> &mut PortState {
+        match type_ {
+            PortType::Control => &mut self.control,
+            PortType::Input => &mut self.inputs[index],
+            PortType::Output => &mut self.outputs[index],
+        }
+    }
+}
+
+struct Shared {
+    kind: Kind,
+    state: Mutex<State>,
+    cv: Condvar,
+    /// Held while port callbacks are being invoked, so that disabling a port waits for them
+    cb_lock: Mutex<()>,
+}
+
+#[repr(C)]
+struct Port {
+    p: ffi::MMAL_PORT_T,
+    format: ffi::MMAL_ES_FORMAT_T,
+    es: ffi::MMAL_ES_SPECIFIC_FORMAT_T,
+    name: CString,
+}
+
+#[repr(C)]
+struct Component {
+    c: ffi::MMAL_COMPONENT_T,
+    name: CString,
+    refcount: AtomicU32,
+    ports: Vec<*mut Port>,
+    inputs: Vec<*mut ffi::MMAL_PORT_T>,
+    outputs: Vec<*mut ffi::MMAL_PORT_T>,
+    all: Vec<*mut ffi::MMAL_PORT_T>,
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+unsafe fn component_of(port: *mut ffi::MMAL_PORT_T) -> &'static mut Component {
+    &mut *((*port).component as *mut Component)
+}
+
+unsafe fn port_address(port: *mut ffi::MMAL_PORT_T) -> (PortType, usize) {
+    let p = &*port;
+    let type_ = match p.type_ {
+        ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_INPUT => PortType::Input,
+        ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_OUTPUT => PortType::Output,
+        _ => PortType::Control,
+    };
+    (type_, p.index as usize)
+}
+
+unsafe fn new_port(component: *mut ffi::MMAL_COMPONENT_T, kind: Kind, type_: PortType, index: usize, name: &str) -> *mut Port {
+    let port = Box::into_raw(Box::new(Port {
+        p: mem::zeroed(),
+        format: mem::zeroed(),
+        es: mem::zeroed(),
+        name: CString::new(format!("{}:{}:{}", name, match type_ {
+            PortType::Control => "ctr", PortType::Input => "in", PortType::Output => "out"
+        }, index)).unwrap_or_default()
+    }));
+    let port = &mut *port;
+    port.format.es = &mut port.es;
+    port.format.type_ = if type_ == PortType::Control { ffi::MMAL_ES_TYPE_T_MMAL_ES_TYPE_CONTROL } else { ffi::MMAL_ES_TYPE_T_MMAL_ES_TYPE_VIDEO };
+    port.p.format = &mut port.format;
+    port.p.name = port.name.as_ptr();
+    port.p.index = index as u16;
+    port.p.component = component;
+    port.p.type_ = match type_ {
+        PortType::Control => ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_CONTROL,
+        PortType::Input => ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_INPUT,
+        PortType::Output => ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_OUTPUT,
+    };
+    let format = match type_ {
+        PortType::Output => kind.default_output_format(),
+        PortType::Input => Format { encoding: ffi::MMAL_ENCODING_I420, ..kind.default_output_format() },
+        PortType::Control => Format::default(),
+    };
+    write_format(&mut port.p, &format);
+    update_buffer_requirements(kind, &mut port.p, type_, &format);
+    port
+}
+
+unsafe fn write_format(port: &mut ffi::MMAL_PORT_T, f: &Format) {
+    let format = &mut *port.format;
+    format.encoding = f.encoding;
+    format.bitrate = f.bitrate;
+    let v = &mut (*format.es).video;
+    v.width = f.width;
+    v.height = f.height;
+    v.crop = ffi::MMAL_RECT_T { x: f.crop.0, y: f.crop.1, width: f.crop.2, height: f.crop.3 };
+    v.frame_rate = ffi::MMAL_RATIONAL_T { num: f.frame_rate.0, den: f.frame_rate.1 };
+}
+
+unsafe fn update_buffer_requirements(kind: Kind, port: &mut ffi::MMAL_PORT_T, type_: PortType, f: &Format) {
+    let (num_min, num_recommended, size_min, size_recommended) = match (kind, type_) {
+        (_, PortType::Control) => (1, 1, CONTROL_BUFFER_SIZE, CONTROL_BUFFER_SIZE),
+        (Kind::ImageEncode, PortType::Output) => (1, 3, 8192, IMAGE_ENCODE_BUFFER_SIZE),
+        (Kind::VideoEncode, PortType::Output) | (Kind::VideoDecode, PortType::Input) => (1, 3, 2048, VIDEO_ENCODE_BUFFER_SIZE),
+        (Kind::ImageDecode, PortType::Input) => (1, 3, 2048, IMAGE_ENCODE_BUFFER_SIZE),
+        _ => {
+            let size = f.raw_frame_size().unwrap_or(OPAQUE_BUFFER_SIZE);
+            (1, 3, size, size)
+        }
+    };
+    port.buffer_num_min = num_min;
+    port.buffer_num_recommended = num_recommended;
+    port.buffer_size_min = size_min;
+    port.buffer_size_recommended = size_recommended;
+    port.buffer_alignment_min = 16;
+    if port.buffer_num < num_min { port.buffer_num = num_min }
+    if port.buffer_size < size_min { port.buffer_size = size_min }
+}
+
+fn new_port_state(port: *mut Port) -> PortState {
+    PortState {
+        port: Ptr(port as *mut ffi::MMAL_PORT_T),
+        cb: None,
+        enabled: false,
+        format: if port.is_null() { Format::default() } else { unsafe { Format::read((*port).p.format) } },
+        buffer_size: 0,
+        buffers: VecDeque::new(),
+        pending: VecDeque::new(),
+        assembling: Vec::new(),
+        params: HashMap::new(),
+        exif: Vec::new(),
+        tunnel: None,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_component_create(name: *const c_char, component: *mut *mut ffi::MMAL_COMPONENT_T) -> Status {
+    let name = CStr::from_ptr(name);
+    let kind = if let Some(kind) = Kind::from_name(name.to_bytes()) { kind } else { return MMAL_ENOENT };
+    let (input_num, output_num) = kind.port_counts();
+
+    let c = Box::into_raw(Box::new(Component {
+        c: mem::zeroed(),
+        name: name.to_owned(),
+        refcount: AtomicU32::new(1),
+        ports: Vec::new(),
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+        all: Vec::new(),
+        shared: Arc::new(Shared {
+            kind,
+            state: Mutex::new(State {
+                enabled: false,
+                control: new_port_state(ptr::null_mut()),
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                inbox: VecDeque::new(),
+                sources: (0..output_num).map(|_| SourceState::default()).collect(),
+                codec: CodecState::default(),
+                enabled_at: None,
+                events: VecDeque::new(),
+                event_pool: Ptr(mmal_pool_create(EVENT_POOL_SIZE, CONTROL_BUFFER_SIZE)),
+            }),
+            cv: Condvar::new(),
+            cb_lock: Mutex::new(()),
+        }),
+        worker: None,
+    }));
+    let cc = &mut *c;
+    let cp = c as *mut ffi::MMAL_COMPONENT_T;
+    let short_name = name.to_string_lossy().into_owned();
+
+    let control = new_port(cp, kind, PortType::Control, 0, &short_name);
+    cc.ports.push(control);
+    for i in 0..input_num {
+        let p = new_port(cp, kind, PortType::Input, i, &short_name);
+        cc.ports.push(p);
+        cc.inputs.push(p as *mut ffi::MMAL_PORT_T);
+    }
+    for i in 0..output_num {
+        let p = new_port(cp, kind, PortType::Output, i, &short_name);
+        cc.ports.push(p);
+        cc.outputs.push(p as *mut ffi::MMAL_PORT_T);
+    }
+    cc.all = cc.ports.iter().map(|p| *p as *mut ffi::MMAL_PORT_T).collect();
+    for (i, p) in cc.all.iter().enumerate() {
+        (**p).index_all = i as u16;
+    }
+
+    {
+        let mut state = lock(&cc.shared.state);
+        state.control = new_port_state(control);
+        state.inputs = cc.inputs.iter().map(|p| new_port_state(*p as *mut Port)).collect();
+        state.outputs = cc.outputs.iter().map(|p| new_port_state(*p as *mut Port)).collect();
+    }
+
+    cc.c.name = cc.name.as_ptr();
+    cc.c.control = control as *mut ffi::MMAL_PORT_T;
+    cc.c.input_num = input_num as u32;
+    cc.c.input = cc.inputs.as_mut_ptr();
+    cc.c.output_num = output_num as u32;
+    cc.c.output = cc.outputs.as_mut_ptr();
+    cc.c.port_num = cc.all.len() as u32;
+    cc.c.port = cc.all.as_mut_ptr();
+
+    *component = cp;
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_component_acquire(component: *mut ffi::MMAL_COMPONENT_T) {
+    (*(component as *mut Component)).refcount.fetch_add(1, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_component_release(component: *mut ffi::MMAL_COMPONENT_T) -> Status {
+    let c = component as *mut Component;
+    if (*c).refcount.fetch_sub(1, Ordering::SeqCst) != 1 {
+        return MMAL_SUCCESS;
+    }
+    mmal_component_disable(component);
+    for p in (*c).all.clone() {
+        if (*p).is_enabled != 0 {
+            mmal_port_disable(p);
+        }
+    }
+    let c = Box::from_raw(c);
+    for p in &c.ports {
+        drop(Box::from_raw(*p));
+    }
+    mmal_pool_destroy(lock(&c.shared.state).event_pool.0);
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_component_destroy(component: *mut ffi::MMAL_COMPONENT_T) -> Status {
+    mmal_component_release(component)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_component_enable(component: *mut ffi::MMAL_COMPONENT_T) -> Status {
+    let c = &mut *(component as *mut Component);
+    if c.worker.is_some() {
+        return MMAL_SUCCESS;
+    }
+    {
+        let mut state = lock(&c.shared.state);
+        state.enabled = true;
+        state.enabled_at = Some(Instant::now());
+    }
+    let shared = c.shared.clone();
+    c.worker = Some(thread::spawn(move || run(shared)));
+    c.c.is_enabled = 1;
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_component_disable(component: *mut ffi::MMAL_COMPONENT_T) -> Status {
+    let c = &mut *(component as *mut Component);
+    if let Some(worker) = c.worker.take() {
+        lock(&c.shared.state).enabled = false;
+        c.shared.cv.notify_all();
+        let _ = worker.join();
+    }
+    c.c.is_enabled = 0;
+    MMAL_SUCCESS
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Ports
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_format_commit(port: *mut ffi::MMAL_PORT_T) -> Status {
+    let c = component_of(port);
+    let kind = c.shared.kind;
+    let (type_, index) = port_address(port);
+    let format = Format::read((*port).format);
+
+    match type_ {
+        PortType::Output if !kind.supports_output(format.encoding) => return MMAL_EINVAL,
+        // the image decoder learns the picture size from the stream
+        PortType::Input if kind == Kind::ImageDecode => { }
+        PortType::Output | PortType::Input if format.width == 0 || format.height == 0 => return MMAL_EINVAL,
+        _ => { }
+    }
+
+    update_buffer_requirements(kind, &mut *port, type_, &format);
+    let mut state = lock(&c.shared.state);
+    state.port_mut(type_, index).format = format;
+
+    // Codecs derive their output picture format from the input one
+    if type_ == PortType::Input && matches!(kind, Kind::ImageEncode | Kind::VideoEncode | Kind::VideoDecode) {
+        if let Some(out) = c.outputs.first() {
+            let mut of = Format::read((**out).format);
+            of.width = format.width;
+            of.height = format.height;
+            of.crop = format.crop;
+            of.frame_rate = format.frame_rate;
+            write_format(&mut **out, &of);
+            state.outputs[0].format = of;
+        }
+    }
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_enable(port: *mut ffi::MMAL_PORT_T, cb: ffi::MMAL_PORT_BH_CB_T) -> Status {
+    if cb.is_none() {
+        return MMAL_EINVAL;
+    }
+    let c = component_of(port);
+    let (type_, index) = port_address(port);
+    {
+        let mut state = lock(&c.shared.state);
+        let ps = state.port_mut(type_, index);
+        if ps.is_active() {
+            return MMAL_EISCONN;
+        }
+        ps.cb = cb;
+        ps.enabled = true;
+        ps.buffer_size = (*port).buffer_size;
+    }
+    (*port).is_enabled = 1;
+    c.shared.cv.notify_all();
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_disable(port: *mut ffi::MMAL_PORT_T) -> Status {
+    let c = component_of(port);
+    let (type_, index) = port_address(port);
+    let _cb_guard = lock(&c.shared.cb_lock);
+    let (cb, buffers) = {
+        let mut state = lock(&c.shared.state);
+        let ps = state.port_mut(type_, index);
+        if !ps.enabled {
+            return MMAL_EINVAL;
+        }
+        ps.enabled = false;
+        ps.pending.clear();
+        ps.assembling.clear();
+        (ps.cb.take(), mem::take(&mut ps.buffers))
+    };
+    (*port).is_enabled = 0;
+    // All buffers are returned to the client before the port is reported disabled
+    if let Some(cb) = cb {
+        for b in buffers {
+            (*b.0).length = 0;
+            cb(port, b.0);
+        }
+    }
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_flush(port: *mut ffi::MMAL_PORT_T) -> Status {
+    let c = component_of(port);
+    let (type_, index) = port_address(port);
+    let _cb_guard = lock(&c.shared.cb_lock);
+    let (cb, buffers) = {
+        let mut state = lock(&c.shared.state);
+        let ps = state.port_mut(type_, index);
+        ps.pending.clear();
+        ps.assembling.clear();
+        (ps.cb, mem::take(&mut ps.buffers))
+    };
+    if let Some(cb) = cb {
+        for b in buffers {
+            (*b.0).length = 0;
+            cb(port, b.0);
+        }
+    }
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_send_buffer(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) -> Status {
+    let c = component_of(port);
+    let (type_, index) = port_address(port);
+    {
+        let mut state = lock(&c.shared.state);
+        let ps = state.port_mut(type_, index);
+        if !ps.enabled {
+            return MMAL_EINVAL;
+        }
+        ps.buffers.push_back(Ptr(buffer));
+    }
+    c.shared.cv.notify_all();
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_util_rgb_order_fixed(_port: *mut ffi::MMAL_PORT_T) -> c_int { 1 }
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_encoding_width_to_stride(encoding: u32, width: u32) -> u32 {
+    match (Format { encoding, width, height: 1, ..Format::default() }).raw_frame_size() {
+        // planar YUV: the stride of the luma plane
+        Some(size) if size < width * 2 && encoding != ffi::MMAL_ENCODING_OPAQUE => width,
+        Some(size) if encoding != ffi::MMAL_ENCODING_OPAQUE => size,
+        _ => 0
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Parameters
+
+fn struct_bytes<T: Copy>(v: &T) -> Vec<u8> {
+    unsafe { std::slice::from_raw_parts(v as *const T as *const u8, mem::size_of::<T>()).to_vec() }
+}
+
+unsafe fn param_bytes<'a>(param: *const ffi::MMAL_PARAMETER_HEADER_T) -> &'a [u8] {
+    std::slice::from_raw_parts(param as *const u8, (*param).size as usize)
+}
+
+/// Values reported for parameters the client has never set
+unsafe fn default_param(kind: Kind, type_: PortType, id: u32) -> Option<Vec<u8>> {
+    match (kind, type_, id) {
+        (Kind::CameraInfo, PortType::Control, ffi::MMAL_PARAMETER_CAMERA_INFO) => {
+            let mut info: ffi::MMAL_PARAMETER_CAMERA_INFO_T = mem::zeroed();
+            info.hdr.id = id;
+            info.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_CAMERA_INFO_T>() as u32;
+            info.num_cameras = 1;
+            let cam = &mut info.cameras[0];
+            (cam.max_width, cam.max_height) = EMULATED_CAMERA_MAX_SIZE;
+            cam.lens_present = 1;
+            for (d, s) in cam.camera_name.iter_mut().zip(EMULATED_CAMERA_NAME.bytes()) {
+                *d = s as c_char;
+            }
+            Some(struct_bytes(&info))
+        }
+        (_, _, ffi::MMAL_PARAMETER_CAPTURE) => {
+            let mut p: ffi::MMAL_PARAMETER_BOOLEAN_T = mem::zeroed();
+            p.hdr.id = id;
+            p.hdr.size = mem::size_of_val(&p) as u32;
+            Some(struct_bytes(&p))
+        }
+        _ => None
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_parameter_set(port: *mut ffi::MMAL_PORT_T, param: *const ffi::MMAL_PARAMETER_HEADER_T) -> Status {
+    let c = component_of(port);
+    let (type_, index) = port_address(port);
+    let bytes = param_bytes(param).to_vec();
+    {
+        let mut state = lock(&c.shared.state);
+        let kind = c.shared.kind;
+        if (*param).id == ffi::MMAL_PARAMETER_CAPTURE && kind == Kind::Camera && type_ == PortType::Output {
+            let enable = (*(param as *const ffi::MMAL_PARAMETER_BOOLEAN_T)).enable != 0;
+            let source = &mut state.sources[index];
+            if enable && !source.capturing {
+                source.start = None;
+                source.next_due = None;
+            }
+            source.capturing = enable;
+        }
+        if (*param).id == ffi::MMAL_PARAMETER_VIDEO_REQUEST_I_FRAME && kind == Kind::VideoEncode {
+            state.codec.idr_requested |= (*(param as *const ffi::MMAL_PARAMETER_BOOLEAN_T)).enable != 0;
+        }
+        if (*param).id == ffi::MMAL_PARAMETER_EXIF {
+            let exif = &mut state.port_mut(type_, index).exif;
+            let data = &bytes[mem::offset_of!(ffi::MMAL_PARAMETER_EXIF_T, data)..];
+            let tag = String::from_utf8_lossy(&data[..data.iter().position(|b| *b == 0).unwrap_or(data.len())]).into_owned();
+            let key = tag.split('=').next().unwrap_or_default().to_owned();
+            exif.retain(|t| t.split('=').next() != Some(&key));
+            exif.push(tag);
+        }
+        state.port_mut(type_, index).params.insert((*param).id, bytes);
+    }
+    c.shared.cv.notify_all();
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_port_parameter_get(port: *mut ffi::MMAL_PORT_T, param: *mut ffi::MMAL_PARAMETER_HEADER_T) -> Status {
+    let c = component_of(port);
+    let (type_, index) = port_address(port);
+    let id = (*param).id;
+    let value = {
+        let mut state = lock(&c.shared.state);
+        state.port_mut(type_, index).params.get(&id).cloned()
+    };
+    let value = if let Some(v) = value.or_else(|| default_param(c.shared.kind, type_, id)) { v } else { return MMAL_ENOSYS };
+    let size = (*param).size as usize;
+    let n = size.min(value.len());
+    let header_size = mem::size_of::<ffi::MMAL_PARAMETER_HEADER_T>();
+    if n > header_size {
+        ptr::copy_nonoverlapping(value[header_size..].as_ptr(), (param as *mut u8).add(header_size), n - header_size);
+    }
+    MMAL_SUCCESS
+}
+
+macro_rules! scalar_param {
+    ($set:ident, $get:ident, $ty:ty, $ffi_ty:ident, $field:ident) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $set(port: *mut ffi::MMAL_PORT_T, id: u32, value: $ty) -> Status {
+            let mut p: ffi::$ffi_ty = mem::zeroed();
+            p.hdr.id = id;
+            p.hdr.size = mem::size_of::<ffi::$ffi_ty>() as u32;
+            p.$field = value;
+            mmal_port_parameter_set(port, &p.hdr)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn $get(port: *mut ffi::MMAL_PORT_T, id: u32, value: *mut $ty) -> Status {
+            let mut p: ffi::$ffi_ty = mem::zeroed();
+            p.hdr.id = id;
+            p.hdr.size = mem::size_of::<ffi::$ffi_ty>() as u32;
+            let status = mmal_port_parameter_get(port, &mut p.hdr);
+            if status == MMAL_SUCCESS {
+                *value = p.$field;
+            }
+            status
+        }
+    };
+}
+
+scalar_param!{mmal_port_parameter_set_boolean, mmal_port_parameter_get_boolean, ffi::MMAL_BOOL_T, MMAL_PARAMETER_BOOLEAN_T, enable}
+scalar_param!{mmal_port_parameter_set_uint32, mmal_port_parameter_get_uint32, u32, MMAL_PARAMETER_UINT32_T, value}
+scalar_param!{mmal_port_parameter_set_int32, mmal_port_parameter_get_int32, i32, MMAL_PARAMETER_INT32_T, value}
+scalar_param!{mmal_port_parameter_set_rational, mmal_port_parameter_get_rational, ffi::MMAL_RATIONAL_T, MMAL_PARAMETER_RATIONAL_T, value}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Connections
+
+#[repr(C)]
+struct Connection {
+    c: ffi::MMAL_CONNECTION_T,
+    refcount: AtomicU32,
+    name: CString,
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_connection_create(
+    connection: *mut *mut ffi::MMAL_CONNECTION_T, out: *mut ffi::MMAL_PORT_T, in_: *mut ffi::MMAL_PORT_T, flags: u32
+) -> Status {
+    if port_address(out).0 != PortType::Output || port_address(in_).0 != PortType::Input {
+        return MMAL_EINVAL;
+    }
+    // The connection copies the output format to the input port and commits it
+    mmal_format_copy((*in_).format, (*out).format);
+    let status = mmal_port_format_commit(in_);
+    if status != MMAL_SUCCESS {
+        return status;
+    }
+    let name = format!("{}->{}", CStr::from_ptr((*out).name).to_string_lossy(), CStr::from_ptr((*in_).name).to_string_lossy());
+    let conn = Box::into_raw(Box::new(Connection {
+        c: mem::zeroed(),
+        refcount: AtomicU32::new(1),
+        name: CString::new(name).unwrap_or_default()
+    }));
+    (*conn).c.flags = flags;
+    (*conn).c.out = out;
+    (*conn).c.in_ = in_;
+    (*conn).c.name = (*conn).name.as_ptr();
+    *connection = conn as *mut ffi::MMAL_CONNECTION_T;
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_connection_acquire(connection: *mut ffi::MMAL_CONNECTION_T) {
+    (*(connection as *mut Connection)).refcount.fetch_add(1, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_connection_release(connection: *mut ffi::MMAL_CONNECTION_T) -> Status {
+    let conn = connection as *mut Connection;
+    if (*conn).refcount.fetch_sub(1, Ordering::SeqCst) != 1 {
+        return MMAL_SUCCESS;
+    }
+    if (*conn).c.is_enabled != 0 {
+        mmal_connection_disable(connection);
+    }
+    drop(Box::from_raw(conn));
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_connection_destroy(connection: *mut ffi::MMAL_CONNECTION_T) -> Status {
+    mmal_connection_release(connection)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_connection_enable(connection: *mut ffi::MMAL_CONNECTION_T) -> Status {
+    let conn = &mut *connection;
+    if conn.is_enabled != 0 {
+        return MMAL_SUCCESS;
+    }
+    let (source, target) = (component_of(conn.out), component_of(conn.in_));
+    let (_, out_index) = port_address(conn.out);
+    let (_, in_index) = port_address(conn.in_);
+    {
+        let mut state = lock(&target.shared.state);
+        let ps = &mut state.inputs[in_index];
+        if ps.is_active() {
+            return MMAL_EISCONN;
+        }
+        ps.enabled = true;
+    }
+    {
+        let mut state = lock(&source.shared.state);
+        let ps = &mut state.outputs[out_index];
+        if ps.is_active() {
+            lock(&target.shared.state).inputs[in_index].enabled = false;
+            return MMAL_EISCONN;
+        }
+        ps.tunnel = Some(Tunnel { target: target.shared.clone(), input: in_index });
+    }
+    (*conn.out).is_enabled = 1;
+    (*conn.in_).is_enabled = 1;
+    conn.is_enabled = 1;
+    source.shared.cv.notify_all();
+    MMAL_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_connection_disable(connection: *mut ffi::MMAL_CONNECTION_T) -> Status {
+    let conn = &mut *connection;
+    if conn.is_enabled == 0 {
+        return MMAL_SUCCESS;
+    }
+    let (source, target) = (component_of(conn.out), component_of(conn.in_));
+    let (_, out_index) = port_address(conn.out);
+    let (_, in_index) = port_address(conn.in_);
+    {
+        let _cb_guard = lock(&source.shared.cb_lock);
+        let mut state = lock(&source.shared.state);
+        state.outputs[out_index].tunnel = None;
+        state.outputs[out_index].pending.clear();
+    }
+    {
+        let mut state = lock(&target.shared.state);
+        state.inputs[in_index].enabled = false;
+        state.inbox.retain(|(i, _)| *i != in_index);
+    }
+    (*conn.out).is_enabled = 0;
+    (*conn.in_).is_enabled = 0;
+    conn.is_enabled = 0;
+    MMAL_SUCCESS
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Misc
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_status_to_string(status: Status) -> *const c_char {
+    let s: &'static [u8] = match status {
+        MMAL_SUCCESS => b"SUCCESS\0",
+        MMAL_ENOMEM => b"ENOMEM\0",
+        MMAL_ENOSPC => b"ENOSPC\0",
+        MMAL_EINVAL => b"EINVAL\0",
+        MMAL_ENOSYS => b"ENOSYS\0",
+        MMAL_ENOENT => b"ENOENT\0",
+        MMAL_ENXIO => b"ENXIO\0",
+        MMAL_EIO => b"EIO\0",
+        MMAL_ESPIPE => b"ESPIPE\0",
+        MMAL_ECORRUPT => b"ECORRUPT\0",
+        MMAL_ENOTREADY => b"ENOTREADY\0",
+        MMAL_ECONFIG => b"ECONFIG\0",
+        MMAL_EISCONN => b"EISCONN\0",
+        MMAL_ENOTCONN => b"ENOTCONN\0",
+        MMAL_EAGAIN => b"EAGAIN\0",
+        MMAL_EFAULT => b"EFAULT\0",
+        _ => b"UNKNOWN\0",
+    };
+    s.as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bcm_host_init() { }
+
+#[no_mangle]
+pub unsafe extern "C" fn vcos_init() -> ffi::VCOS_STATUS_T { ffi::VCOS_STATUS_T_VCOS_SUCCESS }
+
+#[no_mangle]
+pub unsafe extern "C" fn mmal_vc_init() -> Status { MMAL_SUCCESS }
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Component worker
+
+/// Work collected under the state lock, carried out after releasing it
+#[derive(Default)]
+struct Work {
+    callbacks: Vec<(PortPtr, HeaderPtr, unsafe extern "C" fn(*mut ffi::MMAL_PORT_T, *mut ffi::MMAL_BUFFER_HEADER_T))>,
+    deliveries: Vec<(Arc<Shared>, usize, Frame)>,
+}
+
+impl Work {
+    fn is_empty(&self) -> bool { self.callbacks.is_empty() && self.deliveries.is_empty() }
+}
+
+fn run(shared: Arc<Shared>) {
+    loop {
+        let cb_guard = lock(&shared.cb_lock);
+        let mut state = lock(&shared.state);
+        if !state.enabled {
+            break;
+        }
+        let now = Instant::now();
+        let mut work = Work::default();
+
+        unsafe {
+            consume_input_buffers(shared.kind, &mut state, &mut work);
+        }
+        while let Some((input, frame)) = state.inbox.pop_front() {
+            process(shared.kind, &mut state, input, frame);
+        }
+        release_deferred(&mut state);
+        let next_due = if shared.kind == Kind::Camera { produce(&mut state, now) } else { None };
+        unsafe {
+            dispatch_events(&mut state, &mut work);
+        }
+        for ps in state.outputs.iter_mut() {
+            unsafe { pump(ps, &mut work) };
+        }
+        drop(state);
+
+        for (port, buffer, cb) in work.callbacks.iter() {
+            unsafe { cb(port.0, buffer.0) };
+        }
+        drop(cb_guard);
+
+        let idle = work.is_empty();
+        for (target, input, frame) in work.deliveries {
+            let mut state = lock(&target.state);
+            if state.inputs[input].is_active() {
+                state.inbox.push_back((input, frame));
+                target.cv.notify_all();
+            }
+        }
+
+        if idle {
+            let state = lock(&shared.state);
+            if !state.enabled || !state.inbox.is_empty() || has_pumpable(&state) {
+                continue;
+            }
+            match next_due {
+                Some(due) => drop(shared.cv.wait_timeout(state, due.saturating_duration_since(Instant::now()))),
+                None => drop(shared.cv.wait(state)),
+            }
+        }
+    }
+}
+
+/// Releases the output held back after a format change, once the client has committed the new format
+fn release_deferred(state: &mut State) {
+    let out = &mut state.outputs[..];
+    match (state.codec.awaiting, out.first_mut()) {
+        (Some(f), Some(out)) if out.enabled && (out.format.encoding, out.format.width, out.format.height) == (f.encoding, f.width, f.height) => {
+            out.pending.extend(state.codec.deferred.drain(..));
+            state.codec.awaiting = None;
+        }
+        _ => { }
+    }
+}
+
+fn has_pumpable(state: &State) -> bool {
+    (state.control.enabled && !state.events.is_empty()) || state.outputs.iter().any(|ps| !ps.pending.is_empty() && (ps.tunnel.is_some() || (ps.enabled && !ps.buffers.is_empty())))
+        || state.inputs.iter().any(|ps| ps.enabled && !ps.buffers.is_empty())
+}
+
+/// Reads client buffers sent to input ports into frames, and returns the buffers
+unsafe fn consume_input_buffers(kind: Kind, state: &mut State, work: &mut Work) {
+    for index in 0..state.inputs.len() {
+        let ps = &mut state.inputs[index];
+        let cb = if let Some(cb) = ps.cb { cb } else { continue };
+        let mut frames = Vec::new();
+        while let Some(b) = ps.buffers.pop_front() {
+            let h = &mut *b.0;
+            ps.assembling.extend_from_slice(std::slice::from_raw_parts(h.data.add(h.offset as usize), h.length as usize));
+            // decoders parse the stream, so they take it in whatever chunks it comes
+            if kind == Kind::VideoDecode || h.flags & (ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END | ffi::MMAL_BUFFER_HEADER_FLAG_EOS) != 0 {
+                frames.push(Frame::new(mem::take(&mut ps.assembling), h.flags, h.pts));
+            }
+            h.length = 0;
+            work.callbacks.push((ps.port, b, cb));
+        }
+        for f in frames {
+            state.inbox.push_back((index, f));
+        }
+    }
+}
+
+/// Sends pending events to the control port, or drops them if it is not enabled
+unsafe fn dispatch_events(state: &mut State, work: &mut Work) {
+    let cb = match (state.control.enabled, state.control.cb) { (true, Some(cb)) => cb, _ => { state.events.clear(); return } };
+    while let Some((cmd, payload)) = state.events.pop_front() {
+        let b = mmal_queue_get((*state.event_pool.0).queue);
+        if b.is_null() {
+            // out of event buffers, the event is lost like it would be on the firmware side
+            continue;
+        }
+        let h = &mut *b;
+        let n = payload.len().min(h.alloc_size as usize);
+        ptr::copy_nonoverlapping(payload.as_ptr(), h.data, n);
+        h.cmd = cmd;
+        h.length = n as u32;
+        work.callbacks.push((state.control.port, Ptr(b), cb));
+    }
+}
+
+/// Moves pending output frames to the connected component, or into client buffers
+unsafe fn pump(ps: &mut PortState, work: &mut Work) {
+    if let Some(tunnel) = &ps.tunnel {
+        for frame in ps.pending.drain(..) {
+            work.deliveries.push((tunnel.target.clone(), tunnel.input, frame));
+        }
+        return;
+    }
+    let cb = match (ps.enabled, ps.cb) { (true, Some(cb)) => cb, _ => { ps.pending.clear(); return } };
+    while !ps.pending.is_empty() && !ps.buffers.is_empty() {
+        let frame = ps.pending.front_mut().unwrap();
+        let b = ps.buffers.pop_front().unwrap();
+        let h = &mut *b.0;
+        let n = (frame.data.len() - frame.offset).min(h.alloc_size as usize);
+        ptr::copy_nonoverlapping(frame.data.as_ptr().add(frame.offset), h.data, n);
+        let first = frame.offset == 0;
+        frame.offset += n;
+        let last = frame.offset == frame.data.len();
+        h.offset = 0;
+        h.length = n as u32;
+        h.cmd = frame.cmd;
+        h.pts = frame.pts;
+        h.dts = frame.pts;
+        h.flags = frame.flags & !ffi::MMAL_BUFFER_HEADER_FLAG_FRAME;
+        if first && frame.flags & ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_START != 0 { h.flags |= ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_START }
+        if last && frame.flags & ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END != 0 { h.flags |= ffi::MMAL_BUFFER_HEADER_FLAG_FRAME_END }
+        if !last { h.flags &= !ffi::MMAL_BUFFER_HEADER_FLAG_EOS }
+        if last {
+            ps.pending.pop_front();
+        }
+        work.callbacks.push((ps.port, b, cb));
+    }
+}
+
+/// Generates camera frames which are due, returns when the next one is
+fn produce(state: &mut State, now: Instant) -> Option<Instant> {
+    let mut next = None;
+    let enabled_at = state.enabled_at.unwrap_or(now);
+    for index in 0..state.outputs.len() {
+        if !state.outputs[index].is_active() {
+            continue;
+        }
+        let format = state.outputs[index].format;
+        let source = &mut state.sources[index];
+        // preview streams continuously, video and capture on demand
+        if index != 0 && !source.capturing {
+            continue;
+        }
+        let start = *source.start.get_or_insert(now);
+        let due = source.next_due.unwrap_or(now);
+        if due > now {
+            next = Some(next.map_or(due, |n: Instant| n.min(due)));
+            continue;
+        }
+        let pts = if index == 2 { now - enabled_at } else { now - start };
+        let frame_no = source.frame_no;
+        source.frame_no += 1;
+        if index == 2 {
+            // stills capture is one-shot
+            source.capturing = false;
+            source.next_due = None;
+        } else {
+            let due = due + format.frame_interval();
+            source.next_due = Some(due.max(now));
+            next = Some(next.map_or(due, |n: Instant| n.min(due)));
+        }
+        let mut data = synthetic_frame(&format, frame_no);
+        if index == 2 && format.encoding == ffi::MMAL_ENCODING_OPAQUE
+            && param_u32(&state.outputs[index].params, ffi::MMAL_PARAMETER_ENABLE_RAW_CAPTURE).unwrap_or(0) != 0 {
+            // the opaque handle tells the encoder to append the raw data
+            data[8..12].copy_from_slice(BRCM_RAW_MARKER);
+        }
+        if change_event_requested(&state.control.params, ffi::MMAL_PARAMETER_CAMERA_SETTINGS) {
+            let settings = camera_settings(&state.control.params, &format);
+            state.events.push_back((ffi::MMAL_EVENT_PARAMETER_CHANGED, struct_bytes(&settings)));
+        }
+        state.outputs[index].pending.push_back(Frame::new(data, ffi::MMAL_BUFFER_HEADER_FLAG_FRAME, pts.as_micros() as i64));
+    }
+    next
+}
+
+/// Transforms an input frame into output frames
+fn process(kind: Kind, state: &mut State, input: usize, frame: Frame) {
+    let eos = frame.flags & ffi::MMAL_BUFFER_HEADER_FLAG_EOS != 0;
+    let expected_size = state.inputs[input].format.raw_frame_size().unwrap_or(0) as usize;
+    if !frame.data.is_empty() && frame.data.len() < expected_size && (kind == Kind::ImageEncode || kind == Kind::VideoEncode) {
+        // truncated raw frames are rejected by the firmware encoders
+        state.events.push_back((ffi::MMAL_EVENT_ERROR, MMAL_EINVAL.to_ne_bytes().to_vec()));
+        return;
+    }
+    match kind {
+        Kind::ImageEncode if !frame.data.is_empty() => {
+            let out = &mut state.outputs[0];
+            let mut data = encode_image(&out.format, state.codec.frame_no, &frame.data);
+            if out.format.encoding == ffi::MMAL_ENCODING_JPEG && param_u32(&out.params, ffi::MMAL_PARAMETER_EXIF_DISABLE).unwrap_or(0) == 0 {
+                insert_exif(&mut data, &out.exif);
+            }
+            if out.format.encoding == ffi::MMAL_ENCODING_JPEG && frame.data.get(8..12) == Some(&BRCM_RAW_MARKER[..]) {
+                data.extend_from_slice(&brcm_raw_block(state.codec.frame_no));
+            }
+            state.codec.frame_no += 1;
+            out.pending.push_back(Frame::new(data, ffi::MMAL_BUFFER_HEADER_FLAG_FRAME, frame.pts));
+        }
+        Kind::VideoDecode if !frame.data.is_empty() => {
+            let encoding = state.inputs[input].format.encoding;
+            let codec = &mut state.codec;
+            let out = &mut state.outputs[0];
+            for i in 0..count_pictures(encoding, &mut codec.tail, &frame.data) {
+                let pts = if i == 0 { frame.pts } else { ffi::MMAL_TIME_UNKNOWN };
+                out.pending.push_back(Frame::new(synthetic_frame(&out.format, codec.frame_no), ffi::MMAL_BUFFER_HEADER_FLAG_FRAME, pts));
+                codec.frame_no += 1;
+            }
+        }
+        Kind::ImageDecode if !frame.data.is_empty() => {
+            let (width, height) = match image_size(state.inputs[input].format.encoding, &frame.data) {
+                Some(size) => size,
+                None => {
+                    state.events.push_back((ffi::MMAL_EVENT_ERROR, MMAL_EINVAL.to_ne_bytes().to_vec()));
+                    return;
+                }
+            };
+            let encoding = if state.inputs[input].format.encoding == ffi::MMAL_ENCODING_JPEG { ffi::MMAL_ENCODING_I420 } else { ffi::MMAL_ENCODING_RGBA };
+            let out = &mut state.outputs[0];
+            let format = Format {
+                encoding,
+                width: width.next_multiple_of(32),
+                height: height.next_multiple_of(16),
+                crop: (0, 0, width as i32, height as i32),
+                ..out.format
+            };
+            let codec = &mut state.codec;
+            let picture = Frame::new(synthetic_frame(&format, codec.frame_no), ffi::MMAL_BUFFER_HEADER_FLAG_FRAME, frame.pts);
+            codec.frame_no += 1;
+            let current = codec.awaiting.unwrap_or(out.format);
+            if (current.encoding, current.width, current.height, current.crop) != (format.encoding, format.width, format.height, format.crop) {
+                let size = format.raw_frame_size().unwrap_or(0);
+                out.pending.push_back(Frame::event(ffi::MMAL_EVENT_FORMAT_CHANGED, format_changed_event(&format, size)));
+                codec.awaiting = Some(format);
+            }
+            if codec.awaiting.is_some() { codec.deferred.push_back(picture) } else { out.pending.push_back(picture) }
+        }
+        Kind::Splitter | Kind::Isp | Kind::Resize if !frame.data.is_empty() => {
+            let input_format = state.inputs[input].format;
+            // converted or scaled outputs get synthetic pictures of their own format
+            for out in state.outputs.iter_mut().filter(|out| out.is_active()) {
+                let data = if out.format.encoding == input_format.encoding && out.format.width == input_format.width
+                    && out.format.height == input_format.height {
+                    frame.data.clone()
+                } else {
+                    synthetic_frame(&out.format, state.codec.frame_no)
+                };
+                out.pending.push_back(Frame::new(data, frame.flags, frame.pts));
+            }
+            state.codec.frame_no += 1;
+        }
+        Kind::VideoEncode if !frame.data.is_empty() => {
+            let input_rate = state.inputs[input].format.frame_rate;
+            let out = &mut state.outputs[0];
+            let codec = &mut state.codec;
+            if out.format.encoding == ffi::MMAL_ENCODING_H264 {
+                let intra_period = param_u32(&out.params, ffi::MMAL_PARAMETER_INTRAPERIOD).filter(|p| *p > 0).unwrap_or(DEFAULT_INTRA_PERIOD);
+                let inline_headers = param_u32(&out.params, ffi::MMAL_PARAMETER_VIDEO_ENCODE_INLINE_HEADER).unwrap_or(0) != 0;
+                let idr = codec.gop_pos.is_multiple_of(intra_period as u64) || codec.idr_requested;
+                codec.gop_pos = if idr { 1 } else { codec.gop_pos + 1 };
+                codec.idr_requested = false;
+                let timing = (param_u32(&out.params, ffi::MMAL_PARAMETER_VIDEO_ENCODE_SPS_TIMING).unwrap_or(0) != 0)
+                    .then_some(input_rate).filter(|(num, den)| *num > 0 && *den > 0);
+                if !codec.config_sent {
+                    let config = h264_config(&out.format, timing);
+                    out.pending.push_back(Frame::new(config, ffi::MMAL_BUFFER_HEADER_FLAG_CONFIG | ffi::MMAL_BUFFER_HEADER_FLAG_FRAME, frame.pts));
+                    codec.config_sent = true;
+                }
+                let mut data = if idr && inline_headers && codec.frame_no > 0 { h264_config(&out.format, timing) } else { Vec::new() };
+                // once a bitrate is set on the running encoder, P-frames are sized to spend it
+                let budget = param_u32(&out.params, ffi::MMAL_PARAMETER_VIDEO_BIT_RATE).map(|bitrate| {
+                    let (num, den) = param_rational(&out.params, ffi::MMAL_PARAMETER_VIDEO_FRAME_RATE)
+                        .filter(|(num, den)| *num > 0 && *den > 0).unwrap_or((30, 1));
+                    (bitrate as u64 * den as u64 / num as u64 / 8).clamp(16, 65_536) as u32
+                });
+                data.extend_from_slice(&h264_slice(idr, codec.frame_no, &frame.data, budget));
+                let flags = ffi::MMAL_BUFFER_HEADER_FLAG_FRAME | if idr { ffi::MMAL_BUFFER_HEADER_FLAG_KEYFRAME } else { 0 };
+                out.pending.push_back(Frame::new(data, flags, frame.pts));
+                if param_u32(&out.params, ffi::MMAL_PARAMETER_VIDEO_ENCODE_INLINE_VECTORS).unwrap_or(0) != 0 {
+                    let flags = ffi::MMAL_BUFFER_HEADER_FLAG_CODECSIDEINFO | ffi::MMAL_BUFFER_HEADER_FLAG_FRAME;
+                    out.pending.push_back(Frame::new(motion_vectors(&out.format, codec.frame_no), flags, frame.pts));
+                }
+            } else {
+                let data = encode_image(&Format { encoding: ffi::MMAL_ENCODING_JPEG, ..out.format }, codec.frame_no, &frame.data);
+                out.pending.push_back(Frame::new(data, ffi::MMAL_BUFFER_HEADER_FLAG_FRAME | ffi::MMAL_BUFFER_HEADER_FLAG_KEYFRAME, frame.pts));
+            }
+            codec.frame_no += 1;
+        }
+        _ => { }
+    }
+    if eos {
+        let mut event: ffi::MMAL_EVENT_END_OF_STREAM_T = unsafe { mem::zeroed() };
+        event.port_type = ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_INPUT;
+        event.port_index = input as u32;
+        state.events.push_back((ffi::MMAL_EVENT_EOS, struct_bytes(&event)));
+        for (index, out) in state.outputs.iter_mut().enumerate() {
+            // like the firmware, a bare EOS buffer which does not end a frame
+            let eos = Frame::new(Vec::new(), ffi::MMAL_BUFFER_HEADER_FLAG_EOS, frame.pts);
+            if index == 0 && state.codec.awaiting.is_some() { state.codec.deferred.push_back(eos) } else { out.pending.push_back(eos) }
+        }
+    }
+}
+
+/// Counts the pictures completed in a chunk of an encoded stream: each H.264 slice NAL unit, as the emulated
+/// encoder produces single slice pictures, or each JPEG end of image marker
+fn count_pictures(encoding: u32, tail: &mut Vec<u8>, data: &[u8]) -> usize {
+    let mut buf = mem::take(tail);
+    buf.extend_from_slice(data);
+    let (count, keep) = if encoding == ffi::MMAL_ENCODING_H264 {
+        let count = buf.windows(4).filter(|w| w[..3] == [0, 0, 1] && matches!(w[3] & 0x1f, 1 | 5)).count();
+        (count, 3)
+    } else {
+        (buf.windows(2).filter(|w| w == &[0xff, 0xd9]).count(), 1)
+    };
+    *tail = buf[buf.len().saturating_sub(keep)..].to_vec();
+    count
+}
+
+/// Picture size of an image produced by `encode_image`
+fn image_size(encoding: u32, data: &[u8]) -> Option<(u32, u32)> {
+    let magic = image_magic(encoding)?;
+    if !data.starts_with(magic) {
+        return None;
+    }
+    if encoding == ffi::MMAL_ENCODING_JPEG {
+        let sof = data.windows(2).position(|w| w == [0xff, 0xc0])?;
+        let h = data.get(sof + 5..sof + 7)?;
+        let w = data.get(sof + 7..sof + 9)?;
+        Some((u16::from_be_bytes([w[0], w[1]]) as u32, u16::from_be_bytes([h[0], h[1]]) as u32))
+    } else {
+        let size = data.get(magic.len()..magic.len() + 8)?;
+        Some((u32::from_le_bytes(size[..4].try_into().ok()?), u32::from_le_bytes(size[4..].try_into().ok()?)))
+    }
+}
+
+fn change_event_requested(params: &HashMap<u32, Vec<u8>>, change_id: u32) -> bool {
+    let request = params.get(&ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST)
+        .and_then(|v| unsafe { read_struct::<ffi::MMAL_PARAMETER_CHANGE_EVENT_REQUEST_T>(v) });
+    matches!(request, Some(r) if r.change_id == change_id && r.enable != 0)
+}
+
+/// Settings the emulated sensor reports: exposure follows the shutter speed, or the frame interval in auto mode
+fn camera_settings(params: &HashMap<u32, Vec<u8>>, format: &Format) -> ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T {
+    let mut settings: ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T = unsafe { mem::zeroed() };
+    settings.hdr.id = ffi::MMAL_PARAMETER_CAMERA_SETTINGS;
+    settings.hdr.size = mem::size_of_val(&settings) as u32;
+    settings.exposure = param_u32(params, ffi::MMAL_PARAMETER_SHUTTER_SPEED)
+        .filter(|s| *s > 0)
+        .unwrap_or(format.frame_interval().as_micros() as u32);
+    settings.analog_gain = ffi::MMAL_RATIONAL_T { num: 1, den: 1 };
+    settings.digital_gain = ffi::MMAL_RATIONAL_T { num: 1, den: 1 };
+    settings.awb_red_gain = ffi::MMAL_RATIONAL_T { num: 3, den: 2 };
+    settings.awb_blue_gain = ffi::MMAL_RATIONAL_T { num: 6, den: 5 };
+    settings
+}
+
+unsafe fn read_struct<T: Copy>(data: &[u8]) -> Option<T> {
+    if data.len() < mem::size_of::<T>() {
+        None
+    } else {
+        Some(ptr::read_unaligned(data.as_ptr() as *const T))
+    }
+}
+
+fn param_u32(params: &HashMap<u32, Vec<u8>>, id: u32) -> Option<u32> {
+    let v = params.get(&id)?;
+    let offset = mem::size_of::<ffi::MMAL_PARAMETER_HEADER_T>();
+    Some(u32::from_ne_bytes(v.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn param_rational(params: &HashMap<u32, Vec<u8>>, id: u32) -> Option<(i32, i32)> {
+    let v = params.get(&id)?;
+    let offset = mem::size_of::<ffi::MMAL_PARAMETER_HEADER_T>();
+    Some((i32::from_ne_bytes(v.get(offset..offset + 4)?.try_into().ok()?), i32::from_ne_bytes(v.get(offset + 4..offset + 8)?.try_into().ok()?)))
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+// Synthetic payloads
+
+/// Fills a raw frame with a diagonal gradient moving by one pixel per frame
+fn synthetic_frame(format: &Format, frame_no: u64) -> Vec<u8> {
+    let size = format.raw_frame_size().unwrap_or(OPAQUE_BUFFER_SIZE) as usize;
+    let (w, h) = (format.width.max(1) as usize, format.height.max(1) as usize);
+    let shift = frame_no as usize;
+    let mut data = vec![0u8; size];
+    match format.encoding {
+        ffi::MMAL_ENCODING_I420 | ffi::MMAL_ENCODING_YV12 | ffi::MMAL_ENCODING_NV12 | ffi::MMAL_ENCODING_NV21 => {
+            let (luma, chroma) = data.split_at_mut((w * h).min(size));
+            for (i, p) in luma.iter_mut().enumerate() {
+                *p = ((i % w + i / w + shift) & 0xff) as u8;
+            }
+            chroma.fill(128);
+        }
+        ffi::MMAL_ENCODING_OPAQUE => {
+            data[..8].copy_from_slice(&frame_no.to_le_bytes());
+        }
+        _ => {
+            let bpp = (size / (w * h)).max(1);
+            for (i, px) in data.chunks_mut(bpp).enumerate() {
+                let (x, y) = (i % w, i / w);
+                let c = [(x + shift) as u8, (y + shift) as u8, (x + y) as u8, 0xff];
+                for (d, s) in px.iter_mut().zip(c.iter()) {
+                    *d = *s;
+                }
+            }
+        }
+    }
+    data
+}
+
+fn image_magic(encoding: u32) -> Option<&'static [u8]> {
+    Some(match encoding {
+        ffi::MMAL_ENCODING_JPEG => b"\xff\xd8",
+        ffi::MMAL_ENCODING_PNG => b"\x89PNG\r\n\x1a\n",
+        ffi::MMAL_ENCODING_GIF => b"GIF89a",
+        ffi::MMAL_ENCODING_BMP => b"BM",
+        ffi::MMAL_ENCODING_TGA => b"\0\0\x02\0",
+        ffi::MMAL_ENCODING_PPM => b"P6\n",
+        _ => return None
+    })
+}
+
+/// Wraps a picture into a file that starts with the right signature. JPEG output consists of a
+/// comment, a SOF0 header carrying the picture size, and a digest of the input frame.
+fn encode_image(format: &Format, frame_no: u64, input: &[u8]) -> Vec<u8> {
+    let (w, h) = format.display_size();
+    let mut out = image_magic(format.encoding).unwrap_or_default().to_vec();
+    let digest = input.iter().step_by(61).fold(0u32, |a, b| a.rotate_left(5) ^ *b as u32);
+    let comment = format!("mmal-rs emulation frame {frame_no} digest {digest:08x}");
+    if format.encoding == ffi::MMAL_ENCODING_JPEG {
+        out.extend_from_slice(&[0xff, 0xfe]);
+        out.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(comment.as_bytes());
+        out.extend_from_slice(&[0xff, 0xc0, 0x00, 0x11, 0x08]);
+        out.extend_from_slice(&(h as u16).to_be_bytes());
+        out.extend_from_slice(&(w as u16).to_be_bytes());
+        out.extend_from_slice(&[0x03, 0x01, 0x22, 0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01]);
+        out.extend_from_slice(&[0xff, 0xd9]);
+    } else {
+        out.extend_from_slice(&w.to_le_bytes());
+        out.extend_from_slice(&h.to_le_bytes());
+        out.extend_from_slice(comment.as_bytes());
+    }
+    out
+}
+
+const BRCM_RAW_MARKER: &[u8; 4] = b"BRCM";
+
+/// Raw data of `PEnableRawCapture`: the BRCM header and RAW10 pixels of the whole sensor, laid out like the OV5647
+/// ones, with GRBG order and a diagonal gradient moving by one per frame
+fn brcm_raw_block(frame_no: u64) -> Vec<u8> {
+    const HEADER_SIZE: usize = 32768;
+    let (width, height) = (EMULATED_CAMERA_MAX_SIZE.0 as usize, EMULATED_CAMERA_MAX_SIZE.1 as usize);
+    let stride = (width * 10 / 8).next_multiple_of(32);
+    let mut block = vec![0u8; HEADER_SIZE + stride * (height + 1).next_multiple_of(16)];
+    block[..4].copy_from_slice(BRCM_RAW_MARKER);
+    let header = &mut block[176..];
+    header[..EMULATED_CAMERA_NAME.len()].copy_from_slice(EMULATED_CAMERA_NAME.as_bytes());
+    header[32..34].copy_from_slice(&(width as u16).to_le_bytes());
+    header[34..36].copy_from_slice(&(height as u16).to_le_bytes());
+    header[68] = 3;
+    // RAW10
+    header[69] = 0;
+    for (y, row) in block[HEADER_SIZE..].chunks_exact_mut(stride).take(height).enumerate() {
+        for (g, p) in row.chunks_exact_mut(5).take(width / 4).enumerate() {
+            for i in 0..4 {
+                let v = (g * 4 + i + y + frame_no as usize) & 0x3ff;
+                p[i] = (v >> 2) as u8;
+                p[4] |= ((v & 3) << (i * 2)) as u8;
+            }
+        }
+    }
+    block
+}
+
+/// Inserts an APP1 segment after SOI. Instead of a TIFF structure it holds the `key=value` tags as
+/// written by the client, separated by NULs.
+fn insert_exif(jpeg: &mut Vec<u8>, tags: &[String]) {
+    let mut segment = b"Exif\0\0".to_vec();
+    for tag in tags {
+        segment.extend_from_slice(tag.as_bytes());
+        segment.push(0);
+    }
+    let mut app1 = vec![0xff, 0xe1];
+    app1.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+    app1.extend_from_slice(&segment);
+    jpeg.splice(2..2, app1);
+}
+
+/// Minimal MSB-first bit writer with Exp-Golomb support
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self { Self { bytes: Vec::new(), bit: 0 } }
+
+    fn put(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.bit == 0 { self.bytes.push(0) }
+            if (value >> i) & 1 != 0 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> self.bit;
+            }
+            self.bit = (self.bit + 1) % 8;
+        }
+    }
+
+    fn ue(&mut self, value: u32) {
+        let v = value + 1;
+        let len = 32 - v.leading_zeros();
+        self.put(0, len - 1);
+        self.put(v, len);
+    }
+
+    fn trailing(mut self) -> Vec<u8> {
+        self.put(1, 1);
+        if self.bit != 0 { self.put(0, 8 - self.bit) }
+        self.bytes
+    }
+}
+
+/// Annex-B SPS and PPS describing a baseline profile stream of the format's size, with VUI timing
+/// for a (num, den) frame rate if given
+fn h264_config(format: &Format, timing: Option<(i32, i32)>) -> Vec<u8> {
+    let (w, h) = format.display_size();
+    let (mbs_w, mbs_h) = (w.div_ceil(16), h.div_ceil(16));
+    let mut sps = BitWriter::new();
+    sps.put(66, 8); // profile_idc: baseline
+    sps.put(0xc0, 8); // constraint flags
+    sps.put(40, 8); // level_idc
+    sps.ue(0); // seq_parameter_set_id
+    sps.ue(0); // log2_max_frame_num_minus4
+    sps.ue(2); // pic_order_cnt_type
+    sps.ue(1); // max_num_ref_frames
+    sps.put(0, 1); // gaps_in_frame_num_value_allowed_flag
+    sps.ue(mbs_w - 1);
+    sps.ue(mbs_h - 1);
+    sps.put(1, 1); // frame_mbs_only_flag
+    sps.put(1, 1); // direct_8x8_inference_flag
+    let (crop_r, crop_b) = ((mbs_w * 16 - w) / 2, (mbs_h * 16 - h) / 2);
+    if crop_r > 0 || crop_b > 0 {
+        sps.put(1, 1);
+        sps.ue(0);
+        sps.ue(crop_r);
+        sps.ue(0);
+        sps.ue(crop_b);
+    } else {
+        sps.put(0, 1);
+    }
+    if let Some((num, den)) = timing {
+        sps.put(1, 1); // vui_parameters_present_flag
+        sps.put(0, 4); // aspect_ratio, overscan, video_signal_type, chroma_loc info
+        sps.put(1, 1); // timing_info_present_flag
+        sps.put(den as u32, 32); // num_units_in_tick
+        sps.put(2 * num as u32, 32); // time_scale
+        sps.put(1, 1); // fixed_frame_rate_flag
+        sps.put(0, 4); // nal and vcl hrd parameters, pic_struct, bitstream_restriction
+    } else {
+        sps.put(0, 1); // vui_parameters_present_flag
+    }
+
+    let mut pps = BitWriter::new();
+    pps.ue(0); // pic_parameter_set_id
+    pps.ue(0); // seq_parameter_set_id
+    pps.put(0, 1); // entropy_coding_mode_flag
+    pps.put(0, 1); // bottom_field_pic_order_in_frame_present_flag
+    pps.ue(0); // num_slice_groups_minus1
+    pps.ue(0); // num_ref_idx_l0_default_active_minus1
+    pps.ue(0); // num_ref_idx_l1_default_active_minus1
+    pps.put(0, 1); // weighted_pred_flag
+    pps.put(0, 2); // weighted_bipred_idc
+    pps.ue(0); // pic_init_qp_minus26 (se, 0)
+    pps.ue(0); // pic_init_qs_minus26 (se, 0)
+    pps.ue(0); // chroma_qp_index_offset (se, 0)
+    pps.put(1, 1); // deblocking_filter_control_present_flag
+    pps.put(0, 1); // constrained_intra_pred_flag
+    pps.put(0, 1); // redundant_pic_cnt_present_flag
+
+    let mut out = vec![0, 0, 0, 1, 0x67];
+    out.extend_from_slice(&escape_nal(&sps.trailing()));
+    out.extend_from_slice(&[0, 0, 0, 1, 0x68]);
+    out.extend_from_slice(&escape_nal(&pps.trailing()));
+    out
+}
+
+/// Side info of `MMAL_PARAMETER_VIDEO_ENCODE_INLINE_VECTORS`: a 2x2 macroblock object in the middle rows, moving right by
+/// a macroblock per frame, on a still background. Each row has an extra macroblock, as with the firmware encoder.
+fn motion_vectors(format: &Format, frame_no: u64) -> Vec<u8> {
+    let (columns, rows) = (format.width.div_ceil(16) as usize, format.height.div_ceil(16) as usize);
+    let mut data = vec![0u8; (columns + 1) * rows * 4];
+    let (x, y) = (frame_no as usize % columns.saturating_sub(1).max(1), rows.saturating_sub(1) / 2);
+    for (column, row) in [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)] {
+        if column < columns && row < rows {
+            let i = (row * (columns + 1) + column) * 4;
+            data[i..i + 4].copy_from_slice(&[16, 0, 0x00, 0x04]);
+        }
+    }
+    data
+}
+
+/// A slice NAL unit whose payload never contains a start code
+fn h264_slice(idr: bool, frame_no: u64, input: &[u8], budget: Option<u32>) -> Vec<u8> {
+    let mut out = vec![0, 0, 0, 1, if idr { 0x65 } else { 0x41 }];
+    let len = if idr { 512 } else { budget.unwrap_or(128) };
+    let seed = input.iter().step_by(97).fold(frame_no as u32, |a, b| a.wrapping_mul(31).wrapping_add(*b as u32));
+    out.extend((0..len).map(|i: u32| (seed.wrapping_add(i.wrapping_mul(2_654_435_761)) >> 24) as u8 | 1));
+    out
+}
+
+fn escape_nal(rbsp: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(rbsp.len());
+    let mut zeros = 0;
+    for b in rbsp {
+        if zeros >= 2 && *b <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+        zeros = if *b == 0 { zeros + 1 } else { 0 };
+        out.push(*b);
+    }
+    out
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+type CameraPipeline = (
+    crate::ComponentEnabler<crate::CameraEntity>,
+    crate::ComponentEnabler<crate::VideoEncoderEntity>,
+    crate::ConnectionHandle<crate::CameraVideoPort, crate::VideoEncoderInputPort>
+);
+
+#[cfg(test)]
+fn camera_pipeline_setup(encoding: u32) -> crate::Result<CameraPipeline> {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create()?;
+    CameraControlPort::write(&camera, &PCameraNum::from(0))?;
+    let mut vcfg = CAMERA_PORT_CONFIG_320X240;
+    vcfg.encoding = ffi::MMAL_ENCODING_I420;
+    vcfg.es_video_frame_rate_num = 100;
+    CameraVideoPort::configure(&camera, vcfg)?;
+    let camera = ComponentEnabler::new(camera)?;
+
+    let encoder = VideoEncoderComponentHandle::create()?;
+    let format = VideoEncoderOutFormat { encoding, ..Default::default() };
+    VideoEncoderOutputPort::configure(&encoder, format)?;
+    let encoder = ComponentEnabler::new(encoder)?;
+
+    let connection = ConnectionHandle::<CameraVideoPort, VideoEncoderInputPort>::create(&camera, &encoder)?;
+    connection.enable()?;
+    Ok((camera, encoder, connection))
+}
+
+#[test]
+fn test_camera_info() {
+    use crate::*;
+    init().unwrap();
+    let camera_info = CameraInfoComponentHandle::create().unwrap();
+    let mut p = CameraInformation::default();
+    p.read(&camera_info).unwrap();
+    let info: CameraInfo = p.get();
+    assert_eq!(info.cameras.len(), 1);
+    assert_eq!(info.cameras[0].camera_name, EMULATED_CAMERA_NAME);
+    assert_eq!((info.cameras[0].max_width, info.cameras[0].max_height), EMULATED_CAMERA_MAX_SIZE);
+}
+
+#[test]
+fn test_unknown_component() {
+    assert!(crate::ComponentHandle::<crate::NullSinkEntity>::create().is_ok());
+    let mut c = ptr::null_mut();
+    assert_eq!(unsafe { mmal_component_create(c"vc.ril.nonexistent".as_ptr(), &mut c) }, MMAL_ENOENT);
+}
+
+#[test]
+fn test_video_pipeline_h264() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let (mut config, mut frames, mut keyframes, mut stream) = (0, 0, 0, Vec::new());
+    while frames < 5 {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        let (_, (flags, len)) = sink.consume(b, |flags, payload| {
+            stream.extend_from_slice(payload);
+            Ok((true, (flags, payload.len())))
+        }).unwrap();
+        assert!(len > 0);
+        if flags.test_one(FrameFlags::FLAG_CONFIG) { config += 1 }
+        else if flags.is_terminal_frame() {
+            frames += 1;
+            if flags.test_one(FrameFlags::FLAG_KEYFRAME) { keyframes += 1 }
+        }
+    }
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+    assert_eq!(config, 1);
+    assert_eq!(keyframes, 1);
+    assert_eq!(&stream[..5], &[0, 0, 0, 1, 0x67]);
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_still_pipeline_jpeg() {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    CameraCapturePort::configure(&camera, CAMERA_PORT_CONFIG_320X240).unwrap();
+    let camera = ComponentEnabler::new(camera).unwrap();
+    let encoder = EncoderComponentHandle::create().unwrap();
+    EncoderOutputPort::configure(&encoder, EncoderOutFormat::default()).unwrap();
+    let encoder = ComponentEnabler::new(encoder).unwrap();
+    let connection = ConnectionHandle::<CameraCapturePort, EncoderInputPort>::create(&camera, &encoder).unwrap();
+    connection.enable().unwrap();
+    let sink = SinkAggregate::<EncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+
+    CameraCapturePort::write(&camera, &PCapture::from(true)).unwrap();
+    let mut jpeg = Vec::new();
+    while let Some(b) = sink.timedwait(5000) {
+        let (_, is_terminal) = sink.consume(b, |flags, payload| {
+            jpeg.extend_from_slice(payload);
+            Ok((true, flags.is_terminal_frame()))
+        }).unwrap();
+        if is_terminal { break }
+    }
+    assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+    assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+    // no further frames unless capture is triggered again
+    assert!(sink.timedwait(100).is_none());
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_port_disable_returns_buffers() {
+    use crate::*;
+    let (_camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_MJPEG).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    assert!(sink.get().is_none());
+    sink.disable().unwrap();
+    let mut returned = 0;
+    while let Some(b) = sink.get() {
+        b.do_locked(|_, payload| { assert!(payload.is_empty()); Ok((true, ())) }).unwrap();
+        returned += 1;
+    }
+    assert_eq!(returned, VideoEncoderOutputPort::get_buffers_config(encoder.inner()).0.0);
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_frame_stream() {
+    use crate::*;
+    use futures_util::StreamExt;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_MJPEG).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut frames = sink.frames();
+    rt.block_on(async {
+        let mut last_pts = None;
+        for _ in 0..10 {
+            let frame = frames.next().await.unwrap().unwrap();
+            assert!(frame.flags.test_all(FrameFlags::FLAG_FRAME | FrameFlags::FLAG_KEYFRAME));
+            assert!(frame.pts.is_some() && frame.pts > last_pts);
+            assert_eq!(frame.pts, frame.dts);
+            last_pts = frame.pts;
+            assert_eq!(&frame.data[..2], &[0xff, 0xd8]);
+            assert_eq!(&frame.data[frame.data.len() - 2..], &[0xff, 0xd9]);
+        }
+    });
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+    sink.disable().unwrap();
+    assert!(rt.block_on(frames.next()).is_none());
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_camera_settings_events() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_MJPEG).unwrap();
+    let events = camera.events().unwrap();
+    CameraControlPort::write(&camera, &PShutterSpeed::from(20000)).unwrap();
+    CameraControlPort::write(&camera, &PChangeEventRequest::from((ffi::MMAL_PARAMETER_CAMERA_SETTINGS, true))).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let settings = loop {
+        match events.try_next() {
+            Some(ComponentEvent::CameraSettings(s)) => break s,
+            Some(e) => panic!("unexpected event {:?}", e),
+            None if std::time::Instant::now() < deadline => std::thread::sleep(std::time::Duration::from_millis(5)),
+            None => panic!("no camera settings event"),
+        }
+    };
+    assert_eq!(settings.exposure, 20000);
+    assert!(settings.awb_red_gain > 1.0);
+
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+    drop(events);
+}
+
+#[test]
+fn test_pipeline_builder() {
+    use crate::*;
+    use futures_util::StreamExt;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    let mut vcfg = CAMERA_PORT_CONFIG_320X240;
+    vcfg.encoding = ffi::MMAL_ENCODING_I420;
+    vcfg.es_video_frame_rate_num = 100;
+    CameraVideoPort::configure(&camera, vcfg).unwrap();
+    let encoder = VideoEncoderComponentHandle::create().unwrap();
+    let format = VideoEncoderOutFormat { encoding: ffi::MMAL_ENCODING_MJPEG, ..Default::default() };
+    VideoEncoderOutputPort::configure(&encoder, format).unwrap();
+
+    let mut b = PipelineBuilder::new();
+    let camera = b.node(camera);
+    let encoder = b.node(encoder);
+    b.connect::<CameraVideoPort, VideoEncoderInputPort>(camera, encoder);
+    let output = b.sink::<VideoEncoderOutputPort>(encoder);
+    let pipeline = b.build().unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(true)).unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut frames = pipeline.sink(output).frames();
+    rt.block_on(async {
+        for _ in 0..3 {
+            let frame = frames.next().await.unwrap().unwrap();
+            assert_eq!(&frame.data[..2], &[0xff, 0xd8]);
+        }
+    });
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(false)).unwrap();
+    pipeline.stop().unwrap();
+    assert!(rt.block_on(frames.next()).is_none());
+    pipeline.stop().unwrap();
+}
+
+#[test]
+fn test_pipeline_builder_validation() {
+    use crate::*;
+    init().unwrap();
+    let is_invalid = |r: Result<Pipeline>| matches!(r.err().as_ref().map(MmalError::cause), Some(Cause::InvalidPipeline));
+
+    // encoded output into an encoder input
+    let mut b = PipelineBuilder::new();
+    let first = b.node(VideoEncoderComponentHandle::create().unwrap());
+    let second = b.node(VideoEncoderComponentHandle::create().unwrap());
+    b.connect::<VideoEncoderOutputPort, VideoEncoderInputPort>(first, second);
+    assert!(is_invalid(b.build()));
+
+    // the same output port used twice
+    let camera = CameraComponentHandle::create().unwrap();
+    CameraVideoPort::configure(&camera, CAMERA_PORT_CONFIG_320X240).unwrap();
+    let mut b = PipelineBuilder::new();
+    let camera = b.node(camera);
+    let encoder = b.node(VideoEncoderComponentHandle::create().unwrap());
+    b.connect::<CameraVideoPort, VideoEncoderInputPort>(camera, encoder);
+    b.sink::<CameraVideoPort>(camera);
+    assert!(is_invalid(b.build()));
+}
+
+#[test]
+fn test_splitter_outputs() {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    let mut vcfg = CAMERA_PORT_CONFIG_320X240;
+    vcfg.encoding = ffi::MMAL_ENCODING_I420;
+    vcfg.es_video_frame_rate_num = 100;
+    CameraVideoPort::configure(&camera, vcfg).unwrap();
+
+    let splitter = SplitterComponentHandle::create().unwrap();
+    SplitterInputPort::configure(&splitter, vcfg).unwrap();
+    SplitterOutputPort::<0>::configure(&splitter, SplitterOutFormat::default()).unwrap();
+    SplitterOutputPort::<1>::configure(&splitter, SplitterOutFormat::default()).unwrap();
+    SplitterOutputPort::<2>::configure(&splitter, SplitterOutFormat::from(ffi::MMAL_ENCODING_RGB24)).unwrap();
+
+    let video_encoder = VideoEncoderComponentHandle::create().unwrap();
+    VideoEncoderOutputPort::configure(&video_encoder, VideoEncoderOutFormat::default()).unwrap();
+    let encoder = EncoderComponentHandle::create().unwrap();
+    EncoderOutputPort::configure(&encoder, EncoderOutFormat::default()).unwrap();
+
+    let mut b = PipelineBuilder::new();
+    let camera = b.node(camera);
+    let splitter = b.node(splitter);
+    let video_encoder = b.node(video_encoder);
+    let encoder = b.node(encoder);
+    b.connect::<CameraVideoPort, SplitterInputPort>(camera, splitter)
+        .connect::<SplitterOutputPort<0>, VideoEncoderInputPort>(splitter, video_encoder)
+        .connect::<SplitterOutputPort<1>, EncoderInputPort>(splitter, encoder);
+    let h264 = b.sink::<VideoEncoderOutputPort>(video_encoder);
+    let jpeg = b.sink::<EncoderOutputPort>(encoder);
+    let raw = b.sink::<SplitterOutputPort<2>>(splitter);
+    let pipeline = b.build().unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(true)).unwrap();
+    let mut assembler = FrameAssembler::new();
+    let h264 = pipeline.sink(h264);
+    let frame = loop {
+        let b = h264.timedwait(5000).expect("no H.264 output");
+        let (_, frame) = h264.consume_view(b, |view| Ok((true, assembler.push(view)))).unwrap();
+        match frame {
+            Some(frame) if !frame.flags.test_one(FrameFlags::FLAG_CONFIG) => break frame,
+            _ => { }
+        }
+    };
+    assert_eq!(&frame.data[..4], &[0, 0, 0, 1]);
+    let jpeg = pipeline.sink(jpeg);
+    let b = jpeg.timedwait(5000).expect("no JPEG output");
+    jpeg.consume(b, |_, data| { assert_eq!(&data[..2], &[0xff, 0xd8]); Ok((true, ())) }).unwrap();
+    let raw = pipeline.sink(raw);
+    let b = raw.timedwait(5000).expect("no raw output");
+    raw.consume(b, |_, data| { assert_eq!(data.len(), 320 * 240 * 3); Ok((true, ())) }).unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(false)).unwrap();
+    pipeline.stop().unwrap();
+}
+
+#[test]
+fn test_pipeline_declared_out_of_order() {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    let mut vcfg = CAMERA_PORT_CONFIG_320X240;
+    vcfg.encoding = ffi::MMAL_ENCODING_I420;
+    vcfg.es_video_frame_rate_num = 100;
+    CameraVideoPort::configure(&camera, vcfg).unwrap();
+    let splitter = SplitterComponentHandle::create().unwrap();
+    SplitterInputPort::configure(&splitter, vcfg).unwrap();
+    SplitterOutputPort::<0>::configure(&splitter, SplitterOutFormat::default()).unwrap();
+    let video_encoder = VideoEncoderComponentHandle::create().unwrap();
+    VideoEncoderOutputPort::configure(&video_encoder, VideoEncoderOutFormat::default()).unwrap();
+
+    // downstream first: built upstream first, torn down downstream first
+    let mut b = PipelineBuilder::new();
+    let video_encoder = b.node(video_encoder);
+    let splitter = b.node(splitter);
+    let camera = b.node(camera);
+    let h264 = b.sink::<VideoEncoderOutputPort>(video_encoder);
+    b.connect::<SplitterOutputPort<0>, VideoEncoderInputPort>(splitter, video_encoder)
+        .connect::<CameraVideoPort, SplitterInputPort>(camera, splitter);
+    let pipeline = b.build().unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(true)).unwrap();
+    let h264 = pipeline.sink(h264);
+    let b = h264.timedwait(5000).expect("no H.264 output");
+    h264.consume(b, |_, data| { assert_eq!(&data[..4], &[0, 0, 0, 1]); Ok((true, ())) }).unwrap();
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(false)).unwrap();
+    pipeline.stop().unwrap();
+
+    let mut b = PipelineBuilder::new();
+    let splitter = b.node(SplitterComponentHandle::create().unwrap());
+    let resizer = b.node(ResizerComponentHandle::create().unwrap());
+    b.connect::<SplitterOutputPort<0>, ResizerInputPort>(splitter, resizer)
+        .connect::<ResizerOutputPort, SplitterInputPort>(resizer, splitter);
+    assert!(matches!(b.build().err().unwrap().cause(), Cause::InvalidPipeline));
+}
+
+#[test]
+fn test_isp_and_resizer() {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    let mut vcfg = camera_port_config(640, 480);
+    vcfg.encoding = ffi::MMAL_ENCODING_I420;
+    vcfg.es_video_frame_rate_num = 100;
+    CameraPreviewPort::configure(&camera, vcfg).unwrap();
+    CameraVideoPort::configure(&camera, vcfg).unwrap();
+
+    let isp = IspComponentHandle::create().unwrap();
+    let mut icfg = vcfg;
+    icfg.es_video_crop_x = 160;
+    icfg.es_video_crop_width = 320;
+    IspInputPort::configure(&isp, icfg).unwrap();
+    IspOutputPort::configure(&isp, ScalerOutFormat::new(ffi::MMAL_ENCODING_RGB24, 160, 120)).unwrap();
+
+    let resizer = ResizerComponentHandle::create().unwrap();
+    ResizerInputPort::configure(&resizer, vcfg).unwrap();
+    ResizerOutputPort::configure(&resizer, ScalerOutFormat::new(ffi::MMAL_ENCODING_I420, 100, 75)).unwrap();
+
+    let mut b = PipelineBuilder::new();
+    let camera = b.node(camera);
+    let isp = b.node(isp);
+    let resizer = b.node(resizer);
+    b.connect::<CameraPreviewPort, IspInputPort>(camera, isp)
+        .connect::<CameraVideoPort, ResizerInputPort>(camera, resizer);
+    let rgb = b.sink::<IspOutputPort>(isp);
+    let small = b.sink::<ResizerOutputPort>(resizer);
+    let pipeline = b.build().unwrap();
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(true)).unwrap();
+
+    let rgb = pipeline.sink(rgb);
+    let b = rgb.timedwait(5000).expect("no ISP output");
+    // buffers hold the aligned picture: width to 32, height to 16
+    rgb.consume(b, |_, data| { assert_eq!(data.len(), 160 * 128 * 3); Ok((true, ())) }).unwrap();
+    let small = pipeline.sink(small);
+    let b = small.timedwait(5000).expect("no resizer output");
+    small.consume(b, |_, data| { assert_eq!(data.len(), 128 * 80 * 3 / 2); Ok((true, ())) }).unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(false)).unwrap();
+    pipeline.stop().unwrap();
+}
+
+#[cfg(test)]
+fn decode_all(encoding: u32, feed: impl FnOnce(&crate::SourceAggregate<crate::VideoDecoderInputPort>)) -> Vec<crate::Frame> {
+    use crate::*;
+    init().unwrap();
+    let decoder = VideoDecoderComponentHandle::create().unwrap();
+    VideoDecoderInputPort::configure(&decoder, VideoDecoderInFormat::new(encoding, 320, 240)).unwrap();
+    let decoder = ComponentEnabler::new(decoder).unwrap();
+    let source = SourceAggregate::<VideoDecoderInputPort>::create(decoder.inner().clone()).unwrap();
+    source.enable().unwrap();
+    let sink = SinkAggregate::<VideoDecoderOutputPort>::create(decoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+
+    feed(&source);
+    source.send_eos().unwrap();
+    let mut frames = Vec::new();
+    let mut assembler = FrameAssembler::new();
+    while let Some(b) = sink.timedwait(5000) {
+        let (_, (eos, frame)) = sink.consume_view(b, |view| Ok((true, (view.meta.flags.test_one(FrameFlags::FLAG_EOS), assembler.push(view))))).unwrap();
+        frames.extend(frame);
+        if eos { break }
+    }
+    sink.disable().unwrap();
+    source.disable().unwrap();
+    frames
+}
+
+#[test]
+fn test_source_without_payload() {
+    use crate::*;
+    init().unwrap();
+    let decoder = VideoDecoderComponentHandle::create().unwrap();
+    VideoDecoderInputPort::configure(&decoder, VideoDecoderInFormat::new(ffi::MMAL_ENCODING_H264, 320, 240)).unwrap();
+    unsafe { (*VideoDecoderInputPort::get_port(&decoder)).buffer_size = 0 };
+    let decoder = ComponentEnabler::new(decoder).unwrap();
+    let mut source = SourceAggregate::<VideoDecoderInputPort>::create(decoder.inner().clone()).unwrap();
+    source.set_timeout(100);
+    source.enable().unwrap();
+    let e = source.send(&[0, 0, 0, 1], 0, None).unwrap_err();
+    assert!(matches!(e.cause(), Cause::Status(ffi::MMAL_STATUS_T::MMAL_ENOSPC)), "{e}");
+    // the buffer went back to the pool
+    source.send_eos().unwrap();
+    source.disable().unwrap();
+}
+
+#[test]
+fn test_video_decoder_h264() {
+    let format = Format { encoding: ffi::MMAL_ENCODING_H264, width: 320, height: 240, crop: (0, 0, 320, 240), frame_rate: (30, 1), bitrate: 0 };
+    let mut stream = h264_config(&format, None);
+    for i in 0..3 {
+        stream.extend_from_slice(&h264_slice(i == 0, i, &[], None));
+    }
+    let slice = h264_slice(false, 3, &[], None);
+    let frames = decode_all(ffi::MMAL_ENCODING_H264, |source| {
+        // arbitrary chunks, with start codes split between them
+        for chunk in stream.chunks(100) {
+            source.send(chunk, 0, None).unwrap();
+        }
+        source.send_nal_units([&slice[4..]], Some(Duration::from_millis(100))).unwrap();
+    });
+    assert_eq!(frames.len(), 4);
+    assert!(frames.iter().all(|f| f.data.len() == 320 * 240 * 3 / 2));
+    assert_eq!(frames[3].pts, Some(Duration::from_millis(100)));
+}
+
+#[test]
+fn test_video_decoder_mjpeg() {
+    let format = Format { encoding: ffi::MMAL_ENCODING_JPEG, width: 320, height: 240, ..Format::default() };
+    let frames = decode_all(ffi::MMAL_ENCODING_MJPEG, |source| {
+        for i in 0..2 {
+            source.send_frame(&encode_image(&format, i, &[]), Some(Duration::from_millis(i * 40))).unwrap();
+        }
+    });
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[1].pts, Some(Duration::from_millis(40)));
+}
+
+#[test]
+fn test_image_decoder() {
+    use crate::*;
+    init().unwrap();
+    let jpeg = encode_image(&Format { encoding: ffi::MMAL_ENCODING_JPEG, width: 100, height: 75, ..Format::default() }, 0, &[]);
+    let image = decode_jpeg(&jpeg).unwrap();
+    assert_eq!((image.width, image.height, image.stride, image.encoding), (100, 75, 128, ffi::MMAL_ENCODING_I420));
+    assert_eq!(image.data.len(), 128 * 80 * 3 / 2);
+
+    let png = encode_image(&Format { encoding: ffi::MMAL_ENCODING_PNG, width: 64, height: 48, ..Format::default() }, 0, &[]);
+    let image = decode_image(ffi::MMAL_ENCODING_PNG, &png).unwrap();
+    assert_eq!((image.width, image.height, image.stride, image.encoding), (64, 48, 256, ffi::MMAL_ENCODING_RGBA));
+
+    assert!(decode_jpeg(b"not a picture").is_err());
+}
+
+#[cfg(test)]
+fn capture_still(configure: impl Fn(&crate::EncoderComponentHandle)) -> Vec<u8> {
+    use crate::*;
+    let camera = CameraComponentHandle::create().unwrap();
+    CameraCapturePort::configure(&camera, CAMERA_PORT_CONFIG_320X240).unwrap();
+    let camera = ComponentEnabler::new(camera).unwrap();
+    let encoder = EncoderComponentHandle::create().unwrap();
+    configure(&encoder);
+    let encoder = ComponentEnabler::new(encoder).unwrap();
+    let connection = ConnectionHandle::<CameraCapturePort, EncoderInputPort>::create(&camera, &encoder).unwrap();
+    connection.enable().unwrap();
+    let sink = SinkAggregate::<EncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+
+    CameraCapturePort::write(&camera, &PCapture::from(true)).unwrap();
+    let mut image = Vec::new();
+    while let Some(b) = sink.timedwait(5000) {
+        let (_, is_terminal) = sink.consume(b, |flags, payload| {
+            image.extend_from_slice(payload);
+            Ok((true, flags.is_terminal_frame()))
+        }).unwrap();
+        if is_terminal { break }
+    }
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+    image
+}
+
+#[test]
+fn test_image_encodings() {
+    use crate::*;
+    init().unwrap();
+    assert_eq!("PNG".parse::<ImageEncoding>().unwrap(), ImageEncoding::Png);
+    assert_eq!("jpeg".parse::<ImageEncoding>().unwrap(), ImageEncoding::default());
+    assert!("webp".parse::<ImageEncoding>().is_err());
+    assert_eq!(ImageEncoding::try_from(ffi::MMAL_ENCODING_TGA).unwrap().extension(), "tga");
+
+    let jpeg = ImageEncoding::Jpeg(JpegSettings { q_factor: 75, restart_interval: 4 });
+    let encoder = EncoderComponentHandle::create().unwrap();
+    jpeg.configure(&encoder).unwrap();
+    let mut q = JpegQFactor::default();
+    EncoderOutputPort::read(&encoder, &mut q).unwrap();
+    assert_eq!(q.get::<u32>(), 75);
+
+    for encoding in [ImageEncoding::Png, ImageEncoding::Gif, ImageEncoding::Bmp, ImageEncoding::Tga, ImageEncoding::Ppm] {
+        let image = capture_still(|encoder| encoding.configure(encoder).unwrap());
+        assert!(image.starts_with(image_magic(encoding.fourcc()).unwrap()), "{encoding}");
+    }
+}
+
+#[test]
+fn test_exif_tags() {
+    use crate::*;
+    init().unwrap();
+    assert_eq!(exif_dms(-51.5125), "51/1,30/1,45000/1000");
+    assert_eq!(exif_datetime(std::time::UNIX_EPOCH + Duration::from_secs(1_685_622_600)), "2023:06:01 12:30:00");
+
+    let mut tag = PExifTag::from(("IFD0.Model", "cam-17"));
+    assert_eq!(tag.get::<(String, String)>(), ("IFD0.Model".to_owned(), "cam-17".to_owned()));
+    tag.set(("EXIF.UserComment", "x".repeat(200).as_str()));
+    assert_eq!(tag.get::<(String, String)>().1.len(), MAX_EXIF_PAYLOAD_LENGTH - 1 - "EXIF.UserComment=".len());
+
+    let jpeg = capture_still(|encoder| {
+        ImageEncoding::default().configure(encoder).unwrap();
+        EncoderControlPort::write(encoder, &PThumbnailConfig::from(&ThumbnailConfig { width: 96, height: 72, ..Default::default() })).unwrap();
+        EncoderOutputPort::write(encoder, &PExifTag::from(("IFD0.Model", "cam-1"))).unwrap();
+        EncoderOutputPort::write(encoder, &PExifTag::from(("IFD0.Model", "cam-17"))).unwrap();
+        EncoderOutputPort::write(encoder, &PExifTag::from(("GPS.GPSLatitude", exif_dms(51.5125).as_str()))).unwrap();
+
+        let mut thumbnail = PThumbnailConfig::default();
+        EncoderControlPort::read(encoder, &mut thumbnail).unwrap();
+        assert_eq!(thumbnail.get::<ThumbnailConfig>(), ThumbnailConfig { width: 96, height: 72, ..Default::default() });
+    });
+    assert_eq!(&jpeg[2..4], &[0xff, 0xe1]);
+    let app1 = &jpeg[6..6 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize - 2];
+    assert_eq!(app1, b"Exif\0\0IFD0.Model=cam-17\0GPS.GPSLatitude=51/1,30/1,45000/1000\0");
+
+    let jpeg = capture_still(|encoder| {
+        EncoderOutputPort::configure(encoder, EncoderOutFormat::default()).unwrap();
+        EncoderOutputPort::write(encoder, &PExifTag::from(("IFD0.Model", "cam-17"))).unwrap();
+        EncoderOutputPort::write(encoder, &PExifDisable::from(true)).unwrap();
+    });
+    assert_eq!(&jpeg[2..4], &[0xff, 0xfe]);
+}
+
+#[cfg(test)]
+fn next_h264_frame(sink: &crate::SinkAggregate<crate::VideoEncoderOutputPort>) -> (bool, Vec<u8>) {
+    use crate::*;
+    loop {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        let (_, frame) = sink.consume(b, |flags, payload| {
+            let frame = (!flags.test_one(FrameFlags::FLAG_CONFIG)).then(|| (flags.test_one(FrameFlags::FLAG_KEYFRAME), payload.to_vec()));
+            Ok((true, frame))
+        }).unwrap();
+        if let Some(frame) = frame { return frame }
+    }
+}
+
+#[test]
+fn test_h264_encoder_controls() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    VideoEncoderOutputPort::write_multi(&encoder, param_iter![
+        &PIntraPeriod::from(4), &PInlineHeader::from(true), &PSpsTiming::from(true), &PInlineVectors::from(false),
+        &PInitialQuant::from(25), &PMinQuant::from(20), &PMaxQuant::from(30), &PDeblockIdc::from(1),
+        &PRateControl::from(RateControl::Variable)
+    ]).unwrap();
+    let mut refresh = PIntraRefresh::from(IntraRefreshType::Both);
+    refresh.inner_mut().set_mbs(8, 0, 16, 0);
+    VideoEncoderOutputPort::write(&encoder, &refresh).unwrap();
+
+    let mut rc = PRateControl::default();
+    VideoEncoderOutputPort::read(&encoder, &mut rc).unwrap();
+    assert!(matches!(rc.try_get::<RateControl>(), Ok(RateControl::Variable)));
+    let mut refresh = PIntraRefresh::default();
+    VideoEncoderOutputPort::read(&encoder, &mut refresh).unwrap();
+    assert!(matches!(refresh.try_get::<IntraRefreshType>(), Ok(IntraRefreshType::Both)));
+    let mut max_quant = PMaxQuant::default();
+    VideoEncoderOutputPort::read(&encoder, &mut max_quant).unwrap();
+    assert_eq!(max_quant.get::<u32>(), 30);
+
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+    for i in 0..9 {
+        let (keyframe, data) = next_h264_frame(&sink);
+        assert_eq!(keyframe, i % 4 == 0, "frame {i}");
+        // SPS in front of every IDR frame but the first one, which follows the config buffer
+        assert_eq!(data[4] == 0x67, keyframe && i > 0, "frame {i}");
+    }
+
+    // without periodic IDR frames, the next key frame is the requested one
+    VideoEncoderOutputPort::write(&encoder, &PIntraPeriod::from(1000)).unwrap();
+    let mut since_key = 0;
+    while since_key < 8 {
+        since_key = if next_h264_frame(&sink).0 { 0 } else { since_key + 1 };
+    }
+    VideoEncoderOutputPort::write(&encoder, &PRequestIFrame::from(true)).unwrap();
+    assert!((0..100).any(|_| next_h264_frame(&sink).0));
+    assert!((0..10).all(|_| !next_h264_frame(&sink).0));
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_video_encoder_runtime_update() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+    next_h264_frame(&sink);
+
+    let update = VideoEncoderUpdate { bitrate: Some(800_000), frame_rate: Some((25, 1)), ..Default::default() };
+    VideoEncoderOutputPort::update(&encoder, &update).unwrap();
+    // frames encoded before the update may still be queued
+    assert!((0..100).any(|_| next_h264_frame(&sink).1.len() == 5 + 4000));
+
+    let update = VideoEncoderUpdate { bitrate: Some(80_000), quant: Some((10, 40)), qp_p: Some(28), force_idr: true, ..Default::default() };
+    VideoEncoderOutputPort::update(&encoder, &update).unwrap();
+    assert!((0..100).any(|_| next_h264_frame(&sink).0));
+    assert_eq!(next_h264_frame(&sink).1.len(), 5 + 400);
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+
+    let mut frame_rate = PVideoFrameRate::default();
+    VideoEncoderOutputPort::read(&encoder, &mut frame_rate).unwrap();
+    assert_eq!(frame_rate.inner().get(), (25, 1));
+    let mut qp = PQpP::default();
+    VideoEncoderOutputPort::read(&encoder, &mut qp).unwrap();
+    assert_eq!(qp.get::<u32>(), 28);
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_h264_parse_encoder_output() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    VideoEncoderOutputPort::write(&encoder, &PSpsTiming::from(true)).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let mut reader = AnnexBReader::new();
+    let mut nals = Vec::new();
+    while nals.len() < 5 {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        sink.consume(b, |flags, payload| {
+            nals.extend(reader.push(payload));
+            if flags.test_one(FrameFlags::FLAG_FRAME_END) {
+                nals.extend(reader.flush());
+            }
+            Ok((true, ()))
+        }).unwrap();
+    }
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+
+    let types: Vec<_> = nals.iter().map(|nal| NalUnitType::from(nal[0])).collect();
+    assert_eq!(&types[..4], &[NalUnitType::Sps, NalUnitType::Pps, NalUnitType::IdrSlice, NalUnitType::NonIdrSlice]);
+    let sps = Sps::parse(&nals[0]).unwrap();
+    assert_eq!((sps.profile_idc, sps.width, sps.height), (66, 320, 240));
+    assert_eq!(sps.timing.and_then(|t| t.frame_rate()), Some(100.));
+    let pps = Pps::parse(&nals[1]).unwrap();
+    assert_eq!((pps.sps_id, pps.entropy_coding_mode), (sps.sps_id, false));
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_fmp4_encoder_output() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    VideoEncoderOutputPort::write_multi(&encoder, param_iter![&PIntraPeriod::from(5), &PSpsTiming::from(true)]).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let mut writer = Fmp4Writer::new(Vec::new());
+    let mut assembler = FrameAssembler::new();
+    let mut frames = 0;
+    while frames < 12 {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        let (_, frame) = sink.consume_view(b, |view| Ok((true, assembler.push(view)))).unwrap();
+        if let Some(frame) = frame {
+            writer.write_frame(&frame).unwrap();
+            frames += !frame.flags.test_one(FrameFlags::FLAG_CONFIG) as usize;
+        }
+    }
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+    let out = writer.finish().unwrap();
+
+    // one fragment per GOP
+    let types: Vec<_> = crate::mp4::mp4_boxes(&out).into_iter().map(|(t, _)| t).collect();
+    assert_eq!(types.iter().filter(|t| t == &b"moof").count(), 3);
+    assert_eq!(&types[..4], &[*b"ftyp", *b"moov", *b"moof", *b"mdat"]);
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_ts_encoder_output() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    VideoEncoderOutputPort::write(&encoder, &PIntraPeriod::from(4)).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let mut writer = TsWriter::new(Vec::new());
+    let mut buffers = 0;
+    while buffers < 10 {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        sink.consume_view(b, |view| Ok((true, writer.write_view(view)?))).unwrap();
+        buffers += 1;
+    }
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+    let out = writer.finish().unwrap();
+
+    let packets = crate::mpegts::ts_packets(&out);
+    assert_eq!(packets[..2].iter().map(|p| p.pid).collect::<Vec<_>>(), [0, TS_PID_PMT]);
+    // parameter sets from the config buffer are repeated before each keyframe, along with PAT/PMT
+    let es: Vec<u8> = packets.iter().filter(|p| p.pid == TS_PID_VIDEO)
+        .flat_map(|p| if p.payload_unit_start { &p.payload[14..] } else { p.payload }).copied().collect();
+    let types: Vec<_> = split_annex_b(&es).map(|nal| nal.nal_type()).filter(|t| *t != NalUnitType::Aud).collect();
+    let keyframes = types.iter().filter(|t| **t == NalUnitType::IdrSlice).count();
+    assert!(keyframes >= 2);
+    assert_eq!(types.iter().filter(|t| **t == NalUnitType::Sps).count(), keyframes);
+    assert_eq!(packets.iter().filter(|p| p.pid == 0).count(), keyframes);
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_hls_encoder_output() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    VideoEncoderOutputPort::write(&encoder, &PIntraPeriod::from(4)).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("mmal-rs-hls-encoder-{}", std::process::id()));
+    // a segment per GOP
+    let mut writer = HlsWriter::create(&dir).unwrap().with_target_duration(std::time::Duration::ZERO).with_playlist_len(10);
+    for _ in 0..13 {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        sink.consume_view(b, |view| Ok((true, writer.write_view(view)?))).unwrap();
+    }
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+    writer.finish().unwrap();
+
+    let playlist = std::fs::read_to_string(dir.join(HLS_PLAYLIST)).unwrap();
+    let segments: Vec<_> = playlist.lines().filter(|l| !l.starts_with('#')).collect();
+    assert!(segments.len() >= 3);
+    assert!(segments.iter().enumerate().all(|(i, name)| *name == format!("segment{i:06}.ts")));
+    assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    for name in segments {
+        let data = std::fs::read(dir.join(name)).unwrap();
+        let packets = crate::mpegts::ts_packets(&data);
+        assert_eq!(packets[..2].iter().map(|p| p.pid).collect::<Vec<_>>(), [0, TS_PID_PMT]);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_event_recorder_encoder_output() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    VideoEncoderOutputPort::write(&encoder, &PIntraPeriod::from(4)).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let mut recorder = EventRecorder::new(CircularBuffer::new(std::time::Duration::from_secs(10)));
+    let mut recorded = None;
+    for i in 0..20 {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        let (_, r) = sink.consume_view(b, |view| Ok((true, recorder.push_view(view)?))).unwrap();
+        if i == 9 {
+            recorder.trigger(TsWriter::new(Vec::new()), RecordingStop::Frames(3)).unwrap();
+        }
+        if let Some(r) = r {
+            recorded = Some(r);
+            break;
+        }
+    }
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+    let out = recorded.expect("recording not stopped").finish().unwrap();
+
+    // the pre-event frames start at the first keyframe, with the parameter sets of the config buffer
+    let packets = crate::mpegts::ts_packets(&out);
+    assert_eq!(packets[..2].iter().map(|p| p.pid).collect::<Vec<_>>(), [0, TS_PID_PMT]);
+    let es: Vec<u8> = packets.iter().filter(|p| p.pid == TS_PID_VIDEO)
+        .flat_map(|p| if p.payload_unit_start { &p.payload[14..] } else { p.payload }).copied().collect();
+    let types: Vec<_> = split_annex_b(&es).map(|nal| nal.nal_type()).filter(|t| *t != NalUnitType::Aud).collect();
+    assert_eq!(types[..3], [NalUnitType::Sps, NalUnitType::Pps, NalUnitType::IdrSlice]);
+    assert_eq!(types.iter().filter(|t| t.is_vcl()).count(), 9 + 3);
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_motion_vectors_encoder_output() {
+    use crate::*;
+    let (camera, encoder, connection) = camera_pipeline_setup(ffi::MMAL_ENCODING_H264).unwrap();
+    VideoEncoderOutputPort::write(&encoder, &PInlineVectors::from(true)).unwrap();
+    let sink = SinkAggregate::<VideoEncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(true)).unwrap();
+
+    let mut assembler = FrameAssembler::new();
+    let mut muxer = TsMuxer::new();
+    let mut fields = Vec::new();
+    while fields.len() < 4 {
+        let b = sink.timedwait(5000).expect("no buffer from the emulated encoder");
+        let (_, frame) = sink.consume_view(b, |view| Ok((true, assembler.push(view)))).unwrap();
+        let Some(frame) = frame else { continue };
+        if frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            // the side info follows its frame
+            assert!(muxer.push_frame(&frame).is_empty());
+            fields.push(MotionField::from_frame(&frame, 320, 240).unwrap());
+        } else {
+            muxer.push_frame(&frame);
+        }
+    }
+    CameraVideoPort::write(&camera, &PCaptureVideo::from(false)).unwrap();
+
+    assert_eq!((fields[0].columns(), fields[0].rows()), (20, 15));
+    let detector = MotionDetector::new();
+    let xs: Vec<_> = fields.iter().map(|field| {
+        let detections = detector.detect(field);
+        assert_eq!(detections.len(), 1);
+        assert_eq!((detections[0].blocks, detections[0].bounds.y, detections[0].bounds.width), (4, 112, 32));
+        detections[0].bounds.x
+    }).collect();
+    assert!(xs.windows(2).all(|w| w[1] == w[0] + 16), "{xs:?}");
+    // nothing moves in the top rows
+    let detector = MotionDetector::new().with_region(MotionRegion::new(0, 0, 320, 96));
+    assert!(fields.iter().all(|field| detector.detect(field).is_empty()));
+
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+}
+
+#[test]
+fn test_raw_frame_camera_output() {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    // padded to 320x208
+    let mut vcfg = camera_port_config(300, 200);
+    vcfg.encoding = ffi::MMAL_ENCODING_I420;
+    vcfg.es_video_frame_rate_num = 100;
+    CameraVideoPort::configure(&camera, vcfg).unwrap();
+    let layout = RawFrameLayout::from_port::<CameraVideoPort>(&camera).unwrap();
+    assert_eq!((layout.width, layout.height, layout.crop_size()), (320, 208, (300, 200)));
+    assert_eq!(layout.frame_size(), 320 * 208 * 3 / 2);
+
+    let mut b = PipelineBuilder::new();
+    let camera = b.node(camera);
+    let raw = b.sink::<CameraVideoPort>(camera);
+    let pipeline = b.build().unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(true)).unwrap();
+    let raw = pipeline.sink(raw);
+    let b = raw.timedwait(5000).expect("no raw output");
+    raw.consume(b, |_, data| {
+        let frame = RawFrame::new(&layout, data)?;
+        let y = frame.plane(0).unwrap();
+        assert_eq!(y.rows().len(), 200);
+        // the diagonal gradient of the emulated camera
+        let origin = y.row(0).unwrap()[0];
+        for (r, row) in y.rows().enumerate() {
+            assert_eq!(row.len(), 300);
+            assert!(row.iter().enumerate().all(|(x, &v)| v == origin.wrapping_add((x + r) as u8)), "row {r}");
+        }
+        let u = frame.plane(1).unwrap();
+        assert_eq!((u.width(), u.height()), (150, 100));
+        assert!(u.rows().all(|row| row.iter().all(|&v| v == 128)));
+        assert_eq!(frame.to_packed().len(), 300 * 200 + 2 * 150 * 100);
+        Ok((true, ()))
+    }).unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(false)).unwrap();
+    pipeline.stop().unwrap();
+}
+
+#[test]
+fn test_convert_camera_output() {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    let mut vcfg = camera_port_config(300, 200);
+    vcfg.encoding = ffi::MMAL_ENCODING_RGB24;
+    vcfg.es_video_frame_rate_num = 100;
+    CameraVideoPort::configure(&camera, vcfg).unwrap();
+    // the emulated firmware has the RGB order fixed
+    let layout = RawFrameLayout::from_port::<CameraVideoPort>(&camera).unwrap();
+    assert_eq!(layout.encoding, ffi::MMAL_ENCODING_RGB24);
+    let converter = Converter::from_format(&CameraVideoPort::format(&camera));
+
+    let mut b = PipelineBuilder::new();
+    let camera = b.node(camera);
+    let raw = b.sink::<CameraVideoPort>(camera);
+    let pipeline = b.build().unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(true)).unwrap();
+    let raw = pipeline.sink(raw);
+    let b = raw.timedwait(5000).expect("no raw output");
+    raw.consume(b, |_, data| {
+        let frame = RawFrame::new(&layout, data)?;
+        let bgr = converter.convert(&frame, ffi::MMAL_ENCODING_BGR24)?;
+        assert_eq!(bgr.layout.crop_size(), (300, 200));
+        // the emulated camera writes x + frame, y + frame and x + y
+        let (px, bgr_px) = (&frame.plane(0).unwrap().row(7).unwrap()[5 * 3..6 * 3], &bgr.data[(7 * 300 + 5) * 3..][..3]);
+        assert_eq!(px[2], 12);
+        assert_eq!(bgr_px, [px[2], px[1], px[0]]);
+
+        let i420 = converter.convert(&frame, ffi::MMAL_ENCODING_I420)?;
+        assert_eq!(i420.data.len(), 300 * 200 * 3 / 2);
+        assert_eq!(converter.to_gray(&i420.frame()), converter.to_gray(&frame));
+        let rgb = converter.convert(&i420.frame(), ffi::MMAL_ENCODING_RGB24)?;
+        assert_eq!(rgb.data.len(), 300 * 200 * 3);
+        Ok((true, ()))
+    }).unwrap();
+
+    CameraVideoPort::write(pipeline.component(camera), &PCaptureVideo::from(false)).unwrap();
+    pipeline.stop().unwrap();
+}
+
+#[test]
+fn test_raw_capture_dng() {
+    use crate::*;
+    init().unwrap();
+    let camera = CameraComponentHandle::create().unwrap();
+    CameraCapturePort::configure(&camera, CAMERA_PORT_CONFIG_320X240).unwrap();
+    CameraCapturePort::write(&camera, &PEnableRawCapture::from(true)).unwrap();
+    let camera = ComponentEnabler::new(camera).unwrap();
+    let encoder = EncoderComponentHandle::create().unwrap();
+    EncoderOutputPort::configure(&encoder, EncoderOutFormat::default()).unwrap();
+    let encoder = ComponentEnabler::new(encoder).unwrap();
+    let connection = ConnectionHandle::<CameraCapturePort, EncoderInputPort>::create(&camera, &encoder).unwrap();
+    connection.enable().unwrap();
+    let sink = SinkAggregate::<EncoderOutputPort>::create(encoder.inner().clone()).unwrap();
+    sink.enable().unwrap();
+    sink.feed_all().unwrap();
+
+    CameraCapturePort::write(&camera, &PCapture::from(true)).unwrap();
+    let mut jpeg = Vec::new();
+    while let Some(b) = sink.timedwait(5000) {
+        let (_, is_terminal) = sink.consume(b, |flags, payload| {
+            jpeg.extend_from_slice(payload);
+            Ok((true, flags.is_terminal_frame()))
+        }).unwrap();
+        if is_terminal { break }
+    }
+    sink.disable().unwrap();
+    connection.disable().unwrap();
+
+    let raw = BayerRaw::find(&jpeg).unwrap();
+    assert_eq!(&jpeg[raw.offset - 2..raw.offset], &[0xff, 0xd9]);
+    assert_eq!((raw.sensor.as_str(), raw.width, raw.height), (EMULATED_CAMERA_NAME, 2592, 1944));
+    assert_eq!((raw.order, raw.bits, raw.stride), (BayerOrder::Grbg, 10, 3264));
+    let mut row = vec![0; 2592];
+    raw.unpack_row(10, &mut row).unwrap();
+    assert!(row.windows(2).all(|w| w[1] == (w[0] + 1) & 0x3ff));
+
+    let mut dng = Vec::new();
+    DngWriter::new().with_black_level(16).write(&raw, &mut dng).unwrap();
+    assert_eq!(&dng[..4], b"II*\0");
+    assert!(dng.len() > 2592 * 1944 * 2);
+    // the pixels end the file
+    raw.unpack_row(1943, &mut row).unwrap();
+    assert_eq!(&dng[dng.len() - 2..], &row[2591].to_le_bytes());
+}
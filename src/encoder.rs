@@ -28,6 +28,17 @@ impl EncoderComponentHandle {
 
 //------------------------------------------------------------------------------------------------------------------------------
 
+pub struct EncoderControlPort;
+impl ComponentPort for EncoderControlPort {
+    type E = EncoderEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.control_port()
+    }
+
+    fn name() -> &'static str { "encoder control port" }
+}
+
 pub struct EncoderInputPort;
 impl ComponentPort for EncoderInputPort {
     type E = EncoderEntity;
@@ -37,6 +48,8 @@ impl ComponentPort for EncoderInputPort {
     }
 
     fn name() -> &'static str { "encoder input port" }
+
+    fn accepted_encodings() -> Option<&'static [u32]> { Some(RAW_PICTURE_ENCODINGS) }
 }
 
 pub struct EncoderOutputPort;
@@ -61,22 +74,293 @@ idp!{MMAL_PARAMETER_JPEG_RESTART_INTERVAL}
 pub type JpegRestartInterval = Param<EncoderOutputPort, Uint32<MMAL_PARAMETER_JPEG_RESTART_INTERVAL>>;
 
 
+idp!{MMAL_PARAMETER_EXIF_DISABLE}
+/// Disables EXIF data in the JPEG output altogether (default false)
+pub type PExifDisable = Param<EncoderOutputPort, Boolean<MMAL_PARAMETER_EXIF_DISABLE>>;
+
+/// Maximum length of the `key=value` payload of an EXIF tag, as in raspistill
+pub const MAX_EXIF_PAYLOAD_LENGTH: usize = 128;
+
+#[repr(C)]
+struct ExifTagBuffer {
+    param: ffi::MMAL_PARAMETER_EXIF_T,
+    payload: [u8; MAX_EXIF_PAYLOAD_LENGTH],
+}
+
+pub struct ExifTagInnerType {
+    inner: Box<ExifTagBuffer>,
+}
+
+impl Default for ExifTagInnerType {
+    fn default() -> Self {
+        let mut inner: Box<ExifTagBuffer> = Box::new(unsafe { mem::zeroed() });
+        inner.param.hdr.id = ffi::MMAL_PARAMETER_EXIF;
+        inner.param.hdr.size = mem::size_of::<ffi::MMAL_PARAMETER_EXIF_T>() as u32;
+        Self { inner }
+    }
+}
+
+impl ExifTagInnerType {
+    fn data(&self) -> &[u8] {
+        let offset = mem::offset_of!(ffi::MMAL_PARAMETER_EXIF_T, data);
+        let len = mem::size_of::<ExifTagBuffer>() - offset;
+        unsafe { std::slice::from_raw_parts((&*self.inner as *const ExifTagBuffer as *const u8).add(offset), len) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        let offset = mem::offset_of!(ffi::MMAL_PARAMETER_EXIF_T, data);
+        let len = mem::size_of::<ExifTagBuffer>() - offset;
+        unsafe { std::slice::from_raw_parts_mut((&mut *self.inner as *mut ExifTagBuffer as *mut u8).add(offset), len) }
+    }
+}
+
+impl InnerParamType for ExifTagInnerType {
+    unsafe fn get_param(&mut self, port: *mut ffi::MMAL_PORT_T) -> MmalStatus {
+        ffi::mmal_port_parameter_get(port, &mut self.inner.param.hdr)
+    }
+
+    unsafe fn set_param(&self, port: *mut ffi::MMAL_PORT_T) -> MmalStatus {
+        ffi::mmal_port_parameter_set(port, &self.inner.param.hdr)
+    }
+
+    fn name() -> &'static str { "ExifTagInnerType" }
+}
+
+/// Sets the tag `key` (e.g. `IFD0.Model`, `EXIF.DateTimeOriginal` or `GPS.GPSLatitude`) to `value`.
+/// The value is truncated so that `key=value` fits `MAX_EXIF_PAYLOAD_LENGTH`.
+impl Apply<(&str, &str)> for ExifTagInnerType {
+    fn apply(&mut self, (key, value): (&str, &str)) {
+        let mut payload = format!("{key}={value}");
+        let mut n = payload.len().min(MAX_EXIF_PAYLOAD_LENGTH - 1);
+        while !payload.is_char_boundary(n) { n -= 1 }
+        payload.truncate(n);
+
+        let data = self.data_mut();
+        data.fill(0);
+        data[..payload.len()].copy_from_slice(payload.as_bytes());
+        self.inner.param.keylen = 0;
+        self.inner.param.value_offset = 0;
+        self.inner.param.valuelen = 0;
+        self.inner.param.hdr.size = (mem::size_of::<ffi::MMAL_PARAMETER_EXIF_T>() + payload.len() + 1) as u32;
+    }
+}
+
+impl From<&ExifTagInnerType> for (String, String) {
+    fn from(a: &ExifTagInnerType) -> Self {
+        let data = a.data();
+        let payload = String::from_utf8_lossy(&data[..data.iter().position(|b| *b == 0).unwrap_or(data.len())]).into_owned();
+        match payload.split_once('=') {
+            Some((key, value)) => (key.to_owned(), value.to_owned()),
+            None => (payload, String::new()),
+        }
+    }
+}
+
+/// EXIF tag added to every JPEG, e.g. `PExifTag::from(("IFD0.Model", "cam-17"))`.
+/// Write one parameter per tag; writing a tag again replaces its value.
+pub type PExifTag = Param<EncoderOutputPort, ExifTagInnerType>;
+
+/// Formats an angle in degrees as the EXIF degrees/minutes/seconds rational triple, e.g. for `GPS.GPSLatitude`.
+/// The sign is dropped, it goes into the `GPSLatitudeRef`/`GPSLongitudeRef` tags.
+pub fn exif_dms(degrees: f64) -> String {
+    let ms = (degrees.abs() * 3_600_000.).round() as u64;
+    format!("{}/1,{}/1,{}/1000", ms / 3_600_000, ms / 60_000 % 60, ms % 60_000)
+}
+
+/// Formats a UTC time the way EXIF date tags expect it, e.g. `2023:06:01 12:30:00`
+pub fn exif_datetime(t: std::time::SystemTime) -> String {
+    let secs = t.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    let s = secs % 86_400;
+    format!("{y:04}:{m:02}:{d:02} {:02}:{:02}:{:02}", s / 3_600, s / 60 % 60, s % 60)
+}
+
+/// Thumbnail embedded into the EXIF data of JPEG output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThumbnailConfig {
+    pub enable: bool,
+    pub width: u32,
+    pub height: u32,
+    /// JPEG quality factor of the thumbnail
+    pub quality: u32,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self { Self {
+        enable: true,
+        width: 64,
+        height: 48,
+        quality: 35,
+    } }
+}
+
+pub struct ThumbnailConfigInnerType {
+    inner: ffi::MMAL_PARAMETER_THUMBNAIL_CONFIG_T,
+}
+
+impl_inner_param_default!{ThumbnailConfigInnerType, MMAL_PARAMETER_THUMBNAIL_CONFIG_T, MMAL_PARAMETER_THUMBNAIL_CONFIGURATION}
+impl_inner_param_type!{ThumbnailConfigInnerType}
+
+impl Apply<&ThumbnailConfig> for ThumbnailConfigInnerType {
+    fn apply(&mut self, w: &ThumbnailConfig) {
+        self.inner.enable = bool_rust_to_mmal_u32(w.enable);
+        self.inner.width = w.width;
+        self.inner.height = w.height;
+        self.inner.quality = w.quality;
+    }
+}
+
+impl From<&ThumbnailConfigInnerType> for ThumbnailConfig {
+    fn from(a: &ThumbnailConfigInnerType) -> Self { Self {
+        enable: bool_mmal_u32_to_rust(a.inner.enable),
+        width: a.inner.width,
+        height: a.inner.height,
+        quality: a.inner.quality,
+    } }
+}
+
+/// Thumbnail configuration, set on the control port before the capture
+pub type PThumbnailConfig = Param<EncoderControlPort, ThumbnailConfigInnerType>;
+
 //------------------------------------------------------------------------------------------------------------------------------
-pub struct EncoderOutFormat {
-    encoding: u32,
+
+/// JPEG encoder settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JpegSettings {
+    /// Quality factor, 1..100
+    pub q_factor: u32,
+    /// Restart interval in MCUs, 0 to disable restart markers
+    pub restart_interval: u32,
 }
 
-impl Default for EncoderOutFormat {
-    fn default() -> Self { Self { 
-        encoding: ffi::MMAL_ENCODING_JPEG
+impl Default for JpegSettings {
+    fn default() -> Self { Self {
+        q_factor: 90,
+        restart_interval: 0,
     } }
 }
 
+/// Output encoding of the image encoder along with its settings.
+///
+/// The firmware has no tunables for the lossless encodings (e.g. PNG compression level or GIF palette),
+/// so these are plain variants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageEncoding {
+    Jpeg(JpegSettings),
+    Png,
+    Gif,
+    Bmp,
+    Tga,
+    Ppm,
+}
+
+impl ImageEncoding {
+    /// MMAL encoding FourCC
+    pub fn fourcc(&self) -> u32 {
+        match self {
+            Self::Jpeg(_) => ffi::MMAL_ENCODING_JPEG,
+            Self::Png => ffi::MMAL_ENCODING_PNG,
+            Self::Gif => ffi::MMAL_ENCODING_GIF,
+            Self::Bmp => ffi::MMAL_ENCODING_BMP,
+            Self::Tga => ffi::MMAL_ENCODING_TGA,
+            Self::Ppm => ffi::MMAL_ENCODING_PPM,
+        }
+    }
+
+    /// Usual file name extension
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg(_) => "jpg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::Bmp => "bmp",
+            Self::Tga => "tga",
+            Self::Ppm => "ppm",
+        }
+    }
+
+    /// Whether the picture is stored without loss (GIF is lossless only for palettized input)
+    pub fn is_lossless(&self) -> bool {
+        !matches!(self, Self::Jpeg(_))
+    }
+
+    /// Configures the encoder output port for this encoding and writes the encoding specific parameters.
+    /// Must be called before the encoder is enabled.
+    pub fn configure(&self, encoder: &EncoderComponentHandle) -> Result<()> {
+        EncoderOutputPort::configure(encoder, EncoderOutFormat::from(*self))?;
+        if let Self::Jpeg(settings) = self {
+            EncoderOutputPort::write(encoder, &JpegQFactor::from(settings.q_factor))?;
+            EncoderOutputPort::write(encoder, &JpegRestartInterval::from(settings.restart_interval))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ImageEncoding {
+    fn default() -> Self { Self::Jpeg(JpegSettings::default()) }
+}
+
+impl TryFrom<u32> for ImageEncoding {
+    type Error = MmalError;
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            ffi::MMAL_ENCODING_JPEG => Ok(Self::default()),
+            ffi::MMAL_ENCODING_PNG => Ok(Self::Png),
+            ffi::MMAL_ENCODING_GIF => Ok(Self::Gif),
+            ffi::MMAL_ENCODING_BMP => Ok(Self::Bmp),
+            ffi::MMAL_ENCODING_TGA => Ok(Self::Tga),
+            ffi::MMAL_ENCODING_PPM => Ok(Self::Ppm),
+            w => Err(MmalError::new(Cause::InvalidEnumValue, format!("invalid binary value {} for `ImageEncoding`", w)))
+        }
+    }
+}
+
+impl std::str::FromStr for ImageEncoding {
+    type Err = MmalError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Ok(Self::default()),
+            "png" => Ok(Self::Png),
+            "gif" => Ok(Self::Gif),
+            "bmp" => Ok(Self::Bmp),
+            "tga" => Ok(Self::Tga),
+            "ppm" => Ok(Self::Ppm),
+            w => Err(MmalError::new(Cause::InvalidEnumValue, format!("invalid string value `{}` for `ImageEncoding`", w)))
+        }
+    }
+}
+
+impl std::fmt::Display for ImageEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+#[derive(Default)]
+pub struct EncoderOutFormat {
+    pub encoding: ImageEncoding,
+}
+
+impl From<ImageEncoding> for EncoderOutFormat {
+    fn from(encoding: ImageEncoding) -> Self { Self { encoding } }
+}
+
 
 impl PortConfig for EncoderOutFormat {
     unsafe fn apply_format(&self, port: *mut ffi::MMAL_PORT_T) {
         let format = &mut (*(*port).format);
-        format.encoding = self.encoding;
+        format.encoding = self.encoding.fourcc();
     }
 
     unsafe fn apply_buffer_policy(&self, port: *mut ffi::MMAL_PORT_T) {
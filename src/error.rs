@@ -11,6 +11,10 @@ pub enum Cause {
     QueueEmpty,
     GetPort,
     InvalidEnumValue,
+    NotAvailable,
+    InvalidPipeline,
+    InvalidBitstream,
+    Io,
 }
 
 #[derive(Debug)]
@@ -25,6 +29,7 @@ impl MmalError {
     //pub(crate) fn no_status(message: String) -> Self { Self { status: None, message } }
     pub(crate) fn with_cause(cause: Cause) -> Self { Self { cause, message: "".to_owned() } }
 
+    pub fn cause(&self) -> &Cause { &self.cause }
     pub fn message(&self) -> &str { &self.message }
     pub fn status_str(&self) -> Option<Cow<'static, str>> {
         unsafe {
@@ -46,6 +51,10 @@ impl Display for MmalError {
             Cause::QueueEmpty => write!(f, "(queue empty)")?,
             Cause::GetPort => write!(f, "(get port)")?,
             Cause::InvalidEnumValue => write!(f, "(invalid enum value)")?,
+            Cause::NotAvailable => write!(f, "(MMAL not available)")?,
+            Cause::InvalidPipeline => write!(f, "(invalid pipeline)")?,
+            Cause::InvalidBitstream => write!(f, "(invalid bitstream)")?,
+            Cause::Io => write!(f, "(I/O error)")?,
         }
         if !self.message.is_empty() {
             write!(f, ": {}", self.message())?
@@ -56,6 +65,10 @@ impl Display for MmalError {
 
 impl std::error::Error for MmalError { }
 
+impl From<std::io::Error> for MmalError {
+    fn from(e: std::io::Error) -> Self { Self::new(Cause::Io, e.to_string()) }
+}
+
 type StdResult<T, E> = std::result::Result<T, E>;
 pub type Result<T> = StdResult<T, MmalError>;
 
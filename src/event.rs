@@ -0,0 +1,291 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}, pin::Pin, task::{Context, Poll, Waker}};
+use super::*;
+
+/// Port type, as reported by events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortType {
+    Control,
+    Input,
+    Output,
+    Clock,
+    Unknown(u32),
+}
+
+impl From<ffi::MMAL_PORT_TYPE_T> for PortType {
+    fn from(value: ffi::MMAL_PORT_TYPE_T) -> Self {
+        match value {
+            ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_CONTROL => Self::Control,
+            ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_INPUT => Self::Input,
+            ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_OUTPUT => Self::Output,
+            ffi::MMAL_PORT_TYPE_T_MMAL_PORT_TYPE_CLOCK => Self::Clock,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// Video part of `EsFormat`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VideoFormat {
+    pub width: u32,
+    pub height: u32,
+    pub crop_x: i32,
+    pub crop_y: i32,
+    pub crop_width: i32,
+    pub crop_height: i32,
+    pub frame_rate_num: i32,
+    pub frame_rate_den: i32,
+    pub par_num: i32,
+    pub par_den: i32,
+    pub color_space: u32,
+}
+
+/// Decoded `MMAL_ES_FORMAT_T`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EsFormat {
+    pub es_type: u32,
+    pub encoding: u32,
+    pub encoding_variant: u32,
+    pub bitrate: u32,
+    pub flags: u32,
+    /// Present for video elementary streams only
+    pub video: Option<VideoFormat>,
+    pub extradata: Vec<u8>,
+}
+
+impl EsFormat {
+    pub(crate) unsafe fn from_ffi(f: &ffi::MMAL_ES_FORMAT_T) -> Self {
+        let video = match f.es.as_ref() {
+            Some(es) if f.type_ == ffi::MMAL_ES_TYPE_T_MMAL_ES_TYPE_VIDEO => {
+                let v = &es.video;
+                Some(VideoFormat {
+                    width: v.width,
+                    height: v.height,
+                    crop_x: v.crop.x,
+                    crop_y: v.crop.y,
+                    crop_width: v.crop.width,
+                    crop_height: v.crop.height,
+                    frame_rate_num: v.frame_rate.num,
+                    frame_rate_den: v.frame_rate.den,
+                    par_num: v.par.num,
+                    par_den: v.par.den,
+                    color_space: v.color_space,
+                })
+            }
+            _ => None
+        };
+        let extradata = if f.extradata.is_null() || f.extradata_size == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(f.extradata, f.extradata_size as usize).to_vec()
+        };
+        Self {
+            es_type: f.type_,
+            encoding: f.encoding,
+            encoding_variant: f.encoding_variant,
+            bitrate: f.bitrate,
+            flags: f.flags,
+            video,
+            extradata,
+        }
+    }
+}
+
+/// Payload of `MMAL_EVENT_FORMAT_CHANGED`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatChange {
+    pub buffer_num_min: u32,
+    pub buffer_size_min: u32,
+    pub buffer_num_recommended: u32,
+    pub buffer_size_recommended: u32,
+    pub format: EsFormat,
+}
+
+/// Sensor settings reported by the camera, see `PChangeEventRequest`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CameraSettings {
+    /// Exposure time, microseconds
+    pub exposure: u32,
+    pub analog_gain: f64,
+    pub digital_gain: f64,
+    pub awb_red_gain: f64,
+    pub awb_blue_gain: f64,
+    pub focus_position: u32,
+}
+
+fn rational_to_f64(r: ffi::MMAL_RATIONAL_T) -> f64 {
+    if r.den == 0 { 0.0 } else { r.num as f64 / r.den as f64 }
+}
+
+impl From<&ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T> for CameraSettings {
+    fn from(value: &ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T) -> Self {
+        Self {
+            exposure: value.exposure,
+            analog_gain: rational_to_f64(value.analog_gain),
+            digital_gain: rational_to_f64(value.digital_gain),
+            awb_red_gain: rational_to_f64(value.awb_red_gain),
+            awb_blue_gain: rational_to_f64(value.awb_blue_gain),
+            focus_position: value.focus_position,
+        }
+    }
+}
+
+/// Event received on a component's control port
+#[derive(Debug, Clone)]
+pub enum ComponentEvent {
+    /// `MMAL_EVENT_ERROR`; the status is `None` if the event carried none
+    Error(Option<MmalStatus>),
+    /// `MMAL_EVENT_EOS`
+    EndOfStream { port_type: PortType, port_index: u32 },
+    /// `MMAL_EVENT_FORMAT_CHANGED`
+    FormatChanged(FormatChange),
+    /// `MMAL_EVENT_PARAMETER_CHANGED` for `MMAL_PARAMETER_CAMERA_SETTINGS`
+    CameraSettings(CameraSettings),
+    /// `MMAL_EVENT_PARAMETER_CHANGED` for other parameters. `data` is the parameter struct, including its header.
+    ParameterChanged { id: u32, data: Vec<u8> },
+    /// Any other event
+    Other { cmd: u32, data: Vec<u8> },
+}
+
+unsafe fn read_struct<T: Copy>(data: &[u8]) -> Option<T> {
+    if data.len() < mem::size_of::<T>() {
+        None
+    } else {
+        Some(std::ptr::read_unaligned(data.as_ptr() as *const T))
+    }
+}
+
+impl ComponentEvent {
+    /// Decodes an event buffer received on a control port
+    pub(crate) unsafe fn from_buffer(b: *mut ffi::MMAL_BUFFER_HEADER_T) -> Self {
+        let h = &*b;
+        let data = if h.data.is_null() {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(h.data.add(h.offset as usize), h.length as usize)
+        };
+        match h.cmd {
+            ffi::MMAL_EVENT_ERROR => Self::Error(read_struct::<MmalStatus>(data)),
+            ffi::MMAL_EVENT_EOS => match read_struct::<ffi::MMAL_EVENT_END_OF_STREAM_T>(data) {
+                Some(eos) => Self::EndOfStream { port_type: eos.port_type.into(), port_index: eos.port_index },
+                None => Self::Other { cmd: h.cmd, data: data.to_vec() },
+            }
+            ffi::MMAL_EVENT_FORMAT_CHANGED => match ffi::mmal_event_format_changed_get(b).as_ref() {
+                Some(fc) if !fc.format.is_null() => Self::FormatChanged(FormatChange {
+                    buffer_num_min: fc.buffer_num_min,
+                    buffer_size_min: fc.buffer_size_min,
+                    buffer_num_recommended: fc.buffer_num_recommended,
+                    buffer_size_recommended: fc.buffer_size_recommended,
+                    format: EsFormat::from_ffi(&*fc.format),
+                }),
+                _ => Self::Other { cmd: h.cmd, data: data.to_vec() },
+            }
+            ffi::MMAL_EVENT_PARAMETER_CHANGED => match read_struct::<ffi::MMAL_PARAMETER_HEADER_T>(data) {
+                Some(hdr) if hdr.id == ffi::MMAL_PARAMETER_CAMERA_SETTINGS =>
+                    match read_struct::<ffi::MMAL_PARAMETER_CAMERA_SETTINGS_T>(data) {
+                        Some(cs) => Self::CameraSettings((&cs).into()),
+                        None => Self::ParameterChanged { id: hdr.id, data: data.to_vec() },
+                    }
+                Some(hdr) => Self::ParameterChanged { id: hdr.id, data: data.to_vec() },
+                None => Self::Other { cmd: h.cmd, data: data.to_vec() },
+            }
+            cmd => Self::Other { cmd, data: data.to_vec() },
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+type EventCallback = Box<dyn FnMut(ComponentEvent) + Send>;
+
+/// Keeps the control port of a component enabled, passing its events to a callback. The port is disabled on drop.
+pub struct EventSubscription<E: ComponentEntity> {
+    c: ComponentHandle<E>,
+    _f: Box<EventCallback>,
+}
+
+impl<E: ComponentEntity> EventSubscription<E> {
+    fn create(c: ComponentHandle<E>, f: EventCallback) -> Result<Self> {
+        let mut f = Box::new(f);
+        unsafe {
+            let port = c.control_port();
+            (*port).userdata = f.as_mut() as *mut EventCallback as *mut ffi::MMAL_PORT_USERDATA_T;
+            let status = ffi::mmal_port_enable(port, Some(Self::cb));
+            if let Err(e) = cst!(status, "{}: unable to enable control port", E::name()) {
+                (*port).userdata = std::ptr::null_mut();
+                return Err(e);
+            }
+        }
+        Ok(Self { c, _f: f })
+    }
+
+    unsafe extern "C" fn cb(port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+        if let Some(f) = ((*port).userdata as *mut EventCallback).as_mut() {
+            f(ComponentEvent::from_buffer(buffer));
+        }
+        ffi::mmal_buffer_header_release(buffer);
+    }
+}
+
+impl<E: ComponentEntity> Drop for EventSubscription<E> {
+    fn drop(&mut self) {
+        unsafe {
+            let port = self.c.control_port();
+            log_deinit!(cst!(ffi::mmal_port_disable(port), "{}: unable to disable control port", E::name()));
+            (*port).userdata = std::ptr::null_mut();
+        }
+    }
+}
+
+#[derive(Default)]
+struct EventQueue {
+    events: VecDeque<ComponentEvent>,
+    w: Option<Waker>,
+}
+
+/// `futures_core::Stream` of control port events. The control port stays enabled as long as the stream lives.
+pub struct EventStream<E: ComponentEntity> {
+    _s: EventSubscription<E>,
+    q: Arc<Mutex<EventQueue>>,
+}
+
+impl<E: ComponentEntity> EventStream<E> {
+    /// Takes the next event without waiting
+    pub fn try_next(&self) -> Option<ComponentEvent> {
+        self.q.lock().unwrap_or_else(|e| e.into_inner()).events.pop_front()
+    }
+}
+
+impl<E: ComponentEntity> futures_core::Stream for EventStream<E> {
+    type Item = ComponentEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut q = self.q.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(event) = q.events.pop_front() {
+            Poll::Ready(Some(event))
+        } else {
+            q.w = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<E: ComponentEntity> ComponentEnabler<E> {
+    /// Enables the control port and passes its events to `f`, which is called on an MMAL thread
+    pub fn subscribe(&self, f: impl FnMut(ComponentEvent) + Send + 'static) -> Result<EventSubscription<E>> {
+        EventSubscription::create(self.inner().clone(), Box::new(f))
+    }
+
+    /// Enables the control port and queues its events for the returned stream
+    pub fn events(&self) -> Result<EventStream<E>> {
+        let q = Arc::new(Mutex::new(EventQueue::default()));
+        let qc = q.clone();
+        let s = self.subscribe(move |event| {
+            let mut q = qc.lock().unwrap_or_else(|e| e.into_inner());
+            q.events.push_back(event);
+            if let Some(w) = q.w.take() {
+                w.wake()
+            }
+        })?;
+        Ok(EventStream { _s: s, q })
+    }
+}
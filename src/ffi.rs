@@ -276,14 +276,17 @@ pub const MMAL_EVENT_FORMAT_CHANGED: c_uint = mmal_fourcc!('E', 'F', 'C', 'H');
 /// \ref MMAL_EVENT_PARAMETER_CHANGED_T
 pub const MMAL_EVENT_PARAMETER_CHANGED: c_uint = mmal_fourcc!('E', 'P', 'C', 'H');
 
+/// Timestamp value used when the pts or dts of a buffer is unknown
+pub const MMAL_TIME_UNKNOWN: i64 = i64::MIN;
+
 /// Note that there appears to be no constant for the null sink but it does exist in the
 /// binaries.
 /// If this ever breaks because C has this then we can delete this one.
 pub const MMAL_COMPONENT_NULL_SINK: &'static [u8; 13usize] = b"vc.null_sink\0";
 
 
-#[cfg(feature = "generate_bindings")]
+#[cfg(any(feature = "generate_bindings", feature = "emulation", feature = "dynamic-loading"))]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
-#[cfg(not(feature = "generate_bindings"))]
+#[cfg(not(any(feature = "generate_bindings", feature = "emulation", feature = "dynamic-loading")))]
 include!("bindings.rs");
@@ -0,0 +1,615 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// NAL unit type, ITU-T H.264 table 7-1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NalUnitType {
+    NonIdrSlice,
+    PartitionA,
+    PartitionB,
+    PartitionC,
+    IdrSlice,
+    Sei,
+    Sps,
+    Pps,
+    Aud,
+    EndOfSequence,
+    EndOfStream,
+    Filler,
+    Other(u8),
+}
+
+impl From<u8> for NalUnitType {
+    fn from(value: u8) -> Self {
+        match value & 0x1f {
+            1 => Self::NonIdrSlice,
+            2 => Self::PartitionA,
+            3 => Self::PartitionB,
+            4 => Self::PartitionC,
+            5 => Self::IdrSlice,
+            6 => Self::Sei,
+            7 => Self::Sps,
+            8 => Self::Pps,
+            9 => Self::Aud,
+            10 => Self::EndOfSequence,
+            11 => Self::EndOfStream,
+            12 => Self::Filler,
+            w => Self::Other(w),
+        }
+    }
+}
+
+impl From<NalUnitType> for u8 {
+    fn from(value: NalUnitType) -> Self {
+        match value {
+            NalUnitType::NonIdrSlice => 1,
+            NalUnitType::PartitionA => 2,
+            NalUnitType::PartitionB => 3,
+            NalUnitType::PartitionC => 4,
+            NalUnitType::IdrSlice => 5,
+            NalUnitType::Sei => 6,
+            NalUnitType::Sps => 7,
+            NalUnitType::Pps => 8,
+            NalUnitType::Aud => 9,
+            NalUnitType::EndOfSequence => 10,
+            NalUnitType::EndOfStream => 11,
+            NalUnitType::Filler => 12,
+            NalUnitType::Other(w) => w,
+        }
+    }
+}
+
+impl NalUnitType {
+    /// Whether the NAL unit carries coded picture data
+    pub fn is_vcl(&self) -> bool {
+        matches!(self, Self::NonIdrSlice | Self::PartitionA | Self::PartitionB | Self::PartitionC | Self::IdrSlice)
+    }
+}
+
+/// NAL unit without the start code. `data` starts with the NAL header byte and still contains emulation prevention bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NalUnit<'a> {
+    pub data: &'a [u8],
+}
+
+impl<'a> NalUnit<'a> {
+    /// Wraps `data`, which must hold at least the header byte
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if data.is_empty() { None } else { Some(Self { data }) }
+    }
+
+    pub fn nal_type(&self) -> NalUnitType { self.data[0].into() }
+    pub fn nal_ref_idc(&self) -> u8 { (self.data[0] >> 5) & 3 }
+    pub fn is_keyframe(&self) -> bool { self.nal_type() == NalUnitType::IdrSlice }
+
+    /// Payload with emulation prevention bytes removed
+    pub fn rbsp(&self) -> Vec<u8> { unescape_rbsp(&self.data[1..]) }
+}
+
+/// Removes emulation prevention bytes (`00 00 03` → `00 00`)
+pub fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for b in data {
+        if zeros >= 2 && *b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if *b == 0 { zeros + 1 } else { 0 };
+        out.push(*b);
+    }
+    out
+}
+
+/// Position and length of the next start code in `data`, and the number of zero bytes before it
+fn find_start_code(data: &[u8]) -> Option<(usize, usize)> {
+    let mut i = 2;
+    while i < data.len() {
+        if data[i] > 1 {
+            i += 3;
+        } else if data[i] == 1 && data[i - 1] == 0 && data[i - 2] == 0 {
+            let mut start = i - 2;
+            while start > 0 && data[start - 1] == 0 { start -= 1 }
+            return Some((start, i + 1 - start));
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Drops `trailing_zero_8bits`
+fn trim_trailing_zeros(data: &[u8]) -> &[u8] {
+    let n = data.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+    &data[..n]
+}
+
+/// Iterator over the NAL units of a complete Annex-B byte stream, see `split_annex_b`
+pub struct AnnexBIter<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for AnnexBIter<'a> {
+    type Item = NalUnit<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (start, len) = find_start_code(self.rest)?;
+            let body = &self.rest[start + len..];
+            let (nal, rest) = match find_start_code(body) {
+                Some((next, _)) => (&body[..next], &body[next..]),
+                None => (trim_trailing_zeros(body), &body[body.len()..]),
+            };
+            self.rest = rest;
+            if let Some(nal) = NalUnit::new(nal) {
+                return Some(nal);
+            }
+        }
+    }
+}
+
+/// Splits a complete Annex-B byte stream, e.g. a whole encoder frame, into NAL units. Bytes before the first start code
+/// are skipped.
+pub fn split_annex_b(data: &[u8]) -> AnnexBIter<'_> {
+    AnnexBIter { rest: data }
+}
+
+/// Splits an Annex-B stream arriving in arbitrary chunks, e.g. `SinkAggregate` buffers, into NAL units
+#[derive(Default)]
+pub struct AnnexBReader {
+    buf: Vec<u8>,
+}
+
+impl AnnexBReader {
+    pub fn new() -> Self { Self::default() }
+
+    /// Appends `data` and returns the NAL units it completes. A NAL unit is complete once the next start code is seen.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+        let mut rv = Vec::new();
+        let mut consumed = 0;
+        if let Some((first, len)) = find_start_code(&self.buf) {
+            let mut start = first + len;
+            while let Some((next, len)) = find_start_code(&self.buf[start..]) {
+                if next > 0 {
+                    rv.push(self.buf[start..start + next].to_vec());
+                }
+                consumed = start + next;
+                start += next + len;
+            }
+        } else {
+            // keep a possible start code prefix split between chunks
+            consumed = self.buf.len().saturating_sub(3);
+        }
+        self.buf.drain(..consumed);
+        rv
+    }
+
+    /// Returns the last NAL unit, e.g. at the end of an access unit (`MMAL_BUFFER_HEADER_FLAG_FRAME_END`)
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        let rv = split_annex_b(&self.buf).next().map(|nal| nal.data.to_vec());
+        self.buf.clear();
+        rv
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+fn bitstream_error(what: &str) -> MmalError {
+    MmalError::new(Cause::InvalidBitstream, format!("h264: {what}"))
+}
+
+/// Exp-Golomb codes go up to 2^32 - 2, so arithmetic on them is checked
+fn checked(v: Option<u32>, what: &str) -> Result<u32> {
+    v.ok_or_else(|| bitstream_error(&format!("{what} out of range")))
+}
+
+/// Checks that a syntax element is in the range the standard allows
+fn in_range<T: PartialOrd>(v: T, range: std::ops::RangeInclusive<T>, what: &str) -> Result<T> {
+    if range.contains(&v) { Ok(v) } else { Err(bitstream_error(&format!("{what} out of range"))) }
+}
+
+/// MSB-first reader of RBSP data with Exp-Golomb support
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self { Self { data, pos: 0 } }
+
+    pub fn bit(&mut self) -> Result<u32> {
+        let byte = self.data.get(self.pos / 8).ok_or_else(|| bitstream_error("unexpected end of data"))?;
+        let rv = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(rv as u32)
+    }
+
+    pub fn flag(&mut self) -> Result<bool> { Ok(self.bit()? != 0) }
+
+    /// Reads `n` <= 32 bits
+    pub fn bits(&mut self, n: u32) -> Result<u32> {
+        (0..n).try_fold(0u32, |a, _| Ok((a << 1) | self.bit()?))
+    }
+
+    pub fn skip(&mut self, n: usize) { self.pos += n }
+
+    /// Unsigned Exp-Golomb code
+    pub fn ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return Err(bitstream_error("invalid Exp-Golomb code"));
+            }
+        }
+        Ok(((1u64 << zeros) - 1 + self.bits(zeros)? as u64) as u32)
+    }
+
+    /// Signed Exp-Golomb code
+    pub fn se(&mut self) -> Result<i32> {
+        let v = self.ue()? as i64;
+        Ok(if v & 1 != 0 { (v + 1) / 2 } else { -v / 2 } as i32)
+    }
+
+    /// Whether there is data before the `rbsp_trailing_bits`
+    pub fn more_rbsp_data(&self) -> bool {
+        let last = match self.data.iter().rposition(|b| *b != 0) { Some(p) => p, None => return false };
+        let stop_bit = last * 8 + 7 - self.data[last].trailing_zeros() as usize;
+        self.pos < stop_bit
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// VUI timing information, present when the encoder runs with `PSpsTiming`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VuiTiming {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate: bool,
+}
+
+impl VuiTiming {
+    /// Frame rate, two ticks per frame
+    pub fn frame_rate(&self) -> Option<f64> {
+        if self.num_units_in_tick == 0 { None } else { Some(self.time_scale as f64 / (2. * self.num_units_in_tick as f64)) }
+    }
+}
+
+/// Sequence parameter set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    /// constraint_set0_flag..constraint_set5_flag and the reserved bits, as in the `avcC` box
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub sps_id: u32,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub log2_max_frame_num: u32,
+    pub pic_order_cnt_type: u32,
+    pub log2_max_pic_order_cnt_lsb: u32,
+    pub max_num_ref_frames: u32,
+    pub frame_mbs_only: bool,
+    /// Picture size after cropping
+    pub width: u32,
+    pub height: u32,
+    /// Sample aspect ratio, (1, 1) unless signalled
+    pub sar: (u32, u32),
+    pub timing: Option<VuiTiming>,
+}
+
+/// Sample aspect ratios of aspect_ratio_idc 1..16, table E-1
+const SAR_TABLE: [(u32, u32); 16] = [
+    (1, 1), (12, 11), (10, 11), (16, 11), (40, 33), (24, 11), (20, 11), (32, 11),
+    (80, 33), (18, 11), (15, 11), (64, 33), (160, 99), (4, 3), (3, 2), (2, 1),
+];
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Result<()> {
+    let (mut last, mut next) = (8i32, 8i32);
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + in_range(r.se()?, -128..=127, "delta_scale")? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Ok(())
+}
+
+impl Sps {
+    /// Parses an SPS NAL unit, header byte included
+    pub fn parse(nal: &[u8]) -> Result<Self> {
+        let nal = NalUnit::new(nal).ok_or_else(|| bitstream_error("empty SPS"))?;
+        if nal.nal_type() != NalUnitType::Sps {
+            return Err(bitstream_error("not an SPS"));
+        }
+        let rbsp = nal.rbsp();
+        let mut r = BitReader::new(&rbsp);
+        let profile_idc = r.bits(8)? as u8;
+        let constraint_flags = r.bits(8)? as u8;
+        let level_idc = r.bits(8)? as u8;
+        let sps_id = r.ue()?;
+
+        let (mut chroma_format_idc, mut separate_colour_plane, mut bit_depth_luma, mut bit_depth_chroma) = (1, false, 8, 8);
+        if matches!(profile_idc, 100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135) {
+            chroma_format_idc = in_range(r.ue()?, 0..=3, "chroma_format_idc")?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = r.flag()?;
+            }
+            bit_depth_luma = in_range(r.ue()?, 0..=6, "bit_depth_luma")? + 8;
+            bit_depth_chroma = in_range(r.ue()?, 0..=6, "bit_depth_chroma")? + 8;
+            r.skip(1); // qpprime_y_zero_transform_bypass_flag
+            if r.flag()? {
+                for i in 0..if chroma_format_idc == 3 { 12 } else { 8 } {
+                    if r.flag()? {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        let log2_max_frame_num = in_range(r.ue()?, 0..=12, "log2_max_frame_num")? + 4;
+        let pic_order_cnt_type = r.ue()?;
+        let mut log2_max_pic_order_cnt_lsb = 0;
+        match pic_order_cnt_type {
+            0 => log2_max_pic_order_cnt_lsb = in_range(r.ue()?, 0..=12, "log2_max_pic_order_cnt_lsb")? + 4,
+            1 => {
+                r.skip(1); // delta_pic_order_always_zero_flag
+                r.se()?; // offset_for_non_ref_pic
+                r.se()?; // offset_for_top_to_bottom_field
+                for _ in 0..r.ue()? {
+                    r.se()?;
+                }
+            }
+            _ => { }
+        }
+        let max_num_ref_frames = r.ue()?;
+        r.skip(1); // gaps_in_frame_num_value_allowed_flag
+        let width_mbs = checked(r.ue()?.checked_add(1), "pic_width_in_mbs")?;
+        let height_map_units = checked(r.ue()?.checked_add(1), "pic_height_in_map_units")?;
+        let frame_mbs_only = r.flag()?;
+        if !frame_mbs_only {
+            r.skip(1); // mb_adaptive_frame_field_flag
+        }
+        r.skip(1); // direct_8x8_inference_flag
+
+        let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+        let (sub_width, sub_height) = match chroma_array_type {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let (crop_unit_x, crop_unit_y) = if chroma_array_type == 0 { (1, 1) } else { (sub_width, sub_height) };
+        let crop_unit_y = crop_unit_y * if frame_mbs_only { 1 } else { 2 };
+        let mut width = checked(width_mbs.checked_mul(16), "width")?;
+        let mut height = checked(height_map_units.checked_mul(if frame_mbs_only { 16 } else { 32 }), "height")?;
+        if r.flag()? {
+            let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+            let crop_x = left.checked_add(right).and_then(|v| v.checked_mul(crop_unit_x));
+            let crop_y = top.checked_add(bottom).and_then(|v| v.checked_mul(crop_unit_y));
+            width = crop_x.and_then(|v| width.checked_sub(v)).ok_or_else(|| bitstream_error("invalid crop"))?;
+            height = crop_y.and_then(|v| height.checked_sub(v)).ok_or_else(|| bitstream_error("invalid crop"))?;
+        }
+
+        let mut sar = (1, 1);
+        let mut timing = None;
+        if r.flag()? {
+            if r.flag()? {
+                sar = match r.bits(8)? {
+                    255 => (r.bits(16)?, r.bits(16)?),
+                    idc @ 1..=16 => SAR_TABLE[idc as usize - 1],
+                    _ => (1, 1),
+                };
+            }
+            if r.flag()? {
+                r.skip(1); // overscan_appropriate_flag
+            }
+            if r.flag()? {
+                r.skip(4); // video_format, video_full_range_flag
+                if r.flag()? {
+                    r.skip(24); // colour_primaries, transfer_characteristics, matrix_coefficients
+                }
+            }
+            if r.flag()? {
+                r.ue()?;
+                r.ue()?;
+            }
+            if r.flag()? {
+                timing = Some(VuiTiming { num_units_in_tick: r.bits(32)?, time_scale: r.bits(32)?, fixed_frame_rate: r.flag()? });
+            }
+        }
+
+        Ok(Self {
+            profile_idc, constraint_flags, level_idc, sps_id, chroma_format_idc, bit_depth_luma, bit_depth_chroma,
+            log2_max_frame_num, pic_order_cnt_type, log2_max_pic_order_cnt_lsb, max_num_ref_frames, frame_mbs_only,
+            width, height, sar, timing,
+        })
+    }
+}
+
+/// Picture parameter set, up to the fields every profile has
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pps {
+    pub pps_id: u32,
+    pub sps_id: u32,
+    /// CABAC rather than CAVLC
+    pub entropy_coding_mode: bool,
+    pub bottom_field_pic_order_in_frame_present: bool,
+    pub num_slice_groups: u32,
+    pub num_ref_idx_l0_default_active: u32,
+    pub num_ref_idx_l1_default_active: u32,
+    pub weighted_pred: bool,
+    pub weighted_bipred_idc: u32,
+    pub pic_init_qp: i32,
+    pub pic_init_qs: i32,
+    pub chroma_qp_index_offset: i32,
+    pub deblocking_filter_control_present: bool,
+    pub constrained_intra_pred: bool,
+    pub redundant_pic_cnt_present: bool,
+    /// High profile extension
+    pub transform_8x8_mode: bool,
+}
+
+impl Pps {
+    /// Parses a PPS NAL unit, header byte included
+    pub fn parse(nal: &[u8]) -> Result<Self> {
+        let nal = NalUnit::new(nal).ok_or_else(|| bitstream_error("empty PPS"))?;
+        if nal.nal_type() != NalUnitType::Pps {
+            return Err(bitstream_error("not a PPS"));
+        }
+        let rbsp = nal.rbsp();
+        let mut r = BitReader::new(&rbsp);
+        let pps_id = r.ue()?;
+        let sps_id = r.ue()?;
+        let entropy_coding_mode = r.flag()?;
+        let bottom_field_pic_order_in_frame_present = r.flag()?;
+        let num_slice_groups = in_range(r.ue()?, 0..=7, "num_slice_groups")? + 1;
+        if num_slice_groups > 1 {
+            match r.ue()? {
+                0 => for _ in 0..num_slice_groups {
+                    r.ue()?; // run_length_minus1
+                },
+                2 => for _ in 1..num_slice_groups {
+                    r.ue()?; // top_left
+                    r.ue()?; // bottom_right
+                },
+                3..=5 => {
+                    r.skip(1); // slice_group_change_direction_flag
+                    r.ue()?; // slice_group_change_rate_minus1
+                }
+                6 => {
+                    let bits = 32 - (num_slice_groups - 1).leading_zeros();
+                    for _ in 0..=r.ue()? {
+                        r.bits(bits)?;
+                    }
+                }
+                _ => { }
+            }
+        }
+        let num_ref_idx_l0_default_active = in_range(r.ue()?, 0..=31, "num_ref_idx_l0_default_active")? + 1;
+        let num_ref_idx_l1_default_active = in_range(r.ue()?, 0..=31, "num_ref_idx_l1_default_active")? + 1;
+        let weighted_pred = r.flag()?;
+        let weighted_bipred_idc = r.bits(2)?;
+        let pic_init_qp = in_range(r.se()?, -26..=25, "pic_init_qp")? + 26;
+        let pic_init_qs = in_range(r.se()?, -26..=25, "pic_init_qs")? + 26;
+        let chroma_qp_index_offset = in_range(r.se()?, -12..=12, "chroma_qp_index_offset")?;
+        let deblocking_filter_control_present = r.flag()?;
+        let constrained_intra_pred = r.flag()?;
+        let redundant_pic_cnt_present = r.flag()?;
+        let transform_8x8_mode = r.more_rbsp_data() && r.flag()?;
+
+        Ok(Self {
+            pps_id, sps_id, entropy_coding_mode, bottom_field_pic_order_in_frame_present, num_slice_groups,
+            num_ref_idx_l0_default_active, num_ref_idx_l1_default_active, weighted_pred, weighted_bipred_idc,
+            pic_init_qp, pic_init_qs, chroma_qp_index_offset, deblocking_filter_control_present, constrained_intra_pred,
+            redundant_pic_cnt_present, transform_8x8_mode,
+        })
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) const SAMPLE_HIGH_1080P: &[u8] = include_bytes!("../testdata/h264/high_1080p25_vui.h264");
+#[cfg(test)]
+pub(crate) const SAMPLE_BASELINE_240P: &[u8] = include_bytes!("../testdata/h264/baseline_320x240.h264");
+
+#[test]
+fn test_split_annex_b() {
+    use NalUnitType::*;
+    let types: Vec<_> = split_annex_b(SAMPLE_HIGH_1080P).map(|nal| nal.nal_type()).collect();
+    assert_eq!(types, [Aud, Sps, Pps, Sei, IdrSlice, Aud, NonIdrSlice, Aud, NonIdrSlice, Aud, NonIdrSlice]);
+
+    let nals: Vec<_> = split_annex_b(SAMPLE_BASELINE_240P).collect();
+    assert_eq!(nals.len(), 7);
+    assert!(nals[2].is_keyframe() && !nals[3].is_keyframe());
+    assert_eq!((nals[2].nal_ref_idc(), nals[3].nal_ref_idc()), (3, 2));
+    // trailing_zero_8bits are not part of the NAL units
+    assert!(nals.iter().all(|nal| *nal.data.last().unwrap() != 0));
+
+    // emulation prevention bytes are kept in the NAL unit and removed from the RBSP
+    let idr = split_annex_b(SAMPLE_HIGH_1080P).find(|nal| nal.is_keyframe()).unwrap();
+    assert!(idr.data.windows(3).any(|w| w == [0, 0, 3]));
+    assert!(idr.rbsp().ends_with(&[0, 0, 0, 1, 0, 0, 2, 0xaa, 0, 0, 3]));
+}
+
+#[test]
+fn test_annex_b_reader() {
+    let expected: Vec<_> = split_annex_b(SAMPLE_HIGH_1080P).map(|nal| nal.data.to_vec()).collect();
+    for chunk in [1, 2, 3, 5, 64, SAMPLE_HIGH_1080P.len()] {
+        let mut reader = AnnexBReader::new();
+        let mut nals: Vec<_> = SAMPLE_HIGH_1080P.chunks(chunk).flat_map(|c| reader.push(c)).collect();
+        nals.extend(reader.flush());
+        assert_eq!(nals, expected, "chunk size {chunk}");
+    }
+}
+
+#[test]
+fn test_parse_sps_pps() {
+    let mut nals = split_annex_b(SAMPLE_HIGH_1080P).filter(|nal| matches!(nal.nal_type(), NalUnitType::Sps | NalUnitType::Pps));
+    let sps = Sps::parse(nals.next().unwrap().data).unwrap();
+    assert_eq!((sps.profile_idc, sps.constraint_flags, sps.level_idc), (100, 0, 40));
+    assert_eq!((sps.width, sps.height), (1920, 1080));
+    assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma), (1, 8));
+    assert_eq!((sps.pic_order_cnt_type, sps.log2_max_pic_order_cnt_lsb, sps.max_num_ref_frames), (0, 6, 4));
+    assert_eq!(sps.sar, (4, 3));
+    let timing = sps.timing.unwrap();
+    assert!(timing.fixed_frame_rate);
+    assert_eq!(timing.frame_rate(), Some(25.));
+
+    let pps = Pps::parse(nals.next().unwrap().data).unwrap();
+    assert!(pps.entropy_coding_mode && pps.transform_8x8_mode && pps.deblocking_filter_control_present);
+    assert_eq!((pps.num_ref_idx_l0_default_active, pps.pic_init_qp, pps.chroma_qp_index_offset), (3, 23, 2));
+
+    let mut nals = split_annex_b(SAMPLE_BASELINE_240P);
+    let sps = Sps::parse(nals.next().unwrap().data).unwrap();
+    assert_eq!((sps.profile_idc, sps.constraint_flags, sps.level_idc, sps.sps_id), (66, 0xc0, 13, 1));
+    assert_eq!((sps.width, sps.height, sps.pic_order_cnt_type, sps.log2_max_frame_num), (320, 240, 2, 4));
+    assert_eq!(sps.timing, None);
+    let pps = Pps::parse(nals.next().unwrap().data).unwrap();
+    assert_eq!((pps.pps_id, pps.sps_id, pps.entropy_coding_mode, pps.transform_8x8_mode), (1, 1, false, false));
+
+    assert!(Sps::parse(&[0x68, 0xce]).is_err());
+    assert!(Sps::parse(&[0x67, 0x64]).is_err());
+}
+
+#[test]
+fn test_parse_malformed_sps_pps() {
+    let ue = |v: u64| { let code = format!("{:b}", v + 1); format!("{}{code}", "0".repeat(code.len() - 1)) };
+    let se = |v: i64| ue(if v > 0 { 2 * v as u64 - 1 } else { (-2 * v) as u64 });
+    // NAL header, then the fields as bit strings and the rbsp_stop_one_bit
+    let nal = |header: u8, fields: &[&str]| {
+        let bits = fields.concat() + "1";
+        let mut nal = vec![header];
+        nal.extend(bits.as_bytes().chunks(8).map(|b| u8::from_str_radix(&format!("{:0<8}", std::str::from_utf8(b).unwrap()), 2).unwrap()));
+        nal
+    };
+    let invalid = |r: Result<()>| matches!(r.unwrap_err().cause(), Cause::InvalidBitstream);
+    let sps = |profile: &str, fields: &[&str]| Sps::parse(&nal(0x67, &[&[profile, "00000000", "00011110", &ue(0)], fields].concat())).map(|_| ());
+    let baseline = |log2_max_frame_num: u64, width_mbs: u64, crop: u64| {
+        let fields = [ue(log2_max_frame_num), ue(2), ue(1), "0".into(), ue(width_mbs), ue(0), "11".into(), "1".into(), ue(0), ue(crop), ue(0), ue(0)];
+        sps("01000010", &fields.iter().map(String::as_str).collect::<Vec<_>>())
+    };
+    assert!(baseline(0, 19, 0).is_ok());
+    // 31 leading zeros
+    assert!(invalid(baseline((1 << 32) - 2, 19, 0)));
+    assert!(invalid(baseline(13, 19, 0)));
+    assert!(invalid(baseline(0, (1 << 32) - 2, 0)));
+    assert!(invalid(baseline(0, (1 << 28) - 1, 0)));
+    assert!(invalid(baseline(0, 19, (1 << 32) - 2)));
+    let high = |bit_depth: u64| sps("01100100", &[&ue(1), &ue(bit_depth), &ue(0), "00", &ue(0), &ue(2), &ue(1), "0", &ue(0), &ue(0), "110"]);
+    assert!(high(6).is_ok());
+    assert!(invalid(high(7)));
+    assert!(invalid(high((1 << 32) - 2)));
+
+    let pps = |qp: i64| Pps::parse(&nal(0x68, &[&ue(0), &ue(0), "00", &ue(0), &ue(0), &ue(0), "000", &se(qp), &se(0), &se(0), "000"])).map(|_| ());
+    assert!(pps(25).is_ok() && pps(-26).is_ok());
+    assert!(invalid(pps(26)));
+    assert!(invalid(pps(-27)));
+    assert!(invalid(pps(-(1 << 31))));
+}
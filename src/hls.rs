@@ -0,0 +1,381 @@
+use super::*;
+use std::{collections::VecDeque, fs, io::{BufWriter, Write}, path::{Path, PathBuf}, time::Duration};
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Name of the media playlist written by `HlsWriter`
+pub const HLS_PLAYLIST: &str = "index.m3u8";
+/// Name of the fMP4 init segment, referenced by `EXT-X-MAP`
+pub const HLS_INIT_SEGMENT: &str = "init.mp4";
+
+/// Container of the HLS media segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HlsSegmentFormat {
+    /// MPEG-TS segments (`.ts`), supported by every HLS client
+    #[default]
+    MpegTs,
+    /// Fragmented MP4 segments (`.m4s`) sharing the init segment `init.mp4`
+    Fmp4,
+}
+
+impl HlsSegmentFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::MpegTs => "ts",
+            Self::Fmp4 => "m4s",
+        }
+    }
+}
+
+enum SegmentMuxer {
+    Ts(TsMuxer),
+    Fmp4(Fmp4Muxer),
+}
+
+impl SegmentMuxer {
+    fn new(format: HlsSegmentFormat) -> Self {
+        match format {
+            HlsSegmentFormat::MpegTs => Self::Ts(TsMuxer::new()),
+            HlsSegmentFormat::Fmp4 => Self::Fmp4(Fmp4Muxer::new()),
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        match self {
+            Self::Ts(m) => m.is_started(),
+            Self::Fmp4(m) => m.is_started(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HlsSegment {
+    sequence: u64,
+    name: String,
+    duration: Duration,
+}
+
+struct OpenSegment {
+    sequence: u64,
+    name: String,
+    start: Duration,
+    file: BufWriter<fs::File>,
+}
+
+/// Writes an HLS live stream of H.264 encoder output into a local directory, e.g. one served by a web server.
+///
+/// The stream is cut into MPEG-TS or fMP4 segments at keyframes, i.e. frames flagged `FrameFlags::FLAG_KEYFRAME`, once a
+/// segment is at least the target duration long. Set the intra period of the encoder so that GOPs divide the target
+/// duration, otherwise segments get longer. The rolling playlist `index.m3u8` lists the latest segments and is replaced
+/// atomically after every segment. Segments removed from the playlist are deleted once clients can't request them any
+/// more, i.e. after their duration plus the duration of the playlist, as RFC 8216 6.2.2 requires.
+///
+/// Segments are numbered from 0, files left over from a previous stream in the directory are overwritten.
+pub struct HlsWriter {
+    dir: PathBuf,
+    format: HlsSegmentFormat,
+    target_duration: Duration,
+    playlist_len: usize,
+    muxer: SegmentMuxer,
+    assembler: FrameAssembler,
+    current: Option<OpenSegment>,
+    segments: VecDeque<HlsSegment>,
+    /// Segments removed from the playlist, with the stream time at which they are deleted
+    expired: VecDeque<(HlsSegment, Duration)>,
+    next_sequence: u64,
+    /// Longest segment so far, `EXT-X-TARGETDURATION` must not be shorter
+    max_duration: Duration,
+    last_pts: Option<Duration>,
+    frame_duration: Duration,
+}
+
+impl HlsWriter {
+    /// Writes MPEG-TS segments of 2 seconds into `dir`, creating it if necessary. The playlist lists 5 segments.
+    pub fn create(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir, format: HlsSegmentFormat::MpegTs, target_duration: Duration::from_secs(2), playlist_len: 5,
+            muxer: SegmentMuxer::new(HlsSegmentFormat::MpegTs), assembler: FrameAssembler::new(), current: None,
+            segments: VecDeque::new(), expired: VecDeque::new(), next_sequence: 0, max_duration: Duration::ZERO,
+            last_pts: None, frame_duration: Duration::from_secs(1) / 30,
+        })
+    }
+
+    pub fn with_format(mut self, format: HlsSegmentFormat) -> Self {
+        self.format = format;
+        self.muxer = SegmentMuxer::new(format);
+        self
+    }
+
+    /// Minimum duration of the segments
+    pub fn with_target_duration(mut self, duration: Duration) -> Self {
+        self.target_duration = duration;
+        self
+    }
+
+    /// Number of segments in the playlist, at least 1
+    pub fn with_playlist_len(mut self, len: usize) -> Self {
+        self.playlist_len = len.max(1);
+        self
+    }
+
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// Adds a frame assembled from the encoder output, see `FrameAssembler`. Frames before the first keyframe are
+    /// dropped, codec config frames only update the parameter sets and codec side info frames are ignored.
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        if frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            return Ok(());
+        }
+        if frame.flags.test_one(FrameFlags::FLAG_CONFIG) {
+            match &mut self.muxer {
+                SegmentMuxer::Ts(m) => { m.push(&frame.data, frame.pts, frame.dts); }
+                SegmentMuxer::Fmp4(m) => { m.push(&frame.data, frame.pts)?; }
+            }
+            return Ok(());
+        }
+        let pts = self.timestamp(frame.pts);
+        let out = match &mut self.muxer {
+            SegmentMuxer::Ts(m) => m.push(&frame.data, frame.pts, frame.dts),
+            SegmentMuxer::Fmp4(m) => {
+                let started = m.is_started();
+                let out = m.push(&frame.data, frame.pts)?;
+                if !started && m.is_started() {
+                    // the first output is the init segment alone, the keyframe is returned with its fragment
+                    fs::write(self.dir.join(HLS_INIT_SEGMENT), out)?;
+                    Vec::new()
+                } else {
+                    out
+                }
+            }
+        };
+        let keyframe = frame.flags.test_one(FrameFlags::FLAG_KEYFRAME);
+        let cut = keyframe && self.current.as_ref().is_some_and(|s| pts - s.start >= self.target_duration);
+        if self.current.is_none() {
+            if !self.muxer.is_started() {
+                return Ok(());
+            }
+            self.open_segment(pts)?;
+        }
+        match (cut, &self.muxer) {
+            // the TS packets of the keyframe start the next segment
+            (true, SegmentMuxer::Ts(_)) => {
+                self.close_segment(pts)?;
+                self.open_segment(pts)?;
+                self.write_segment(&out)
+            }
+            // the keyframe completes the fragment of the previous GOP, and goes into the next one
+            (true, SegmentMuxer::Fmp4(_)) => {
+                self.write_segment(&out)?;
+                self.close_segment(pts)?;
+                self.open_segment(pts)
+            }
+            (false, _) => self.write_segment(&out),
+        }
+    }
+
+    /// Adds an encoder output buffer. The frame is written once its last buffer arrives.
+    pub fn write_view(&mut self, view: BufferView<'_>) -> Result<()> {
+        match self.assembler.push(view) {
+            Some(frame) => self.write_frame(&frame),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes the last segment and ends the playlist with `EXT-X-ENDLIST`. Segments which are no longer in the playlist
+    /// are deleted.
+    pub fn finish(mut self) -> Result<()> {
+        if let SegmentMuxer::Fmp4(m) = &mut self.muxer {
+            let out = m.finish();
+            self.write_segment(&out)?;
+        }
+        let end = self.last_pts.map_or(Duration::ZERO, |pts| pts + self.frame_duration);
+        self.close_segment(end)?;
+        self.write_playlist(true)?;
+        for (segment, _) in std::mem::take(&mut self.expired) {
+            self.remove_segment(&segment)?;
+        }
+        Ok(())
+    }
+
+    /// Timestamp of the frame, continuing the timeline at the frame rate if the encoder gives none
+    fn timestamp(&mut self, pts: Option<Duration>) -> Duration {
+        let pts = match (pts, self.last_pts) {
+            (Some(pts), Some(last)) if pts > last => pts,
+            (_, Some(last)) => last + self.frame_duration,
+            (Some(pts), None) => pts,
+            (None, None) => Duration::ZERO,
+        };
+        if let Some(last) = self.last_pts {
+            self.frame_duration = pts - last;
+        }
+        self.last_pts = Some(pts);
+        pts
+    }
+
+    fn open_segment(&mut self, start: Duration) -> Result<()> {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let name = format!("segment{sequence:06}.{}", self.format.extension());
+        let file = BufWriter::new(fs::File::create(self.dir.join(&name))?);
+        self.current = Some(OpenSegment { sequence, name, start, file });
+        Ok(())
+    }
+
+    fn write_segment(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(s) = &mut self.current {
+            s.file.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    fn close_segment(&mut self, end: Duration) -> Result<()> {
+        let Some(OpenSegment { sequence, name, start, mut file }) = self.current.take() else { return Ok(()) };
+        file.flush()?;
+        let duration = end.saturating_sub(start);
+        self.max_duration = self.max_duration.max(duration);
+        self.segments.push_back(HlsSegment { sequence, name, duration });
+
+        // the longest playlist containing a removed segment is the one before its removal
+        let playlist_duration: Duration = self.segments.iter().map(|s| s.duration).sum();
+        while self.segments.len() > self.playlist_len {
+            let segment = self.segments.pop_front().unwrap();
+            let delete_at = end + segment.duration + playlist_duration;
+            self.expired.push_back((segment, delete_at));
+        }
+        self.write_playlist(false)?;
+
+        while self.expired.front().is_some_and(|(_, delete_at)| *delete_at <= end) {
+            let (segment, _) = self.expired.pop_front().unwrap();
+            self.remove_segment(&segment)?;
+        }
+        Ok(())
+    }
+
+    fn remove_segment(&self, segment: &HlsSegment) -> Result<()> {
+        match fs::remove_file(self.dir.join(&segment.name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_playlist(&self, end: bool) -> Result<()> {
+        let target_duration = self.target_duration.max(self.max_duration).as_secs_f64().round().max(1.) as u64;
+        let mut m3u8 = String::from("#EXTM3U\n");
+        m3u8 += match self.format {
+            HlsSegmentFormat::MpegTs => "#EXT-X-VERSION:3\n",
+            HlsSegmentFormat::Fmp4 => "#EXT-X-VERSION:7\n",
+        };
+        m3u8 += &format!("#EXT-X-TARGETDURATION:{target_duration}\n");
+        m3u8 += &format!("#EXT-X-MEDIA-SEQUENCE:{}\n", self.segments.front().map_or(0, |s| s.sequence));
+        // every segment starts with a keyframe
+        m3u8 += "#EXT-X-INDEPENDENT-SEGMENTS\n";
+        if self.format == HlsSegmentFormat::Fmp4 {
+            m3u8 += &format!("#EXT-X-MAP:URI=\"{HLS_INIT_SEGMENT}\"\n");
+        }
+        for s in &self.segments {
+            m3u8 += &format!("#EXTINF:{:.3},\n{}\n", s.duration.as_secs_f64(), s.name);
+        }
+        if end {
+            m3u8 += "#EXT-X-ENDLIST\n";
+        }
+
+        // clients never see a partially written playlist
+        let tmp = self.dir.join(format!("{HLS_PLAYLIST}.tmp"));
+        fs::write(&tmp, m3u8)?;
+        fs::rename(tmp, self.dir.join(HLS_PLAYLIST))?;
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// `gops` times the GOP of `SAMPLE_BASELINE_240P`, after its parameter sets in a config frame
+#[cfg(test)]
+pub(crate) fn test_frames(gops: usize, frame_duration: Duration) -> Vec<Frame> {
+    let mut nals = split_annex_b(crate::h264::SAMPLE_BASELINE_240P);
+    let config: Vec<u8> = nals.by_ref().take(2).flat_map(|nal| [&[0, 0, 0, 1], nal.data].concat()).collect();
+    let gop: Vec<_> = nals.map(|nal| [&[0, 0, 1], nal.data].concat()).collect();
+
+    let mut frames = vec![Frame { flags: FrameFlags::from_bits(FrameFlags::FLAG_CONFIG), data: config, ..Default::default() }];
+    for (i, data) in std::iter::repeat_n(&gop, gops).flatten().enumerate() {
+        let keyframe = if i % gop.len() == 0 { FrameFlags::FLAG_KEYFRAME } else { 0 };
+        let flags = FrameFlags::from_bits(FrameFlags::FLAG_FRAME | keyframe);
+        frames.push(Frame { flags, pts: Some(frame_duration * i as u32), dts: None, data: data.clone() });
+    }
+    frames
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mmal-rs-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_hls_writer_ts() {
+    let dir = test_dir("hls-ts");
+    let mut writer = HlsWriter::create(&dir).unwrap()
+        .with_target_duration(Duration::from_secs(1))
+        .with_playlist_len(2);
+    // GOPs of 0.5s, segments of two GOPs
+    let frames = test_frames(10, Duration::from_millis(100));
+    for frame in &frames[..41] {
+        writer.write_frame(frame).unwrap();
+    }
+    // segments 0 to 2 closed, the last one at the keyframe at 3s
+    let playlist = fs::read_to_string(dir.join(HLS_PLAYLIST)).unwrap();
+    assert_eq!(playlist, "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:1\n\
+        #EXT-X-INDEPENDENT-SEGMENTS\n#EXTINF:1.000,\nsegment000001.ts\n#EXTINF:1.000,\nsegment000002.ts\n");
+    // still available to clients which loaded the previous playlist
+    assert!(dir.join("segment000000.ts").exists());
+
+    for frame in &frames[41..] {
+        writer.write_frame(frame).unwrap();
+    }
+    writer.finish().unwrap();
+    let playlist = fs::read_to_string(dir.join(HLS_PLAYLIST)).unwrap();
+    assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:3\n"));
+    assert!(playlist.ends_with("#EXTINF:1.000,\nsegment000003.ts\n#EXTINF:1.000,\nsegment000004.ts\n#EXT-X-ENDLIST\n"));
+    let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+    files.sort();
+    assert_eq!(files, [HLS_PLAYLIST, "segment000003.ts", "segment000004.ts"]);
+
+    // each segment starts with PAT/PMT and parameter sets
+    let segment = fs::read(dir.join("segment000004.ts")).unwrap();
+    let packets = crate::mpegts::ts_packets(&segment);
+    assert_eq!(packets[..2].iter().map(|p| p.pid).collect::<Vec<_>>(), [0, TS_PID_PMT]);
+    assert_eq!(packets.iter().filter(|p| p.pid == 0).count(), 2);
+    let es: Vec<u8> = packets.iter().filter(|p| p.pid == TS_PID_VIDEO)
+        .flat_map(|p| if p.payload_unit_start { &p.payload[14..] } else { p.payload }).copied().collect();
+    let types: Vec<_> = split_annex_b(&es).map(|nal| nal.nal_type()).take(4).collect();
+    assert_eq!(types, [NalUnitType::Aud, NalUnitType::Sps, NalUnitType::Pps, NalUnitType::IdrSlice]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_hls_writer_fmp4() {
+    let dir = test_dir("hls-fmp4");
+    let mut writer = HlsWriter::create(&dir).unwrap()
+        .with_format(HlsSegmentFormat::Fmp4)
+        .with_target_duration(Duration::from_millis(900));
+    for frame in &test_frames(4, Duration::from_millis(100)) {
+        writer.write_frame(frame).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let playlist = fs::read_to_string(dir.join(HLS_PLAYLIST)).unwrap();
+    assert_eq!(playlist, "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:1\n#EXT-X-MEDIA-SEQUENCE:0\n\
+        #EXT-X-INDEPENDENT-SEGMENTS\n#EXT-X-MAP:URI=\"init.mp4\"\n\
+        #EXTINF:1.000,\nsegment000000.m4s\n#EXTINF:1.000,\nsegment000001.m4s\n#EXT-X-ENDLIST\n");
+    let init = fs::read(dir.join(HLS_INIT_SEGMENT)).unwrap();
+    assert_eq!(crate::mp4::mp4_boxes(&init).iter().map(|b| &b.0).collect::<Vec<_>>(), [b"ftyp", b"moov"]);
+    // a fragment per GOP
+    for name in ["segment000000.m4s", "segment000001.m4s"] {
+        let segment = fs::read(dir.join(name)).unwrap();
+        assert_eq!(crate::mp4::mp4_boxes(&segment).iter().map(|b| &b.0).collect::<Vec<_>>(), [b"moof", b"mdat", b"moof", b"mdat"]);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
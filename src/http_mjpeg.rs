@@ -0,0 +1,439 @@
+use super::*;
+use std::{future::Future, pin::Pin, sync::Arc, task::{Context, Poll}, time::{Duration, Instant}};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+pub const MJPEG_BOUNDARY: &str = "MJPEGBOUNDARY";
+/// Content type of the responses of `MjpegClient::into_stream`
+pub const MJPEG_CONTENT_TYPE: &str = "multipart/x-mixed-replace;boundary=MJPEGBOUNDARY";
+
+const MAX_REQUEST_HEAD_LEN: usize = 8 * 1024;
+
+/// JPEG image published to the clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MjpegFrame {
+    pub data: Vec<u8>,
+    /// Timestamp sent as `X-Timestamp`
+    pub pts: Duration,
+}
+
+impl MjpegFrame {
+    /// The frame as a part of the `multipart/x-mixed-replace` response
+    pub fn to_part(&self) -> Vec<u8> {
+        let header = format!(
+            "\r\n--{MJPEG_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Timestamp: {}.{:06}\r\n\r\n",
+            self.data.len(), self.pts.as_secs(), self.pts.subsec_micros()
+        );
+        [header.as_bytes(), &self.data].concat()
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+struct Shared {
+    tx: watch::Sender<Option<Arc<MjpegFrame>>>,
+    clients: Arc<Semaphore>,
+    max_clients: usize,
+    start: Instant,
+}
+
+/// Distributes JPEG frames, e.g. of an MJPEG `VideoEncoderOutputPort`, to any number of `MjpegClient`s, up to a limit.
+///
+/// Clients always get the latest frame: slow clients skip frames rather than lagging behind. Cloning the broadcaster is
+/// cheap, e.g. to publish from the encoder loop and subscribe from the request handlers of an axum or hyper app:
+/// the body of a stream response is `MjpegClient::into_stream` with the content type `MJPEG_CONTENT_TYPE`, a snapshot
+/// response is `MjpegBroadcaster::snapshot` as `image/jpeg`. `MjpegServer` is a standalone server doing just that.
+#[derive(Clone)]
+pub struct MjpegBroadcaster {
+    shared: Arc<Shared>,
+}
+
+impl MjpegBroadcaster {
+    pub fn new(max_clients: usize) -> Self {
+        let (tx, _) = watch::channel(None);
+        Self { shared: Arc::new(Shared { tx, clients: Arc::new(Semaphore::new(max_clients)), max_clients, start: Instant::now() }) }
+    }
+
+    /// Publishes a JPEG image. `pts` of `None` is the time since the broadcaster was created.
+    pub fn publish(&self, data: Vec<u8>, pts: Option<Duration>) {
+        let pts = pts.unwrap_or_else(|| self.shared.start.elapsed());
+        self.shared.tx.send_replace(Some(Arc::new(MjpegFrame { data, pts })));
+    }
+
+    /// Publishes a frame assembled from the encoder output, see `FrameAssembler`
+    pub fn publish_frame(&self, frame: Frame) {
+        self.publish(frame.data, frame.pts)
+    }
+
+    /// The latest frame
+    pub fn snapshot(&self) -> Option<Arc<MjpegFrame>> {
+        self.shared.tx.borrow().clone()
+    }
+
+    /// Adds a client receiving at most `max_fps` frames per second, if given and positive.
+    ///
+    /// Fails with `Cause::NotAvailable` if the number of clients is at the limit. The client counts until it is dropped.
+    pub fn subscribe(&self, max_fps: Option<f64>) -> Result<MjpegClient> {
+        let permit = self.shared.clients.clone().try_acquire_owned()
+            .map_err(|_| MmalError::new(Cause::NotAvailable, format!("mjpeg: all {} client slots are taken", self.shared.max_clients)))?;
+        let mut rx = self.shared.tx.subscribe();
+        // the latest frame is sent right away
+        rx.mark_changed();
+        // rates too low for a `Duration` never send another frame
+        let min_interval = max_fps.filter(|fps| *fps > 0.).map(|fps| Duration::try_from_secs_f64(1. / fps).unwrap_or(Duration::MAX));
+        Ok(MjpegClient { rx, min_interval, last_sent: None, _permit: permit })
+    }
+
+    /// Number of subscribed clients
+    pub fn clients(&self) -> usize {
+        self.shared.max_clients - self.shared.clients.available_permits()
+    }
+}
+
+/// Subscription of one client, see `MjpegBroadcaster::subscribe`
+pub struct MjpegClient {
+    rx: watch::Receiver<Option<Arc<MjpegFrame>>>,
+    min_interval: Option<Duration>,
+    last_sent: Option<Instant>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl MjpegClient {
+    /// Waits for the next frame, respecting the frame rate limit. Returns `None` once the broadcaster is dropped.
+    pub async fn next_frame(&mut self) -> Option<Arc<MjpegFrame>> {
+        if let (Some(last_sent), Some(min_interval)) = (self.last_sent, self.min_interval) {
+            match last_sent.checked_add(min_interval) {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => std::future::pending().await,
+            }
+        }
+        loop {
+            self.rx.changed().await.ok()?;
+            if let Some(frame) = self.rx.borrow_and_update().clone() {
+                self.last_sent = Some(Instant::now());
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Stream of `multipart/x-mixed-replace` parts, e.g. the body of an axum or hyper response
+    pub fn into_stream(self) -> MjpegPartStream {
+        MjpegPartStream { next: Box::pin(next_part(self)) }
+    }
+}
+
+type NextPart = Pin<Box<dyn Future<Output = Option<(Vec<u8>, MjpegClient)>> + Send>>;
+
+async fn next_part(mut client: MjpegClient) -> Option<(Vec<u8>, MjpegClient)> {
+    let frame = client.next_frame().await?;
+    Some((frame.to_part(), client))
+}
+
+/// See `MjpegClient::into_stream`
+pub struct MjpegPartStream {
+    next: NextPart,
+}
+
+impl futures_core::Stream for MjpegPartStream {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.next.as_mut().poll(cx) {
+            Poll::Ready(Some((part, client))) => {
+                self.next = Box::pin(next_part(client));
+                Poll::Ready(Some(Ok(part)))
+            }
+            Poll::Ready(None) => {
+                self.next = Box::pin(std::future::ready(None));
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Standalone HTTP server of an `MjpegBroadcaster`.
+///
+/// `GET /` and `GET /stream` return the MJPEG stream, `GET /snapshot` the latest frame as a single JPEG. The frame rate
+/// of a stream is limited to `max_fps`, or lower with the `fps` query parameter, e.g. `/stream?fps=2`. Requests beyond
+/// the client limit of the broadcaster are answered with `503 Service Unavailable`.
+#[derive(Clone)]
+pub struct MjpegServer {
+    broadcaster: MjpegBroadcaster,
+    max_fps: Option<f64>,
+}
+
+impl MjpegServer {
+    pub fn new(broadcaster: MjpegBroadcaster) -> Self { Self { broadcaster, max_fps: None } }
+
+    pub fn with_max_fps(mut self, max_fps: f64) -> Self {
+        self.max_fps = Some(max_fps);
+        self
+    }
+
+    /// Accepts connections until an accept error, serving each from its own task
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move { server.handle(stream).await });
+        }
+    }
+
+    /// Serves a single request on `stream`. Returns once the response is complete or the client disconnects.
+    pub async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let Some((method, target)) = read_request_head(&mut stream).await? else {
+            return Ok(());
+        };
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+        if method != "GET" {
+            return write_status(&mut stream, "405 Method Not Allowed").await;
+        }
+        match path {
+            "/" | "/stream" => {
+                let fps = query.split('&').find_map(|p| p.strip_prefix("fps=")).and_then(|fps| fps.parse::<f64>().ok())
+                    .filter(|fps| fps.is_finite() && *fps > 0.);
+                let max_fps = match (fps, self.max_fps) {
+                    (Some(fps), Some(max)) => Some(fps.min(max)),
+                    (fps, max) => fps.or(max),
+                };
+                let mut client = match self.broadcaster.subscribe(max_fps) {
+                    Ok(client) => client,
+                    Err(_) => return write_status(&mut stream, "503 Service Unavailable").await,
+                };
+                stream.write_all(format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {MJPEG_CONTENT_TYPE}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+                ).as_bytes()).await?;
+                // a disconnecting client frees its slot without waiting for the next frame
+                let (mut reader, mut writer) = stream.split();
+                let mut buf = [0; 256];
+                loop {
+                    tokio::select! {
+                        frame = client.next_frame() => match frame {
+                            Some(frame) => writer.write_all(&frame.to_part()).await?,
+                            None => return Ok(()),
+                        },
+                        len = reader.read(&mut buf) => if len? == 0 {
+                            return Ok(());
+                        },
+                    }
+                }
+            }
+            "/snapshot" => match self.broadcaster.snapshot() {
+                Some(frame) => {
+                    stream.write_all(format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Timestamp: {}.{:06}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+                        frame.data.len(), frame.pts.as_secs(), frame.pts.subsec_micros()
+                    ).as_bytes()).await?;
+                    Ok(stream.write_all(&frame.data).await?)
+                }
+                None => write_status(&mut stream, "503 Service Unavailable").await,
+            },
+            _ => write_status(&mut stream, "404 Not Found").await,
+        }
+    }
+}
+
+/// Method and target of the request, `None` if the connection was closed before the end of the head
+async fn read_request_head(stream: &mut TcpStream) -> Result<Option<(String, String)>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD_LEN {
+            return Err(MmalError::new(Cause::InvalidBitstream, "mjpeg: request head too long".to_owned()));
+        }
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..len]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => Ok(Some((method.to_owned(), target.to_owned()))),
+        _ => Err(MmalError::new(Cause::InvalidBitstream, "mjpeg: invalid request line".to_owned())),
+    }
+}
+
+async fn write_status(stream: &mut TcpStream, status: &str) -> Result<()> {
+    Ok(stream.write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").as_bytes()).await?)
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Synthetic JPEG of frame `n`, only the markers are real
+#[cfg(test)]
+fn synthetic_jpeg(n: u32) -> Vec<u8> {
+    [&[0xff, 0xd8][..], &n.to_be_bytes(), &[0xff, 0xd9]].concat()
+}
+
+#[cfg(test)]
+async fn http_get(addr: std::net::SocketAddr, target: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes()).await.unwrap();
+    stream
+}
+
+/// Reads the response head and returns the status code and headers
+#[cfg(test)]
+async fn read_response_head(stream: &mut TcpStream) -> (u32, String) {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap();
+    (head[9..12].parse().unwrap(), head)
+}
+
+/// Reads the next part of an MJPEG stream and returns its timestamp header and payload
+#[cfg(test)]
+async fn read_part(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    let mut head = Vec::new();
+    while head.len() < 4 || !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with(&format!("\r\n--{MJPEG_BOUNDARY}\r\nContent-Type: image/jpeg\r\n")));
+    let value = |name: &str| head.lines().find_map(|l| l.strip_prefix(name)).unwrap().trim().to_owned();
+    let mut data = vec![0; value("Content-Length:").parse().unwrap()];
+    stream.read_exact(&mut data).await.unwrap();
+    (value("X-Timestamp:"), data)
+}
+
+#[test]
+fn test_mjpeg_server() {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broadcaster = MjpegBroadcaster::new(2);
+        tokio::spawn(MjpegServer::new(broadcaster.clone()).serve(listener));
+
+        // nothing to snapshot yet
+        let mut stream = http_get(addr, "/snapshot").await;
+        assert_eq!(read_response_head(&mut stream).await.0, 503);
+
+        broadcaster.publish(synthetic_jpeg(0), Some(Duration::from_micros(1_500_250)));
+        let mut stream = http_get(addr, "/snapshot").await;
+        let (status, head) = read_response_head(&mut stream).await;
+        assert_eq!(status, 200);
+        assert!(head.contains("Content-Type: image/jpeg\r\n") && head.contains("X-Timestamp: 1.500250\r\n"));
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, synthetic_jpeg(0));
+
+        // a new client gets the latest frame first, then each published one
+        let mut stream = http_get(addr, "/stream").await;
+        let (status, head) = read_response_head(&mut stream).await;
+        assert_eq!(status, 200);
+        assert!(head.contains(&format!("Content-Type: {MJPEG_CONTENT_TYPE}\r\n")));
+        assert_eq!(read_part(&mut stream).await, ("1.500250".to_owned(), synthetic_jpeg(0)));
+        for n in 1..4 {
+            broadcaster.publish(synthetic_jpeg(n), Some(Duration::from_millis(1500 + 40 * n as u64)));
+            assert_eq!(read_part(&mut stream).await, (format!("1.{:06}", 500_000 + 40_000 * n), synthetic_jpeg(n)));
+        }
+
+        // client limit
+        let mut second = http_get(addr, "/").await;
+        assert_eq!(read_response_head(&mut second).await.0, 200);
+        let mut third = http_get(addr, "/stream").await;
+        assert_eq!(read_response_head(&mut third).await.0, 503);
+        drop(second);
+        drop(stream);
+        let start = Instant::now();
+        while broadcaster.clients() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let mut stream = http_get(addr, "/nope").await;
+        assert_eq!(read_response_head(&mut stream).await.0, 404);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"POST /stream HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(read_response_head(&mut stream).await.0, 405);
+    });
+}
+
+#[test]
+fn test_mjpeg_frame_rate_limit() {
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broadcaster = MjpegBroadcaster::new(5);
+        broadcaster.publish(synthetic_jpeg(0), None);
+        tokio::spawn(MjpegServer::new(broadcaster.clone()).with_max_fps(50.).serve(listener));
+
+        // synthetic 200 fps source
+        let source = broadcaster.clone();
+        let publisher = tokio::spawn(async move {
+            for n in 1..=100 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                source.publish(synthetic_jpeg(n), None);
+            }
+        });
+
+        let count_frames = |target: &'static str| async move {
+            let mut stream = http_get(addr, target).await;
+            assert_eq!(read_response_head(&mut stream).await.0, 200);
+            let start = Instant::now();
+            let mut frames = Vec::new();
+            while start.elapsed() < Duration::from_millis(400) {
+                frames.push(read_part(&mut stream).await.1);
+            }
+            frames
+        };
+        let (limited, capped, zero, negative) = tokio::join!(
+            count_frames("/stream?fps=10"), count_frames("/stream?fps=1000"), count_frames("/stream?fps=0"), count_frames("/stream?fps=-1"),
+        );
+        publisher.await.unwrap();
+
+        // 10 fps: about 5 frames in 400ms, capped at 50 fps by the server: about 21
+        assert!((3..=7).contains(&limited.len()), "{} frames at 10 fps", limited.len());
+        assert!((10..=24).contains(&capped.len()), "{} frames at 50 fps", capped.len());
+        // invalid rates get the cap of the server rather than no limit
+        assert!((10..=24).contains(&zero.len()), "{} frames at fps=0", zero.len());
+        assert!((10..=24).contains(&negative.len()), "{} frames at fps=-1", negative.len());
+        // the latest frame is sent, skipping the ones in between
+        let n = |jpeg: &Vec<u8>| u32::from_be_bytes(jpeg[2..6].try_into().unwrap());
+        assert!(limited.windows(2).all(|w| n(&w[1]) > n(&w[0]) + 1));
+
+        // too low for a `Duration`: the latest frame, then nothing
+        let mut stream = http_get(addr, "/stream?fps=1e-300").await;
+        assert_eq!(read_response_head(&mut stream).await.0, 200);
+        read_part(&mut stream).await;
+        broadcaster.publish(synthetic_jpeg(101), None);
+        let mut buf = [0; 1];
+        assert!(tokio::time::timeout(Duration::from_millis(100), stream.read(&mut buf)).await.is_err());
+    });
+}
+
+#[test]
+fn test_mjpeg_client_stream() {
+    use {futures_core::Stream, std::task::Waker};
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        let broadcaster = MjpegBroadcaster::new(1);
+        let mut parts = broadcaster.subscribe(None).unwrap().into_stream();
+        assert_eq!(broadcaster.clients(), 1);
+        assert!(broadcaster.subscribe(None).is_err());
+
+        let mut next = || Pin::new(&mut parts).poll_next(&mut Context::from_waker(Waker::noop()));
+        assert!(next().is_pending());
+        let frame = MjpegFrame { data: synthetic_jpeg(7), pts: Duration::from_secs(2) };
+        broadcaster.publish(frame.data.clone(), Some(frame.pts));
+        assert!(matches!(next(), Poll::Ready(Some(Ok(part))) if part == frame.to_part()));
+        drop(broadcaster);
+        assert!(matches!(next(), Poll::Ready(None)));
+        drop(parts);
+    });
+}
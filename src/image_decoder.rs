@@ -0,0 +1,234 @@
+use std::pin::Pin;
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+pub struct ImageDecoderEntity;
+
+impl Entity for ImageDecoderEntity {
+    fn name() -> &'static str { "image_decoder" }
+}
+
+impl ComponentEntity for ImageDecoderEntity { }
+
+pub type ImageDecoderComponentHandle = ComponentHandle<ImageDecoderEntity>;
+
+impl ImageDecoderComponentHandle {
+    pub fn create() -> Result<Self> {
+        let component_name: *const c_char = ffi::MMAL_COMPONENT_DEFAULT_IMAGE_DECODER.as_ptr() as *const c_char;
+        unsafe {
+            Self::create_from(component_name)
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Image decoder input port, fed with `SourceAggregate`
+pub struct ImageDecoderInputPort;
+impl ComponentPort for ImageDecoderInputPort {
+    type E = ImageDecoderEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.input_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "image_decoder input port" }
+}
+
+/// Image decoder output port. The decoder announces the picture format with a `MMAL_EVENT_FORMAT_CHANGED`
+/// buffer (see `FrameMeta::cmd`), after which the port has to be reconfigured.
+pub struct ImageDecoderOutputPort;
+impl ComponentPort for ImageDecoderOutputPort {
+    type E = ImageDecoderEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.output_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "image_decoder output port" }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Image decoder input port configuration: the encoding of the images, e.g. `MMAL_ENCODING_JPEG`
+pub struct ImageDecoderInFormat {
+    pub encoding: u32,
+}
+
+impl Default for ImageDecoderInFormat {
+    fn default() -> Self { Self {
+        encoding: ffi::MMAL_ENCODING_JPEG
+    } }
+}
+
+impl From<u32> for ImageDecoderInFormat {
+    fn from(encoding: u32) -> Self { Self { encoding } }
+}
+
+impl PortConfig for ImageDecoderInFormat {
+    unsafe fn apply_format(&self, port: *mut ffi::MMAL_PORT_T) {
+        let format = &mut (*(*port).format);
+        format.encoding = self.encoding;
+        format.encoding_variant = 0;
+    }
+
+    unsafe fn apply_buffer_policy(&self, port: *mut ffi::MMAL_PORT_T) {
+        let port = &mut *port;
+
+        port.buffer_num = port.buffer_num_recommended;
+        if port.buffer_num < port.buffer_num_min {
+            port.buffer_num = port.buffer_num_min;
+        }
+
+        port.buffer_size = port.buffer_size_recommended;
+        if port.buffer_size < port.buffer_size_min {
+            port.buffer_size = port.buffer_size_min;
+        }
+    }
+}
+
+/// Format announced by a `MMAL_EVENT_FORMAT_CHANGED` event, copied out of the event buffer
+struct ChangedFormat {
+    format: ffi::MMAL_ES_FORMAT_T,
+    es: ffi::MMAL_ES_SPECIFIC_FORMAT_T,
+}
+
+impl ChangedFormat {
+    unsafe fn from_event(b: &BufferRef) -> Result<Box<Self>> {
+        let event = ffi::mmal_event_format_changed_get(b.as_ptr());
+        if event.is_null() || (*event).format.is_null() {
+            return Err(MmalError::with_status(ffi::MMAL_STATUS_T::MMAL_ECORRUPT, "image_decoder: malformed format changed event".to_owned()));
+        }
+        let mut rv = Box::new(Self { format: *(*event).format, es: *(*(*event).format).es });
+        rv.format.es = &mut rv.es;
+        rv.format.extradata = std::ptr::null_mut();
+        rv.format.extradata_size = 0;
+        Ok(rv)
+    }
+}
+
+impl PortConfig for &'_ ChangedFormat {
+    unsafe fn apply_format(&self, port: *mut ffi::MMAL_PORT_T) {
+        ffi::mmal_format_copy((*port).format, &self.format as *const _ as *mut _);
+    }
+
+    unsafe fn apply_buffer_policy(&self, port: *mut ffi::MMAL_PORT_T) {
+        let port = &mut *port;
+        port.buffer_num = port.buffer_num_recommended.max(port.buffer_num_min);
+        port.buffer_size = port.buffer_size_recommended.max(port.buffer_size_min);
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Decoded picture, see `decode_image`
+#[derive(Debug, Clone)]
+pub struct DecodedImage {
+    /// Visible picture size
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per line of the first plane
+    pub stride: u32,
+    /// Pixel encoding, e.g. `MMAL_ENCODING_I420` for JPEG
+    pub encoding: u32,
+    /// Picture as produced by the decoder, with the planes padded to the aligned size
+    pub data: Vec<u8>,
+}
+
+type ImageDecoderSink = Pin<Box<SinkAggregate<ImageDecoderOutputPort>>>;
+
+/// Ports of a decoder enabled for one image, disabled on drop
+struct OneShot<'a> {
+    c: &'a ComponentHandle<ImageDecoderEntity>,
+    source: SourceAggregate<ImageDecoderInputPort>,
+    sink: Option<ImageDecoderSink>,
+}
+
+impl<'a> OneShot<'a> {
+    fn create(c: &'a ComponentHandle<ImageDecoderEntity>) -> Result<Self> {
+        let source = SourceAggregate::create(c.clone())?;
+        source.enable()?;
+        let mut rv = Self { c, source, sink: None };
+        rv.open_sink()?;
+        Ok(rv)
+    }
+
+    fn open_sink(&mut self) -> Result<()> {
+        let sink = SinkAggregate::create(self.c.clone())?;
+        sink.enable()?;
+        self.sink = Some(sink);
+        self.sink.as_ref().map_or(Ok(()), |s| s.feed_all())
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<Frame> {
+        self.source.send_frame(data, None)?;
+        self.source.send_eos()?;
+        let mut assembler = FrameAssembler::new();
+        let mut image = None;
+        loop {
+            let sink = self.sink.as_ref().ok_or_else(|| MmalError::with_cause(Cause::GetPort))?;
+            let b = sink.timedwait(DEFAULT_SOURCE_TIMEOUT_MS)
+                .ok_or_else(|| MmalError::new(Cause::QueueEmpty, "image_decoder: no output".to_owned()))?;
+            if b.meta().cmd == ffi::MMAL_EVENT_FORMAT_CHANGED {
+                let format = unsafe { ChangedFormat::from_event(&b) };
+                // the event buffer goes back to the pool before the pool is replaced
+                drop(b);
+                sink.disable()?;
+                self.sink = None;
+                ImageDecoderOutputPort::configure(self.c, &*format?)?;
+                self.open_sink()?;
+                continue;
+            }
+            // decoders flag EOS on a buffer of its own, which does not end a frame
+            let (_, (eos, frame)) = sink.consume_view(b, |view| {
+                let eos = view.meta.flags.test_one(FrameFlags::FLAG_EOS);
+                let frame = assembler.push(view);
+                Ok((true, (eos, if eos { frame.or_else(|| assembler.flush()) } else { frame })))
+            })?;
+            if let Some(frame) = frame.filter(|f| !f.data.is_empty()) {
+                image = Some(frame);
+            }
+            if eos {
+                return image.ok_or_else(|| MmalError::with_status(ffi::MMAL_STATUS_T::MMAL_ECORRUPT, "image_decoder: no picture decoded".to_owned()));
+            }
+        }
+    }
+}
+
+impl Drop for OneShot<'_> {
+    fn drop(&mut self) {
+        if let Some(sink) = &self.sink {
+            log_deinit!(sink.disable());
+        }
+        log_deinit!(self.source.disable());
+    }
+}
+
+/// Decodes a single image of `encoding`, e.g. `MMAL_ENCODING_PNG`, with a temporary image decoder component
+pub fn decode_image(encoding: u32, data: &[u8]) -> Result<DecodedImage> {
+    let decoder = ImageDecoderComponentHandle::create()?;
+    ImageDecoderInputPort::configure(&decoder, ImageDecoderInFormat::from(encoding))?;
+    let decoder = ComponentEnabler::new(decoder)?;
+
+    let frame = OneShot::create(decoder.inner())?.decode(data)?;
+    let format = unsafe { EsFormat::from_ffi(&*(*ImageDecoderOutputPort::get_port(decoder.inner())).format) };
+    let video = format.video.unwrap_or_default();
+    let (width, height) = if video.crop_width > 0 && video.crop_height > 0 {
+        (video.crop_width as u32, video.crop_height as u32)
+    } else {
+        (video.width, video.height)
+    };
+    Ok(DecodedImage {
+        width,
+        height,
+        stride: unsafe { ffi::mmal_encoding_width_to_stride(format.encoding, video.width) },
+        encoding: format.encoding,
+        data: frame.data,
+    })
+}
+
+/// Decodes a JPEG picture, see `decode_image`
+pub fn decode_jpeg(data: &[u8]) -> Result<DecodedImage> {
+    decode_image(ffi::MMAL_ENCODING_JPEG, data)
+}
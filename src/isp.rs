@@ -0,0 +1,126 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Name of the ISP component, not present in the MMAL headers
+pub const MMAL_COMPONENT_ISP: &[u8] = b"vc.ril.isp\0";
+
+pub struct IspEntity;
+
+impl Entity for IspEntity {
+    fn name() -> &'static str { "isp" }
+}
+
+impl ComponentEntity for IspEntity { }
+
+pub type IspComponentHandle = ComponentHandle<IspEntity>;
+
+impl IspComponentHandle {
+    pub fn create() -> Result<Self> {
+        let component_name: *const c_char = MMAL_COMPONENT_ISP.as_ptr() as *const c_char;
+        unsafe {
+            Self::create_from(component_name)
+        }
+    }
+}
+
+#[repr(isize)]
+enum IspOutput {
+    Main = 0,
+    LowRes = 1,
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// ISP input port. Configure it with the format of the connected output, with crop set to the region to be scaled.
+pub struct IspInputPort;
+impl ComponentPort for IspInputPort {
+    type E = IspEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.input_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "isp input port" }
+
+    fn accepted_encodings() -> Option<&'static [u32]> { Some(RAW_PICTURE_ENCODINGS) }
+}
+
+/// ISP main output port
+pub struct IspOutputPort;
+impl ComponentPort for IspOutputPort {
+    type E = IspEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.output_port_n(IspOutput::Main as isize)
+    }
+
+    fn name() -> &'static str { "isp output port" }
+}
+
+/// ISP secondary output port. The firmware requires it to be no larger than the main output.
+pub struct IspLowResOutputPort;
+impl ComponentPort for IspLowResOutputPort {
+    type E = IspEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.output_port_n(IspOutput::LowRes as isize)
+    }
+
+    fn name() -> &'static str { "isp low resolution output port" }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Output port configuration of the ISP and resizer components
+///
+/// The format is copied from the input port, which should be configured first, then the encoding and
+/// the picture size are replaced. Use `GenericPortConfig` instead for full control, e.g. of output crop.
+#[derive(Debug, Clone, Copy)]
+pub struct ScalerOutFormat {
+    pub encoding: u32,
+    pub width: u32,
+    pub height: u32,
+    pub buffer_count_policy: BufferCountPolicy,
+}
+
+impl ScalerOutFormat {
+    pub fn new(encoding: u32, width: u32, height: u32) -> Self {
+        Self { encoding, width, height, buffer_count_policy: BufferCountPolicy::Recommended }
+    }
+}
+
+impl PortConfig for ScalerOutFormat {
+    unsafe fn apply_format(&self, port: *mut ffi::MMAL_PORT_T) {
+        let input = *(*(*port).component).input;
+        ffi::mmal_format_copy((*port).format, (*input).format);
+        let format = &mut (*(*port).format);
+        format.encoding = fix_encoding(port, self.encoding);
+        format.encoding_variant = 0;
+
+        let es = &mut (*format.es);
+        es.video.width = ffi::vcos_align_up(self.width, 32);
+        es.video.height = ffi::vcos_align_up(self.height, 16);
+        es.video.crop.x = 0;
+        es.video.crop.y = 0;
+        es.video.crop.width = self.width as i32;
+        es.video.crop.height = self.height as i32;
+    }
+
+    unsafe fn apply_buffer_policy(&self, port: *mut ffi::MMAL_PORT_T) {
+        let port = &mut *port;
+
+        port.buffer_num = match self.buffer_count_policy {
+            BufferCountPolicy::Recommended => port.buffer_num_recommended,
+            BufferCountPolicy::Explicit(n) => n,
+        };
+        if port.buffer_num < port.buffer_num_min {
+            port.buffer_num = port.buffer_num_min;
+        }
+
+        port.buffer_size = port.buffer_size_recommended;
+        if port.buffer_size < port.buffer_size_min {
+            port.buffer_size = port.buffer_size_min;
+        }
+    }
+}
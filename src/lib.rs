@@ -1,33 +1,80 @@
 mod error;
 pub mod mmalcore;
+pub mod event;
+pub mod pipeline;
 pub mod param;
 pub mod camera_info;
 pub mod camera;
 pub mod encoder;
 pub mod video_encoder;
+pub mod video_decoder;
+pub mod image_decoder;
+pub mod splitter;
+pub mod isp;
+pub mod resizer;
+pub mod h264;
+pub mod mp4;
+pub mod mpegts;
+pub mod rtp;
+pub mod rtsp;
+pub mod hls;
+pub mod circular;
+pub mod motion;
+pub mod raw;
+pub mod convert;
+pub mod bayer;
+pub mod dng;
+#[cfg(feature = "http_mjpeg")]
+pub mod http_mjpeg;
 pub mod ffi;
+#[cfg(feature = "emulation")]
+mod emulation;
+#[cfg(all(feature = "dynamic-loading", not(feature = "emulation")))]
+mod dynamic_loading;
 
 use std::{mem, ffi::{CStr, c_char}, fmt::Debug};
 
 pub use error::*;
 pub use mmalcore::*;
+pub use event::*;
+pub use pipeline::*;
 pub use param::*;
 pub use camera_info::*;
 pub use camera::*;
 pub use encoder::*;
 pub use video_encoder::*;
+pub use video_decoder::*;
+pub use image_decoder::*;
+pub use splitter::*;
+pub use isp::*;
+pub use resizer::*;
+pub use h264::*;
+pub use mp4::*;
+pub use mpegts::*;
+pub use rtp::*;
+pub use rtsp::*;
+pub use hls::*;
+pub use circular::*;
+pub use motion::*;
+pub use raw::*;
+pub use convert::*;
+pub use bayer::*;
+pub use dng::*;
+#[cfg(feature = "http_mjpeg")]
+pub use http_mjpeg::*;
 
 unsafe fn fix_encoding(port: *mut ffi::MMAL_PORT_T, encoding: u32) -> u32 {
-    // On firmware prior to June 2016, camera and video_splitter
-    // had BGR24 and RGB24 support reversed.
-    if encoding == ffi::MMAL_ENCODING_RGB24 || encoding == ffi::MMAL_ENCODING_BGR24 {
-        if ffi::mmal_util_rgb_order_fixed(port) != 0 {
-            ffi::MMAL_ENCODING_RGB24
-        } else {
-            ffi::MMAL_ENCODING_BGR24
-        }
-    } else {
-        encoding
+    rgb_encoding(encoding, ffi::mmal_util_rgb_order_fixed(port) != 0)
+}
+
+/// On firmware prior to June 2016, camera and video_splitter
+/// had BGR24 and RGB24 support reversed: swaps them unless the order is fixed.
+/// The swap is its own inverse, so it also gives the channel order of the frames of a port set to `encoding`.
+fn rgb_encoding(encoding: u32, order_fixed: bool) -> u32 {
+    match encoding {
+        ffi::MMAL_ENCODING_RGB24 if !order_fixed => ffi::MMAL_ENCODING_BGR24,
+        ffi::MMAL_ENCODING_BGR24 if !order_fixed => ffi::MMAL_ENCODING_RGB24,
+        _ => encoding,
     }
 }
 
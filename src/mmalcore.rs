@@ -1,5 +1,6 @@
-use std::{ptr::NonNull, mem::MaybeUninit, marker::{PhantomData, PhantomPinned}, ffi::c_char, sync::Once, pin::Pin};
+use std::{ptr::NonNull, mem::MaybeUninit, marker::{PhantomData, PhantomPinned}, ffi::c_char, sync::{Once, Mutex}, pin::Pin};
 use std::task::{Poll, Context, Waker};
+use std::time::Duration;
 use super::*;
 
 
@@ -229,7 +230,7 @@ impl<PS: ComponentPort, PT: ComponentPort> Clone for ConnectionHandle<PS, PT> {
 
 //------------------------------------------------------------------------------------------------------------------------------
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct FrameFlags {
     flags: u32
 }
@@ -264,6 +265,8 @@ impl FrameFlags {
 
     pub const FLAG_TERMINAL_FRAME: u32 = Self::FLAG_FRAME_END | Self::FLAG_TRANSMISSION_FAILED;
 
+    pub fn from_bits(flags: u32) -> Self { Self { flags } }
+    pub fn bits(&self) -> u32 { self.flags }
     pub fn test_one(&self, mask: u32) -> bool { self.flags & mask != 0 }
     pub fn test_all(&self, mask: u32) -> bool { self.flags & mask == mask }
     pub fn is_terminal_frame(&self) -> bool { self.test_one(Self::FLAG_TERMINAL_FRAME) }
@@ -295,13 +298,82 @@ impl BufferRef {
         }
     }
 
+    /// Same as `do_locked`, but the closure gets the buffer metadata along with the payload
+    pub fn do_locked_view<R>(&self, mut f: impl FnMut(BufferView<'_>) -> Result<(bool, R)>) -> Result<(bool, R)> {
+        let meta = self.meta();
+        self.do_locked(|_, data| f(BufferView { meta, data }))
+    }
+
+    pub fn meta(&self) -> FrameMeta {
+        unsafe { FrameMeta::from_header(self.p.as_ref()) }
+    }
+
     fn new(buffer_ptr: *mut ffi::MMAL_BUFFER_HEADER_T) -> Option<Self> {
         let p = NonNull::new(buffer_ptr)?;
         Some(Self { p })
     }
+
+    pub(crate) fn as_ptr(&self) -> *mut ffi::MMAL_BUFFER_HEADER_T { self.p.as_ptr() }
     
 }
 
+/// Video specific properties of a buffer (`MMAL_BUFFER_HEADER_VIDEO_SPECIFIC_T`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VideoBufferInfo {
+    pub planes: u32,
+    pub offset: [u32; 4],
+    pub pitch: [u32; 4],
+    pub flags: u32,
+}
+
+/// Buffer header fields besides the payload
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameMeta {
+    pub flags: FrameFlags,
+    /// Presentation timestamp, `None` if unknown
+    pub pts: Option<Duration>,
+    /// Decode timestamp, `None` if unknown
+    pub dts: Option<Duration>,
+    /// FourCC of the event carried by the buffer, 0 for stream data
+    pub cmd: u32,
+    pub alloc_size: u32,
+    pub video: Option<VideoBufferInfo>,
+}
+
+impl FrameMeta {
+    unsafe fn from_header(h: &ffi::MMAL_BUFFER_HEADER_T) -> Self {
+        Self {
+            flags: FrameFlags { flags: h.flags },
+            pts: mmal_time_to_duration(h.pts),
+            dts: mmal_time_to_duration(h.dts),
+            cmd: h.cmd,
+            alloc_size: h.alloc_size,
+            video: h.type_.as_ref().map(|t| VideoBufferInfo {
+                planes: t.video.planes,
+                offset: t.video.offset,
+                pitch: t.video.pitch,
+                flags: t.video.flags,
+            }),
+        }
+    }
+}
+
+/// MMAL timestamps are in microseconds. `MMAL_TIME_UNKNOWN` (and any other negative value) maps to `None`.
+pub fn mmal_time_to_duration(t: i64) -> Option<Duration> {
+    u64::try_from(t).ok().map(Duration::from_micros)
+}
+
+pub fn duration_to_mmal_time(d: Option<Duration>) -> i64 {
+    d.map(|d| d.as_micros() as i64).unwrap_or(ffi::MMAL_TIME_UNKNOWN)
+}
+
+/// Payload of a locked buffer along with its metadata
+#[derive(Debug, Clone, Copy)]
+pub struct BufferView<'a> {
+    pub meta: FrameMeta,
+    pub data: &'a [u8],
+}
+
 impl Clone for BufferRef {
     fn clone(&self) -> Self {
         unsafe { ffi::mmal_buffer_header_acquire(self.p.as_ptr()); }
@@ -411,6 +483,12 @@ impl<P: ComponentPort> PortPoolHandle<P> {
             Some(BufferRef { p })
         }
     }
+    /// Waits for a buffer to be returned to the pool at most specified number of milliseconds
+    pub fn timedwait_buffer(&self, timeout_ms: u32) -> Option<BufferRef> {
+        unsafe {
+            BufferRef::new(ffi::mmal_queue_timedwait(self.pool.as_ref().queue, timeout_ms))
+        }
+    }
 }
 
 impl<P: ComponentPort> Drop for PortPoolHandle<P> {
@@ -479,13 +557,20 @@ impl Drop for QueueHandle {
 /// mmal: mmal_component_create_core: could not find component 'vc.camera_info'
 ///
 /// See this for more info https://github.com/thaytan/gst-rpicamsrc/issues/28
-pub fn init() {
+///
+/// With the `dynamic-loading` feature, this also opens the MMAL libraries and fails with `Cause::NotAvailable`
+/// if they can't be loaded.
+pub fn init() -> Result<()> {
+    #[cfg(all(feature = "dynamic-loading", not(feature = "emulation")))]
+    crate::dynamic_loading::load().map_err(|e| MmalError::new(Cause::NotAvailable, e.to_owned()))?;
+
     static INIT: Once = Once::new();
     INIT.call_once(|| unsafe {
         ffi::bcm_host_init();
         ffi::vcos_init();
         ffi::mmal_vc_init();
     });
+    Ok(())
 }
 
 //------------------------------------------------------------------------------------------------------------------------------
@@ -502,6 +587,25 @@ pub trait ParamIO<P: ComponentPort>  {
 /// Offset of 1st/the only port
 pub const DEFAULT_PORT_OFFSET: isize = 0;
 
+/// Uncompressed picture encodings accepted by the encoders' input ports
+pub const RAW_PICTURE_ENCODINGS: &[u32] = &[
+    ffi::MMAL_ENCODING_OPAQUE,
+    ffi::MMAL_ENCODING_I420,
+    ffi::MMAL_ENCODING_YV12,
+    ffi::MMAL_ENCODING_NV12,
+    ffi::MMAL_ENCODING_NV21,
+    ffi::MMAL_ENCODING_I422,
+    ffi::MMAL_ENCODING_YUYV,
+    ffi::MMAL_ENCODING_YVYU,
+    ffi::MMAL_ENCODING_UYVY,
+    ffi::MMAL_ENCODING_VYUY,
+    ffi::MMAL_ENCODING_RGB16,
+    ffi::MMAL_ENCODING_RGB24,
+    ffi::MMAL_ENCODING_BGR24,
+    ffi::MMAL_ENCODING_RGBA,
+    ffi::MMAL_ENCODING_BGRA,
+];
+
 /// Port configuration values
 pub trait PortConfig {
     unsafe fn apply_format(&self, port: *mut ffi::MMAL_PORT_T);
@@ -514,6 +618,9 @@ pub trait ComponentPort {
     unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T;
     fn name() -> &'static str;
 
+    /// Encodings the port accepts from a connected output port; `None` if unrestricted. Checked by `PipelineBuilder`.
+    fn accepted_encodings() -> Option<&'static [u32]> { None }
+
     fn write<'p>(component: impl AsRef<ComponentHandle<Self::E>>, param: &'p dyn ParamIO<Self>) -> Result<()> 
     where Self: 'p + Sized{
         param.write(component.as_ref())
@@ -559,6 +666,11 @@ pub trait ComponentPort {
         }
     }
 
+    /// The committed format of the port
+    fn format(component: impl AsRef<ComponentHandle<Self::E>>) -> EsFormat {
+        unsafe { EsFormat::from_ffi(&*(*Self::get_port(component.as_ref())).format) }
+    }
+
     fn get_buffers_config(component: &ComponentHandle<Self::E>) -> ((u32, u32, u32), (u32, u32, u32)) {
         unsafe {
             let p = & *Self::get_port(component.as_ref());
@@ -596,7 +708,7 @@ pub trait ComponentPort {
 pub struct SinkAggregate<P: ComponentPort> {
     q: QueueHandle,
     p: PortPoolHandle<P>,
-    w: Option<Waker>,
+    w: Mutex<Option<Waker>>,
     _self: NonNull<Self>,
     _p: PhantomPinned
 }
@@ -606,7 +718,7 @@ impl<P: ComponentPort> SinkAggregate<P> {
     pub fn create(c: ComponentHandle<P::E>) -> Result<Pin<Box<Self>>> {
         let q = QueueHandle::create()?;
         let p = PortPoolHandle::create(c)?;
-        let rv = Self { q, p, w: None, _self: NonNull::dangling(), _p: PhantomPinned };
+        let rv = Self { q, p, w: Mutex::new(None), _self: NonNull::dangling(), _p: PhantomPinned };
         let mut rv = Box::new(rv);
         rv._self = rv.as_ref().into();
         unsafe { Ok(Pin::new_unchecked(rv)) }
@@ -636,7 +748,7 @@ impl<P: ComponentPort> SinkAggregate<P> {
         let udp = (*port).userdata as *mut Self;
         let ud = if let Some(ud) = udp.as_mut() { ud } else { return };
         ud.q.put_unsafe(buffer);
-        ud.w.take().map(|w| w.wake());
+        ud.wake();
     }
 
     pub fn feed_one(&self) -> Result<()> {
@@ -655,8 +767,13 @@ impl<P: ComponentPort> SinkAggregate<P> {
     /// `Ok((false, user_data))` to indicate that the fuffer shall be returned (ungot) to the queue, or `Err(_)` upon error.
     /// 
     /// The method returns the return value of the consumer closure.
-    pub fn consume<R>(&self, b: BufferRef, f: impl FnMut(FrameFlags, &[u8]) -> Result<(bool, R)>) -> Result<(bool, R)>  {
-        let (is_consumed, user_data) = b.do_locked(f)?;
+    pub fn consume<R>(&self, b: BufferRef, mut f: impl FnMut(FrameFlags, &[u8]) -> Result<(bool, R)>) -> Result<(bool, R)>  {
+        self.consume_view(b, |view| f(view.meta.flags, view.data))
+    }
+
+    /// Same as `consume`, but the closure gets the buffer metadata along with the payload
+    pub fn consume_view<R>(&self, b: BufferRef, f: impl FnMut(BufferView<'_>) -> Result<(bool, R)>) -> Result<(bool, R)>  {
+        let (is_consumed, user_data) = b.do_locked_view(f)?;
         if is_consumed {
             // drop buffer so it's released to the pool
             std::mem::drop(b);
@@ -675,22 +792,296 @@ impl<P: ComponentPort> SinkAggregate<P> {
     pub fn timedwait(&self, timeout_ms: u32) -> Option<BufferRef> { self.q.timedwait(timeout_ms) }
     /// Get a buffer from the queue, if any
     pub fn get(&self) -> Option<BufferRef> { self.q.get() }
+
+    /// Stream of complete frames received on the port. See `FrameStream`.
+    pub fn frames(&self) -> FrameStream<'_, P> {
+        FrameStream { sa: self, assembler: FrameAssembler::new(), done: false }
+    }
+
+    fn is_enabled(&self) -> bool {
+        unsafe { (*self.p.get_port()).is_enabled != 0 }
+    }
+
+    fn register(&self, waker: &Waker) {
+        *self.w.lock().unwrap_or_else(|e| e.into_inner()) = Some(waker.clone());
+    }
+
+    fn wake(&self) {
+        if let Some(w) = self.w.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            w.wake()
+        }
+    }
 }
 
 impl<P: ComponentPort> std::future::Future for SinkAggregate<P> {
     type Output = BufferRef;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        unsafe {
-            let sa = self.get_unchecked_mut();
+        self.register(cx.waker());
 
-            sa.w = Some(cx.waker().clone());
+        if let Some(b) = self.q.get() {
+            Poll::Ready(b)
+        } else {
+            Poll::Pending
+        }
+    }
+}
 
-            if let Some(b) = sa.q.get() {
-                Poll::Ready(b)
-            } else {
-                Poll::Pending
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Default time `SourceAggregate` waits for a free buffer
+pub const DEFAULT_SOURCE_TIMEOUT_MS: u32 = 5000;
+
+/// Feeds data into an input port
+///
+/// Data is copied into buffers of a pool created for the port, which the port returns to the pool once consumed.
+/// The `send*` methods block until a buffer is free, at most for the timeout (`DEFAULT_SOURCE_TIMEOUT_MS` unless set),
+/// and fail with `Cause::QueueEmpty` afterwards.
+pub struct SourceAggregate<P: ComponentPort> {
+    p: PortPoolHandle<P>,
+    timeout_ms: u32,
+}
+
+impl<P: ComponentPort> SourceAggregate<P> {
+
+    pub fn create(c: ComponentHandle<P::E>) -> Result<Self> {
+        let p = PortPoolHandle::create(c)?;
+        Ok(Self { p, timeout_ms: DEFAULT_SOURCE_TIMEOUT_MS })
+    }
+
+    pub fn enable(&self) -> Result<()> {
+        let status = unsafe { ffi::mmal_port_enable(self.p.get_port(), Some(Self::cb)) };
+        cst!(status, "{}: unable to enable", P::name())
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        let status = unsafe { ffi::mmal_port_disable(self.p.get_port()) };
+        cst!(status, "{}: unable to disable", P::name())
+    }
+
+    unsafe extern "C" fn cb(_port: *mut ffi::MMAL_PORT_T, buffer: *mut ffi::MMAL_BUFFER_HEADER_T) {
+        ffi::mmal_buffer_header_release(buffer);
+    }
+
+    pub fn set_timeout(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Sends a chunk of data, split over as many buffers as needed.
+    ///
+    /// `FLAG_FRAME_START` and `pts` are only set on the first buffer, `FLAG_FRAME_END` and `FLAG_EOS` only on the last one,
+    /// other flags on all of them. An empty chunk is sent as one empty buffer, e.g. to signal `FLAG_EOS`.
+    pub fn send(&self, data: &[u8], flags: u32, pts: Option<Duration>) -> Result<()> {
+        let mut offset = 0;
+        loop {
+            let b = self.p.timedwait_buffer(self.timeout_ms)
+                .ok_or_else(|| MmalError::new(Cause::QueueEmpty, format!("{}: no free buffer", P::name())))?;
+            let last = unsafe {
+                let h = &mut *b.p.as_ptr();
+                if h.alloc_size == 0 && !data.is_empty() {
+                    return Err(MmalError::with_status(ffi::MMAL_STATUS_T::MMAL_ENOSPC,
+                        format!("{}: buffers have no payload to send {} bytes", P::name(), data.len())));
+                }
+                let n = (data.len() - offset).min(h.alloc_size as usize);
+                cst!(ffi::mmal_buffer_header_mem_lock(h), "could not lock buffer")?;
+                std::ptr::copy_nonoverlapping(data.as_ptr().add(offset), h.data, n);
+                ffi::mmal_buffer_header_mem_unlock(h);
+                let (first, last) = (offset == 0, offset + n == data.len());
+                h.offset = 0;
+                h.length = n as u32;
+                h.flags = flags & !(FrameFlags::FLAG_FRAME | FrameFlags::FLAG_EOS);
+                if first { h.flags |= flags & FrameFlags::FLAG_FRAME_START }
+                if last { h.flags |= flags & (FrameFlags::FLAG_FRAME_END | FrameFlags::FLAG_EOS) }
+                h.pts = if first { duration_to_mmal_time(pts) } else { ffi::MMAL_TIME_UNKNOWN };
+                h.dts = ffi::MMAL_TIME_UNKNOWN;
+                offset += n;
+                last
+            };
+            let status = unsafe { ffi::mmal_port_send_buffer(self.p.get_port(), b.p.as_ptr()) };
+            // the buffer belongs to the port now, or has to be released
+            if status == ffi::MMAL_STATUS_T::MMAL_SUCCESS {
+                mem::forget(b);
+            }
+            cst!(status, "{}: could not send buffer", P::name())?;
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sends a complete frame, e.g. an H.264 access unit in Annex-B format or a JPEG picture
+    pub fn send_frame(&self, data: &[u8], pts: Option<Duration>) -> Result<()> {
+        self.send(data, FrameFlags::FLAG_FRAME, pts)
+    }
+
+    /// Sends the NAL units of an access unit, given without start codes, as one frame in Annex-B format
+    pub fn send_nal_units<'a>(&self, nal_units: impl IntoIterator<Item=&'a [u8]>, pts: Option<Duration>) -> Result<()> {
+        let mut data = Vec::new();
+        for nal in nal_units {
+            data.extend_from_slice(&[0, 0, 0, 1]);
+            data.extend_from_slice(nal);
+        }
+        self.send_frame(&data, pts)
+    }
+
+    /// Signals the end of the stream
+    pub fn send_eos(&self) -> Result<()> {
+        self.send(&[], FrameFlags::FLAG_EOS, None)
+    }
+}
+
+/// Complete frame, assembled from the buffers between `FLAG_FRAME_START` and `FLAG_FRAME_END`
+#[derive(Debug, Clone, Default)]
+pub struct Frame {
+    /// Union of the flags of all buffers of the frame
+    pub flags: FrameFlags,
+    /// Timestamps of the first buffer of the frame
+    pub pts: Option<Duration>,
+    pub dts: Option<Duration>,
+    pub data: Vec<u8>,
+}
+
+/// Concatenates the payloads of multi-buffer frames
+#[derive(Debug, Default)]
+pub struct FrameAssembler {
+    frame: Frame,
+    started: bool,
+}
+
+impl FrameAssembler {
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds a buffer to the current frame. Returns the frame if the buffer completes it.
+    ///
+    /// A buffer flagged `FLAG_FRAME_START` discards an incomplete frame, if any. Event buffers, see `FrameMeta::cmd`,
+    /// are not part of the stream and are skipped.
+    pub fn push(&mut self, b: BufferView<'_>) -> Option<Frame> {
+        if b.meta.cmd != 0 {
+            return None;
+        }
+        if b.meta.flags.test_one(FrameFlags::FLAG_FRAME_START) && self.started {
+            self.reset();
+        }
+        if !self.started {
+            self.started = true;
+            self.frame.pts = b.meta.pts;
+            self.frame.dts = b.meta.dts;
+        }
+        self.frame.flags.flags |= b.meta.flags.flags;
+        self.frame.data.extend_from_slice(b.data);
+        if b.meta.flags.is_terminal_frame() {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// Takes the current frame, even if incomplete. Returns `None` if no buffers were pushed since the last frame.
+    pub fn flush(&mut self) -> Option<Frame> {
+        if !self.started {
+            return None;
+        }
+        self.started = false;
+        Some(mem::take(&mut self.frame))
+    }
+
+    /// Discards the current frame
+    pub fn reset(&mut self) {
+        self.started = false;
+        self.frame = Frame::default();
+    }
+}
+
+/// `futures_core::Stream` of complete frames received by a `SinkAggregate`.
+///
+/// Buffers are recycled to the port as soon as their payload is copied, event buffers are skipped. The stream ends after
+/// a buffer flagged `FLAG_EOS`, or when the port is disabled. Buffers must have been fed to the port, e.g. by `feed_all()`,
+/// before polling the stream.
+pub struct FrameStream<'a, P: ComponentPort> {
+    sa: &'a SinkAggregate<P>,
+    assembler: FrameAssembler,
+    done: bool,
+}
+
+impl<'a, P: ComponentPort> FrameStream<'a, P> {
+    fn end(&mut self) -> Poll<Option<Result<Frame>>> {
+        self.done = true;
+        Poll::Ready(self.assembler.flush().filter(|f| !f.data.is_empty()).map(Ok))
+    }
+}
+
+impl<'a, P: ComponentPort> futures_core::Stream for FrameStream<'a, P> {
+    type Item = Result<Frame>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let fs = self.get_mut();
+        if fs.done {
+            return Poll::Ready(None);
+        }
+        fs.sa.register(cx.waker());
+
+        loop {
+            let b = if let Some(b) = fs.sa.get() { b } else {
+                return if fs.sa.is_enabled() { Poll::Pending } else { fs.end() }
+            };
+            if !fs.sa.is_enabled() {
+                // buffers returned by a disabled port go straight back to the pool
+                continue;
+            }
+            let assembler = &mut fs.assembler;
+            let (eos, frame) = match fs.sa.consume_view(b, |view| {
+                let eos = view.meta.cmd == 0 && view.meta.flags.test_one(FrameFlags::FLAG_EOS);
+                Ok((true, (eos, assembler.push(view))))
+            }) {
+                Ok((_, r)) => r,
+                Err(_) if !fs.sa.is_enabled() => return fs.end(),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+            if eos {
+                if let Some(frame) = frame.filter(|f| !f.data.is_empty()) {
+                    fs.done = true;
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+                return fs.end();
+            }
+            if let Some(frame) = frame {
+                return Poll::Ready(Some(Ok(frame)));
             }
         }
     }
 }
+
+#[test]
+fn test_frame_assembler() {
+    let view = |flags, pts: Option<u64>, data| BufferView {
+        meta: FrameMeta { flags: FrameFlags { flags }, pts: pts.map(Duration::from_micros), ..Default::default() },
+        data
+    };
+    let event = |data| BufferView { meta: FrameMeta { cmd: ffi::MMAL_EVENT_FORMAT_CHANGED, ..Default::default() }, data };
+    let mut fa = FrameAssembler::new();
+    assert!(fa.push(view(FrameFlags::FLAG_FRAME_START | FrameFlags::FLAG_KEYFRAME, Some(100), b"ab")).is_none());
+    assert!(fa.push(view(0, None, b"cd")).is_none());
+    let f = fa.push(view(FrameFlags::FLAG_FRAME_END, Some(200), b"ef")).unwrap();
+    assert_eq!(f.data, b"abcdef");
+    assert_eq!(f.pts, Some(Duration::from_micros(100)));
+    assert!(f.flags.test_all(FrameFlags::FLAG_FRAME | FrameFlags::FLAG_KEYFRAME));
+    assert!(fa.flush().is_none());
+
+    // an incomplete frame is dropped when the next one starts
+    assert!(fa.push(view(FrameFlags::FLAG_FRAME_START, Some(300), b"xx")).is_none());
+    let f = fa.push(view(FrameFlags::FLAG_FRAME, Some(400), b"gh")).unwrap();
+    assert_eq!(f.data, b"gh");
+    assert_eq!(f.pts, Some(Duration::from_micros(400)));
+    assert!(!f.flags.test_one(FrameFlags::FLAG_KEYFRAME));
+
+    // events between and within frames are not part of them
+    assert!(fa.push(event(b"format")).is_none());
+    assert!(fa.flush().is_none());
+    assert!(fa.push(view(FrameFlags::FLAG_FRAME_START, Some(500), b"ij")).is_none());
+    assert!(fa.push(event(b"format")).is_none());
+    let f = fa.push(view(FrameFlags::FLAG_FRAME_END, None, b"kl")).unwrap();
+    assert_eq!((f.data.as_slice(), f.pts), (&b"ijkl"[..], Some(Duration::from_micros(500))));
+
+    assert_eq!(mmal_time_to_duration(ffi::MMAL_TIME_UNKNOWN), None);
+    assert_eq!(duration_to_mmal_time(None), ffi::MMAL_TIME_UNKNOWN);
+}
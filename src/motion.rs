@@ -0,0 +1,236 @@
+use super::*;
+use std::time::Duration;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Size of the H.264 macroblocks, in pixels
+pub const MACROBLOCK_SIZE: u32 = 16;
+
+/// Motion vector of a macroblock, as estimated by the H.264 encoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MotionVector {
+    pub dx: i8,
+    pub dy: i8,
+    /// Sum of absolute differences between the macroblock and its reference, high if no good match was found
+    pub sad: u16,
+}
+
+impl MotionVector {
+    pub fn length_squared(&self) -> u32 {
+        (self.dx as i32 * self.dx as i32 + self.dy as i32 * self.dy as i32) as u32
+    }
+}
+
+/// Motion vectors of a frame, from the codec side info buffers which the encoder sends after each frame when
+/// `PInlineVectors` is enabled.
+///
+/// The side info holds 4 bytes per macroblock: `dx` and `dy` as signed bytes and the SAD as little endian `u16`, row by
+/// row. Each row has one more macroblock than the picture, which is dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MotionField {
+    columns: usize,
+    rows: usize,
+    vectors: Vec<MotionVector>,
+    /// Timestamp of the frame the vectors belong to
+    pub pts: Option<Duration>,
+}
+
+impl MotionField {
+    /// Parses the side info of a `width` x `height` picture
+    pub fn parse(data: &[u8], width: u32, height: u32) -> Result<Self> {
+        let columns = width.div_ceil(MACROBLOCK_SIZE) as usize;
+        let rows = height.div_ceil(MACROBLOCK_SIZE) as usize;
+        let stride = (columns + 1) * 4;
+        if data.len() < stride * rows {
+            return Err(MmalError::new(Cause::InvalidBitstream,
+                format!("motion vectors of {width}x{height} need {} bytes, got {}", stride * rows, data.len())));
+        }
+        let vectors = data.chunks_exact(stride).take(rows)
+            .flat_map(|row| row[..columns * 4].chunks_exact(4))
+            .map(|mb| MotionVector { dx: mb[0] as i8, dy: mb[1] as i8, sad: u16::from_le_bytes([mb[2], mb[3]]) })
+            .collect();
+        Ok(Self { columns, rows, vectors, pts: None })
+    }
+
+    /// Parses a frame assembled from the encoder output, which must be flagged `FrameFlags::FLAG_CODECSIDEINFO`
+    pub fn from_frame(frame: &Frame, width: u32, height: u32) -> Result<Self> {
+        if !frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            return Err(MmalError::new(Cause::InvalidBitstream, "not a codec side info frame".to_owned()));
+        }
+        Ok(Self { pts: frame.pts, ..Self::parse(&frame.data, width, height)? })
+    }
+
+    /// Number of macroblocks per row
+    pub fn columns(&self) -> usize { self.columns }
+    pub fn rows(&self) -> usize { self.rows }
+
+    /// Vector of the macroblock at `column`, `row`
+    pub fn get(&self, column: usize, row: usize) -> Option<MotionVector> {
+        (column < self.columns).then(|| self.vectors.get(row * self.columns + column).copied()).flatten()
+    }
+
+    /// The vectors with the column and row of their macroblock, row by row
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, MotionVector)> + '_ {
+        self.vectors.iter().enumerate().map(|(i, mv)| (i % self.columns, i / self.columns, *mv))
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Rectangle of the picture, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MotionRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MotionRegion {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self { Self { x, y, width, height } }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && y >= self.y && x - self.x < self.width && y - self.y < self.height
+    }
+}
+
+/// Motion found in a region by `MotionDetector::detect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotionDetection {
+    /// Index of the region, in the order they were added. 0 for the whole picture if no region was added.
+    pub region: usize,
+    /// Number of moving macroblocks in the region
+    pub blocks: usize,
+    /// Bounding box of the moving macroblocks
+    pub bounds: MotionRegion,
+}
+
+/// Detects motion in regions of interest from the encoder motion vectors, without decoding the frames.
+///
+/// A macroblock moves if its vector is at least as long as the vector threshold, or if its SAD reaches the SAD
+/// threshold, when set, as the encoder finds no good vector for objects entering the picture. A region has motion if
+/// at least the minimum area of its macroblocks move; macroblocks belong to the regions which contain their center.
+#[derive(Debug, Clone)]
+pub struct MotionDetector {
+    vector_threshold: u32,
+    sad_threshold: Option<u16>,
+    min_blocks: usize,
+    regions: Vec<MotionRegion>,
+}
+
+impl Default for MotionDetector {
+    fn default() -> Self {
+        Self { vector_threshold: 4, sad_threshold: None, min_blocks: 4, regions: Vec::new() }
+    }
+}
+
+impl MotionDetector {
+    /// A detector for the whole picture, with vectors of length 4 and an area of 4 macroblocks
+    pub fn new() -> Self { Self::default() }
+
+    /// Minimum length of the vectors of moving macroblocks
+    pub fn with_vector_threshold(mut self, length: u8) -> Self {
+        self.vector_threshold = length as u32;
+        self
+    }
+
+    /// SAD from which macroblocks move, whatever their vector
+    pub fn with_sad_threshold(mut self, sad: u16) -> Self {
+        self.sad_threshold = Some(sad);
+        self
+    }
+
+    /// Number of moving macroblocks a region needs to have motion, at least 1
+    pub fn with_min_area(mut self, blocks: usize) -> Self {
+        self.min_blocks = blocks.max(1);
+        self
+    }
+
+    /// Adds a region of interest. Without regions the whole picture is watched.
+    pub fn with_region(mut self, region: MotionRegion) -> Self {
+        self.regions.push(region);
+        self
+    }
+
+    pub fn is_moving(&self, mv: &MotionVector) -> bool {
+        mv.length_squared() >= self.vector_threshold * self.vector_threshold
+            || self.sad_threshold.is_some_and(|sad| mv.sad >= sad)
+    }
+
+    /// Returns the regions with motion
+    pub fn detect(&self, field: &MotionField) -> Vec<MotionDetection> {
+        let picture = [MotionRegion::new(0, 0, field.columns as u32 * MACROBLOCK_SIZE, field.rows as u32 * MACROBLOCK_SIZE)];
+        let regions = if self.regions.is_empty() { &picture[..] } else { &self.regions };
+        regions.iter().enumerate().filter_map(|(region, r)| {
+            let mut blocks = 0;
+            let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+            for (column, row, mv) in field.iter() {
+                let (x, y) = (column as u32 * MACROBLOCK_SIZE, row as u32 * MACROBLOCK_SIZE);
+                if !r.contains(x + MACROBLOCK_SIZE / 2, y + MACROBLOCK_SIZE / 2) || !self.is_moving(&mv) {
+                    continue;
+                }
+                blocks += 1;
+                (left, top) = (left.min(x), top.min(y));
+                (right, bottom) = (right.max(x + MACROBLOCK_SIZE), bottom.max(y + MACROBLOCK_SIZE));
+            }
+            (blocks >= self.min_blocks).then(|| MotionDetection {
+                region, blocks, bounds: MotionRegion::new(left, top, right - left, bottom - top)
+            })
+        }).collect()
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_motion_field() {
+    // 40x20 pixels: 3x2 macroblocks, plus the extra column
+    let mut data = vec![0u8; 4 * 4 * 2];
+    data[4..8].copy_from_slice(&[0xfe, 3, 0x34, 0x12]);
+    data[12..16].copy_from_slice(&[0x7f, 0x7f, 0xff, 0xff]);
+    data[16 + 8..16 + 12].copy_from_slice(&[0, 0x80, 1, 0]);
+    let field = MotionField::parse(&data, 40, 20).unwrap();
+    assert_eq!((field.columns(), field.rows()), (3, 2));
+    assert_eq!(field.get(1, 0), Some(MotionVector { dx: -2, dy: 3, sad: 0x1234 }));
+    assert_eq!(field.get(2, 1), Some(MotionVector { dx: 0, dy: -128, sad: 1 }));
+    assert_eq!(field.get(3, 0), None);
+    // the extra column is dropped
+    assert_eq!(field.iter().filter(|(_, _, mv)| *mv != MotionVector::default()).count(), 2);
+    assert_eq!(field.iter().nth(5).map(|(c, r, _)| (c, r)), Some((2, 1)));
+
+    assert!(matches!(MotionField::parse(&data[..31], 40, 20).unwrap_err().cause(), Cause::InvalidBitstream));
+    let frame = Frame { flags: FrameFlags::from_bits(FrameFlags::FLAG_FRAME), data, ..Default::default() };
+    assert!(MotionField::from_frame(&frame, 40, 20).is_err());
+}
+
+#[test]
+fn test_motion_detector() {
+    // 128x64 pixels, a 2x2 macroblock object moving right at column 5, noise elsewhere
+    let (columns, rows) = (8, 4);
+    let mut data = vec![0u8; (columns + 1) * rows * 4];
+    let mut set = |column: usize, row: usize, mv: [u8; 4]| {
+        let i = (row * (columns + 1) + column) * 4;
+        data[i..i + 4].copy_from_slice(&mv);
+    };
+    for (column, row) in [(5, 1), (6, 1), (5, 2), (6, 2)] {
+        set(column, row, [6, 0, 0x00, 0x02]);
+    }
+    set(0, 0, [1, 1, 0, 0]);
+    set(1, 3, [0, 0, 0x00, 0x10]);
+    let field = MotionField::parse(&data, 128, 64).unwrap();
+
+    let whole = MotionDetector::new().detect(&field);
+    assert_eq!(whole, [MotionDetection { region: 0, blocks: 4, bounds: MotionRegion::new(80, 16, 32, 32) }]);
+    assert!(MotionDetector::new().with_min_area(5).detect(&field).is_empty());
+    assert!(MotionDetector::new().with_vector_threshold(7).detect(&field).is_empty());
+    // the large SAD of the macroblock without a vector counts with a SAD threshold
+    let detector = MotionDetector::new().with_sad_threshold(0x1000).with_min_area(1)
+        .with_region(MotionRegion::new(0, 0, 64, 64))
+        .with_region(MotionRegion::new(90, 0, 38, 64));
+    let detections = detector.detect(&field);
+    assert_eq!(detections, [
+        MotionDetection { region: 0, blocks: 1, bounds: MotionRegion::new(16, 48, 16, 16) },
+        // macroblocks whose center is in the region
+        MotionDetection { region: 1, blocks: 2, bounds: MotionRegion::new(96, 16, 16, 32) },
+    ]);
+}
@@ -0,0 +1,564 @@
+use super::*;
+use std::{io::Write, path::Path, time::Duration};
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Timescale of the video track, the usual 90kHz clock of MPEG video
+pub const MP4_TIMESCALE: u32 = 90_000;
+
+/// Sample duration used when neither the SPS timing nor the timestamps give one, i.e. 30 fps
+const DEFAULT_SAMPLE_DURATION: u32 = MP4_TIMESCALE / 30;
+
+const TRACK_ID: u32 = 1;
+
+/// Identity transformation, ISO/IEC 14496-12 8.2.2
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// `sample_depends_on` = 2 (I-picture)
+const SAMPLE_FLAGS_KEYFRAME: u32 = 0x0200_0000;
+/// `sample_depends_on` = 1, `sample_is_non_sync_sample`
+const SAMPLE_FLAGS_NON_KEYFRAME: u32 = 0x0101_0000;
+
+/// Appends a box, `f` writes its payload
+fn mp4_box(out: &mut Vec<u8>, fourcc: &[u8; 4], f: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(fourcc);
+    f(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Appends a box with the version and flags header
+fn mp4_full_box(out: &mut Vec<u8>, fourcc: &[u8; 4], version: u8, flags: u32, f: impl FnOnce(&mut Vec<u8>)) {
+    mp4_box(out, fourcc, |out| {
+        out.extend_from_slice(&((version as u32) << 24 | flags & 0x00ff_ffff).to_be_bytes());
+        f(out)
+    })
+}
+
+trait PutBe {
+    fn u16(&mut self, v: u16);
+    fn u32(&mut self, v: u32);
+    fn u64(&mut self, v: u64);
+    fn zeros(&mut self, n: usize);
+}
+
+impl PutBe for Vec<u8> {
+    fn u16(&mut self, v: u16) { self.extend_from_slice(&v.to_be_bytes()) }
+    fn u32(&mut self, v: u32) { self.extend_from_slice(&v.to_be_bytes()) }
+    fn u64(&mut self, v: u64) { self.extend_from_slice(&v.to_be_bytes()) }
+    fn zeros(&mut self, n: usize) { self.resize(self.len() + n, 0) }
+}
+
+fn to_ticks(d: Duration) -> u64 {
+    (d.as_nanos() * MP4_TIMESCALE as u128 / 1_000_000_000) as u64
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[derive(Debug)]
+struct Mp4Sample {
+    /// NAL units with 4 byte length prefixes
+    data: Vec<u8>,
+    /// Presentation time, equal to the decode time as the encoder emits no B-frames
+    pts: u64,
+    duration: u32,
+    keyframe: bool,
+}
+
+/// Fragmented MP4 (ISO BMFF) muxer for an H.264 elementary stream, e.g. the output of `VideoEncoderOutputPort`.
+///
+/// Takes Annex-B access units and returns the bytes to write: the init segment (`ftyp` + `moov`) once the first
+/// SPS, PPS and IDR frame were seen, then a `moof` + `mdat` fragment per GOP, or more GOPs if they are shorter than
+/// the fragment duration. Access units before the first IDR frame are dropped. The sample description is built from the
+/// first SPS/PPS; parameter sets sent later, e.g. with `PInlineHeader`, are assumed to be identical and are removed
+/// from the samples.
+#[derive(Debug)]
+pub struct Fmp4Muxer {
+    fragment_duration: u64,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    init_written: bool,
+    /// Duration of the latest sample, assumed for the next one until its timestamp is known
+    sample_duration: u32,
+    first_pts: Option<u64>,
+    last_pts: Option<u64>,
+    sequence_number: u32,
+    pending: Vec<Mp4Sample>,
+}
+
+impl Default for Fmp4Muxer {
+    fn default() -> Self {
+        Self {
+            fragment_duration: 0, sps: None, pps: None, init_written: false, sample_duration: DEFAULT_SAMPLE_DURATION,
+            first_pts: None, last_pts: None, sequence_number: 0, pending: Vec::new(),
+        }
+    }
+}
+
+impl Fmp4Muxer {
+    /// A muxer writing one fragment per GOP
+    pub fn new() -> Self { Self::default() }
+
+    /// Groups GOPs into fragments of at least `duration`. Fragments are always cut at keyframes.
+    pub fn with_fragment_duration(mut self, duration: Duration) -> Self {
+        self.fragment_duration = to_ticks(duration);
+        self
+    }
+
+    /// Adds an access unit in Annex-B format. `pts` of `None` continues the timeline at the frame rate of the stream.
+    ///
+    /// Returns the init segment and the fragments completed by this access unit, possibly empty.
+    pub fn push(&mut self, access_unit: &[u8], pts: Option<Duration>) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(access_unit.len() + 16);
+        let mut keyframe = false;
+        let mut has_vcl = false;
+        for nal in split_annex_b(access_unit) {
+            match nal.nal_type() {
+                NalUnitType::Sps => if self.sps.is_none() { self.sps = Some(nal.data.to_vec()) },
+                NalUnitType::Pps => if self.pps.is_none() { self.pps = Some(nal.data.to_vec()) },
+                NalUnitType::Aud => { }
+                t => {
+                    has_vcl |= t.is_vcl();
+                    keyframe |= nal.is_keyframe();
+                    data.u32(nal.data.len() as u32);
+                    data.extend_from_slice(nal.data);
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        if !has_vcl {
+            return Ok(out);
+        }
+        if !self.init_written {
+            if !keyframe || self.sps.is_none() || self.pps.is_none() {
+                return Ok(out);
+            }
+            self.write_init_segment(&mut out)?;
+        }
+
+        let pts = match (pts.map(to_ticks), self.last_pts) {
+            (Some(pts), Some(last)) if pts > last => pts,
+            (Some(pts), None) => pts,
+            (_, Some(last)) => last + self.sample_duration as u64,
+            (None, None) => 0,
+        };
+        let first_pts = *self.first_pts.get_or_insert(pts);
+        if let Some(last) = self.pending.last_mut() {
+            last.duration = (pts - last.pts) as u32;
+            self.sample_duration = last.duration;
+        }
+        self.last_pts = Some(pts);
+        if keyframe && self.pending.first().is_some_and(|s| pts - s.pts >= self.fragment_duration) {
+            self.write_fragment(&mut out, first_pts);
+        }
+        self.pending.push(Mp4Sample { data, pts, duration: self.sample_duration, keyframe });
+        Ok(out)
+    }
+
+    /// Adds a frame assembled from the encoder output, see `FrameAssembler`. Codec config frames only update the
+    /// parameter sets, codec side info frames, e.g. motion vectors, are ignored.
+    pub fn push_frame(&mut self, frame: &Frame) -> Result<Vec<u8>> {
+        if frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            return Ok(Vec::new());
+        }
+        self.push(&frame.data, frame.pts)
+    }
+
+    /// Returns the last fragment. The duration of its last sample is the duration of the one before it.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(first_pts) = self.first_pts {
+            self.write_fragment(&mut out, first_pts);
+        }
+        out
+    }
+
+    /// Whether the init segment was returned, i.e. the stream has started
+    pub fn is_started(&self) -> bool { self.init_written }
+
+    fn write_init_segment(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let (sps, pps) = (self.sps.as_deref().unwrap_or_default(), self.pps.as_deref().unwrap_or_default());
+        let info = Sps::parse(sps)?;
+        if let Some(rate) = info.timing.and_then(|t| t.frame_rate()).filter(|r| *r > 0.) {
+            self.sample_duration = (MP4_TIMESCALE as f64 / rate).round() as u32;
+        }
+        let (width, height) = (info.width, info.height);
+
+        mp4_box(out, b"ftyp", |out| {
+            out.extend_from_slice(b"isom");
+            out.u32(0x200);
+            out.extend_from_slice(b"isomiso6avc1mp41");
+        });
+        mp4_box(out, b"moov", |out| {
+            mp4_full_box(out, b"mvhd", 0, 0, |out| {
+                out.zeros(8); // creation_time, modification_time
+                out.u32(MP4_TIMESCALE);
+                out.u32(0); // duration, unknown for fragmented files
+                out.u32(0x0001_0000); // rate 1.0
+                out.u16(0x0100); // volume 1.0
+                out.zeros(10);
+                UNITY_MATRIX.iter().for_each(|m| out.u32(*m));
+                out.zeros(24); // pre_defined
+                out.u32(TRACK_ID + 1); // next_track_ID
+            });
+            mp4_box(out, b"trak", |out| {
+                mp4_full_box(out, b"tkhd", 0, 3, |out| { // track_enabled | track_in_movie
+                    out.zeros(8);
+                    out.u32(TRACK_ID);
+                    out.zeros(4);
+                    out.u32(0); // duration
+                    out.zeros(8);
+                    out.zeros(8); // layer, alternate_group, volume, reserved
+                    UNITY_MATRIX.iter().for_each(|m| out.u32(*m));
+                    // presentation size, in square pixels
+                    let display_width = width as u64 * info.sar.0.max(1) as u64 / info.sar.1.max(1) as u64;
+                    out.u32((display_width as u32) << 16);
+                    out.u32(height << 16);
+                });
+                mp4_box(out, b"mdia", |out| {
+                    mp4_full_box(out, b"mdhd", 0, 0, |out| {
+                        out.zeros(8);
+                        out.u32(MP4_TIMESCALE);
+                        out.u32(0);
+                        out.u16(0x55c4); // language `und`
+                        out.u16(0);
+                    });
+                    mp4_full_box(out, b"hdlr", 0, 0, |out| {
+                        out.u32(0);
+                        out.extend_from_slice(b"vide");
+                        out.zeros(12);
+                        out.extend_from_slice(b"VideoHandler\0");
+                    });
+                    mp4_box(out, b"minf", |out| {
+                        mp4_full_box(out, b"vmhd", 0, 1, |out| out.zeros(8));
+                        mp4_box(out, b"dinf", |out| {
+                            mp4_full_box(out, b"dref", 0, 0, |out| {
+                                out.u32(1);
+                                mp4_full_box(out, b"url ", 0, 1, |_| { }); // media data in the same file
+                            });
+                        });
+                        mp4_box(out, b"stbl", |out| {
+                            mp4_full_box(out, b"stsd", 0, 0, |out| {
+                                out.u32(1);
+                                write_avc1(out, &info, sps, pps);
+                            });
+                            // samples are described by the fragments
+                            mp4_full_box(out, b"stts", 0, 0, |out| out.u32(0));
+                            mp4_full_box(out, b"stsc", 0, 0, |out| out.u32(0));
+                            mp4_full_box(out, b"stsz", 0, 0, |out| out.zeros(8));
+                            mp4_full_box(out, b"stco", 0, 0, |out| out.u32(0));
+                        });
+                    });
+                });
+            });
+            mp4_box(out, b"mvex", |out| {
+                mp4_full_box(out, b"trex", 0, 0, |out| {
+                    out.u32(TRACK_ID);
+                    out.u32(1); // default_sample_description_index
+                    out.zeros(12); // default duration, size and flags, all given by `trun`
+                });
+            });
+        });
+        self.init_written = true;
+        Ok(())
+    }
+
+    fn write_fragment(&mut self, out: &mut Vec<u8>, first_pts: u64) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let samples = std::mem::take(&mut self.pending);
+        self.sequence_number += 1;
+
+        let moof_start = out.len();
+        let mut data_offset_pos = 0;
+        mp4_box(out, b"moof", |out| {
+            mp4_full_box(out, b"mfhd", 0, 0, |out| out.u32(self.sequence_number));
+            mp4_box(out, b"traf", |out| {
+                mp4_full_box(out, b"tfhd", 0, 0x02_0000, |out| out.u32(TRACK_ID)); // default-base-is-moof
+                mp4_full_box(out, b"tfdt", 1, 0, |out| out.u64(samples[0].pts - first_pts));
+                // data-offset, sample-duration, sample-size and sample-flags present
+                mp4_full_box(out, b"trun", 0, 0x00_0701, |out| {
+                    out.u32(samples.len() as u32);
+                    data_offset_pos = out.len();
+                    out.u32(0);
+                    for s in &samples {
+                        out.u32(s.duration);
+                        out.u32(s.data.len() as u32);
+                        out.u32(if s.keyframe { SAMPLE_FLAGS_KEYFRAME } else { SAMPLE_FLAGS_NON_KEYFRAME });
+                    }
+                });
+            });
+        });
+        // samples start right after the mdat header
+        let data_offset = (out.len() - moof_start + 8) as u32;
+        out[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+        mp4_box(out, b"mdat", |out| samples.iter().for_each(|s| out.extend_from_slice(&s.data)));
+    }
+}
+
+fn write_avc1(out: &mut Vec<u8>, info: &Sps, sps: &[u8], pps: &[u8]) {
+    mp4_box(out, b"avc1", |out| {
+        out.zeros(6);
+        out.u16(1); // data_reference_index
+        out.zeros(16);
+        out.u16(info.width as u16);
+        out.u16(info.height as u16);
+        out.u32(0x0048_0000); // 72 dpi
+        out.u32(0x0048_0000);
+        out.u32(0);
+        out.u16(1); // frame_count
+        out.zeros(32); // compressorname
+        out.u16(0x0018); // depth
+        out.u16(0xffff); // pre_defined = -1
+        mp4_box(out, b"avcC", |out| {
+            out.extend_from_slice(&[1, info.profile_idc, info.constraint_flags, info.level_idc]);
+            out.push(0xfc | 3); // 4 byte NAL unit lengths
+            out.push(0xe0 | 1);
+            out.u16(sps.len() as u16);
+            out.extend_from_slice(sps);
+            out.push(1);
+            out.u16(pps.len() as u16);
+            out.extend_from_slice(pps);
+            if matches!(info.profile_idc, 100 | 110 | 122 | 144 | 244) {
+                out.push(0xfc | info.chroma_format_idc as u8);
+                out.push(0xf8 | (info.bit_depth_luma - 8) as u8);
+                out.push(0xf8 | (info.bit_depth_chroma - 8) as u8);
+                out.push(0); // numOfSequenceParameterSetExt
+            }
+        });
+        if info.sar != (1, 1) {
+            mp4_box(out, b"pasp", |out| {
+                out.u32(info.sar.0);
+                out.u32(info.sar.1);
+            });
+        }
+    });
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Writes the fragmented MP4 stream of an `Fmp4Muxer` to `W`, e.g. a file or a socket
+pub struct Fmp4Writer<W: Write> {
+    muxer: Fmp4Muxer,
+    out: W,
+}
+
+impl Fmp4Writer<std::io::BufWriter<std::fs::File>> {
+    /// Creates or truncates the file at `path`
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(std::io::BufWriter::new(std::fs::File::create(path)?)))
+    }
+}
+
+impl<W: Write> Fmp4Writer<W> {
+    pub fn new(out: W) -> Self { Self::with_muxer(Fmp4Muxer::new(), out) }
+    pub fn with_muxer(muxer: Fmp4Muxer, out: W) -> Self { Self { muxer, out } }
+
+    /// See `Fmp4Muxer::push`
+    pub fn write(&mut self, access_unit: &[u8], pts: Option<Duration>) -> Result<()> {
+        let data = self.muxer.push(access_unit, pts)?;
+        Ok(self.out.write_all(&data)?)
+    }
+
+    /// See `Fmp4Muxer::push_frame`
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let data = self.muxer.push_frame(frame)?;
+        Ok(self.out.write_all(&data)?)
+    }
+
+    /// Writes the last fragment and returns the flushed writer
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(&self.muxer.finish())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    pub fn muxer(&self) -> &Fmp4Muxer { &self.muxer }
+}
+
+/// `Fmp4Writer` for a `tokio::io::AsyncWrite`
+#[cfg(feature = "tokio")]
+pub struct AsyncFmp4Writer<W: tokio::io::AsyncWrite + Unpin> {
+    muxer: Fmp4Muxer,
+    out: W,
+}
+
+#[cfg(feature = "tokio")]
+impl<W: tokio::io::AsyncWrite + Unpin> AsyncFmp4Writer<W> {
+    pub fn new(out: W) -> Self { Self::with_muxer(Fmp4Muxer::new(), out) }
+    pub fn with_muxer(muxer: Fmp4Muxer, out: W) -> Self { Self { muxer, out } }
+
+    /// See `Fmp4Muxer::push`
+    pub async fn write(&mut self, access_unit: &[u8], pts: Option<Duration>) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let data = self.muxer.push(access_unit, pts)?;
+        Ok(self.out.write_all(&data).await?)
+    }
+
+    /// See `Fmp4Muxer::push_frame`
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let data = self.muxer.push_frame(frame)?;
+        Ok(self.out.write_all(&data).await?)
+    }
+
+    /// Writes the last fragment and returns the flushed writer
+    pub async fn finish(mut self) -> Result<W> {
+        use tokio::io::AsyncWriteExt;
+        self.out.write_all(&self.muxer.finish()).await?;
+        self.out.flush().await?;
+        Ok(self.out)
+    }
+
+    pub fn muxer(&self) -> &Fmp4Muxer { &self.muxer }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Top level boxes of `data` as (type, payload)
+#[cfg(test)]
+pub(crate) fn mp4_boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut rv = Vec::new();
+    while data.len() >= 8 {
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        rv.push((data[4..8].try_into().unwrap(), &data[8..size]));
+        data = &data[size..];
+    }
+    assert!(data.is_empty(), "trailing bytes after the last box");
+    rv
+}
+
+#[cfg(test)]
+fn mp4_child<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+    path.iter().fold(data, |data, fourcc| {
+        mp4_boxes(data).into_iter().find(|(t, _)| t == *fourcc).unwrap_or_else(|| panic!("no {:?} box", fourcc)).1
+    })
+}
+
+/// Annex-B access units of a sample stream, split at the access unit delimiters
+#[cfg(test)]
+fn access_units(stream: &[u8]) -> Vec<Vec<u8>> {
+    let mut rv: Vec<Vec<u8>> = Vec::new();
+    for nal in split_annex_b(stream) {
+        if nal.nal_type() == NalUnitType::Aud || rv.is_empty() {
+            rv.push(Vec::new());
+        }
+        let au = rv.last_mut().unwrap();
+        au.extend_from_slice(&[0, 0, 0, 1]);
+        au.extend_from_slice(nal.data);
+    }
+    rv
+}
+
+#[test]
+fn test_fmp4_muxer() {
+    let aus = access_units(crate::h264::SAMPLE_HIGH_1080P);
+    assert_eq!(aus.len(), 4);
+    let mut muxer = Fmp4Muxer::new().with_fragment_duration(Duration::from_millis(100));
+    let mut out = Vec::new();
+    // a P frame before the first IDR frame is dropped
+    assert!(muxer.push(&aus[3], Some(Duration::ZERO)).unwrap().is_empty());
+    for (i, au) in aus.iter().chain(aus.iter()).enumerate() {
+        out.extend(muxer.push(au, Some(Duration::from_millis(1000 + 40 * i as u64))).unwrap());
+        assert!(muxer.is_started());
+    }
+    out.extend(muxer.finish());
+
+    let boxes = mp4_boxes(&out);
+    let types: Vec<_> = boxes.iter().map(|(t, _)| t).collect();
+    assert_eq!(types, [b"ftyp", b"moov", b"moof", b"mdat", b"moof", b"mdat"]);
+
+    let stsd = mp4_child(boxes[1].1, &[b"trak", b"mdia", b"minf", b"stbl", b"stsd"]);
+    let avc1 = mp4_boxes(&stsd[8..])[0].1;
+    assert_eq!((u16::from_be_bytes([avc1[24], avc1[25]]), u16::from_be_bytes([avc1[26], avc1[27]])), (1920, 1080));
+    let avc1_children = mp4_boxes(&avc1[78..]);
+    let (avcc, pasp) = (avc1_children[0].1, avc1_children[1].1);
+    let sps = split_annex_b(crate::h264::SAMPLE_HIGH_1080P).find(|nal| nal.nal_type() == NalUnitType::Sps).unwrap();
+    assert_eq!(&avcc[..6], &[1, 100, 0, 40, 0xff, 0xe1]);
+    assert_eq!(&avcc[8..8 + sps.data.len()], sps.data);
+    assert_eq!(&avcc[avcc.len() - 4..], &[0xfd, 0xf8, 0xf8, 0]);
+    assert_eq!(pasp, &[0, 0, 0, 4, 0, 0, 0, 3]);
+    // High 4:4:4 Predictive has the extension too
+    let info = Sps { profile_idc: 244, chroma_format_idc: 3, bit_depth_luma: 10, bit_depth_chroma: 10, ..Sps::parse(sps.data).unwrap() };
+    let mut avc1 = Vec::new();
+    write_avc1(&mut avc1, &info, sps.data, &[0x68]);
+    assert_eq!(&avc1[avc1.len() - 4 - 16..][..4], &[0xff, 0xfa, 0xfa, 0]);
+
+    let mut moof_offset = boxes[0].1.len() + boxes[1].1.len() + 16;
+    for (fragment, base_time) in [(2, 0u64), (4, 4 * 3600)] {
+        let (moof, mdat) = (boxes[fragment].1, boxes[fragment + 1].1);
+        assert_eq!(mp4_child(moof, &[b"mfhd"])[4..], (fragment as u32 / 2).to_be_bytes());
+        assert_eq!(mp4_child(moof, &[b"traf", b"tfdt"])[4..], base_time.to_be_bytes());
+        let trun = mp4_child(moof, &[b"traf", b"trun"]);
+        let field = |i: usize| u32::from_be_bytes(trun[4 * i..4 * i + 4].try_into().unwrap());
+        assert_eq!(field(1), 4);
+        // data_offset is relative to the start of the moof box
+        assert_eq!(&out[moof_offset + field(2) as usize..][..mdat.len()], mdat);
+        let samples: Vec<_> = (0..4).map(|i| (field(3 + 3 * i), field(4 + 3 * i), field(5 + 3 * i))).collect();
+        assert!(samples.iter().all(|s| s.0 == 3600));
+        assert_eq!(samples.iter().map(|s| s.1 as usize).sum::<usize>(), mdat.len());
+        assert_eq!(samples[0].2, SAMPLE_FLAGS_KEYFRAME);
+        assert!(samples[1..].iter().all(|s| s.2 == SAMPLE_FLAGS_NON_KEYFRAME));
+
+        // length prefixed NAL units, without AUD/SPS/PPS
+        let mut types = Vec::new();
+        let mut rest = mdat;
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            types.push(NalUnitType::from(rest[4]));
+            rest = &rest[4 + len..];
+        }
+        use NalUnitType::*;
+        assert_eq!(types, [Sei, IdrSlice, NonIdrSlice, NonIdrSlice, NonIdrSlice]);
+        moof_offset += moof.len() + mdat.len() + 16;
+    }
+}
+
+#[test]
+fn test_fmp4_writer() {
+    // parameter sets in a separate access unit, as the encoder sends them with `MMAL_BUFFER_HEADER_FLAG_CONFIG`
+    let mut nals = split_annex_b(crate::h264::SAMPLE_BASELINE_240P);
+    let mut config = Vec::new();
+    for nal in nals.by_ref().take(2) {
+        config.extend_from_slice(&[0, 0, 1]);
+        config.extend_from_slice(nal.data);
+    }
+    let mut writer = Fmp4Writer::new(Vec::new());
+    writer.write(&config, None).unwrap();
+    assert!(!writer.muxer().is_started());
+    for nal in nals {
+        writer.write(&[&[0, 0, 1], nal.data].concat(), None).unwrap();
+    }
+    let out = writer.finish().unwrap();
+
+    let boxes = mp4_boxes(&out);
+    assert_eq!(boxes.len(), 4);
+    let tkhd = mp4_child(boxes[1].1, &[b"trak", b"tkhd"]);
+    assert_eq!(tkhd[tkhd.len() - 8..], [1, 0x40, 0, 0, 0, 0xf0, 0, 0]); // 320x240 in 16.16
+    // no timestamps and no VUI timing: 30 fps
+    let trun = mp4_child(boxes[2].1, &[b"traf", b"trun"]);
+    assert_eq!(trun[4..8], 5u32.to_be_bytes());
+    assert!(trun[12..].chunks(12).all(|s| s[..4] == DEFAULT_SAMPLE_DURATION.to_be_bytes()));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_async_fmp4_writer() {
+    let mut sync = Fmp4Writer::new(Vec::new());
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let out = rt.block_on(async {
+        let mut writer = AsyncFmp4Writer::new(Vec::new());
+        for (i, au) in access_units(crate::h264::SAMPLE_HIGH_1080P).iter().enumerate() {
+            let pts = Some(Duration::from_millis(40 * i as u64));
+            writer.write(au, pts).await.unwrap();
+            sync.write(au, pts).unwrap();
+        }
+        writer.finish().await.unwrap()
+    });
+    assert_eq!(out, sync.finish().unwrap());
+}
@@ -0,0 +1,393 @@
+use super::*;
+use std::{io::Write, path::Path, time::Duration};
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+pub const TS_PACKET_SIZE: usize = 188;
+pub const TS_PID_PMT: u16 = 0x1000;
+pub const TS_PID_VIDEO: u16 = 0x100;
+
+const TS_SYNC_BYTE: u8 = 0x47;
+const TS_PAYLOAD_SIZE: usize = TS_PACKET_SIZE - 4;
+const STREAM_TYPE_H264: u8 = 0x1b;
+const PES_STREAM_ID_VIDEO: u8 = 0xe0;
+const PROGRAM_NUMBER: u16 = 1;
+
+/// PTS/DTS are ahead of the PCR by this many 90kHz ticks, leaving the decoder time to buffer the access unit
+const TS_PCR_DELAY: u64 = 63_000;
+
+const AUD: [u8; 6] = [0, 0, 0, 1, 0x09, 0xf0];
+
+fn to_ticks(d: Duration) -> u64 {
+    (d.as_nanos() * 90_000 / 1_000_000_000) as u64
+}
+
+/// CRC-32/MPEG-2 of PSI sections
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    data.iter().fold(0xffff_ffff, |crc, b| {
+        (0..8).fold(crc ^ (*b as u32) << 24, |crc, _| if crc & 0x8000_0000 != 0 { crc << 1 ^ 0x04c1_1db7 } else { crc << 1 })
+    })
+}
+
+/// PTS or DTS field, ISO/IEC 13818-1 2.4.3.7
+fn pes_timestamp(out: &mut Vec<u8>, prefix: u8, ts: u64) {
+    out.extend_from_slice(&[
+        prefix << 4 | ((ts >> 29) & 0x0e) as u8 | 1,
+        (ts >> 22) as u8,
+        ((ts >> 14) & 0xfe) as u8 | 1,
+        (ts >> 7) as u8,
+        ((ts << 1) & 0xfe) as u8 | 1,
+    ]);
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// MPEG transport stream muxer for an H.264 elementary stream, e.g. the output of `VideoEncoderOutputPort`.
+///
+/// Produces a single program with one video stream. PAT and PMT are sent at the start and before every keyframe, so
+/// that receivers can join at any GOP, and every PES packet carries a PCR. Takes Annex-B access units and returns whole
+/// 188 byte packets; the output depends only on the input, which makes it suitable for UDP and for golden tests.
+///
+/// Access units before the first IDR frame are dropped. Access unit delimiters are added where missing, and the latest
+/// SPS/PPS are repeated in front of keyframes which come without them, as the encoder sends them only once by default.
+#[derive(Debug)]
+pub struct TsMuxer {
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    started: bool,
+    first_ts: Option<u64>,
+    last_dts: Option<u64>,
+    frame_duration: u64,
+    /// Continuity counters of PAT, PMT and video
+    cc: [u8; 3],
+}
+
+impl Default for TsMuxer {
+    fn default() -> Self {
+        Self { sps: None, pps: None, started: false, first_ts: None, last_dts: None, frame_duration: 90_000 / 30, cc: [0; 3] }
+    }
+}
+
+impl TsMuxer {
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds an access unit in Annex-B format. Timestamps of `None` continue the timeline at 30 fps; `dts` defaults to `pts`.
+    ///
+    /// Returns the TS packets of the access unit, preceded by PAT/PMT for keyframes.
+    pub fn push(&mut self, access_unit: &[u8], pts: Option<Duration>, dts: Option<Duration>) -> Vec<u8> {
+        let mut keyframe = false;
+        let (mut has_vcl, mut has_aud, mut has_sps) = (false, false, false);
+        for nal in split_annex_b(access_unit) {
+            match nal.nal_type() {
+                NalUnitType::Sps => { has_sps = true; self.sps = Some(nal.data.to_vec()) }
+                NalUnitType::Pps => self.pps = Some(nal.data.to_vec()),
+                NalUnitType::Aud => has_aud = true,
+                t => {
+                    has_vcl |= t.is_vcl();
+                    keyframe |= nal.is_keyframe();
+                }
+            }
+        }
+        let mut out = Vec::new();
+        if !has_vcl || !(self.started || keyframe) {
+            return out;
+        }
+        self.started = true;
+
+        let dts = match (dts.or(pts).map(to_ticks), self.last_dts) {
+            (Some(dts), Some(last)) if dts > last => dts,
+            (_, Some(last)) => last + self.frame_duration,
+            (Some(dts), None) => dts,
+            (None, None) => 0,
+        };
+        let pts = pts.map(to_ticks).filter(|pts| *pts >= dts).unwrap_or(dts);
+        if let Some(last) = self.last_dts {
+            self.frame_duration = dts - last;
+        }
+        self.last_dts = Some(dts);
+        let first = *self.first_ts.get_or_insert(dts);
+        let (pts, dts) = (pts - first + TS_PCR_DELAY, dts - first + TS_PCR_DELAY);
+
+        let mut es = Vec::with_capacity(access_unit.len() + 64);
+        if !has_aud {
+            es.extend_from_slice(&AUD);
+        }
+        if keyframe && !has_sps {
+            for ps in [&self.sps, &self.pps].into_iter().flatten() {
+                es.extend_from_slice(&[0, 0, 0, 1]);
+                es.extend_from_slice(ps);
+            }
+        }
+        es.extend_from_slice(access_unit);
+
+        let mut pes = Vec::with_capacity(es.len() + 19);
+        pes.extend_from_slice(&[0, 0, 1, PES_STREAM_ID_VIDEO, 0, 0, 0x80]); // unbounded PES_packet_length
+        if pts != dts {
+            pes.extend_from_slice(&[0xc0, 10]);
+            pes_timestamp(&mut pes, 3, pts & 0x1_ffff_ffff);
+            pes_timestamp(&mut pes, 1, dts & 0x1_ffff_ffff);
+        } else {
+            pes.extend_from_slice(&[0x80, 5]);
+            pes_timestamp(&mut pes, 2, pts & 0x1_ffff_ffff);
+        }
+        pes.extend_from_slice(&es);
+
+        if keyframe {
+            self.write_psi(&mut out);
+        }
+        self.write_pes(&mut out, &pes, (dts - TS_PCR_DELAY) & 0x1_ffff_ffff, keyframe);
+        out
+    }
+
+    /// Adds a frame assembled from the encoder output, see `FrameAssembler`. Codec side info frames, e.g. motion vectors,
+    /// are ignored.
+    pub fn push_frame(&mut self, frame: &Frame) -> Vec<u8> {
+        if frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            return Vec::new();
+        }
+        self.push(&frame.data, frame.pts, frame.dts)
+    }
+
+    /// Whether the first keyframe was seen
+    pub fn is_started(&self) -> bool { self.started }
+
+    fn write_psi(&mut self, out: &mut Vec<u8>) {
+        // program_association_section, one program
+        let mut pat = vec![0x00, 0xb0, 13, 0, 1, 0xc1, 0, 0];
+        pat.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend_from_slice(&(0xe000 | TS_PID_PMT).to_be_bytes());
+        self.write_section(out, 0, pat);
+
+        // TS_program_map_section, video PID carries the PCR
+        let mut pmt = vec![0x02, 0xb0, 18];
+        pmt.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pmt.extend_from_slice(&[0xc1, 0, 0]);
+        pmt.extend_from_slice(&(0xe000 | TS_PID_VIDEO).to_be_bytes());
+        pmt.extend_from_slice(&[0xf0, 0, STREAM_TYPE_H264]);
+        pmt.extend_from_slice(&(0xe000 | TS_PID_VIDEO).to_be_bytes());
+        pmt.extend_from_slice(&[0xf0, 0]);
+        self.write_section(out, 1, pmt);
+    }
+
+    fn write_section(&mut self, out: &mut Vec<u8>, index: usize, mut section: Vec<u8>) {
+        let crc = crc32_mpeg2(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        let pid = [0, TS_PID_PMT][index];
+        let start = out.len();
+        out.extend_from_slice(&[TS_SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | self.next_cc(index), 0]);
+        out.extend_from_slice(&section);
+        out.resize(start + TS_PACKET_SIZE, 0xff);
+    }
+
+    fn write_pes(&mut self, out: &mut Vec<u8>, pes: &[u8], pcr: u64, random_access: bool) {
+        let mut rest = pes;
+        let mut first = true;
+        while first || !rest.is_empty() {
+            // adaptation field without its length byte
+            let mut af = None;
+            if first {
+                let mut field = vec![0x10 | if random_access { 0x40 } else { 0 }];
+                // program_clock_reference, the extension is always 0
+                field.extend_from_slice(&[(pcr >> 25) as u8, (pcr >> 17) as u8, (pcr >> 9) as u8, (pcr >> 1) as u8, (pcr << 7) as u8 | 0x7e, 0]);
+                af = Some(field);
+            }
+            let space = TS_PAYLOAD_SIZE - af.as_ref().map_or(0, |af: &Vec<u8>| af.len() + 1);
+            let n = space.min(rest.len());
+            if n < space {
+                // stuffing
+                let af = af.get_or_insert_with(Vec::new);
+                let af_len = TS_PAYLOAD_SIZE - n - 1;
+                if af.is_empty() && af_len > 0 {
+                    af.push(0);
+                }
+                af.resize(af_len, 0xff);
+            }
+
+            let pusi = if first { 0x40 } else { 0 };
+            let control = if af.is_some() { 0x30 } else { 0x10 };
+            out.extend_from_slice(&[TS_SYNC_BYTE, pusi | (TS_PID_VIDEO >> 8) as u8, TS_PID_VIDEO as u8, control | self.next_cc(2)]);
+            if let Some(af) = af {
+                out.push(af.len() as u8);
+                out.extend_from_slice(&af);
+            }
+            out.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            first = false;
+        }
+    }
+
+    fn next_cc(&mut self, index: usize) -> u8 {
+        let cc = self.cc[index];
+        self.cc[index] = (cc + 1) & 0x0f;
+        cc
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Writes the transport stream of a `TsMuxer` to `W`, e.g. a file or a `UdpSocket` wrapper.
+///
+/// Can be fed buffer by buffer from `SinkAggregate::consume_view`, or with whole frames from `SinkAggregate::frames`.
+pub struct TsWriter<W: Write> {
+    muxer: TsMuxer,
+    assembler: FrameAssembler,
+    out: W,
+}
+
+impl TsWriter<std::io::BufWriter<std::fs::File>> {
+    /// Creates or truncates the file at `path`
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(std::io::BufWriter::new(std::fs::File::create(path)?)))
+    }
+}
+
+impl<W: Write> TsWriter<W> {
+    pub fn new(out: W) -> Self { Self { muxer: TsMuxer::new(), assembler: FrameAssembler::new(), out } }
+
+    /// See `TsMuxer::push`
+    pub fn write(&mut self, access_unit: &[u8], pts: Option<Duration>, dts: Option<Duration>) -> Result<()> {
+        Ok(self.out.write_all(&self.muxer.push(access_unit, pts, dts))?)
+    }
+
+    /// See `TsMuxer::push_frame`
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        Ok(self.out.write_all(&self.muxer.push_frame(frame))?)
+    }
+
+    /// Adds an encoder output buffer. The access unit is written once its last buffer arrives.
+    pub fn write_view(&mut self, view: BufferView<'_>) -> Result<()> {
+        match self.assembler.push(view) {
+            Some(frame) => self.write_frame(&frame),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the flushed writer
+    pub fn finish(mut self) -> Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    pub fn muxer(&self) -> &TsMuxer { &self.muxer }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) struct TsPacket<'a> {
+    pub pid: u16,
+    pub payload_unit_start: bool,
+    pub cc: u8,
+    /// Adaptation field with its length byte, empty if absent
+    pub af: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+#[cfg(test)]
+pub(crate) fn ts_packets(data: &[u8]) -> Vec<TsPacket<'_>> {
+    assert_eq!(data.len() % TS_PACKET_SIZE, 0);
+    data.chunks(TS_PACKET_SIZE).map(|p| {
+        assert_eq!(p[0], TS_SYNC_BYTE);
+        let (af, payload) = if p[3] & 0x20 != 0 { p[4..].split_at(1 + p[4] as usize) } else { (&p[4..4], &p[4..]) };
+        TsPacket {
+            pid: u16::from_be_bytes([p[1] & 0x1f, p[2]]), payload_unit_start: p[1] & 0x40 != 0, cc: p[3] & 0x0f, af,
+            payload: if p[3] & 0x10 != 0 { payload } else { &payload[..0] },
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+fn read_timestamp(b: &[u8]) -> u64 {
+    (b[0] as u64 & 0x0e) << 29 | (b[1] as u64) << 22 | (b[2] as u64 & 0xfe) << 14 | (b[3] as u64) << 7 | (b[4] as u64) >> 1
+}
+
+#[test]
+fn test_crc32_mpeg2() {
+    assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_e6e7);
+}
+
+#[test]
+fn test_ts_muxer() {
+    let mut nals = split_annex_b(crate::h264::SAMPLE_BASELINE_240P);
+    let config: Vec<u8> = nals.by_ref().take(2).flat_map(|nal| [&[0, 0, 0, 1], nal.data].concat()).collect();
+    let frames: Vec<_> = nals.map(|nal| [&[0, 0, 1], nal.data].concat()).collect();
+
+    let mut muxer = TsMuxer::new();
+    let mut out = Vec::new();
+    // a P frame before the first keyframe and a config-only access unit produce no packets
+    out.extend(muxer.push(&frames[1], Some(Duration::ZERO), None));
+    out.extend(muxer.push(&config, Some(Duration::ZERO), None));
+    assert!(out.is_empty() && !muxer.is_started());
+    for (i, frame) in frames.iter().chain(frames.iter()).enumerate() {
+        out.extend(muxer.push(frame, Some(Duration::from_millis(2000 + 40 * i as u64)), None));
+    }
+    assert_eq!(out, include_bytes!("../testdata/mpegts/baseline_320x240.ts"));
+
+    let packets = ts_packets(&out);
+    // PAT/PMT before both keyframes
+    let pids: Vec<_> = packets.iter().filter(|p| p.payload_unit_start).map(|p| p.pid).collect();
+    assert_eq!(pids, [0, TS_PID_PMT, 0x100, 0x100, 0x100, 0x100, 0x100, 0, TS_PID_PMT, 0x100, 0x100, 0x100, 0x100, 0x100]);
+    for (pid, table_id) in [(0, 0), (TS_PID_PMT, 2)] {
+        let payload = packets.iter().find(|p| p.pid == pid).unwrap().payload;
+        let len = 3 + (u16::from_be_bytes([payload[2], payload[3]]) & 0xfff) as usize;
+        assert_eq!(payload[..2], [0, table_id]);
+        assert_eq!(crc32_mpeg2(&payload[1..1 + len]), 0, "CRC of pid {pid}");
+    }
+    let pmt = packets[1].payload;
+    assert_eq!(pmt[13..18], [STREAM_TYPE_H264, 0xe1, 0x00, 0xf0, 0]);
+
+    let mut es = Vec::new();
+    let mut cc = None;
+    let mut pes_timestamps = Vec::new();
+    for TsPacket { pid, payload_unit_start, cc: counter, af, payload } in packets.iter().filter(|p| p.pid == TS_PID_VIDEO) {
+        assert_eq!(Some(*counter), cc.map(|c: u8| (c + 1) & 0xf).or(Some(0)));
+        cc = Some(*counter);
+        let mut payload = *payload;
+        if *payload_unit_start {
+            // PCR in every PES, random access for keyframes
+            assert_eq!(af[1] & 0x10, 0x10);
+            let pcr = (af[2] as u64) << 25 | (af[3] as u64) << 17 | (af[4] as u64) << 9 | (af[5] as u64) << 1 | (af[6] as u64) >> 7;
+            assert_eq!(payload[..4], [0, 0, 1, 0xe0]);
+            assert_eq!(payload[7], 0x80, "PTS only");
+            pes_timestamps.push((pcr, read_timestamp(&payload[9..14]), af[1] & 0x40 != 0));
+            payload = &payload[14..];
+        }
+        assert_eq!(*pid, TS_PID_VIDEO);
+        es.extend_from_slice(payload);
+    }
+    assert_eq!(pes_timestamps.len(), 10);
+    for (i, (pcr, pts, random_access)) in pes_timestamps.into_iter().enumerate() {
+        assert_eq!((pcr, pts, random_access), (3600 * i as u64, 3600 * i as u64 + TS_PCR_DELAY, i % 5 == 0));
+    }
+    // AUDs added, parameter sets repeated before the second keyframe
+    use NalUnitType::*;
+    let types: Vec<_> = split_annex_b(&es).map(|nal| nal.nal_type()).collect();
+    let gop = [Aud, Sps, Pps, IdrSlice, Aud, NonIdrSlice, Aud, NonIdrSlice, Aud, NonIdrSlice, Aud, NonIdrSlice];
+    assert_eq!(types, [gop, gop].concat());
+}
+
+#[test]
+fn test_ts_pts_dts() {
+    let idr = [0, 0, 1, 0x65, 0x88, 0x80];
+    let mut muxer = TsMuxer::new();
+    let out = muxer.push(&idr, Some(Duration::from_millis(80)), Some(Duration::from_millis(40)));
+    let packets = ts_packets(&out);
+    let pes = packets[2].payload;
+    assert_eq!(pes[7..9], [0xc0, 10]);
+    assert_eq!((pes[9] >> 4, pes[14] >> 4), (3, 1));
+    assert_eq!((read_timestamp(&pes[9..14]), read_timestamp(&pes[14..19])), (TS_PCR_DELAY + 3600, TS_PCR_DELAY));
+    // a single packet, padded with the adaptation field
+    assert_eq!(packets.len(), 3);
+    assert_eq!(&pes[19..], &[&AUD[..], &idr].concat());
+
+    // an access unit spanning several packets, continued without adaptation fields until the stuffed last one
+    let slice = [&[0, 0, 1, 0x41][..], &[0x11; 500]].concat();
+    let packets_out = muxer.push(&slice, None, None);
+    let packets = ts_packets(&packets_out);
+    assert_eq!(packets.len(), 3);
+    assert!(packets[1].af.is_empty() && !packets[1].payload_unit_start && packets[1].payload.len() == TS_PAYLOAD_SIZE);
+    let pes: Vec<u8> = packets.iter().flat_map(|p| p.payload.iter().copied()).collect();
+    // no timestamps: 30 fps after the first frame
+    assert_eq!(read_timestamp(&pes[9..14]), TS_PCR_DELAY + 3000);
+    assert_eq!(&pes[14..], &[&AUD[..], &slice].concat());
+}
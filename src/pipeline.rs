@@ -0,0 +1,328 @@
+use std::{any::{Any, TypeId}, cell::Cell, marker::PhantomData, pin::Pin};
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Typed reference to a component added to a `PipelineBuilder`
+pub struct Node<E: ComponentEntity> {
+    index: usize,
+    t: PhantomData<E>,
+}
+
+impl<E: ComponentEntity> Clone for Node<E> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<E: ComponentEntity> Copy for Node<E> { }
+
+/// Typed reference to a `SinkAggregate` declared in a `PipelineBuilder`
+pub struct Sink<P: ComponentPort> {
+    index: usize,
+    t: PhantomData<P>,
+}
+
+impl<P: ComponentPort> Clone for Sink<P> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<P: ComponentPort> Copy for Sink<P> { }
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+trait PipelineNode {
+    fn as_any(&self) -> &dyn Any;
+    fn enable(&self) -> Result<()>;
+    fn disable(&self) -> Result<()>;
+}
+
+impl<E: ComponentEntity + 'static> PipelineNode for ComponentHandle<E> {
+    fn as_any(&self) -> &dyn Any { self }
+    fn enable(&self) -> Result<()> { ComponentHandle::enable(self) }
+    fn disable(&self) -> Result<()> { ComponentHandle::disable(self) }
+}
+
+trait PipelineLink {
+    fn disable(&self) -> Result<()>;
+}
+
+impl<PS: ComponentPort, PT: ComponentPort> PipelineLink for ConnectionHandle<PS, PT> {
+    fn disable(&self) -> Result<()> { ConnectionHandle::disable(self) }
+}
+
+trait PipelineSink {
+    fn as_any(&self) -> &dyn Any;
+    fn feed_all(&self) -> Result<()>;
+    fn disable(&self) -> Result<()>;
+}
+
+impl<P: ComponentPort + 'static> PipelineSink for Pin<Box<SinkAggregate<P>>> {
+    fn as_any(&self) -> &dyn Any { self }
+    fn feed_all(&self) -> Result<()> { SinkAggregate::feed_all(self) }
+    fn disable(&self) -> Result<()> { SinkAggregate::disable(self) }
+}
+
+type Nodes = [Box<dyn PipelineNode>];
+type CreateLink = Box<dyn Fn(&Nodes) -> Result<Box<dyn PipelineLink>>>;
+type CreateSink = Box<dyn Fn(&Nodes) -> Result<Box<dyn PipelineSink>>>;
+
+fn node_handle<E: ComponentEntity + 'static>(nodes: &Nodes, index: usize) -> &ComponentHandle<E> {
+    nodes.get(index)
+        .and_then(|n| n.as_any().downcast_ref())
+        .expect("node does not belong to this pipeline")
+}
+
+/// A port of a particular node
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct PortKey {
+    node: usize,
+    port: TypeId,
+}
+
+impl PortKey {
+    fn of<P: ComponentPort + 'static>(node: usize) -> Self { Self { node, port: TypeId::of::<P>() } }
+}
+
+struct Edge {
+    source: PortKey,
+    target: PortKey,
+    name: String,
+    create: CreateLink,
+}
+
+struct SinkDecl {
+    port: PortKey,
+    name: &'static str,
+    create: CreateSink,
+}
+
+fn invalid(message: String) -> MmalError { MmalError::new(Cause::InvalidPipeline, message) }
+
+pub(crate) fn fourcc(encoding: u32) -> String {
+    encoding.to_le_bytes().iter().map(|&b| if b.is_ascii_graphic() { b as char } else { '?' }).collect()
+}
+
+/// Checks that `PS` on `source` may feed `PT` on `target` with its current format
+fn check_edge<PS: ComponentPort, PT: ComponentPort>(source: &ComponentHandle<PS::E>, target: &ComponentHandle<PT::E>) -> Result<()> {
+    let name = || format!("{} -> {}", PS::name(), PT::name());
+    unsafe {
+        let (sp, tp) = (&*PS::get_port(source), &*PT::get_port(target));
+        if PortType::from(sp.type_) != PortType::Output {
+            return Err(invalid(format!("{}: source is not an output port", name())));
+        }
+        if PortType::from(tp.type_) != PortType::Input {
+            return Err(invalid(format!("{}: target is not an input port", name())));
+        }
+        let format = EsFormat::from_ffi(&*sp.format);
+        if format.encoding == 0 {
+            return Err(invalid(format!("{}: source format is not set", name())));
+        }
+        if let Some(video) = &format.video {
+            if video.width == 0 || video.height == 0 {
+                return Err(invalid(format!("{}: source frame size is not set", name())));
+            }
+        }
+        if let Some(accepted) = PT::accepted_encodings() {
+            if !accepted.contains(&format.encoding) {
+                return Err(invalid(format!("{}: encoding `{}` is not accepted by the target", name(), fourcc(format.encoding))));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Orders `nodes` so that the sources of the `edges` come before their targets, keeping the declaration order
+/// otherwise. Fails on cycles.
+fn topological_order(nodes: usize, edges: impl Iterator<Item = (usize, usize)>) -> Result<Vec<usize>> {
+    let edges: Vec<_> = edges.collect();
+    let mut incoming = vec![0usize; nodes];
+    for &(_, t) in &edges {
+        incoming[t] += 1;
+    }
+    let mut order = Vec::with_capacity(nodes);
+    let mut done = vec![false; nodes];
+    while order.len() < nodes {
+        let n = (0..nodes).find(|&n| !done[n] && incoming[n] == 0)
+            .ok_or_else(|| invalid("connections form a cycle".to_owned()))?;
+        done[n] = true;
+        order.push(n);
+        for &(_, t) in edges.iter().filter(|(s, _)| *s == n) {
+            incoming[t] -= 1;
+        }
+    }
+    Ok(order)
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Declares a graph of components, tunnelled connections between their ports and sinks on output ports,
+/// and builds it into a `Pipeline`.
+///
+/// Components are added already configured, i.e. with port formats committed. The graph is checked
+/// when built: each port may be used by a single edge or sink, and the format of each source port
+/// must be set and accepted by the target port.
+///
+/// ```ignore
+/// let mut b = PipelineBuilder::new();
+/// let camera = b.node(camera);
+/// let encoder = b.node(encoder);
+/// b.connect::<CameraVideoPort, VideoEncoderInputPort>(camera, encoder);
+/// let output = b.sink::<VideoEncoderOutputPort>(encoder);
+/// let pipeline = b.build()?;
+/// ```
+#[derive(Default)]
+pub struct PipelineBuilder {
+    nodes: Vec<Box<dyn PipelineNode>>,
+    edges: Vec<Edge>,
+    sinks: Vec<SinkDecl>,
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds a configured component
+    pub fn node<E: ComponentEntity + 'static>(&mut self, h: ComponentHandle<E>) -> Node<E> {
+        self.nodes.push(Box::new(h));
+        Node { index: self.nodes.len() - 1, t: PhantomData }
+    }
+
+    /// Declares a tunnelled connection from port `PS` of `source` to port `PT` of `target`
+    pub fn connect<PS, PT>(&mut self, source: Node<PS::E>, target: Node<PT::E>) -> &mut Self
+    where PS: ComponentPort + 'static, PT: ComponentPort + 'static, PS::E: 'static, PT::E: 'static {
+        let (si, ti) = (source.index, target.index);
+        self.edges.push(Edge {
+            source: PortKey::of::<PS>(si),
+            target: PortKey::of::<PT>(ti),
+            name: format!("{} -> {}", PS::name(), PT::name()),
+            create: Box::new(move |nodes| {
+                let (s, t) = (node_handle::<PS::E>(nodes, si), node_handle::<PT::E>(nodes, ti));
+                check_edge::<PS, PT>(s, t)?;
+                let c = ConnectionHandle::<PS, PT>::create(s, t)?;
+                c.enable()?;
+                Ok(Box::new(c) as Box<dyn PipelineLink>)
+            }),
+        });
+        self
+    }
+
+    /// Declares a `SinkAggregate` on output port `P` of `node`
+    pub fn sink<P>(&mut self, node: Node<P::E>) -> Sink<P>
+    where P: ComponentPort + 'static, P::E: 'static {
+        let ni = node.index;
+        self.sinks.push(SinkDecl {
+            port: PortKey::of::<P>(ni),
+            name: P::name(),
+            create: Box::new(move |nodes| {
+                let s = SinkAggregate::<P>::create(node_handle::<P::E>(nodes, ni).clone())?;
+                s.enable()?;
+                Ok(Box::new(s) as Box<dyn PipelineSink>)
+            }),
+        });
+        Sink { index: self.sinks.len() - 1, t: PhantomData }
+    }
+
+    fn check_ports(&self) -> Result<()> {
+        let mut used: Vec<(PortKey, &str)> = Vec::new();
+        let ports = self.edges.iter()
+            .flat_map(|e| [(e.source, e.name.as_str()), (e.target, e.name.as_str())])
+            .chain(self.sinks.iter().map(|s| (s.port, s.name)));
+        for (port, name) in ports {
+            if let Some((_, other)) = used.iter().find(|(p, _)| *p == port) {
+                return Err(invalid(format!("{}: port already used by {}", name, other)));
+            }
+            used.push((port, name));
+        }
+        Ok(())
+    }
+
+    /// Enables the components, then creates and enables the connections and finally the sinks, which are
+    /// fed with buffers. Components and connections are ordered upstream first along the edges, whatever the order
+    /// they were declared in. On error, the part built so far is torn down.
+    pub fn build(self) -> Result<Pipeline> {
+        self.check_ports()?;
+        let order = topological_order(self.nodes.len(), self.edges.iter().map(|e| (e.source.node, e.target.node)))?;
+        let mut rank = vec![0; order.len()];
+        for (r, &n) in order.iter().enumerate() {
+            rank[n] = r;
+        }
+        let mut edges: Vec<&Edge> = self.edges.iter().collect();
+        edges.sort_by_key(|e| (rank[e.source.node], rank[e.target.node]));
+
+        let mut pipeline = Pipeline { nodes: self.nodes, order, links: Vec::new(), sinks: Vec::new(), stopped: Cell::new(false) };
+        for &n in &pipeline.order {
+            pipeline.nodes[n].enable()?;
+        }
+        for e in edges {
+            let link = (e.create)(&pipeline.nodes)?;
+            pipeline.links.push(link);
+        }
+        for s in &self.sinks {
+            let sink = (s.create)(&pipeline.nodes)?;
+            pipeline.sinks.push(sink);
+        }
+        for s in &pipeline.sinks {
+            s.feed_all()?;
+        }
+        Ok(pipeline)
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// A built component graph, see `PipelineBuilder`.
+///
+/// `stop()`, or drop, tears the graph down in reverse order: sinks are disabled first, then connections,
+/// downstream first, then components, downstream first too. Capture should be stopped beforehand.
+pub struct Pipeline {
+    nodes: Vec<Box<dyn PipelineNode>>,
+    /// Node indices, upstream first
+    order: Vec<usize>,
+    links: Vec<Box<dyn PipelineLink>>,
+    sinks: Vec<Box<dyn PipelineSink>>,
+    stopped: Cell<bool>,
+}
+
+impl Pipeline {
+    pub fn component<E: ComponentEntity + 'static>(&self, node: Node<E>) -> &ComponentHandle<E> {
+        node_handle(&self.nodes, node.index)
+    }
+
+    pub fn sink<P: ComponentPort + 'static>(&self, sink: Sink<P>) -> &SinkAggregate<P> {
+        self.sinks.get(sink.index)
+            .and_then(|s| s.as_any().downcast_ref::<Pin<Box<SinkAggregate<P>>>>())
+            .expect("sink does not belong to this pipeline")
+    }
+
+    /// Disables sinks, connections and components. Continues on errors, returning the first one.
+    /// Subsequent calls do nothing.
+    pub fn stop(&self) -> Result<()> {
+        if self.stopped.replace(true) {
+            return Ok(());
+        }
+        let results = self.sinks.iter().rev().map(|s| s.disable())
+            .chain(self.links.iter().rev().map(|l| l.disable()))
+            .chain(self.order.iter().rev().map(|&n| self.nodes[n].disable()))
+            .collect::<Vec<_>>();
+        results.into_iter().collect()
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        log_deinit!(self.stop());
+        while self.sinks.pop().is_some() { }
+        while self.links.pop().is_some() { }
+        while self.nodes.pop().is_some() { }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_topological_order() {
+    // encoder <- splitter <- camera, declared downstream first
+    assert_eq!(topological_order(3, [(1, 0), (2, 1)].into_iter()).unwrap(), [2, 1, 0]);
+    // unconnected nodes keep their declaration order
+    assert_eq!(topological_order(4, [(3, 0), (3, 1)].into_iter()).unwrap(), [2, 3, 0, 1]);
+    let e = topological_order(2, [(0, 1), (1, 0)].into_iter()).unwrap_err();
+    assert!(matches!(e.cause(), Cause::InvalidPipeline));
+}
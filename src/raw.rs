@@ -0,0 +1,262 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Layout of a plane of a raw frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneLayout {
+    /// Offset of the plane in the frame
+    pub offset: usize,
+    /// Bytes between the starts of two rows, including the padding
+    pub stride: usize,
+    /// Rows in the buffer, including the padding rows
+    pub rows: usize,
+    /// Bytes per sample, e.g. 3 for RGB24, 2 for YUYV or the interleaved chroma of NV12
+    pub sample_size: usize,
+    /// Visible part of the plane, in samples
+    pub crop_x: usize,
+    pub crop_y: usize,
+    pub crop_width: usize,
+    pub crop_height: usize,
+}
+
+impl PlaneLayout {
+    /// Size of the plane, including the padding
+    pub fn size(&self) -> usize { self.stride * self.rows }
+}
+
+/// Plane offsets, strides and crop of the raw frames of a port format.
+///
+/// The buffer size of the format is padded, e.g. to a multiple of 32 x 16 pixels for the camera ports, see
+/// `camera_port_config`, while the crop gives the visible picture. Rows are as wide as the padded width, as
+/// `mmal_encoding_width_to_stride` gives, and the chroma planes of I420 and YV12 have half the luma stride.
+/// Planes are in memory order: Y, U, V for I420, Y, V, U for YV12 and Y, UV for NV12. Packed formats have one plane.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrameLayout {
+    pub encoding: u32,
+    /// Picture size of the buffers, including the padding
+    pub width: u32,
+    pub height: u32,
+    planes: Vec<PlaneLayout>,
+}
+
+impl RawFrameLayout {
+    /// Layout of `encoding` frames of `width` x `height`, padding included, showing the whole picture.
+    ///
+    /// Supports I420, YV12, NV12, RGB24, BGR24, RGBA and YUYV, fails with `MMAL_ENOSYS` for other encodings.
+    pub fn new(encoding: u32, width: u32, height: u32) -> Result<Self> {
+        Self::with_crop(encoding, width, height, (0, 0, width, height))
+    }
+
+    /// Layout of frames showing the `(x, y, width, height)` crop rectangle of the picture, clipped to it.
+    ///
+    /// YUYV frames and the chroma planes of I420, YV12 and NV12 start at the even column at or before `x`.
+    pub fn with_crop(encoding: u32, width: u32, height: u32, crop: (u32, u32, u32, u32)) -> Result<Self> {
+        let (w, h) = (width as usize, height as usize);
+        let (chroma_w, chroma_h) = (w.div_ceil(2), h.div_ceil(2));
+        // stride, rows, sample size and subsampling of each plane
+        let planes: &[(usize, usize, usize, usize)] = match encoding {
+            ffi::MMAL_ENCODING_I420 | ffi::MMAL_ENCODING_YV12 => &[(w, h, 1, 1), (chroma_w, chroma_h, 1, 2), (chroma_w, chroma_h, 1, 2)],
+            ffi::MMAL_ENCODING_NV12 => &[(w, h, 1, 1), (chroma_w * 2, chroma_h, 2, 2)],
+            ffi::MMAL_ENCODING_RGB24 | ffi::MMAL_ENCODING_BGR24 => &[(w * 3, h, 3, 1)],
+            ffi::MMAL_ENCODING_RGBA => &[(w * 4, h, 4, 1)],
+            ffi::MMAL_ENCODING_YUYV => &[(w * 2, h, 2, 1)],
+            _ => return Err(MmalError::with_status(ffi::MMAL_STATUS_T::MMAL_ENOSYS,
+                format!("no raw frame layout for encoding {:?}", fourcc(encoding)))),
+        };
+        let (x, y) = (crop.0.min(width) as usize, crop.1.min(height) as usize);
+        let (right, bottom) = (crop.0.saturating_add(crop.2).min(width) as usize, crop.1.saturating_add(crop.3).min(height) as usize);
+        // a YUYV sample and a subsampled chroma sample cover two columns, the crop starts at the first of them
+        let pair_x = x & !1;
+        let mut offset = 0;
+        let planes = planes.iter().map(|&(stride, rows, sample_size, sub)| {
+            let x = if sub > 1 || encoding == ffi::MMAL_ENCODING_YUYV { pair_x } else { x };
+            let plane = PlaneLayout {
+                offset, stride, rows, sample_size,
+                crop_x: x / sub,
+                crop_y: y / sub,
+                crop_width: right.div_ceil(sub) - x / sub,
+                crop_height: bottom.div_ceil(sub) - y / sub,
+            };
+            offset += plane.size();
+            plane
+        }).collect();
+        Ok(Self { encoding, width, height, planes })
+    }
+
+    /// Layout of the video frames of `format`, with its crop rectangle if it has one
+    pub fn from_format(format: &EsFormat) -> Result<Self> {
+        let video = format.video.clone().unwrap_or_default();
+        let crop = if video.crop_width > 0 && video.crop_height > 0 {
+            (video.crop_x.max(0) as u32, video.crop_y.max(0) as u32, video.crop_width as u32, video.crop_height as u32)
+        } else {
+            (0, 0, video.width, video.height)
+        };
+        Self::with_crop(format.encoding, video.width, video.height, crop)
+    }
+
+    /// Layout of the frames of port `P`, as committed by `ComponentPort::configure`.
+    ///
+    /// The encoding is the channel order of the frames, which differs from the port format for RGB24 and BGR24 on
+    /// firmware with the order reversed, see `fix_encoding`.
+    pub fn from_port<P: ComponentPort>(component: impl AsRef<ComponentHandle<P::E>>) -> Result<Self> {
+        let component = component.as_ref();
+        let mut format = P::format(component);
+        format.encoding = unsafe { fix_encoding(P::get_port(component), format.encoding) };
+        Self::from_format(&format)
+    }
+
+    pub fn planes(&self) -> &[PlaneLayout] { &self.planes }
+
+    /// Size of a frame, including the padding
+    pub fn frame_size(&self) -> usize {
+        self.planes.last().map_or(0, |p| p.offset + p.size())
+    }
+
+    /// Visible picture size
+    pub fn crop_size(&self) -> (usize, usize) {
+        (self.planes[0].crop_width, self.planes[0].crop_height)
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// A raw frame of a `RawFrameLayout`, e.g. a camera video port buffer, with access to the visible part of its planes
+#[derive(Debug, Clone, Copy)]
+pub struct RawFrame<'a> {
+    layout: &'a RawFrameLayout,
+    data: &'a [u8],
+}
+
+impl<'a> RawFrame<'a> {
+    /// Fails with `MMAL_EINVAL` if `data` is shorter than a frame of `layout`
+    pub fn new(layout: &'a RawFrameLayout, data: &'a [u8]) -> Result<Self> {
+        if data.len() < layout.frame_size() {
+            return Err(MmalError::with_status(ffi::MMAL_STATUS_T::MMAL_EINVAL,
+                format!("raw frame of {} bytes, {}x{} {} needs {}", data.len(), layout.width, layout.height,
+                    fourcc(layout.encoding), layout.frame_size())));
+        }
+        Ok(Self { layout, data })
+    }
+
+    pub fn layout(&self) -> &'a RawFrameLayout { self.layout }
+
+    pub fn plane(&self, index: usize) -> Option<Plane<'a>> {
+        let layout = *self.layout.planes.get(index)?;
+        Some(Plane { layout, data: &self.data[layout.offset..layout.offset + layout.size()] })
+    }
+
+    pub fn planes(&self) -> impl Iterator<Item = Plane<'a>> + '_ {
+        (0..self.layout.planes.len()).filter_map(|i| self.plane(i))
+    }
+
+    /// Copies the visible part of the planes, without padding
+    pub fn to_packed(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for plane in self.planes() {
+            plane.rows().for_each(|row| out.extend_from_slice(row));
+        }
+        out
+    }
+}
+
+/// A plane of a `RawFrame`
+#[derive(Debug, Clone, Copy)]
+pub struct Plane<'a> {
+    layout: PlaneLayout,
+    data: &'a [u8],
+}
+
+impl<'a> Plane<'a> {
+    pub fn layout(&self) -> &PlaneLayout { &self.layout }
+
+    /// Visible samples per row
+    pub fn width(&self) -> usize { self.layout.crop_width }
+    /// Visible rows
+    pub fn height(&self) -> usize { self.layout.crop_height }
+
+    /// Visible part of row `y` of the crop rectangle
+    pub fn row(&self, y: usize) -> Option<&'a [u8]> {
+        (y < self.layout.crop_height).then(|| self.visible(self.layout.crop_y + y))
+    }
+
+    /// Visible part of the rows of the crop rectangle
+    pub fn rows(&self) -> impl ExactSizeIterator<Item = &'a [u8]> + 'a {
+        let plane = *self;
+        (plane.layout.crop_y..plane.layout.crop_y + plane.layout.crop_height).map(move |y| plane.visible(y))
+    }
+
+    /// All rows of the plane, with their padding
+    pub fn padded_rows(&self) -> impl ExactSizeIterator<Item = &'a [u8]> + 'a {
+        self.data.chunks_exact(self.layout.stride)
+    }
+
+    fn visible(&self, y: usize) -> &'a [u8] {
+        let start = y * self.layout.stride + self.layout.crop_x * self.layout.sample_size;
+        &self.data[start..start + self.layout.crop_width * self.layout.sample_size]
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_raw_frame_layout() {
+    // 100x75 as configured by `camera_port_config`
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_I420, 128, 80, (0, 0, 100, 75)).unwrap();
+    let offsets: Vec<_> = layout.planes().iter().map(|p| (p.offset, p.stride, p.rows, p.crop_width, p.crop_height)).collect();
+    assert_eq!(offsets, [(0, 128, 80, 100, 75), (10240, 64, 40, 50, 38), (12800, 64, 40, 50, 38)]);
+    assert_eq!(layout.frame_size(), 128 * 80 * 3 / 2);
+    assert_eq!(layout.crop_size(), (100, 75));
+
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_NV12, 128, 80, (2, 4, 100, 70)).unwrap();
+    let uv = layout.planes()[1];
+    assert_eq!((uv.offset, uv.stride, uv.sample_size, uv.crop_x, uv.crop_y, uv.crop_width, uv.crop_height), (10240, 128, 2, 1, 2, 50, 35));
+    assert_eq!(layout.frame_size(), 128 * 80 * 3 / 2);
+
+    // odd crops: the chroma of I420 and the YUYV samples start at the even column before
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_I420, 128, 80, (3, 1, 10, 5)).unwrap();
+    let crops: Vec<_> = layout.planes().iter().map(|p| (p.crop_x, p.crop_y, p.crop_width, p.crop_height)).collect();
+    assert_eq!(crops, [(3, 1, 10, 5), (1, 0, 6, 3), (1, 0, 6, 3)]);
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_YUYV, 32, 16, (3, 1, 10, 5)).unwrap();
+    let yuyv = layout.planes()[0];
+    assert_eq!((yuyv.crop_x, yuyv.crop_y, yuyv.crop_width, yuyv.crop_height), (2, 1, 11, 5));
+    // crops beyond the picture are clipped
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_RGBA, 32, 16, (4, 2, u32::MAX, u32::MAX)).unwrap();
+    assert_eq!(layout.crop_size(), (28, 14));
+
+    for (encoding, stride) in [(ffi::MMAL_ENCODING_RGB24, 96), (ffi::MMAL_ENCODING_BGR24, 96), (ffi::MMAL_ENCODING_RGBA, 128), (ffi::MMAL_ENCODING_YUYV, 64)] {
+        let layout = RawFrameLayout::new(encoding, 32, 16).unwrap();
+        assert_eq!(layout.planes().len(), 1);
+        assert_eq!((layout.planes()[0].stride, layout.frame_size()), (stride, stride * 16));
+    }
+    let e = RawFrameLayout::new(ffi::MMAL_ENCODING_H264, 32, 16).unwrap_err();
+    assert!(matches!(e.cause(), Cause::Status(ffi::MMAL_STATUS_T::MMAL_ENOSYS)), "{e}");
+}
+
+#[test]
+fn test_raw_frame_rows() {
+    // 5x3 YV12 picture at (1, 1) of 8x6, each byte is its offset
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_YV12, 8, 6, (1, 1, 5, 3)).unwrap();
+    let data: Vec<u8> = (0..layout.frame_size()).map(|i| i as u8).collect();
+    assert!(RawFrame::new(&layout, &data[1..]).is_err());
+    let frame = RawFrame::new(&layout, &data).unwrap();
+
+    let y = frame.plane(0).unwrap();
+    assert_eq!((y.width(), y.height()), (5, 3));
+    assert_eq!(y.rows().collect::<Vec<_>>(), [&[9, 10, 11, 12, 13][..], &[17, 18, 19, 20, 21], &[25, 26, 27, 28, 29]]);
+    assert_eq!(y.row(3), None);
+    assert_eq!(y.padded_rows().len(), 6);
+    // chroma of columns 0 to 5 and rows 0 to 3, at half resolution
+    let v = frame.plane(1).unwrap();
+    assert_eq!(v.rows().collect::<Vec<_>>(), [&[48, 49, 50][..], &[52, 53, 54]]);
+    let u = frame.plane(2).unwrap();
+    assert_eq!(u.row(0), Some(&[60, 61, 62][..]));
+    assert!(frame.plane(3).is_none());
+
+    assert_eq!(frame.to_packed().len(), 5 * 3 + 2 * 3 * 2);
+
+    let layout = RawFrameLayout::with_crop(ffi::MMAL_ENCODING_RGB24, 4, 2, (1, 0, 2, 2)).unwrap();
+    let data: Vec<u8> = (0..24).collect();
+    let frame = RawFrame::new(&layout, &data).unwrap();
+    assert_eq!(frame.to_packed(), [3, 4, 5, 6, 7, 8, 15, 16, 17, 18, 19, 20]);
+}
@@ -0,0 +1,53 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Name of the resizer component, not present in the MMAL headers
+pub const MMAL_COMPONENT_RESIZER: &[u8] = b"vc.ril.resize\0";
+
+pub struct ResizerEntity;
+
+impl Entity for ResizerEntity {
+    fn name() -> &'static str { "resizer" }
+}
+
+impl ComponentEntity for ResizerEntity { }
+
+pub type ResizerComponentHandle = ComponentHandle<ResizerEntity>;
+
+impl ResizerComponentHandle {
+    pub fn create() -> Result<Self> {
+        let component_name: *const c_char = MMAL_COMPONENT_RESIZER.as_ptr() as *const c_char;
+        unsafe {
+            Self::create_from(component_name)
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Resizer input port. Configure it with the format of the connected output, with crop set to the region to be scaled.
+pub struct ResizerInputPort;
+impl ComponentPort for ResizerInputPort {
+    type E = ResizerEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.input_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "resizer input port" }
+
+    fn accepted_encodings() -> Option<&'static [u32]> { Some(RAW_PICTURE_ENCODINGS) }
+}
+
+/// Resizer output port, configured with `ScalerOutFormat` or `GenericPortConfig`
+pub struct ResizerOutputPort;
+impl ComponentPort for ResizerOutputPort {
+    type E = ResizerEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.output_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "resizer output port" }
+}
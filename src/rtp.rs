@@ -0,0 +1,313 @@
+use super::*;
+use std::time::Duration;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// First dynamic payload type, used for H.264 unless configured otherwise
+pub const RTP_H264_PAYLOAD_TYPE: u8 = 96;
+/// RTP clock rate of video, RFC 6184 section 8.2.1
+pub const RTP_H264_CLOCK_RATE: u32 = 90_000;
+/// Default maximum size of an RTP packet, header included, leaving room for IP/UDP headers on Ethernet
+pub const RTP_DEFAULT_MTU: usize = 1400;
+
+const RTP_HEADER_SIZE: usize = 12;
+const NAL_TYPE_STAP_A: u8 = 24;
+const NAL_TYPE_FU_A: u8 = 28;
+
+/// 90kHz RTP timestamp of `pts`, wrapping around every ~13 hours
+pub fn rtp_timestamp(pts: Duration) -> u32 {
+    (pts.as_nanos() * RTP_H264_CLOCK_RATE as u128 / 1_000_000_000) as u32
+}
+
+/// Fixed RTP header fields and payload of a packet, RFC 3550 section 5.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpPacket<'a> {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RtpPacket<'a> {
+    /// Parses an RTP version 2 packet, skipping CSRCs, the header extension and padding
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.len() < RTP_HEADER_SIZE || data[0] >> 6 != 2 {
+            return Err(rtp_error("not an RTP packet"));
+        }
+        let mut start = RTP_HEADER_SIZE + 4 * (data[0] & 0x0f) as usize;
+        if data[0] & 0x10 != 0 && data.len() >= start + 4 {
+            start += 4 + 4 * u16::from_be_bytes([data[start + 2], data[start + 3]]) as usize;
+        }
+        let padding = if data[0] & 0x20 != 0 { *data.last().unwrap() as usize } else { 0 };
+        if data.len() < start + padding {
+            return Err(rtp_error("truncated RTP packet"));
+        }
+        Ok(Self {
+            marker: data[1] & 0x80 != 0,
+            payload_type: data[1] & 0x7f,
+            sequence_number: u16::from_be_bytes([data[2], data[3]]),
+            timestamp: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            ssrc: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            payload: &data[start..data.len() - padding],
+        })
+    }
+}
+
+fn rtp_error(what: &str) -> MmalError {
+    MmalError::new(Cause::InvalidBitstream, format!("rtp: {what}"))
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Splits H.264 access units into RTP packets, RFC 6184 packetization mode 1.
+///
+/// NAL units which fit into the MTU are sent as single NAL unit packets, larger ones as FU-A fragments. Access unit
+/// delimiters are dropped. The marker bit is set on the last packet of each access unit.
+#[derive(Debug, Clone)]
+pub struct RtpH264Packetizer {
+    payload_type: u8,
+    ssrc: u32,
+    mtu: usize,
+    sequence_number: u16,
+    timestamp_offset: u32,
+    timestamp: u32,
+}
+
+impl RtpH264Packetizer {
+    pub fn new(ssrc: u32) -> Self {
+        Self { payload_type: RTP_H264_PAYLOAD_TYPE, ssrc, mtu: RTP_DEFAULT_MTU, sequence_number: 0, timestamp_offset: 0, timestamp: 0 }
+    }
+
+    pub fn with_payload_type(mut self, payload_type: u8) -> Self {
+        self.payload_type = payload_type & 0x7f;
+        self
+    }
+
+    /// Maximum packet size, RTP header included
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu.max(RTP_HEADER_SIZE + 3);
+        self
+    }
+
+    /// Initial sequence number and timestamp offset, which RFC 3550 recommends to be random
+    pub fn with_initial(mut self, sequence_number: u16, timestamp_offset: u32) -> Self {
+        self.sequence_number = sequence_number;
+        self.timestamp_offset = timestamp_offset;
+        self
+    }
+
+    pub fn ssrc(&self) -> u32 { self.ssrc }
+    /// Sequence number of the next packet
+    pub fn sequence_number(&self) -> u16 { self.sequence_number }
+
+    /// Packetizes an access unit in Annex-B format. `pts` of `None` reuses the timestamp of the previous access unit.
+    pub fn packetize(&mut self, access_unit: &[u8], pts: Option<Duration>) -> Vec<Vec<u8>> {
+        if let Some(pts) = pts {
+            self.timestamp = rtp_timestamp(pts).wrapping_add(self.timestamp_offset);
+        }
+        let max_payload = self.mtu - RTP_HEADER_SIZE;
+        let mut packets = Vec::new();
+        for nal in split_annex_b(access_unit).filter(|nal| nal.nal_type() != NalUnitType::Aud) {
+            if nal.data.len() <= max_payload {
+                packets.push(self.packet(&[nal.data]));
+                continue;
+            }
+            let indicator = nal.data[0] & 0xe0 | NAL_TYPE_FU_A;
+            let nal_type = nal.data[0] & 0x1f;
+            let mut chunks = nal.data[1..].chunks(max_payload - 2).peekable();
+            let mut start = 0x80;
+            while let Some(chunk) = chunks.next() {
+                let end = if chunks.peek().is_none() { 0x40 } else { 0 };
+                packets.push(self.packet(&[&[indicator, start | end | nal_type], chunk]));
+                start = 0;
+            }
+        }
+        if let Some(last) = packets.last_mut() {
+            last[1] |= 0x80;
+        }
+        packets
+    }
+
+    /// Packetizes a frame assembled from the encoder output, see `FrameAssembler`. Codec side info frames, e.g. motion
+    /// vectors, give no packets.
+    pub fn packetize_frame(&mut self, frame: &Frame) -> Vec<Vec<u8>> {
+        if frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            return Vec::new();
+        }
+        self.packetize(&frame.data, frame.pts)
+    }
+
+    fn packet(&mut self, payload: &[&[u8]]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(self.mtu);
+        packet.extend_from_slice(&[0x80, self.payload_type]);
+        packet.extend_from_slice(&self.sequence_number.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        payload.iter().for_each(|p| packet.extend_from_slice(p));
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        packet
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Reassembles H.264 access units from RTP packets, the counterpart of `RtpH264Packetizer`. Handles single NAL unit,
+/// STAP-A and FU-A packets. Fragmented NAL units with a lost fragment are dropped.
+#[derive(Debug, Default)]
+pub struct RtpH264Depacketizer {
+    access_unit: Vec<u8>,
+    timestamp: Option<u32>,
+    next_sequence_number: Option<u16>,
+    /// Whether an FU-A NAL unit is being reassembled at the end of `access_unit`, and where it starts
+    fragment_start: Option<usize>,
+}
+
+impl RtpH264Depacketizer {
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds a packet. Returns the RTP timestamp and the Annex-B access unit when the packet completes it.
+    pub fn push(&mut self, packet: &[u8]) -> Result<Option<(u32, Vec<u8>)>> {
+        let packet = RtpPacket::parse(packet)?;
+        if self.next_sequence_number.is_some_and(|n| n != packet.sequence_number) {
+            self.drop_fragment();
+        }
+        self.next_sequence_number = Some(packet.sequence_number.wrapping_add(1));
+        if self.timestamp.is_some_and(|t| t != packet.timestamp) {
+            // the marker of the previous access unit was lost
+            self.access_unit.clear();
+            self.fragment_start = None;
+        }
+        self.timestamp = Some(packet.timestamp);
+
+        let payload = packet.payload;
+        let header = *payload.first().ok_or_else(|| rtp_error("empty payload"))?;
+        match header & 0x1f {
+            1..=23 => {
+                self.drop_fragment();
+                self.append_nal(payload);
+            }
+            NAL_TYPE_STAP_A => {
+                self.drop_fragment();
+                let mut rest = &payload[1..];
+                while rest.len() >= 2 {
+                    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    let nal = rest.get(2..2 + len).ok_or_else(|| rtp_error("truncated STAP-A"))?;
+                    self.append_nal(nal);
+                    rest = &rest[2 + len..];
+                }
+            }
+            NAL_TYPE_FU_A => {
+                let fu_header = *payload.get(1).ok_or_else(|| rtp_error("truncated FU-A"))?;
+                if fu_header & 0x80 != 0 {
+                    self.drop_fragment();
+                    self.fragment_start = Some(self.access_unit.len());
+                    self.append_nal(&[header & 0xe0 | fu_header & 0x1f]);
+                }
+                if self.fragment_start.is_some() {
+                    self.access_unit.extend_from_slice(&payload[2..]);
+                    if fu_header & 0x40 != 0 {
+                        self.fragment_start = None;
+                    }
+                }
+            }
+            t => return Err(rtp_error(&format!("unsupported packet type {t}"))),
+        }
+
+        if !packet.marker {
+            return Ok(None);
+        }
+        self.drop_fragment();
+        self.timestamp = None;
+        let access_unit = std::mem::take(&mut self.access_unit);
+        Ok((!access_unit.is_empty()).then_some((packet.timestamp, access_unit)))
+    }
+
+    fn append_nal(&mut self, nal: &[u8]) {
+        self.access_unit.extend_from_slice(&[0, 0, 0, 1]);
+        self.access_unit.extend_from_slice(nal);
+    }
+
+    fn drop_fragment(&mut self) {
+        if let Some(start) = self.fragment_start.take() {
+            self.access_unit.truncate(start);
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// IDR slice NAL unit of `len` bytes without zero bytes, larger than the ones of the sample streams
+#[cfg(test)]
+fn large_idr(len: usize) -> Vec<u8> {
+    std::iter::once(0x65).chain((1..len).map(|i| (i % 251) as u8 | 1)).collect()
+}
+
+#[test]
+fn test_rtp_packetizer() {
+    let nals: Vec<_> = split_annex_b(crate::h264::SAMPLE_HIGH_1080P).collect();
+    let idr = large_idr(3001);
+    // AUD SPS PPS SEI IDR
+    let access_unit: Vec<u8> = nals[..4].iter().map(|nal| nal.data).chain([&idr[..]])
+        .flat_map(|nal| [&[0, 0, 0, 1], nal].concat())
+        .collect();
+
+    let mut packetizer = RtpH264Packetizer::new(0x1234_5678).with_mtu(12 + 1002).with_initial(65534, 1000);
+    let packets = packetizer.packetize(&access_unit, Some(Duration::from_millis(40)));
+    let parsed: Vec<_> = packets.iter().map(|p| RtpPacket::parse(p).unwrap()).collect();
+    // SPS, PPS, SEI and the IDR slice in 3 fragments
+    assert_eq!(parsed.len(), 6);
+    assert!(packets.iter().all(|p| p.len() <= 12 + 1002));
+    assert_eq!(parsed.iter().map(|p| p.sequence_number).collect::<Vec<_>>(), [65534, 65535, 0, 1, 2, 3]);
+    assert!(parsed.iter().all(|p| p.timestamp == 3600 + 1000 && p.ssrc == 0x1234_5678 && p.payload_type == 96));
+    assert_eq!(parsed.iter().map(|p| p.marker).collect::<Vec<_>>(), [false, false, false, false, false, true]);
+    assert_eq!(parsed[0].payload, nals[1].data);
+
+    let fragments = &parsed[3..];
+    assert!(fragments.iter().all(|p| p.payload[0] == 0x60 | NAL_TYPE_FU_A));
+    assert_eq!(fragments.iter().map(|p| p.payload[1]).collect::<Vec<_>>(), [0x85, 0x05, 0x45]);
+    let reassembled: Vec<u8> = fragments.iter().flat_map(|p| p.payload[2..].iter().copied()).collect();
+    assert_eq!(reassembled, idr[1..]);
+
+    // no pts: the timestamp of the previous access unit
+    let packets = packetizer.packetize(&[0, 0, 1, 0x41, 0x9a], None);
+    assert_eq!(RtpPacket::parse(&packets[0]).unwrap().timestamp, 4600);
+}
+
+#[test]
+fn test_rtp_depacketizer() {
+    let mut access_units: Vec<Vec<u8>> = split_annex_b(crate::h264::SAMPLE_HIGH_1080P)
+        .filter(|nal| nal.nal_type() != NalUnitType::Aud)
+        .map(|nal| [&[0, 0, 0, 1], nal.data].concat())
+        .collect();
+    access_units.push([&[0, 0, 0, 1], &large_idr(500)[..]].concat());
+    let mut packetizer = RtpH264Packetizer::new(1).with_mtu(100);
+    let mut depacketizer = RtpH264Depacketizer::new();
+    for (i, au) in access_units.iter().enumerate() {
+        let packets = packetizer.packetize(au, Some(Duration::from_millis(40 * i as u64)));
+        let (last, rest) = packets.split_last().unwrap();
+        for p in rest {
+            assert_eq!(depacketizer.push(p).unwrap(), None);
+        }
+        assert_eq!(depacketizer.push(last).unwrap(), Some((3600 * i as u32, au.clone())));
+    }
+
+    // a lost fragment drops the NAL unit, not the ones before it
+    let au = [&access_units[0][..], access_units.last().unwrap()].concat();
+    let mut packets = packetizer.packetize(&au, Some(Duration::ZERO));
+    assert!(packets.len() > 4);
+    packets.remove(3);
+    let rv = packets.iter().filter_map(|p| depacketizer.push(p).unwrap()).next().unwrap();
+    assert_eq!(rv.1, access_units[0]);
+
+    // STAP-A
+    let (sps, pps) = (&access_units[0][4..], &access_units[1][4..]);
+    let mut packet = vec![0x80, 0x80 | 96, 0, 1, 0, 0, 0, 9, 0, 0, 0, 1, 0x18];
+    for nal in [sps, pps] {
+        packet.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        packet.extend_from_slice(nal);
+    }
+    assert_eq!(depacketizer.push(&packet).unwrap(), Some((9, [&access_units[0][..], &access_units[1]].concat())));
+    assert!(RtpPacket::parse(&[0x40; 12]).is_err());
+}
@@ -0,0 +1,504 @@
+use super::*;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::Duration,
+};
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+pub const RTSP_DEFAULT_PORT: u16 = 8554;
+
+const RTSP_PUBLIC_METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER";
+const RTSP_SESSION_TIMEOUT: u32 = 60;
+const RTSP_MAX_HEADER_LINES: usize = 64;
+const RTSP_MAX_LINE_LEN: usize = 8 * 1024;
+const RTSP_MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Request of an RTSP client, RFC 2326 section 6
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtspRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RtspRequest {
+    /// Reads the next request. Returns `None` if the connection was closed before it.
+    pub fn read(r: &mut impl BufRead) -> Result<Option<Self>> {
+        let mut line = String::new();
+        // empty lines between requests are allowed
+        while line.trim().is_empty() {
+            line.clear();
+            if read_line(r, &mut line)? == 0 {
+                return Ok(None);
+            }
+        }
+        let mut parts = line.split_whitespace();
+        let (method, url) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(url), Some(version)) if version.starts_with("RTSP/") => (method.to_owned(), url.to_owned()),
+            _ => return Err(rtsp_error(&format!("invalid request line `{}`", line.trim()))),
+        };
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if read_line(r, &mut line)? == 0 {
+                return Err(rtsp_error("connection closed within the request headers"));
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+            if headers.len() == RTSP_MAX_HEADER_LINES {
+                return Err(rtsp_error("too many headers"));
+            }
+            let (name, value) = line.split_once(':').ok_or_else(|| rtsp_error(&format!("invalid header `{}`", line.trim())))?;
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+        let mut request = Self { method, url, headers, body: Vec::new() };
+        if let Some(len) = request.header("Content-Length") {
+            let len = len.parse().map_err(|_| rtsp_error("invalid Content-Length"))?;
+            if len > RTSP_MAX_BODY_LEN {
+                return Err(rtsp_error(&format!("body of {len} bytes too long")));
+            }
+            request.body.resize(len, 0);
+            r.read_exact(&mut request.body)?;
+        }
+        Ok(Some(request))
+    }
+
+    /// Value of the header `name`, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Session id without parameters such as `timeout`
+    pub fn session(&self) -> Option<&str> {
+        self.header("Session").map(|s| s.split(';').next().unwrap_or_default().trim())
+    }
+}
+
+/// Reads a line of up to `RTSP_MAX_LINE_LEN` bytes, the line ending included
+fn read_line(r: &mut impl BufRead, line: &mut String) -> Result<usize> {
+    let len = std::io::Read::take(r, RTSP_MAX_LINE_LEN as u64).read_line(line)?;
+    if len == RTSP_MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(rtsp_error("line too long"));
+    }
+    Ok(len)
+}
+
+fn rtsp_error(what: &str) -> MmalError {
+    MmalError::new(Cause::InvalidBitstream, format!("rtsp: {what}"))
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut rv = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            rv.push(if i <= chunk.len() { ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char } else { '=' });
+        }
+    }
+    rv
+}
+
+/// SDP of a single H.264 stream, RFC 6184 section 8.2.1
+pub fn h264_sdp(sps: &[u8], pps: &[u8], session_id: &str, origin: IpAddr, payload_type: u8) -> String {
+    let profile_level_id: String = sps.iter().skip(1).take(3).map(|b| format!("{b:02X}")).collect();
+    let ip = if origin.is_ipv4() { "IP4" } else { "IP6" };
+    format!(
+        "v=0\r\no=- {session_id} 1 IN {ip} {origin}\r\ns=mmal-rs\r\nc=IN {ip} {unspecified}\r\nt=0 0\r\n\
+        m=video 0 RTP/AVP {payload_type}\r\na=rtpmap:{payload_type} H264/{RTP_H264_CLOCK_RATE}\r\n\
+        a=fmtp:{payload_type} packetization-mode=1;profile-level-id={profile_level_id};sprop-parameter-sets={},{}\r\n\
+        a=control:trackID=0\r\n",
+        base64(sps), base64(pps), unspecified = if origin.is_ipv4() { "0.0.0.0" } else { "::" },
+    )
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+struct RtspSession {
+    id: String,
+    socket: UdpSocket,
+    destination: SocketAddr,
+    packetizer: RtpH264Packetizer,
+    playing: bool,
+    waiting_keyframe: bool,
+}
+
+#[derive(Default)]
+struct RtspShared {
+    sps: Mutex<Option<Vec<u8>>>,
+    pps: Mutex<Option<Vec<u8>>>,
+    sessions: Mutex<Vec<RtspSession>>,
+    random_state: Mutex<u64>,
+    stop: AtomicBool,
+}
+
+/// Minimal RTSP server streaming H.264 over RTP/UDP unicast, e.g. to VLC, ffplay or GStreamer's `rtspsrc`.
+///
+/// Supports OPTIONS, DESCRIBE, SETUP, PLAY and TEARDOWN, plus GET_PARAMETER as keep-alive, with a single stream
+/// at any URL. Connections are served by their own threads; frames are sent with `send_frame` from the thread which
+/// reads the encoder output. A client starts receiving at the next keyframe, preceded by SPS/PPS if the keyframe has
+/// none. DESCRIBE is answered with `503 Service Unavailable` until the encoder has sent its parameter sets. Sessions end
+/// with TEARDOWN or when the RTSP connection is closed. There is no RTCP and no interleaved transport.
+pub struct RtspServer {
+    shared: Arc<RtspShared>,
+    local_addr: SocketAddr,
+}
+
+impl RtspServer {
+    /// Listens on `addr`, e.g. `0.0.0.0:8554`
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let seed = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let shared = Arc::new(RtspShared { random_state: Mutex::new(seed | 1), ..Default::default() });
+
+        let accept_shared = shared.clone();
+        std::thread::spawn(move || {
+            while !accept_shared.stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let shared = accept_shared.clone();
+                        std::thread::spawn(move || shared.serve(stream));
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(20)),
+                    Err(_) => { }
+                }
+            }
+        });
+        Ok(Self { shared, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr { self.local_addr }
+
+    /// Number of sessions in the PLAY state
+    pub fn playing(&self) -> usize {
+        self.shared.sessions.lock().unwrap_or_else(|e| e.into_inner()).iter().filter(|s| s.playing).count()
+    }
+
+    /// Sends an access unit in Annex-B format to the playing clients. Codec config frames only update the parameter sets.
+    ///
+    /// Sending is best effort: errors of individual clients are ignored, the session ends with their RTSP connection.
+    pub fn send(&self, access_unit: &[u8], pts: Option<Duration>) {
+        let (mut keyframe, mut has_sps) = (false, false);
+        for nal in split_annex_b(access_unit) {
+            match nal.nal_type() {
+                NalUnitType::Sps => {
+                    has_sps = true;
+                    *self.shared.sps.lock().unwrap_or_else(|e| e.into_inner()) = Some(nal.data.to_vec());
+                }
+                NalUnitType::Pps => *self.shared.pps.lock().unwrap_or_else(|e| e.into_inner()) = Some(nal.data.to_vec()),
+                t => keyframe |= t == NalUnitType::IdrSlice,
+            }
+        }
+        let mut with_parameter_sets = Vec::new();
+        if keyframe && !has_sps {
+            for ps in [&self.shared.sps, &self.shared.pps] {
+                if let Some(ps) = ps.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+                    with_parameter_sets.extend_from_slice(&[0, 0, 0, 1]);
+                    with_parameter_sets.extend_from_slice(ps);
+                }
+            }
+            with_parameter_sets.extend_from_slice(access_unit);
+        }
+        let access_unit = if with_parameter_sets.is_empty() { access_unit } else { &with_parameter_sets };
+
+        let mut sessions = self.shared.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        for session in sessions.iter_mut().filter(|s| s.playing) {
+            if session.waiting_keyframe && !keyframe {
+                continue;
+            }
+            session.waiting_keyframe = false;
+            for packet in session.packetizer.packetize(access_unit, pts) {
+                let _ = session.socket.send_to(&packet, session.destination);
+            }
+        }
+    }
+
+    /// Sends a frame assembled from the encoder output, see `FrameAssembler`. Codec side info frames, e.g. motion
+    /// vectors, are not sent.
+    pub fn send_frame(&self, frame: &Frame) {
+        if frame.flags.test_one(FrameFlags::FLAG_CODECSIDEINFO) {
+            return;
+        }
+        self.send(&frame.data, frame.pts)
+    }
+}
+
+impl Drop for RtspServer {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.shared.sessions.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+impl RtspShared {
+    fn serve(&self, stream: TcpStream) {
+        let mut own_sessions = Vec::new();
+        let _ = self.serve_requests(stream, &mut own_sessions);
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner()).retain(|s| !own_sessions.contains(&s.id));
+    }
+
+    fn serve_requests(&self, mut stream: TcpStream, own_sessions: &mut Vec<String>) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        let (peer, local) = (stream.peer_addr()?, stream.local_addr()?);
+        let mut reader = BufReader::new(stream.try_clone()?);
+        while let Some(request) = RtspRequest::read(&mut reader)? {
+            let response = self.handle(&request, peer, local, own_sessions);
+            stream.write_all(response.as_bytes())?;
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, request: &RtspRequest, peer: SocketAddr, local: SocketAddr, own_sessions: &mut Vec<String>) -> String {
+        let Some(cseq) = request.header("CSeq") else {
+            return rtsp_response(None, "400 Bad Request", &[], "");
+        };
+        let cseq = Some(cseq);
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let session = request.session().and_then(|id| sessions.iter_mut().find(|s| s.id == id));
+
+        match request.method.as_str() {
+            "OPTIONS" => rtsp_response(cseq, "200 OK", &[("Public", RTSP_PUBLIC_METHODS.to_owned())], ""),
+            "DESCRIBE" => {
+                let sps = self.sps.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let pps = self.pps.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let (Some(sps), Some(pps)) = (sps, pps) else {
+                    return rtsp_response(cseq, "503 Service Unavailable", &[("Retry-After", "1".to_owned())], "");
+                };
+                let sdp = h264_sdp(&sps, &pps, &format!("{}", self.random() as u32), local.ip(), RTP_H264_PAYLOAD_TYPE);
+                let base = if request.url.ends_with('/') { request.url.clone() } else { format!("{}/", request.url) };
+                rtsp_response(cseq, "200 OK", &[("Content-Base", base), ("Content-Type", "application/sdp".to_owned())], &sdp)
+            }
+            "SETUP" => {
+                let transport = request.header("Transport").unwrap_or_default();
+                let client_port = transport.split(';').find_map(|p| p.trim().strip_prefix("client_port="));
+                let rtp_port = client_port.and_then(|p| p.split('-').next()).and_then(|p| p.parse::<u16>().ok());
+                let Some(rtp_port) = rtp_port.filter(|_| transport.starts_with("RTP/AVP") && !transport.contains("TCP")) else {
+                    return rtsp_response(cseq, "461 Unsupported Transport", &[], "");
+                };
+                let bind_addr: SocketAddr = if peer.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+                let socket = match UdpSocket::bind(bind_addr) {
+                    Ok(socket) => socket,
+                    Err(_) => return rtsp_response(cseq, "500 Internal Server Error", &[], ""),
+                };
+                let (ssrc, initial) = (self.random() as u32, self.random());
+                let packetizer = RtpH264Packetizer::new(ssrc).with_initial(initial as u16, (initial >> 32) as u32);
+                let id = format!("{:016X}", self.random());
+                let transport = format!("RTP/AVP;unicast;client_port={};ssrc={ssrc:08X}", client_port.unwrap_or_default());
+                let response = rtsp_response(cseq, "200 OK", &[
+                    ("Transport", transport), ("Session", format!("{id};timeout={RTSP_SESSION_TIMEOUT}")),
+                ], "");
+                own_sessions.push(id.clone());
+                sessions.push(RtspSession {
+                    id, socket, destination: SocketAddr::new(peer.ip(), rtp_port), packetizer, playing: false, waiting_keyframe: true,
+                });
+                response
+            }
+            "PLAY" => match session {
+                Some(session) => {
+                    session.playing = true;
+                    let rtp_info = format!("url={};seq={}", request.url, session.packetizer.sequence_number());
+                    rtsp_response(cseq, "200 OK", &[
+                        ("Session", session.id.clone()), ("Range", "npt=0.000-".to_owned()), ("RTP-Info", rtp_info),
+                    ], "")
+                }
+                None => rtsp_response(cseq, "454 Session Not Found", &[], ""),
+            },
+            "TEARDOWN" => match session {
+                Some(_) => {
+                    let id = request.session().unwrap_or_default();
+                    sessions.retain(|s| s.id != id);
+                    own_sessions.retain(|s| s != id);
+                    rtsp_response(cseq, "200 OK", &[], "")
+                }
+                None => rtsp_response(cseq, "454 Session Not Found", &[], ""),
+            },
+            "GET_PARAMETER" => rtsp_response(cseq, "200 OK", &[], ""),
+            _ => rtsp_response(cseq, "501 Not Implemented", &[], ""),
+        }
+    }
+
+    /// xorshift64, good enough for session ids, SSRCs and initial sequence numbers
+    fn random(&self) -> u64 {
+        let mut state = self.random_state.lock().unwrap_or_else(|e| e.into_inner());
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+}
+
+fn rtsp_response(cseq: Option<&str>, status: &str, headers: &[(&str, String)], body: &str) -> String {
+    let mut rv = format!("RTSP/1.0 {status}\r\n");
+    if let Some(cseq) = cseq {
+        rv += &format!("CSeq: {cseq}\r\n");
+    }
+    for (name, value) in headers {
+        rv += &format!("{name}: {value}\r\n");
+    }
+    if !body.is_empty() {
+        rv += &format!("Content-Length: {}\r\n", body.len());
+    }
+    rv += "\r\n";
+    rv + body
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    assert_eq!(base64(&[0x67, 0x42, 0xc0, 0x1e, 0xff]), "Z0LAHv8=");
+}
+
+#[test]
+fn test_rtsp_request() {
+    let data = b"\r\nANNOUNCE rtsp://cam/stream RTSP/1.0\r\nCSeq: 7\r\nsession: 1234;timeout=60\r\nContent-Length: 4\r\n\r\nv=0\nOPTIONS";
+    let mut reader = std::io::Cursor::new(&data[..]);
+    let request = RtspRequest::read(&mut reader).unwrap().unwrap();
+    assert_eq!((request.method.as_str(), request.url.as_str()), ("ANNOUNCE", "rtsp://cam/stream"));
+    assert_eq!((request.header("cseq"), request.session()), (Some("7"), Some("1234")));
+    assert_eq!(request.body, b"v=0\n");
+    assert!(RtspRequest::read(&mut reader).is_err());
+    assert_eq!(RtspRequest::read(&mut std::io::Cursor::new(&b"\r\n"[..])).unwrap(), None);
+
+    let invalid = |data: &[u8]| matches!(RtspRequest::read(&mut BufReader::new(data)).unwrap_err().cause(), Cause::InvalidBitstream);
+    assert!(invalid(b"ANNOUNCE * RTSP/1.0\r\nContent-Length: 18446744073709551615\r\n\r\n"));
+    assert!(invalid(format!("ANNOUNCE * RTSP/1.0\r\nContent-Length: {}\r\n\r\n", RTSP_MAX_BODY_LEN + 1).as_bytes()));
+    assert!(invalid(format!("OPTIONS * RTSP/1.0\r\nX: {}\r\n\r\n", "a".repeat(RTSP_MAX_LINE_LEN)).as_bytes()));
+    // a line without end is not read whole
+    let mut endless = BufReader::new(std::io::Read::take(std::io::repeat(b'a'), 1 << 40));
+    assert!(matches!(RtspRequest::read(&mut endless).unwrap_err().cause(), Cause::InvalidBitstream));
+}
+
+/// RTSP client for the tests, returns the status code, headers and body of each response
+#[cfg(test)]
+struct LoopbackClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    cseq: u32,
+}
+
+#[cfg(test)]
+impl LoopbackClient {
+    fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        Self { reader: BufReader::new(stream.try_clone().unwrap()), stream, cseq: 0 }
+    }
+
+    fn request(&mut self, method: &str, url: &str, headers: &[(&str, &str)]) -> (u32, Vec<(String, String)>, String) {
+        self.cseq += 1;
+        let mut request = format!("{method} {url} RTSP/1.0\r\nCSeq: {}\r\n", self.cseq);
+        for (name, value) in headers {
+            request += &format!("{name}: {value}\r\n");
+        }
+        self.stream.write_all(format!("{request}\r\n").as_bytes()).unwrap();
+
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            self.reader.read_line(&mut line).unwrap();
+            match line.trim().split_once(':') {
+                Some((name, value)) => headers.push((name.to_owned(), value.trim().to_owned())),
+                None => break,
+            }
+        }
+        assert!(headers.contains(&("CSeq".to_owned(), self.cseq.to_string())));
+        let len = headers.iter().find(|(n, _)| n == "Content-Length").map_or(0, |(_, v)| v.parse().unwrap());
+        let mut body = vec![0; len];
+        std::io::Read::read_exact(&mut self.reader, &mut body).unwrap();
+        (status, headers, String::from_utf8(body).unwrap())
+    }
+}
+
+#[cfg(test)]
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+    headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str()).unwrap_or_else(|| panic!("no {name} header"))
+}
+
+#[test]
+fn test_rtsp_server_loopback() {
+    let server = RtspServer::bind("127.0.0.1:0").unwrap();
+    let url = format!("rtsp://{}/camera", server.local_addr());
+    let mut client = LoopbackClient::connect(server.local_addr());
+
+    let (status, headers, _) = client.request("OPTIONS", &url, &[]);
+    assert_eq!(status, 200);
+    assert!(header(&headers, "Public").contains("DESCRIBE"));
+    // no parameter sets yet
+    assert_eq!(client.request("DESCRIBE", &url, &[]).0, 503);
+
+    let mut nals = split_annex_b(crate::h264::SAMPLE_BASELINE_240P);
+    let (sps, pps) = (nals.next().unwrap().data, nals.next().unwrap().data);
+    let slices: Vec<_> = nals.map(|nal| [&[0, 0, 0, 1], nal.data].concat()).collect();
+    server.send(&[&[0, 0, 0, 1], sps, &[0, 0, 0, 1], pps].concat(), None);
+
+    let (status, headers, sdp) = client.request("DESCRIBE", &url, &[("Accept", "application/sdp")]);
+    assert_eq!(status, 200);
+    assert_eq!(header(&headers, "Content-Type"), "application/sdp");
+    assert!(sdp.contains("a=rtpmap:96 H264/90000\r\n"));
+    assert!(sdp.contains(&format!("profile-level-id=42C00D;sprop-parameter-sets={},{}\r\n", base64(sps), base64(pps))));
+
+    let rtp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    rtp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let port = rtp.local_addr().unwrap().port();
+    let transport = format!("RTP/AVP;unicast;client_port={}-{}", port, port + 1);
+    assert_eq!(client.request("SETUP", &format!("{url}/trackID=0"), &[("Transport", "RTP/AVP/TCP;interleaved=0-1")]).0, 461);
+    let (status, headers, _) = client.request("SETUP", &format!("{url}/trackID=0"), &[("Transport", &transport)]);
+    assert_eq!(status, 200);
+    assert!(header(&headers, "Transport").starts_with(&transport));
+    let session = header(&headers, "Session").split(';').next().unwrap().to_owned();
+
+    assert_eq!(client.request("PLAY", &url, &[("Session", "nope")]).0, 454);
+    assert_eq!(client.request("PLAY", &url, &[("Session", &session)]).0, 200);
+    assert_eq!(server.playing(), 1);
+
+    // the P slice before the first keyframe is not sent
+    server.send(&slices[1], Some(Duration::from_millis(0)));
+    for (i, slice) in slices.iter().enumerate() {
+        server.send(slice, Some(Duration::from_millis(100 + 40 * i as u64)));
+    }
+    let mut depacketizer = RtpH264Depacketizer::new();
+    let mut received = Vec::new();
+    let mut buf = [0; 2048];
+    while received.len() < slices.len() {
+        let len = rtp.recv(&mut buf).unwrap();
+        received.extend(depacketizer.push(&buf[..len]).unwrap());
+    }
+    // parameter sets are repeated before the keyframe
+    assert_eq!(received[0].1, [&[0, 0, 0, 1], sps, &[0, 0, 0, 1], pps, &slices[0]].concat());
+    assert_eq!(received[1].1, slices[1]);
+    assert_eq!(received[4].0.wrapping_sub(received[0].0), 4 * 3600);
+
+    assert_eq!(client.request("GET_PARAMETER", &url, &[("Session", &session)]).0, 200);
+    assert_eq!(client.request("RECORD", &url, &[("Session", &session)]).0, 501);
+    assert_eq!(client.request("TEARDOWN", &url, &[("Session", &session)]).0, 200);
+    assert_eq!(server.playing(), 0);
+
+    // sessions end with their connection
+    let mut client = LoopbackClient::connect(server.local_addr());
+    let session = header(&client.request("SETUP", &url, &[("Transport", &transport)]).1, "Session").split(';').next().unwrap().to_owned();
+    client.request("PLAY", &url, &[("Session", &session)]);
+    assert_eq!(server.playing(), 1);
+    drop(client);
+    let start = std::time::Instant::now();
+    while server.playing() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,117 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+pub struct SplitterEntity;
+
+impl Entity for SplitterEntity {
+    fn name() -> &'static str { "splitter" }
+}
+
+impl ComponentEntity for SplitterEntity { }
+
+pub type SplitterComponentHandle = ComponentHandle<SplitterEntity>;
+
+impl SplitterComponentHandle {
+    pub fn create() -> Result<Self> {
+        let component_name: *const c_char = ffi::MMAL_COMPONENT_DEFAULT_VIDEO_SPLITTER.as_ptr() as *const c_char;
+        unsafe {
+            Self::create_from(component_name)
+        }
+    }
+}
+
+/// Number of splitter output ports
+pub const SPLITTER_OUTPUT_PORTS: usize = 4;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+pub struct SplitterInputPort;
+impl ComponentPort for SplitterInputPort {
+    type E = SplitterEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.input_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "splitter input port" }
+
+    fn accepted_encodings() -> Option<&'static [u32]> { Some(RAW_PICTURE_ENCODINGS) }
+}
+
+/// Splitter output port `N`, `N` = 0..3
+pub struct SplitterOutputPort<const N: usize>;
+
+impl<const N: usize> SplitterOutputPort<N> {
+    const INDEX: isize = {
+        assert!(N < SPLITTER_OUTPUT_PORTS, "splitter output port index out of range");
+        N as isize
+    };
+}
+
+impl<const N: usize> ComponentPort for SplitterOutputPort<N> {
+    type E = SplitterEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.output_port_n(Self::INDEX)
+    }
+
+    fn name() -> &'static str {
+        ["splitter output port 0", "splitter output port 1", "splitter output port 2", "splitter output port 3"][Self::INDEX as usize]
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Splitter output port configuration
+///
+/// The format is copied from the input port, which should be configured first, e.g. with the `GenericPortConfig`
+/// of the port feeding the splitter. Each output may then be converted to a different raw encoding.
+#[derive(Debug, Clone, Copy)]
+pub struct SplitterOutFormat {
+    /// Output encoding, `None` to keep the input one
+    pub encoding: Option<u32>,
+    pub buffer_count_policy: BufferCountPolicy,
+}
+
+impl Default for SplitterOutFormat {
+    fn default() -> Self { Self {
+        encoding: None,
+        buffer_count_policy: BufferCountPolicy::Recommended,
+    } }
+}
+
+impl From<u32> for SplitterOutFormat {
+    fn from(encoding: u32) -> Self { Self { encoding: Some(encoding), ..Default::default() } }
+}
+
+impl PortConfig for SplitterOutFormat {
+    unsafe fn apply_format(&self, port: *mut ffi::MMAL_PORT_T) {
+        let input = *(*(*port).component).input;
+        ffi::mmal_format_copy((*port).format, (*input).format);
+        if let Some(encoding) = self.encoding {
+            let format = &mut (*(*port).format);
+            // On firmware prior to June 2016, camera and video_splitter
+            // had BGR24 and RGB24 support reversed.
+            format.encoding = fix_encoding(port, encoding);
+            format.encoding_variant = 0;
+        }
+    }
+
+    unsafe fn apply_buffer_policy(&self, port: *mut ffi::MMAL_PORT_T) {
+        let port = &mut *port;
+
+        port.buffer_num = match self.buffer_count_policy {
+            BufferCountPolicy::Recommended => port.buffer_num_recommended,
+            BufferCountPolicy::Explicit(n) => n,
+        };
+        if port.buffer_num < port.buffer_num_min {
+            port.buffer_num = port.buffer_num_min;
+        }
+
+        port.buffer_size = port.buffer_size_recommended;
+        if port.buffer_size < port.buffer_size_min {
+            port.buffer_size = port.buffer_size_min;
+        }
+    }
+}
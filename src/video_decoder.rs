@@ -0,0 +1,125 @@
+use super::*;
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+pub struct VideoDecoderEntity;
+
+impl Entity for VideoDecoderEntity {
+    fn name() -> &'static str { "video_decoder" }
+}
+
+impl ComponentEntity for VideoDecoderEntity { }
+
+pub type VideoDecoderComponentHandle = ComponentHandle<VideoDecoderEntity>;
+
+impl VideoDecoderComponentHandle {
+    pub fn create() -> Result<Self> {
+        let component_name: *const c_char = ffi::MMAL_COMPONENT_DEFAULT_VIDEO_DECODER.as_ptr() as *const c_char;
+        unsafe {
+            Self::create_from(component_name)
+        }
+    }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Video decoder input port, fed with `SourceAggregate`
+pub struct VideoDecoderInputPort;
+impl ComponentPort for VideoDecoderInputPort {
+    type E = VideoDecoderEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.input_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "video_decoder input port" }
+
+    fn accepted_encodings() -> Option<&'static [u32]> { Some(&[ffi::MMAL_ENCODING_H264, ffi::MMAL_ENCODING_MJPEG]) }
+}
+
+/// Video decoder output port, producing I420 frames by default.
+///
+/// The decoder may send a `MMAL_EVENT_FORMAT_CHANGED` buffer through this port once it has parsed the stream
+/// (see `FrameMeta::cmd`), in which case the port has to be reconfigured.
+pub struct VideoDecoderOutputPort;
+impl ComponentPort for VideoDecoderOutputPort {
+    type E = VideoDecoderEntity;
+
+    unsafe fn get_port(component: &ComponentHandle<Self::E>) -> *mut ffi::MMAL_PORT_T {
+        component.output_port_n(DEFAULT_PORT_OFFSET)
+    }
+
+    fn name() -> &'static str { "video_decoder output port" }
+}
+
+//------------------------------------------------------------------------------------------------------------------------------
+
+/// Video decoder input port configuration
+#[derive(Debug, Clone, Copy)]
+pub struct VideoDecoderInFormat {
+    /// `MMAL_ENCODING_H264` or `MMAL_ENCODING_MJPEG`
+    pub encoding: u32,
+    /// Picture size of the stream
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate_num: i32,
+    pub frame_rate_den: i32,
+    pub buffer_count_policy: BufferCountPolicy,
+    pub buffer_size_policy: BufferSizePolicy,
+}
+
+impl VideoDecoderInFormat {
+    pub fn new(encoding: u32, width: u32, height: u32) -> Self {
+        Self { encoding, width, height, ..Default::default() }
+    }
+}
+
+impl Default for VideoDecoderInFormat {
+    fn default() -> Self { Self {
+        encoding: ffi::MMAL_ENCODING_H264,
+        width: 0,
+        height: 0,
+        frame_rate_num: 0,
+        frame_rate_den: 1,
+        buffer_count_policy: BufferCountPolicy::Recommended,
+        buffer_size_policy: BufferSizePolicy::Recommended,
+    } }
+}
+
+impl PortConfig for VideoDecoderInFormat {
+    unsafe fn apply_format(&self, port: *mut ffi::MMAL_PORT_T) {
+        let format = &mut (*(*port).format);
+        format.encoding = self.encoding;
+        format.encoding_variant = 0;
+
+        let es = &mut (*format.es);
+        es.video.width = ffi::vcos_align_up(self.width, 32);
+        es.video.height = ffi::vcos_align_up(self.height, 16);
+        es.video.crop.x = 0;
+        es.video.crop.y = 0;
+        es.video.crop.width = self.width as i32;
+        es.video.crop.height = self.height as i32;
+        es.video.frame_rate.num = self.frame_rate_num;
+        es.video.frame_rate.den = self.frame_rate_den;
+    }
+
+    unsafe fn apply_buffer_policy(&self, port: *mut ffi::MMAL_PORT_T) {
+        let port = &mut *port;
+
+        port.buffer_num = match self.buffer_count_policy {
+            BufferCountPolicy::Recommended => port.buffer_num_recommended,
+            BufferCountPolicy::Explicit(n) => n,
+        };
+        if port.buffer_num < port.buffer_num_min {
+            port.buffer_num = port.buffer_num_min;
+        }
+
+        port.buffer_size = match self.buffer_size_policy {
+            BufferSizePolicy::Recommended => port.buffer_size_recommended,
+            BufferSizePolicy::Explicit(n) => n,
+        };
+        if port.buffer_size < port.buffer_size_min {
+            port.buffer_size = port.buffer_size_min;
+        }
+    }
+}
@@ -37,6 +37,8 @@ impl ComponentPort for VideoEncoderInputPort {
     }
 
     fn name() -> &'static str { "video_encoder input port" }
+
+    fn accepted_encodings() -> Option<&'static [u32]> { Some(RAW_PICTURE_ENCODINGS) }
 }
 
 pub struct VideoEncoderOutputPort;
@@ -229,6 +231,155 @@ impl TryInto<(VideoProfile, VideoLevel)> for &'_ VideoProfileInnerType {
 /// Video profile
 pub type PVideoProfile = Param<VideoEncoderOutputPort, VideoProfileInnerType>;
 
+idp!{MMAL_PARAMETER_INTRAPERIOD}
+/// Number of frames between I-frames (IDR), 0 for the encoder default
+pub type PIntraPeriod = Param<VideoEncoderOutputPort, Uint32<MMAL_PARAMETER_INTRAPERIOD>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_INITIAL_QUANT}
+/// Quantisation parameter of the first frame, 0 for the encoder default
+pub type PInitialQuant = Param<VideoEncoderOutputPort, Uint32<MMAL_PARAMETER_VIDEO_ENCODE_INITIAL_QUANT>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_MIN_QUANT}
+/// Lowest quantisation parameter the rate control may use, 0 for the encoder default
+pub type PMinQuant = Param<VideoEncoderOutputPort, Uint32<MMAL_PARAMETER_VIDEO_ENCODE_MIN_QUANT>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_MAX_QUANT}
+/// Highest quantisation parameter the rate control may use, 0 for the encoder default.
+/// Setting initial, min and max to the same value with a zero bitrate gives constant QP output.
+pub type PMaxQuant = Param<VideoEncoderOutputPort, Uint32<MMAL_PARAMETER_VIDEO_ENCODE_MAX_QUANT>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_INLINE_HEADER}
+/// Repeat SPS/PPS in front of every I-frame, so that the stream can be joined mid-way (default false)
+pub type PInlineHeader = Param<VideoEncoderOutputPort, Boolean<MMAL_PARAMETER_VIDEO_ENCODE_INLINE_HEADER>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_SPS_TIMING}
+/// Include VUI timing information (frame rate) in the SPS (default false)
+pub type PSpsTiming = Param<VideoEncoderOutputPort, Boolean<MMAL_PARAMETER_VIDEO_ENCODE_SPS_TIMING>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_INLINE_VECTORS}
+/// Output the motion vectors of each frame after it, in a buffer flagged with `MMAL_BUFFER_HEADER_FLAG_CODECSIDEINFO`
+/// (default false)
+pub type PInlineVectors = Param<VideoEncoderOutputPort, Boolean<MMAL_PARAMETER_VIDEO_ENCODE_INLINE_VECTORS>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_H264_DEBLOCK_IDC}
+/// H.264 deblocking filter: 0 enabled (default), 1 disabled, 2 disabled across slice boundaries
+pub type PDeblockIdc = Param<VideoEncoderOutputPort, Uint32<MMAL_PARAMETER_VIDEO_ENCODE_H264_DEBLOCK_IDC>>;
+
+idp!{MMAL_PARAMETER_VIDEO_REQUEST_I_FRAME}
+/// Write `true` while encoding to make the next frame an I-frame
+pub type PRequestIFrame = Param<VideoEncoderOutputPort, Boolean<MMAL_PARAMETER_VIDEO_REQUEST_I_FRAME>>;
+
+enumize!{RateControl,
+    Default => MMAL_VIDEO_RATECONTROL_T_MMAL_VIDEO_RATECONTROL_DEFAULT,
+    Variable => MMAL_VIDEO_RATECONTROL_T_MMAL_VIDEO_RATECONTROL_VARIABLE,
+    Constant => MMAL_VIDEO_RATECONTROL_T_MMAL_VIDEO_RATECONTROL_CONSTANT,
+    VariableSkipFrames => MMAL_VIDEO_RATECONTROL_T_MMAL_VIDEO_RATECONTROL_VARIABLE_SKIP_FRAMES,
+    ConstantSkipFrames => MMAL_VIDEO_RATECONTROL_T_MMAL_VIDEO_RATECONTROL_CONSTANT_SKIP_FRAMES
+}
+enumerated_inner_type!{RateControlInnerType, RateControl, MMAL_PARAMETER_VIDEO_RATECONTROL_T, MMAL_PARAMETER_RATECONTROL, control}
+/// Rate control mode
+///
+/// Native type: `MMAL_PARAMETER_VIDEO_RATECONTROL_T`
+pub type PRateControl = Param<VideoEncoderOutputPort, RateControlInnerType>;
+
+enumize!{IntraRefreshType,
+    Cyclic => MMAL_VIDEO_INTRA_REFRESH_T_MMAL_VIDEO_INTRA_REFRESH_CYCLIC,
+    Adaptive => MMAL_VIDEO_INTRA_REFRESH_T_MMAL_VIDEO_INTRA_REFRESH_ADAPTIVE,
+    Both => MMAL_VIDEO_INTRA_REFRESH_T_MMAL_VIDEO_INTRA_REFRESH_BOTH,
+    CyclicMrows => MMAL_VIDEO_INTRA_REFRESH_T_MMAL_VIDEO_INTRA_REFRESH_CYCLIC_MROWS
+}
+
+pub struct IntraRefreshInnerType {
+    inner: ffi::MMAL_PARAMETER_VIDEO_INTRA_REFRESH_T,
+}
+
+impl_inner_param_default!{IntraRefreshInnerType, MMAL_PARAMETER_VIDEO_INTRA_REFRESH_T, MMAL_PARAMETER_VIDEO_INTRA_REFRESH}
+impl_inner_param_type!{IntraRefreshInnerType}
+
+/// Sets the refresh type, keeping the macroblock counts. Read the parameter first to start from the firmware defaults,
+/// as raspivid does.
+impl Apply<IntraRefreshType> for IntraRefreshInnerType {
+    fn apply(&mut self, source: IntraRefreshType) {
+        self.inner.refresh_mode = source as u32;
+    }
+}
+
+impl TryFrom<&IntraRefreshInnerType> for IntraRefreshType {
+    type Error = MmalError;
+    fn try_from(a: &IntraRefreshInnerType) -> Result<IntraRefreshType> {
+        a.inner.refresh_mode.try_into()
+    }
+}
+
+impl IntraRefreshInnerType {
+    /// Adaptive, cyclic and pseudo-random refresh macroblock counts
+    pub fn set_mbs(&mut self, air_mbs: u32, air_ref: u32, cir_mbs: u32, pir_mbs: u32) {
+        self.inner.air_mbs = air_mbs;
+        self.inner.air_ref = air_ref;
+        self.inner.cir_mbs = cir_mbs;
+        self.inner.pir_mbs = pir_mbs;
+    }
+}
+
+/// Intra refresh: spreads intra coded macroblocks over several frames instead of sending periodic I-frames
+///
+/// Native type: `MMAL_PARAMETER_VIDEO_INTRA_REFRESH_T`
+pub type PIntraRefresh = Param<VideoEncoderOutputPort, IntraRefreshInnerType>;
+
+idp!{MMAL_PARAMETER_VIDEO_BIT_RATE}
+/// Target bitrate in bits per second, may be changed while encoding
+pub type PBitrate = Param<VideoEncoderOutputPort, Uint32<MMAL_PARAMETER_VIDEO_BIT_RATE>>;
+
+idp!{MMAL_PARAMETER_VIDEO_FRAME_RATE}
+/// Frame rate the rate control budgets for, (num, den), may be changed while encoding
+pub type PVideoFrameRate = Param<VideoEncoderOutputPort, Rational<MMAL_PARAMETER_VIDEO_FRAME_RATE>>;
+
+idp!{MMAL_PARAMETER_VIDEO_ENCODE_QP_P}
+/// Quantisation parameter of P-frames, may be changed while encoding. Takes effect with a zero bitrate.
+pub type PQpP = Param<VideoEncoderOutputPort, Uint32<MMAL_PARAMETER_VIDEO_ENCODE_QP_P>>;
+
+/// Settings that can be changed on a running encoder, see `VideoEncoderOutputPort::update`.
+/// `None` leaves the setting unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VideoEncoderUpdate {
+    pub bitrate: Option<u32>,
+    pub frame_rate: Option<(i32, i32)>,
+    /// Minimum and maximum quantisation parameter
+    pub quant: Option<(u32, u32)>,
+    /// Quantisation parameter of P-frames
+    pub qp_p: Option<u32>,
+    /// Make the next frame an IDR frame, e.g. so that the new settings start with a clean picture
+    pub force_idr: bool,
+}
+
+impl VideoEncoderOutputPort {
+    /// Applies `update` to the encoder, which may be enabled and encoding
+    pub fn update(encoder: impl AsRef<VideoEncoderComponentHandle>, update: &VideoEncoderUpdate) -> Result<()> {
+        let encoder = encoder.as_ref();
+        if let Some(bitrate) = update.bitrate {
+            Self::write(encoder, &PBitrate::from(bitrate))?;
+        }
+        if let Some(frame_rate) = update.frame_rate {
+            Self::write(encoder, &PVideoFrameRate::from(frame_rate))?;
+        }
+        if let Some((min, max)) = update.quant {
+            Self::write_multi(encoder, param_iter![&PMinQuant::from(min), &PMaxQuant::from(max)])?;
+        }
+        if let Some(qp) = update.qp_p {
+            Self::write(encoder, &PQpP::from(qp))?;
+        }
+        if update.force_idr {
+            Self::force_idr(encoder)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the next encoded frame an IDR frame
+    pub fn force_idr(encoder: impl AsRef<VideoEncoderComponentHandle>) -> Result<()> {
+        Self::write(encoder, &PRequestIFrame::from(true))
+    }
+}
+
 //------------------------------------------------------------------------------------------------------------------------------
 pub struct VideoEncoderOutFormat {
     pub encoding: u32,